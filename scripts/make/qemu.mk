@@ -4,6 +4,7 @@ QEMU := qemu-system-$(ARCH)
 
 TELNET_PORT ?= 4321
 SECOND_SERIAL ?= n
+GIC_VERSION ?= 2
 
 ifeq ($(BUS), mmio)
   vdev-suffix := device
@@ -24,7 +25,7 @@ qemu_args-riscv64 := \
 
 qemu_args-aarch64 := \
   -cpu cortex-a72 \
-  -machine virt,virtualization=on,gic-version=2 \
+  -machine virt,virtualization=on,gic-version=$(GIC_VERSION) \
   -kernel $(OUT_BIN)
 
 qemu_args-y := -m $(MEM) -smp $(SMP) $(qemu_args-$(ARCH))
@@ -66,7 +67,7 @@ ifeq ($(GRAPHIC), n)
 endif 
 
 ifeq ($(ARCH), aarch64)
-  qemu_args-y += -machine virtualization=on,gic-version=2
+  qemu_args-y += -machine virtualization=on,gic-version=$(GIC_VERSION)
 endif
 
 ifeq ($(QEMU_LOG), y)
@@ -114,6 +114,9 @@ pub(crate) fn enable_virtualization() {
                 .hardware_enable()
                 .expect("Failed to enable virtualization");
 
+            #[cfg(target_arch = "aarch64")]
+            vmm::init_vgic_percpu();
+
             info!("Hardware virtualization support enabled on core {}", cpu_id);
 
             let _ = CORES.fetch_add(1, Ordering::Release);
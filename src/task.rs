@@ -1,6 +1,6 @@
 use std::os::arceos::modules::axtask::def_task_ext;
 
-use crate::vmm::{VCpuRef, VMRef};
+use crate::vmm::{VCpuRef, VMRef, VirtTimer};
 
 /// Task extended data for the hypervisor.
 pub struct TaskExt {
@@ -8,11 +8,14 @@ pub struct TaskExt {
     pub vm: VMRef,
     /// The virtual memory address space.
     pub vcpu: VCpuRef,
+    /// The emulated guest timer of the vCPU.
+    pub vtimer: VirtTimer,
 }
 
 impl TaskExt {
-    pub const fn new(vm: VMRef, vcpu: VCpuRef) -> Self {
-        Self { vm, vcpu }
+    pub fn new(vm: VMRef, vcpu: VCpuRef) -> Self {
+        let vtimer = VirtTimer::new(vm.id(), vcpu.id());
+        Self { vm, vcpu, vtimer }
     }
 }
 
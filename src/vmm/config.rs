@@ -1,6 +1,14 @@
+use axaddrspace::GuestPhysAddr;
 use axvm::config::{AxVMConfig, AxVMCrateConfig};
 
-use crate::vmm::{VM, images::load_vm_images, vm_list::push_vm};
+use crate::vmm::devices::{self, EmuType};
+#[cfg(target_arch = "x86_64")]
+use crate::vmm::vapic;
+#[cfg(target_arch = "aarch64")]
+use crate::vmm::vgic;
+#[cfg(target_arch = "riscv64")]
+use crate::vmm::vplic;
+use crate::vmm::{VM, images::load_vm_images, irq, vm_list::push_vm};
 
 #[allow(clippy::module_inception)]
 pub mod config {
@@ -28,7 +36,11 @@ pub fn init_guest_vms() {
     for raw_cfg_str in gvm_raw_configs {
         let vm_create_config =
             AxVMCrateConfig::from_toml(raw_cfg_str).expect("Failed to resolve VM config");
-        let vm_config = AxVMConfig::from(vm_create_config.clone());
+        // The emulated devices are created below and handle the VM exits of the vCPUs (see
+        // `vcpus::vcpu_run`), `axvm` must not create its own.
+        let mut axvm_create_config = vm_create_config.clone();
+        axvm_create_config.devices.emu_devices.clear();
+        let vm_config = AxVMConfig::from(axvm_create_config);
 
         info!("Creating VM [{}] {:?}", vm_config.id(), vm_config.name());
 
@@ -36,6 +48,81 @@ pub fn init_guest_vms() {
         let vm = VM::new(vm_config).expect("Failed to create VM");
         push_vm(vm.clone());
 
+        // Create the virtual interrupt controller for VM, if it has one.
+        #[cfg(target_arch = "aarch64")]
+        {
+            let mut gicd = None;
+            let mut gicr = None;
+            for emu_dev in &vm_create_config.devices.emu_devices {
+                let region = (GuestPhysAddr::from(emu_dev.base_gpa), emu_dev.length);
+                match EmuType::of(emu_dev) {
+                    Some(EmuType::InterruptController) => gicd = Some(region),
+                    Some(EmuType::GicRedistributor) => gicr = Some(region),
+                    _ => {}
+                }
+            }
+            if let Some(gicd) = gicd {
+                // The affinity of each vCPU is the MPIDR of its physical CPU,
+                // or its index if the VM does not specify them.
+                let affinities = (0..vm.vcpu_num())
+                    .map(|i| {
+                        vm_create_config
+                            .base
+                            .phys_cpu_ids
+                            .as_ref()
+                            .and_then(|ids| ids.get(i).copied())
+                            .unwrap_or(i) as u64
+                    })
+                    .collect();
+                vgic::create_vm_vgic(vm.id(), affinities, gicd, gicr)
+                    .expect("Failed to create vGIC");
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut ioapic = None;
+            let mut lapic = None;
+            for emu_dev in &vm_create_config.devices.emu_devices {
+                let region = (GuestPhysAddr::from(emu_dev.base_gpa), emu_dev.length);
+                match EmuType::of(emu_dev) {
+                    Some(EmuType::InterruptController) => ioapic = Some(region),
+                    Some(EmuType::LocalApic) => lapic = Some(region),
+                    _ => {}
+                }
+            }
+            if let Some(ioapic) = ioapic {
+                // The xAPIC page may be omitted by guests using x2APIC mode only.
+                let lapic = lapic.unwrap_or((GuestPhysAddr::from(0xfee0_0000), 0));
+                vapic::create_vm_vapic(vm.id(), vm.vcpu_num(), lapic, ioapic)
+                    .expect("Failed to create vAPIC");
+            }
+        }
+        #[cfg(target_arch = "riscv64")]
+        if let Some(emu_dev) = vm_create_config
+            .devices
+            .emu_devices
+            .iter()
+            .find(|emu_dev| EmuType::of(emu_dev) == Some(EmuType::InterruptController))
+        {
+            let region = (GuestPhysAddr::from(emu_dev.base_gpa), emu_dev.length);
+            vplic::create_vm_vplic(vm.id(), vm.vcpu_num(), region).expect("Failed to create vPLIC");
+        }
+
+        // Create the other emulated devices of the VM.
+        devices::create_vm_devices(
+            vm.id(),
+            &vm_create_config.devices.emu_devices,
+            &vm_create_config.kernel.memory_regions,
+        )
+        .expect("Failed to create emulated devices");
+
+        // Route the interrupts of the passthrough devices to the VM.
+        for device in &vm_create_config.devices.passthrough_devices {
+            if device.irq_id != 0 {
+                irq::route_irq(vm.id(), device.irq_id).expect("Failed to route device irq");
+            }
+        }
+
         // Load corresponding images for VM.
         info!("VM[{}] created success, loading images...", vm.id());
         load_vm_images(vm_create_config, vm.clone()).expect("Failed to load VM images");
@@ -0,0 +1,162 @@
+//! The hypervisor console multiplexer.
+//!
+//! Guest consoles emulated by the hypervisor, such as the ports of a virtio-console, share the
+//! host console. Their output is printed line by line, each line prefixed by the name of the
+//! console it comes from. Host console input goes to a single console at a time, the focused
+//! one, initially the first registered: typing `Ctrl-A` then `n` moves the focus to the next
+//! console, and `Ctrl-A` twice sends a `Ctrl-A` to the focused one. The
+//! [hypervisor shell](super::shell) has a console of its own, `hv`.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use std::io::{Read, Write};
+use std::thread;
+
+use spin::Mutex;
+
+/// The escape character of the multiplexer commands, `Ctrl-A`.
+const ESCAPE: u8 = 0x01;
+
+/// Receives the host console input of a console.
+pub type InputHandler = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// A console registered in the multiplexer.
+struct Console {
+    name: String,
+    input: Arc<InputHandler>,
+    /// The output not yet printed, up to the end of the current line.
+    line: Vec<u8>,
+}
+
+/// The registered consoles, stored in a BTreeMap where the key is the console ID.
+static CONSOLES: Mutex<BTreeMap<usize, Console>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+/// The ID of the console receiving the host console input.
+static FOCUS: AtomicUsize = AtomicUsize::new(usize::MAX);
+static INPUT_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Registers a console named `name`, which receives the host console input through `input`
+/// while it has the focus.
+///
+/// Returns the ID of the console, used to print its output.
+pub fn register(name: String, input: InputHandler) -> usize {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    info!("Console {} registered as {}", name, id);
+    CONSOLES.lock().insert(id, Console {
+        name,
+        input: Arc::new(input),
+        line: Vec::new(),
+    });
+    let _ = FOCUS.compare_exchange(usize::MAX, id, Ordering::AcqRel, Ordering::Acquire);
+    if !INPUT_STARTED.swap(true, Ordering::AcqRel) {
+        thread::spawn(input_loop);
+    }
+    id
+}
+
+/// Returns whether the multiplexer reads the host console input, which it does once a
+/// console has been registered.
+pub fn is_active() -> bool {
+    INPUT_STARTED.load(Ordering::Acquire)
+}
+
+/// Unregisters a console, generally called when its VM is destroyed.
+#[allow(unused)]
+pub fn unregister(id: usize) {
+    CONSOLES.lock().remove(&id);
+    let _ = FOCUS.compare_exchange(id, usize::MAX, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// Prints the output of a console, once its lines are complete.
+pub fn write(id: usize, data: &[u8]) {
+    let mut consoles = CONSOLES.lock();
+    let Some(console) = consoles.get_mut(&id) else {
+        return;
+    };
+    let mut stdout = std::io::stdout();
+    for &byte in data {
+        if byte == b'\n' {
+            let _ = write!(stdout, "[{}] ", console.name);
+            let _ = stdout.write_all(&console.line);
+            let _ = stdout.write_all(b"\n");
+            console.line.clear();
+        } else if byte != b'\r' {
+            console.line.push(byte);
+        }
+    }
+}
+
+/// Moves the focus to the console following the focused one.
+fn focus_next() {
+    let consoles = CONSOLES.lock();
+    let focus = FOCUS.load(Ordering::Acquire);
+    let next = consoles
+        .range(focus.wrapping_add(1)..)
+        .chain(consoles.iter())
+        .next();
+    if let Some((&id, console)) = next {
+        FOCUS.store(id, Ordering::Release);
+        let _ = writeln!(std::io::stdout(), "[console] input to {}", console.name);
+    }
+}
+
+/// Sends input to the focused console.
+fn send_input(data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    let input = CONSOLES
+        .lock()
+        .get(&FOCUS.load(Ordering::Acquire))
+        .map(|console| console.input.clone());
+    // The handler is called without holding the registry, it may print some output.
+    if let Some(input) = input {
+        input(data);
+    }
+}
+
+/// Reads the host console input, and dispatches it to the focused console.
+fn input_loop() {
+    let mut stdin = std::io::stdin();
+    let mut buf = [0u8; 64];
+    let mut escaped = false;
+    loop {
+        let len = match stdin.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) => {
+                warn!("Console input failed: {:?}", err);
+                return;
+            }
+        };
+        let mut pending = Vec::with_capacity(len);
+        for &byte in &buf[..len] {
+            if escaped {
+                escaped = false;
+                match byte {
+                    b'n' => {
+                        send_input(&pending);
+                        pending.clear();
+                        focus_next();
+                    }
+                    ESCAPE => pending.push(ESCAPE),
+                    _ => {}
+                }
+            } else if byte == ESCAPE {
+                escaped = true;
+            } else {
+                pending.push(byte);
+            }
+        }
+        if !pending.is_empty() {
+            send_input(&pending);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Access to the memory of a guest by the emulated devices, e.g. to the buffers of a virtqueue.
+
+use core::mem::{MaybeUninit, size_of};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::VMRef;
+
+/// The memory of a VM, addressed by guest physical addresses.
+#[derive(Clone)]
+pub struct GuestMemory {
+    vm: VMRef,
+}
+
+impl GuestMemory {
+    pub fn new(vm: VMRef) -> Self {
+        Self { vm }
+    }
+
+    /// Copies the guest memory at `gpa` into `buf`.
+    pub fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut pos = 0;
+        for region in self.vm.get_image_load_region(gpa, buf.len())? {
+            let len = region.len().min(buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&region[..len]);
+            pos += len;
+        }
+        if pos < buf.len() {
+            return ax_err!(BadAddress, "guest memory read out of range");
+        }
+        Ok(())
+    }
+
+    /// Copies `buf` into the guest memory at `gpa`.
+    pub fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut pos = 0;
+        for region in self.vm.get_image_load_region(gpa, buf.len())? {
+            let len = region.len().min(buf.len() - pos);
+            region[..len].copy_from_slice(&buf[pos..pos + len]);
+            pos += len;
+        }
+        if pos < buf.len() {
+            return ax_err!(BadAddress, "guest memory write out of range");
+        }
+        Ok(())
+    }
+
+    /// Reads a plain value from the guest memory at `gpa`, which needs not be aligned.
+    pub fn read_obj<T: Copy>(&self, gpa: GuestPhysAddr) -> AxResult<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        // SAFETY: `T` is a plain `Copy` type, every byte of which is initialized by the read.
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr().cast::<u8>(), size_of::<T>())
+        };
+        self.read(gpa, bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Writes a plain value into the guest memory at `gpa`, which needs not be aligned.
+    pub fn write_obj<T: Copy>(&self, gpa: GuestPhysAddr, value: &T) -> AxResult {
+        // SAFETY: `T` is a plain `Copy` type, viewed as its bytes.
+        let bytes = unsafe {
+            core::slice::from_raw_parts((value as *const T).cast::<u8>(), size_of::<T>())
+        };
+        self.write(gpa, bytes)
+    }
+}
@@ -0,0 +1,456 @@
+//! Emulated devices of the VMs.
+//!
+//! The devices of a VM are created from the `emu_devices` entries of its config,
+//! `[Name, Base-Ipa, Ipa_len, Alloc-Irq, Emu-Type, EmuConfig]`, where the `Emu-Type` selects
+//! the device model, one of [`EmuType`]. Guest accesses trapping into the hypervisor, MMIO, port I/O on x86_64 and
+//! system registers, are dispatched by [`vcpus`](super::vcpus) to the device covering them
+//! through the [`EmuDevice`] trait, on the task of the vCPU doing the access.
+//!
+//! The platform interrupt controller may span several entries, such as the distributor and
+//! the redistributors of a GICv3, so it is built by its own module and then registered here
+//! through [`register_device`], like any other device.
+
+extern crate alloc;
+
+mod guest_mem;
+mod pci;
+mod rtc;
+mod trace;
+mod uart;
+mod virtio;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::{EmulatedDeviceConfig, VmMemConfig};
+use spin::Mutex;
+
+#[cfg(target_arch = "aarch64")]
+use crate::vmm::vgic;
+pub use guest_mem::GuestMemory;
+pub use pci::remove_vm_pci;
+pub use rtc::{host_time_nanos, reset_vm_time, vm_time_nanos};
+pub use trace::{dump_mmio_trace, remove_vm_mmio_trace, take_mmio_trace};
+pub use uart::take_uart_output;
+pub use virtio::{
+    balloon_pages, balloon_stats, handle_balloon_fault, request_balloon_stats, set_balloon_target,
+};
+
+/// The `Emu-Type` of an `emu_devices` entry, i.e. its device model.
+///
+/// The types known to `axvm`, the `EmulatedDeviceType` of `axvmconfig`, keep their values
+/// there: the platform interrupt controller and the virtio-mmio devices it lists. The other
+/// ones are numbered apart from these, in a range per kind of device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum EmuType {
+    /// The platform interrupt controller: the distributor of a GICv2, or of a GICv3 if the VM
+    /// also has [`GicRedistributor`](Self::GicRedistributor), on aarch64, the I/O APIC on
+    /// x86_64, or the PLIC on riscv64.
+    InterruptController = 0x1,
+    /// The redistributor region of a GICv3, holding one frame per vCPU, on aarch64.
+    GicRedistributor = 0x80,
+    /// The xAPIC MMIO page of the local APICs, one per vCPU, on x86_64.
+    LocalApic = 0x81,
+    /// A GICv2m MSI frame, on aarch64.
+    GicV2m = 0x82,
+    /// A traced passthrough MMIO range.
+    MmioTrace = 0xA0,
+    /// The PCIe host bridge.
+    PciHostBridge = 0xB0,
+    /// A physical PCI function passed through to the VM.
+    PciPassthrough = 0xB1,
+    /// A PL011 UART.
+    Pl011 = 0xC0,
+    /// A 16550 UART accessed through MMIO, whose `EmuConfig` may hold the shift of its register
+    /// offsets after the backend, 0 by default.
+    Ns16550Mmio = 0xC1,
+    /// A 16550 UART accessed through I/O ports on x86_64, whose `Base-Ipa` is the first port.
+    Ns16550Pio = 0xC2,
+    /// A PL031 RTC.
+    Pl031 = 0xD0,
+    /// A Goldfish RTC.
+    GoldfishRtc = 0xD1,
+    /// A CMOS RTC on x86_64, whose `Base-Ipa` is its index port, usually 0x70.
+    CmosRtc = 0xD2,
+    VirtioBlk = 0xE1,
+    VirtioNet = 0xE2,
+    VirtioConsole = 0xE3,
+    VirtioVsock = 0xE4,
+    VirtioRng = 0xE5,
+    VirtioBalloon = 0xE6,
+    /// The virtio devices behind the virtio-pci transport, numbered like their virtio-mmio
+    /// variant plus 0x10.
+    VirtioPciBlk = 0xF1,
+    VirtioPciNet = 0xF2,
+    VirtioPciConsole = 0xF3,
+    VirtioPciVsock = 0xF4,
+    VirtioPciRng = 0xF5,
+    VirtioPciBalloon = 0xF6,
+}
+
+impl EmuType {
+    const ALL: &[Self] = &[
+        Self::InterruptController,
+        Self::GicRedistributor,
+        Self::LocalApic,
+        Self::GicV2m,
+        Self::MmioTrace,
+        Self::PciHostBridge,
+        Self::PciPassthrough,
+        Self::Pl011,
+        Self::Ns16550Mmio,
+        Self::Ns16550Pio,
+        Self::Pl031,
+        Self::GoldfishRtc,
+        Self::CmosRtc,
+        Self::VirtioBlk,
+        Self::VirtioNet,
+        Self::VirtioConsole,
+        Self::VirtioVsock,
+        Self::VirtioRng,
+        Self::VirtioBalloon,
+        Self::VirtioPciBlk,
+        Self::VirtioPciNet,
+        Self::VirtioPciConsole,
+        Self::VirtioPciVsock,
+        Self::VirtioPciRng,
+        Self::VirtioPciBalloon,
+    ];
+
+    /// Returns the type of the `Emu-Type` field of an `emu_devices` entry, if it is known.
+    pub fn from_usize(value: usize) -> Option<Self> {
+        Self::ALL.iter().copied().find(|ty| *ty as usize == value)
+    }
+
+    /// Returns the type of an `emu_devices` entry, if it is known.
+    pub fn of(config: &EmulatedDeviceConfig) -> Option<Self> {
+        Self::from_usize(config.emu_type as usize)
+    }
+
+    /// Returns the virtio-mmio variant of a virtio device behind the virtio-pci transport.
+    pub fn virtio_mmio_variant(self) -> Option<Self> {
+        match self {
+            Self::VirtioPciBlk
+            | Self::VirtioPciNet
+            | Self::VirtioPciConsole
+            | Self::VirtioPciVsock
+            | Self::VirtioPciRng
+            | Self::VirtioPciBalloon => Self::from_usize(self as usize - 0x10),
+            _ => None,
+        }
+    }
+}
+
+/// The `Emu-Type`s of the platform interrupt controller, built by its own module.
+#[cfg(target_arch = "aarch64")]
+const INTC_EMU_TYPES: &[EmuType] = &[EmuType::InterruptController, EmuType::GicRedistributor];
+#[cfg(target_arch = "x86_64")]
+const INTC_EMU_TYPES: &[EmuType] = &[EmuType::InterruptController, EmuType::LocalApic];
+#[cfg(target_arch = "riscv64")]
+const INTC_EMU_TYPES: &[EmuType] = &[EmuType::InterruptController];
+
+/// A device emulated by the hypervisor.
+///
+/// All the accesses are made on behalf of the vCPU `vcpu_id`, by its own task.
+pub trait EmuDevice: Send + Sync {
+    /// Returns the name of the device, for logging.
+    fn name(&self) -> &str;
+
+    /// Returns the guest physical address ranges whose accesses trap into the device.
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        Vec::new()
+    }
+
+    /// Returns the I/O port ranges whose accesses trap into the device, on x86_64.
+    fn pio_ports(&self) -> Vec<Range<u16>> {
+        Vec::new()
+    }
+
+    /// Returns the ranges of the system registers whose trapped accesses are offered to the
+    /// device, in the encoding of the `SysRegRead`/`SysRegWrite` exits.
+    fn sysregs(&self) -> Vec<Range<usize>> {
+        Vec::new()
+    }
+
+    /// Handles a guest read of `width` bytes at `addr`, in one of the MMIO regions.
+    fn handle_mmio_read(&self, _vcpu_id: usize, _addr: GuestPhysAddr, _width: usize) -> usize {
+        0
+    }
+
+    /// Handles a guest write of `width` bytes at `addr`, in one of the MMIO regions.
+    fn handle_mmio_write(
+        &self,
+        _vcpu_id: usize,
+        _addr: GuestPhysAddr,
+        _width: usize,
+        _value: usize,
+    ) {
+    }
+
+    /// Handles a guest read of `width` bytes from `port`, in one of the port ranges.
+    fn handle_pio_read(&self, _vcpu_id: usize, _port: u16, _width: usize) -> usize {
+        0
+    }
+
+    /// Handles a guest write of `width` bytes to `port`, in one of the port ranges.
+    fn handle_pio_write(&self, _vcpu_id: usize, _port: u16, _width: usize, _value: usize) {}
+
+    /// Handles a trapped guest read of a system register, in one of the register ranges.
+    ///
+    /// Returns `None` if `addr` is not a register emulated by the device.
+    fn handle_sysreg_read(&self, _vcpu_id: usize, _addr: usize) -> Option<u64> {
+        None
+    }
+
+    /// Handles a trapped guest write of a system register, in one of the register ranges.
+    ///
+    /// Returns `false` if `addr` is not a register emulated by the device.
+    fn handle_sysreg_write(&self, _vcpu_id: usize, _addr: usize, _value: u64) -> bool {
+        false
+    }
+}
+
+/// The emulated devices of a VM, along with the ranges they cover.
+#[derive(Default)]
+struct VmDevices {
+    mmio: Vec<(Range<GuestPhysAddr>, Arc<dyn EmuDevice>)>,
+    pio: Vec<(Range<u16>, Arc<dyn EmuDevice>)>,
+    sysregs: Vec<(Range<usize>, Arc<dyn EmuDevice>)>,
+}
+
+fn overlaps<T: PartialOrd>(a: &Range<T>, b: &Range<T>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl VmDevices {
+    fn add(&mut self, device: Arc<dyn EmuDevice>) -> AxResult {
+        let mmio = device.mmio_regions();
+        let pio = device.pio_ports();
+        let sysregs = device.sysregs();
+        if mmio
+            .iter()
+            .any(|r| self.mmio.iter().any(|(other, _)| overlaps(r, other)))
+            || pio
+                .iter()
+                .any(|r| self.pio.iter().any(|(other, _)| overlaps(r, other)))
+            || sysregs
+                .iter()
+                .any(|r| self.sysregs.iter().any(|(other, _)| overlaps(r, other)))
+        {
+            return ax_err!(AlreadyExists, "emulated device overlaps another one");
+        }
+        self.mmio
+            .extend(mmio.into_iter().map(|r| (r, device.clone())));
+        self.pio
+            .extend(pio.into_iter().map(|r| (r, device.clone())));
+        self.sysregs
+            .extend(sysregs.into_iter().map(|r| (r, device.clone())));
+        Ok(())
+    }
+}
+
+/// The emulated devices of all VMs, stored in a BTreeMap where the key is the VM ID.
+static VM_DEVICES: Mutex<BTreeMap<usize, VmDevices>> = Mutex::new(BTreeMap::new());
+
+/// Registers an emulated device of a VM.
+///
+/// Fails if the device covers an address or a port already covered by another device.
+pub fn register_device(vm_id: usize, device: Arc<dyn EmuDevice>) -> AxResult {
+    debug!("VM[{}] emulated device {}", vm_id, device.name());
+    VM_DEVICES.lock().entry(vm_id).or_default().add(device)
+}
+
+/// Creates the emulated device of an `emu_devices` entry, according to its `Emu-Type`.
+///
+/// Returns `None` for the entries of the platform interrupt controller, which are skipped, and
+/// for the PCI functions, added to the PCI bus of the VM instead. Fails for an unknown
+/// `Emu-Type`, or one not supported on this platform.
+fn create_device(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+    memory_regions: &[VmMemConfig],
+) -> AxResult<Option<Arc<dyn EmuDevice>>> {
+    let Some(emu_type) = EmuType::of(config) else {
+        warn!(
+            "Emulated device {} has an unknown Emu-Type {:#x}",
+            config.name, config.emu_type
+        );
+        return ax_err!(InvalidInput, "unknown Emu-Type");
+    };
+    if INTC_EMU_TYPES.contains(&emu_type) {
+        return Ok(None);
+    }
+    match emu_type {
+        EmuType::VirtioBlk => virtio::create_blk(vm_id, config).map(Some),
+        EmuType::VirtioNet => virtio::create_net(vm_id, config).map(Some),
+        EmuType::VirtioConsole => virtio::create_console(vm_id, config).map(Some),
+        EmuType::VirtioVsock => virtio::create_vsock(vm_id, config).map(Some),
+        EmuType::VirtioRng => virtio::create_rng(vm_id, config).map(Some),
+        EmuType::VirtioBalloon => virtio::create_balloon(vm_id, config, memory_regions).map(Some),
+        EmuType::Pl011 => uart::create_pl011(vm_id, config).map(Some),
+        EmuType::Ns16550Mmio => uart::create_ns16550_mmio(vm_id, config).map(Some),
+        #[cfg(target_arch = "x86_64")]
+        EmuType::Ns16550Pio => uart::create_ns16550_pio(vm_id, config).map(Some),
+        #[cfg(not(target_arch = "x86_64"))]
+        EmuType::Ns16550Pio => ax_err!(Unsupported, "port I/O is only on x86_64"),
+        EmuType::PciHostBridge => pci::create_host_bridge(vm_id, config).map(Some),
+        EmuType::PciPassthrough => pci::create_passthrough(vm_id, config).map(|_| None),
+        EmuType::VirtioPciBlk
+        | EmuType::VirtioPciNet
+        | EmuType::VirtioPciConsole
+        | EmuType::VirtioPciVsock
+        | EmuType::VirtioPciRng
+        | EmuType::VirtioPciBalloon => {
+            virtio::create_pci(vm_id, config, memory_regions).map(|_| None)
+        }
+        #[cfg(target_arch = "aarch64")]
+        EmuType::GicV2m => vgic::create_v2m(vm_id, config).map(Some),
+        EmuType::MmioTrace => trace::create_mmio_trace(vm_id, config).map(Some),
+        EmuType::Pl031 => rtc::create_pl031(vm_id, config).map(Some),
+        EmuType::GoldfishRtc => rtc::create_goldfish(vm_id, config).map(Some),
+        #[cfg(target_arch = "x86_64")]
+        EmuType::CmosRtc => rtc::create_cmos(vm_id, config).map(Some),
+        #[cfg(not(target_arch = "x86_64"))]
+        EmuType::CmosRtc => ax_err!(Unsupported, "port I/O is only on x86_64"),
+        _ => {
+            warn!(
+                "Emulated device {} has an Emu-Type {:?} not supported on this platform",
+                config.name, emu_type
+            );
+            ax_err!(Unsupported, "Emu-Type not supported on this platform")
+        }
+    }
+}
+
+/// Creates and registers the emulated devices of a VM from the `emu_devices` entries of its
+/// config, except for the platform interrupt controller. `memory_regions` are the memory
+/// regions of the VM, for the devices managing its memory.
+pub fn create_vm_devices(
+    vm_id: usize,
+    configs: &[EmulatedDeviceConfig],
+    memory_regions: &[VmMemConfig],
+) -> AxResult {
+    for config in configs {
+        if let Some(device) = create_device(vm_id, config, memory_regions)? {
+            info!(
+                "VM[{}] emulated device {} at {:#x}",
+                vm_id, config.name, config.base_gpa
+            );
+            register_device(vm_id, device)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the emulated devices of a VM, called when the VM is torn down.
+pub fn remove_vm_devices(vm_id: usize) {
+    VM_DEVICES.lock().remove(&vm_id);
+}
+
+/// Returns the device of a VM covering the MMIO address `addr`.
+fn find_mmio_device(vm_id: usize, addr: GuestPhysAddr) -> Option<Arc<dyn EmuDevice>> {
+    let devices = VM_DEVICES.lock();
+    let (_, device) = devices
+        .get(&vm_id)?
+        .mmio
+        .iter()
+        .find(|(range, _)| range.contains(&addr))?;
+    Some(device.clone())
+}
+
+/// Returns the device of a VM covering the I/O port `port`.
+fn find_pio_device(vm_id: usize, port: u16) -> Option<Arc<dyn EmuDevice>> {
+    let devices = VM_DEVICES.lock();
+    let (_, device) = devices
+        .get(&vm_id)?
+        .pio
+        .iter()
+        .find(|(range, _)| range.contains(&port))?;
+    Some(device.clone())
+}
+
+/// Returns the device of a VM covering the system register `addr`.
+fn find_sysreg_device(vm_id: usize, addr: usize) -> Option<Arc<dyn EmuDevice>> {
+    let devices = VM_DEVICES.lock();
+    let (_, device) = devices
+        .get(&vm_id)?
+        .sysregs
+        .iter()
+        .find(|(range, _)| range.contains(&addr))?;
+    Some(device.clone())
+}
+
+/// Handles a guest MMIO read which trapped to the hypervisor.
+///
+/// Returns `None` if no emulated device covers `addr`.
+pub fn handle_mmio_read(
+    vm_id: usize,
+    vcpu_id: usize,
+    addr: GuestPhysAddr,
+    width: usize,
+) -> Option<usize> {
+    let device = find_mmio_device(vm_id, addr)?;
+    Some(device.handle_mmio_read(vcpu_id, addr, width))
+}
+
+/// Handles a guest MMIO write which trapped to the hypervisor.
+///
+/// Returns `false` if no emulated device covers `addr`.
+pub fn handle_mmio_write(
+    vm_id: usize,
+    vcpu_id: usize,
+    addr: GuestPhysAddr,
+    width: usize,
+    value: usize,
+) -> bool {
+    let Some(device) = find_mmio_device(vm_id, addr) else {
+        return false;
+    };
+    device.handle_mmio_write(vcpu_id, addr, width, value);
+    true
+}
+
+/// Handles a guest port I/O read which trapped to the hypervisor.
+///
+/// Returns `None` if no emulated device covers `port`.
+pub fn handle_pio_read(vm_id: usize, vcpu_id: usize, port: u16, width: usize) -> Option<usize> {
+    let device = find_pio_device(vm_id, port)?;
+    Some(device.handle_pio_read(vcpu_id, port, width))
+}
+
+/// Handles a guest port I/O write which trapped to the hypervisor.
+///
+/// Returns `false` if no emulated device covers `port`.
+pub fn handle_pio_write(
+    vm_id: usize,
+    vcpu_id: usize,
+    port: u16,
+    width: usize,
+    value: usize,
+) -> bool {
+    let Some(device) = find_pio_device(vm_id, port) else {
+        return false;
+    };
+    device.handle_pio_write(vcpu_id, port, width, value);
+    true
+}
+
+/// Handles a guest system register read which trapped to the hypervisor.
+///
+/// Returns `None` if no emulated device handles the register.
+pub fn handle_sysreg_read(vm_id: usize, vcpu_id: usize, addr: usize) -> Option<u64> {
+    find_sysreg_device(vm_id, addr)?.handle_sysreg_read(vcpu_id, addr)
+}
+
+/// Handles a guest system register write which trapped to the hypervisor.
+///
+/// Returns `false` if no emulated device handles the register.
+pub fn handle_sysreg_write(vm_id: usize, vcpu_id: usize, addr: usize, value: u64) -> bool {
+    find_sysreg_device(vm_id, addr)
+        .is_some_and(|device| device.handle_sysreg_write(vcpu_id, addr, value))
+}
@@ -0,0 +1,266 @@
+//! The configuration space of an emulated PCI function, with a type 0 header.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+
+/// The size of the configuration space of a PCIe function, as seen through ECAM.
+pub const CONFIG_SPACE_SIZE: usize = 0x1000;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_REVISION_ID: usize = 0x08;
+pub const PCI_CLASS_PROG: usize = 0x09;
+pub const PCI_CACHE_LINE_SIZE: usize = 0x0c;
+pub const PCI_HEADER_TYPE: usize = 0x0e;
+pub const PCI_BASE_ADDRESS_0: usize = 0x10;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+pub const PCI_COMMAND_IO: u16 = 1 << 0;
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+pub const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+pub const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+/// The number of BARs of a type 0 header.
+pub const PCI_NUM_BARS: usize = 6;
+
+const PCI_BASE_ADDRESS_SPACE_IO: u32 = 0x1;
+const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0x4;
+const PCI_BASE_ADDRESS_MEM_PREFETCH: u32 = 0x8;
+
+/// The first offset of the capabilities, after the header.
+const FIRST_CAPABILITY: usize = 0x40;
+/// The end of the configuration space of conventional PCI, holding the capabilities.
+const LEGACY_CONFIG_SPACE_SIZE: usize = 0x100;
+
+/// The kind of a BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    /// A memory BAR below 4G.
+    Mem32,
+    /// A memory BAR anywhere, which takes two BAR slots.
+    Mem64,
+    /// An I/O port BAR.
+    Io,
+}
+
+/// A BAR of a function, whose size is a power of two.
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub kind: BarKind,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+/// What a BAR decodes, once programmed and enabled by the guest.
+#[derive(Debug, Clone)]
+pub enum BarRegion {
+    Mem(Range<GuestPhysAddr>),
+    Io(Range<u16>),
+}
+
+/// The configuration space of an emulated PCI function.
+///
+/// The guest can only change the bits set in the write mask, which covers the command
+/// register, the BAR addresses and the interrupt line by default.
+pub struct ConfigSpace {
+    data: Vec<u8>,
+    write_mask: Vec<u8>,
+    bars: [Option<Bar>; PCI_NUM_BARS],
+    /// The offset of the last capability, if any.
+    last_capability: Option<usize>,
+    /// The offset of the next capability to be added.
+    next_capability: usize,
+}
+
+impl ConfigSpace {
+    /// Creates the configuration space of a function, whose class code is
+    /// `class << 16 | subclass << 8 | prog_if`.
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u32, revision: u8) -> Self {
+        let mut config = Self {
+            data: vec![0; CONFIG_SPACE_SIZE],
+            write_mask: vec![0; CONFIG_SPACE_SIZE],
+            bars: [None; PCI_NUM_BARS],
+            last_capability: None,
+            next_capability: FIRST_CAPABILITY,
+        };
+        config.set(PCI_VENDOR_ID, &vendor_id.to_le_bytes());
+        config.set(PCI_DEVICE_ID, &device_id.to_le_bytes());
+        config.set(PCI_REVISION_ID, &[revision]);
+        config.set(PCI_CLASS_PROG, &class_code.to_le_bytes()[..3]);
+        let command_mask =
+            PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE;
+        config.set_write_mask(PCI_COMMAND, &command_mask.to_le_bytes());
+        config.set_write_mask(PCI_CACHE_LINE_SIZE, &[0xff, 0xff]);
+        config.set_write_mask(PCI_INTERRUPT_LINE, &[0xff]);
+        config
+    }
+
+    /// Sets the subsystem vendor and subsystem IDs.
+    pub fn set_subsystem(&mut self, vendor_id: u16, id: u16) {
+        self.set(PCI_SUBSYSTEM_VENDOR_ID, &vendor_id.to_le_bytes());
+        self.set(PCI_SUBSYSTEM_ID, &id.to_le_bytes());
+    }
+
+    /// Sets the legacy interrupt pin, 1 for INTA, and the interrupt line reported to the guest.
+    pub fn set_interrupt(&mut self, pin: u8, line: u8) {
+        self.set(PCI_INTERRUPT_PIN, &[pin]);
+        self.set(PCI_INTERRUPT_LINE, &[line]);
+    }
+
+    /// Adds the BAR `index`, also taking the next one for a 64-bit BAR.
+    pub fn add_bar(&mut self, index: usize, bar: Bar) {
+        assert!(bar.size.is_power_of_two());
+        let offset = PCI_BASE_ADDRESS_0 + index * 4;
+        let (flags, min_size) = match bar.kind {
+            BarKind::Io => (PCI_BASE_ADDRESS_SPACE_IO, 4),
+            BarKind::Mem32 => (0, 16),
+            BarKind::Mem64 => (PCI_BASE_ADDRESS_MEM_TYPE_64, 16),
+        };
+        let flags = if bar.prefetchable && bar.kind != BarKind::Io {
+            flags | PCI_BASE_ADDRESS_MEM_PREFETCH
+        } else {
+            flags
+        };
+        let mask = !(bar.size.max(min_size) - 1);
+        self.set(offset, &flags.to_le_bytes());
+        self.set_write_mask(offset, &(mask as u32).to_le_bytes());
+        if bar.kind == BarKind::Mem64 {
+            self.set_write_mask(offset + 4, &((mask >> 32) as u32).to_le_bytes());
+        }
+        self.bars[index] = Some(bar);
+    }
+
+    /// Adds a capability `id` whose body, following its ID and next pointer, is `body`.
+    ///
+    /// Returns the offset of the capability, whose fields are read-only unless their write
+    /// mask is then set through [`Self::set_write_mask`].
+    pub fn add_capability(&mut self, id: u8, body: &[u8]) -> usize {
+        let offset = self.next_capability;
+        let len = 2 + body.len();
+        assert!(
+            offset + len <= LEGACY_CONFIG_SPACE_SIZE,
+            "no room for the capability"
+        );
+        self.set(offset, &[id, 0]);
+        self.set(offset + 2, body);
+        match self.last_capability {
+            Some(last) => self.data[last + 1] = offset as u8,
+            None => {
+                self.data[PCI_CAPABILITY_LIST] = offset as u8;
+                let status = self.read_u16(PCI_STATUS) | PCI_STATUS_CAP_LIST;
+                self.set(PCI_STATUS, &status.to_le_bytes());
+            }
+        }
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + len).next_multiple_of(4);
+        offset
+    }
+
+    /// Sets the bytes at `offset`, regardless of the write mask.
+    pub fn set(&mut self, offset: usize, data: &[u8]) {
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Sets the bits the guest can change at `offset`.
+    pub fn set_write_mask(&mut self, offset: usize, mask: &[u8]) {
+        self.write_mask[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    /// Returns the bytes at `offset`.
+    pub fn get(&self, offset: usize, len: usize) -> &[u8] {
+        &self.data[offset..offset + len]
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.get(offset, 4));
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Handles a guest read of `width` bytes at `offset`.
+    pub fn read(&self, offset: usize, width: usize) -> u32 {
+        if offset + width > CONFIG_SPACE_SIZE {
+            return u32::MAX;
+        }
+        let mut bytes = [0; 4];
+        bytes[..width].copy_from_slice(self.get(offset, width));
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Handles a guest write of `width` bytes at `offset`, through the write mask.
+    pub fn write(&mut self, offset: usize, width: usize, value: u32) {
+        if offset + width > CONFIG_SPACE_SIZE {
+            return;
+        }
+        for (i, byte) in value.to_le_bytes()[..width].iter().enumerate() {
+            let mask = self.write_mask[offset + i];
+            let old = self.data[offset + i];
+            self.data[offset + i] = (old & !mask) | (byte & mask);
+        }
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(PCI_COMMAND)
+    }
+
+    /// Returns the BAR `index`, if the function has it.
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Returns the address programmed in the BAR `index`.
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        let bar = self.bar(index)?;
+        let offset = PCI_BASE_ADDRESS_0 + index * 4;
+        let low = self.read_u32(offset);
+        Some(match bar.kind {
+            BarKind::Io => (low & !0x3) as u64,
+            BarKind::Mem32 => (low & !0xf) as u64,
+            BarKind::Mem64 => ((self.read_u32(offset + 4) as u64) << 32) | (low & !0xf) as u64,
+        })
+    }
+
+    /// Returns what the BARs decode, for those which are programmed and whose decoding is
+    /// enabled in the command register.
+    pub fn bar_regions(&self) -> Vec<(usize, BarRegion)> {
+        let command = self.command();
+        let mut regions = Vec::new();
+        for index in 0..PCI_NUM_BARS {
+            let (Some(bar), Some(address)) = (self.bar(index), self.bar_address(index)) else {
+                continue;
+            };
+            if address == 0 {
+                continue;
+            }
+            match bar.kind {
+                BarKind::Io if command & PCI_COMMAND_IO != 0 => {
+                    let start = address as u16;
+                    let end = start.saturating_add(bar.size as u16);
+                    regions.push((index, BarRegion::Io(start..end)));
+                }
+                BarKind::Mem32 | BarKind::Mem64 if command & PCI_COMMAND_MEMORY != 0 => {
+                    let start = GuestPhysAddr::from(address as usize);
+                    regions.push((index, BarRegion::Mem(start..start + bar.size as usize)));
+                }
+                _ => {}
+            }
+        }
+        regions
+    }
+}
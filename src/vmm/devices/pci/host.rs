@@ -0,0 +1,397 @@
+//! The PCIe host bridge, decoding the configuration accesses and the BAR windows of the bus.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+
+use super::config::{PCI_BASE_ADDRESS_0, PCI_NUM_BARS};
+use super::{BarRegion, ConfigSpace, PCI_NUM_DEVICES, PciFunction};
+use crate::vmm::devices::EmuDevice;
+
+/// The ECAM window of a bus, 32 devices of 8 functions of 4K.
+const ECAM_BUS_SIZE: usize = 1 << 20;
+
+/// The `CONFIG_ADDRESS` and `CONFIG_DATA` ports of the PC.
+#[cfg(target_arch = "x86_64")]
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+#[cfg(target_arch = "x86_64")]
+const PCI_CONFIG_DATA: u16 = 0xcfc;
+/// `CONFIG_ADDRESS` enables the configuration accesses through `CONFIG_DATA` with its bit 31.
+#[cfg(target_arch = "x86_64")]
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// The Red Hat, Inc. vendor ID, and the ID of the generic PCIe host bridge of QEMU.
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0008;
+/// A host bridge.
+const HOST_BRIDGE_CLASS: u32 = 0x06_00_00;
+
+/// Returns all ones over `width` bytes, read from a function which does not exist.
+fn all_ones(width: usize) -> usize {
+    (u64::MAX >> (64 - 8 * width.clamp(1, 8))) as usize
+}
+
+/// The host bridge itself, device 0 of the bus.
+struct HostBridgeFunction {
+    config: Mutex<ConfigSpace>,
+}
+
+impl PciFunction for HostBridgeFunction {
+    fn name(&self) -> &str {
+        "host-bridge"
+    }
+
+    fn config_read(&self, offset: usize, width: usize) -> u32 {
+        self.config.lock().read(offset, width)
+    }
+
+    fn config_write(&self, offset: usize, width: usize, value: u32) {
+        self.config.lock().write(offset, width, value);
+    }
+
+    fn bar_regions(&self) -> Vec<(usize, BarRegion)> {
+        Vec::new()
+    }
+}
+
+/// The next free addresses of the BAR windows.
+struct BarAllocator {
+    next_mem: u64,
+    next_io: u64,
+}
+
+/// A PCIe host bridge, with a single bus.
+pub struct PciHostBridge {
+    vm_id: usize,
+    name: String,
+    ecam_base: GuestPhysAddr,
+    ecam_size: usize,
+    mem_window: Range<u64>,
+    /// The I/O port window, empty if none.
+    io_window: Range<u64>,
+    allocator: Mutex<BarAllocator>,
+    /// The functions of the bus, stored in a BTreeMap where the key is the device number.
+    functions: Mutex<BTreeMap<u8, Arc<dyn PciFunction>>>,
+    /// The value of the `CONFIG_ADDRESS` port.
+    #[cfg(target_arch = "x86_64")]
+    config_address: AtomicU32,
+}
+
+impl PciHostBridge {
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        if config.length < ECAM_BUS_SIZE {
+            return ax_err!(InvalidInput, "ECAM window smaller than a bus");
+        }
+        let (mem_window, io_window) = match *config.cfg_list.as_slice() {
+            [mem_base, mem_size] => (mem_base as u64..(mem_base + mem_size) as u64, 0..0),
+            [mem_base, mem_size, io_base, io_size] if cfg!(target_arch = "x86_64") => {
+                if io_base + io_size > u16::MAX as usize + 1 {
+                    return ax_err!(InvalidInput, "PCI I/O window out of the I/O port space");
+                }
+                (
+                    mem_base as u64..(mem_base + mem_size) as u64,
+                    io_base as u64..(io_base + io_size) as u64,
+                )
+            }
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "PCI host bridge EmuConfig must be [mem_base, mem_size, (io_base, io_size)]"
+                );
+            }
+        };
+
+        let mut bridge_config = ConfigSpace::new(
+            HOST_BRIDGE_VENDOR_ID,
+            HOST_BRIDGE_DEVICE_ID,
+            HOST_BRIDGE_CLASS,
+            0,
+        );
+        bridge_config.set_subsystem(HOST_BRIDGE_VENDOR_ID, 0x1100);
+        let bridge: Arc<dyn PciFunction> = Arc::new(HostBridgeFunction {
+            config: Mutex::new(bridge_config),
+        });
+        let mut functions = BTreeMap::new();
+        functions.insert(0, bridge);
+
+        Ok(Self {
+            vm_id,
+            name: config.name.clone(),
+            ecam_base: GuestPhysAddr::from(config.base_gpa),
+            ecam_size: config.length,
+            allocator: Mutex::new(BarAllocator {
+                next_mem: mem_window.start,
+                next_io: io_window.start,
+            }),
+            mem_window,
+            io_window,
+            functions: Mutex::new(functions),
+            #[cfg(target_arch = "x86_64")]
+            config_address: AtomicU32::new(0),
+        })
+    }
+
+    /// Adds a function to the bus, see [`super::add_function`].
+    pub fn add_function(&self, device: Option<u8>, function: Arc<dyn PciFunction>) -> AxResult<u8> {
+        let mut functions = self.functions.lock();
+        let device = match device {
+            Some(0) => return ax_err!(InvalidInput, "PCI device 0 is the host bridge"),
+            Some(device) if device >= PCI_NUM_DEVICES => {
+                return ax_err!(InvalidInput, "PCI device number out of the bus");
+            }
+            Some(device) if functions.contains_key(&device) => {
+                return ax_err!(AlreadyExists, "PCI device number already taken");
+            }
+            Some(device) => device,
+            None => match (1..PCI_NUM_DEVICES).find(|dev| !functions.contains_key(dev)) {
+                Some(device) => device,
+                None => return ax_err!(NoMemory, "PCI bus full"),
+            },
+        };
+        self.assign_bars(function.as_ref());
+        info!(
+            "VM[{}] PCI 00:{:02x}.0 {}",
+            self.vm_id,
+            device,
+            function.name()
+        );
+        functions.insert(device, function);
+        Ok(device)
+    }
+
+    /// Allocates `size` bytes, naturally aligned, from a window.
+    fn allocate(next: &mut u64, window: &Range<u64>, size: u64) -> Option<u64> {
+        if !size.is_power_of_two() {
+            return None;
+        }
+        let start = next.next_multiple_of(size);
+        let end = start.checked_add(size)?;
+        if end > window.end {
+            return None;
+        }
+        *next = end;
+        Some(start)
+    }
+
+    /// Sizes the BARs of `function` through its configuration space and assigns them
+    /// addresses in the windows, like firmware enumerating the bus.
+    fn assign_bars(&self, function: &dyn PciFunction) {
+        let mut allocator = self.allocator.lock();
+        let mut index = 0;
+        while index < PCI_NUM_BARS {
+            let offset = PCI_BASE_ADDRESS_0 + index * 4;
+            function.config_write(offset, 4, u32::MAX);
+            let probe = function.config_read(offset, 4);
+            if probe == 0 {
+                index += 1;
+                continue;
+            }
+            if probe & 0x1 != 0 {
+                let size = (!(probe & !0x3)).wrapping_add(1) as u64 & 0xffff;
+                let allocator = &mut *allocator;
+                match Self::allocate(&mut allocator.next_io, &self.io_window, size) {
+                    Some(address) => function.config_write(offset, 4, address as u32),
+                    None => {
+                        function.config_write(offset, 4, 0);
+                        warn!("{}: no room for I/O BAR {}", function.name(), index);
+                    }
+                }
+                index += 1;
+                continue;
+            }
+            let is_64 = probe & 0x6 == 0x4;
+            let mut mask = 0xffff_ffff_0000_0000 | (probe & !0xf) as u64;
+            if is_64 {
+                function.config_write(offset + 4, 4, u32::MAX);
+                mask = ((function.config_read(offset + 4, 4) as u64) << 32) | (mask & 0xffff_ffff);
+            }
+            let size = (!mask).wrapping_add(1);
+            let allocator = &mut *allocator;
+            let address = Self::allocate(&mut allocator.next_mem, &self.mem_window, size);
+            if address.is_none() {
+                warn!("{}: no room for memory BAR {}", function.name(), index);
+            }
+            let address = address.unwrap_or(0);
+            function.config_write(offset, 4, address as u32);
+            if is_64 {
+                function.config_write(offset + 4, 4, (address >> 32) as u32);
+                index += 2;
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// Returns the function at `devfn` of bus `bus`.
+    fn function(&self, bus: u8, devfn: u8) -> Option<Arc<dyn PciFunction>> {
+        // A single bus of single-function devices.
+        if bus != 0 || devfn & 0x7 != 0 {
+            return None;
+        }
+        self.functions.lock().get(&(devfn >> 3)).cloned()
+    }
+
+    fn config_read(&self, bus: u8, devfn: u8, offset: usize, width: usize) -> usize {
+        match self.function(bus, devfn) {
+            Some(function) => function.config_read(offset, width) as usize,
+            None => all_ones(width),
+        }
+    }
+
+    fn config_write(&self, bus: u8, devfn: u8, offset: usize, width: usize, value: usize) {
+        if let Some(function) = self.function(bus, devfn) {
+            function.config_write(offset, width, value as u32);
+        }
+    }
+
+    /// Returns the function with a BAR decoding the region of `addr`, along with the index of
+    /// the BAR and the offset of `addr` in it.
+    fn find_bar<F>(&self, contains: F) -> Option<(Arc<dyn PciFunction>, usize, usize)>
+    where
+        F: Fn(&BarRegion) -> Option<usize>,
+    {
+        let functions: Vec<_> = self.functions.lock().values().cloned().collect();
+        functions.into_iter().find_map(|function| {
+            let (bar, offset) = function
+                .bar_regions()
+                .iter()
+                .find_map(|(bar, region)| contains(region).map(|offset| (*bar, offset)))?;
+            Some((function, bar, offset))
+        })
+    }
+
+    fn find_mem_bar(&self, addr: GuestPhysAddr) -> Option<(Arc<dyn PciFunction>, usize, usize)> {
+        self.find_bar(|region| match region {
+            BarRegion::Mem(range) if range.contains(&addr) => Some(addr - range.start),
+            _ => None,
+        })
+    }
+
+    fn ecam_contains(&self, addr: GuestPhysAddr) -> bool {
+        (self.ecam_base..self.ecam_base + self.ecam_size).contains(&addr)
+    }
+
+    /// Decodes an ECAM offset into its bus, device and function, and register.
+    fn ecam_decode(&self, addr: GuestPhysAddr) -> (u8, u8, usize) {
+        let offset = addr - self.ecam_base;
+        ((offset >> 20) as u8, (offset >> 12) as u8, offset & 0xfff)
+    }
+
+    /// Decodes `CONFIG_ADDRESS` for an access to the `CONFIG_DATA` port `port`.
+    #[cfg(target_arch = "x86_64")]
+    fn config_address_decode(&self, port: u16) -> Option<(u8, u8, usize)> {
+        let address = self.config_address.load(Ordering::Acquire);
+        if address & CONFIG_ADDRESS_ENABLE == 0 {
+            return None;
+        }
+        // Bits 27:24 extend the register number, as on AMD chipsets.
+        let offset = (((address >> 24) & 0xf) << 8) as usize
+            | (address & 0xfc) as usize
+            | (port - PCI_CONFIG_DATA) as usize;
+        Some(((address >> 16) as u8, (address >> 8) as u8, offset))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn find_io_bar(&self, port: u16) -> Option<(Arc<dyn PciFunction>, usize, usize)> {
+        self.find_bar(|region| match region {
+            BarRegion::Io(range) if range.contains(&port) => Some((port - range.start) as usize),
+            _ => None,
+        })
+    }
+}
+
+impl EmuDevice for PciHostBridge {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        let mut regions = vec![self.ecam_base..self.ecam_base + self.ecam_size];
+        if !self.mem_window.is_empty() {
+            let start = GuestPhysAddr::from(self.mem_window.start as usize);
+            regions.push(start..start + (self.mem_window.end - self.mem_window.start) as usize);
+        }
+        regions
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn pio_ports(&self) -> Vec<Range<u16>> {
+        let mut ports = vec![PCI_CONFIG_ADDRESS..PCI_CONFIG_DATA + 4];
+        if !self.io_window.is_empty() {
+            ports.push(self.io_window.start as u16..self.io_window.end as u16);
+        }
+        ports
+    }
+
+    fn handle_mmio_read(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize) -> usize {
+        if self.ecam_contains(addr) {
+            let (bus, devfn, offset) = self.ecam_decode(addr);
+            return self.config_read(bus, devfn, offset, width);
+        }
+        match self.find_mem_bar(addr) {
+            Some((function, bar, offset)) => function.bar_read(vcpu_id, bar, offset, width),
+            None => all_ones(width),
+        }
+    }
+
+    fn handle_mmio_write(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize, value: usize) {
+        if self.ecam_contains(addr) {
+            let (bus, devfn, offset) = self.ecam_decode(addr);
+            self.config_write(bus, devfn, offset, width, value);
+            return;
+        }
+        if let Some((function, bar, offset)) = self.find_mem_bar(addr) {
+            function.bar_write(vcpu_id, bar, offset, width, value);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn handle_pio_read(&self, vcpu_id: usize, port: u16, width: usize) -> usize {
+        match port {
+            PCI_CONFIG_ADDRESS if width == 4 => {
+                self.config_address.load(Ordering::Acquire) as usize
+            }
+            PCI_CONFIG_ADDRESS..PCI_CONFIG_DATA => all_ones(width),
+            PCI_CONFIG_DATA..=0xcff => match self.config_address_decode(port) {
+                Some((bus, devfn, offset)) => self.config_read(bus, devfn, offset, width),
+                None => all_ones(width),
+            },
+            _ => match self.find_io_bar(port) {
+                Some((function, bar, offset)) => function.bar_read(vcpu_id, bar, offset, width),
+                None => all_ones(width),
+            },
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn handle_pio_write(&self, vcpu_id: usize, port: u16, width: usize, value: usize) {
+        match port {
+            PCI_CONFIG_ADDRESS if width == 4 => {
+                self.config_address.store(value as u32, Ordering::Release);
+            }
+            // The other bytes, e.g. the reset control register at 0xcf9, are ignored.
+            PCI_CONFIG_ADDRESS..PCI_CONFIG_DATA => {}
+            PCI_CONFIG_DATA..=0xcff => {
+                if let Some((bus, devfn, offset)) = self.config_address_decode(port) {
+                    self.config_write(bus, devfn, offset, width, value);
+                }
+            }
+            _ => {
+                if let Some((function, bar, offset)) = self.find_io_bar(port) {
+                    function.bar_write(vcpu_id, bar, offset, width, value);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! An emulated PCIe host bridge and the PCI bus of the VMs.
+//!
+//! The host bridge is the `emu_devices` entry of `Emu-Type` [`EmuType::PciHostBridge`],
+//! whose `Base-Ipa` and `Ipa_len` are its ECAM window, like the `pcie@10000000` of the QEMU
+//! `virt` machines. It has a single bus, bus 0, whose device 0 is the host bridge itself. On
+//! x86_64, its configuration space is also accessed through the `0xcf8`/`0xcfc` ports.
+//!
+//! Its `EmuConfig` is `[mem_base, mem_size]`, the window of guest physical addresses where
+//! the BARs of the functions are assigned, followed by `[io_base, io_size]` on x86_64 for the
+//! I/O port BARs. The BARs are assigned when a function is added to the bus, as firmware
+//! would, but the guest may move them within the windows.
+//!
+//! The functions are added to the bus through [`add_function`], by the devices whose
+//! `emu_devices` entries follow the one of the host bridge, e.g. the virtio-pci devices. The
+//! guest accesses to their BARs in the windows trap into the host bridge, which dispatches
+//! them to the [`PciFunction`] owning the BAR.
+//!
+//! A physical function of the host is passed through to the VM by an entry of `Emu-Type`
+//! [`EmuType::PciPassthrough`], whose `EmuConfig` is its `[bus, device, function]` on the
+//! host, and whose `Alloc-Irq` is the physical interrupt of its INTx, if any, injected into
+//! the guest like the ones of the passthrough devices, see [`passthrough`].
+//!
+//! The MSI and MSI-X capabilities of the functions are emulated, see [`msi`], so that the MSIs
+//! are delivered as the guest programmed them, if the VM has an interrupt controller receiving
+//! them: the virtual local APICs on x86_64, or a GICv2m frame on aarch64, whose entry must come
+//! before the ones of the functions.
+//!
+//! [`EmuType::PciHostBridge`]: super::EmuType::PciHostBridge
+//! [`EmuType::PciPassthrough`]: super::EmuType::PciPassthrough
+
+extern crate alloc;
+
+mod config;
+mod host;
+mod msi;
+mod passthrough;
+mod phys;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+
+use crate::vmm::devices::EmuDevice;
+pub use config::{Bar, BarKind, BarRegion, ConfigSpace};
+use host::PciHostBridge;
+pub use msi::{MsixCapability, MsixTable};
+pub use passthrough::create_passthrough;
+
+/// The number of devices of a PCI bus.
+pub const PCI_NUM_DEVICES: u8 = 32;
+
+/// A function on the PCI bus of a VM.
+///
+/// All the accesses are made on behalf of the vCPU `vcpu_id`, by its own task.
+pub trait PciFunction: Send + Sync {
+    /// Returns the name of the function, for logging.
+    fn name(&self) -> &str;
+
+    /// Handles a guest read of `width` bytes at `offset` of the configuration space.
+    fn config_read(&self, offset: usize, width: usize) -> u32;
+
+    /// Handles a guest write of `width` bytes at `offset` of the configuration space.
+    fn config_write(&self, offset: usize, width: usize, value: u32);
+
+    /// Returns what the BARs of the function decode, with their indexes.
+    fn bar_regions(&self) -> Vec<(usize, BarRegion)>;
+
+    /// Handles a guest read of `width` bytes at `offset` of the BAR `bar`.
+    fn bar_read(&self, _vcpu_id: usize, _bar: usize, _offset: usize, _width: usize) -> usize {
+        0
+    }
+
+    /// Handles a guest write of `width` bytes at `offset` of the BAR `bar`.
+    fn bar_write(
+        &self,
+        _vcpu_id: usize,
+        _bar: usize,
+        _offset: usize,
+        _width: usize,
+        _value: usize,
+    ) {
+    }
+}
+
+/// The PCI host bridges of all VMs, stored in a BTreeMap where the key is the VM ID.
+static VM_PCI_HOSTS: Mutex<BTreeMap<usize, Arc<PciHostBridge>>> = Mutex::new(BTreeMap::new());
+
+/// Adds `function` to the PCI bus of `vm_id` as the device `device`, or as the first free
+/// one if `None`, and assigns its BARs.
+///
+/// Returns the device number of the function.
+pub fn add_function(
+    vm_id: usize,
+    device: Option<u8>,
+    function: Arc<dyn PciFunction>,
+) -> AxResult<u8> {
+    let Some(host) = VM_PCI_HOSTS.lock().get(&vm_id).cloned() else {
+        return ax_err!(
+            NotFound,
+            "VM has no PCI host bridge, it must come first in emu_devices"
+        );
+    };
+    host.add_function(device, function)
+}
+
+/// Returns the device number requested by the `emu_devices` entry of a PCI function in its
+/// `Base-Ipa`, `None` for 0, the first free one.
+pub fn device_number(config: &EmulatedDeviceConfig) -> AxResult<Option<u8>> {
+    match config.base_gpa {
+        0 => Ok(None),
+        device => match u8::try_from(device) {
+            Ok(device) => Ok(Some(device)),
+            Err(_) => ax_err!(InvalidInput, "PCI device number out of the bus"),
+        },
+    }
+}
+
+/// Removes the PCI bus of a VM, called when the VM is torn down.
+pub fn remove_vm_pci(vm_id: usize) {
+    VM_PCI_HOSTS.lock().remove(&vm_id);
+}
+
+/// Creates the PCIe host bridge of a VM from its `emu_devices` entry.
+pub fn create_host_bridge(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<dyn EmuDevice>> {
+    let mut hosts = VM_PCI_HOSTS.lock();
+    if hosts.contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "VM already has a PCI host bridge");
+    }
+    let host = Arc::new(PciHostBridge::new(vm_id, config)?);
+    hosts.insert(vm_id, host.clone());
+    Ok(host)
+}
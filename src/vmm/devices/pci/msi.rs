@@ -0,0 +1,274 @@
+//! The MSI and MSI-X capabilities of the PCI functions of a VM, and their MSI-X tables.
+//!
+//! The guest programs the messages of a function into its capability or its MSI-X table,
+//! which are emulated here, and the messages are then delivered through
+//! [`irq::deliver_msi`](crate::vmm::irq::deliver_msi) when the function raises them.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::ConfigSpace;
+use crate::vmm::irq::MsiMessage;
+
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// The offsets of the registers of an MSI capability.
+pub const PCI_MSI_FLAGS: usize = 0x02;
+pub const PCI_MSI_ADDRESS_LO: usize = 0x04;
+pub const PCI_MSI_ADDRESS_HI: usize = 0x08;
+pub const PCI_MSI_DATA_32: usize = 0x08;
+pub const PCI_MSI_DATA_64: usize = 0x0c;
+pub const PCI_MSI_MASK_32: usize = 0x0c;
+pub const PCI_MSI_MASK_64: usize = 0x10;
+
+pub const PCI_MSI_FLAGS_ENABLE: u16 = 1 << 0;
+pub const PCI_MSI_FLAGS_64BIT: u16 = 1 << 7;
+pub const PCI_MSI_FLAGS_MASKBIT: u16 = 1 << 8;
+
+/// The offsets of the registers of an MSI-X capability.
+pub const PCI_MSIX_FLAGS: usize = 0x02;
+pub const PCI_MSIX_TABLE: usize = 0x04;
+
+pub const PCI_MSIX_FLAGS_QSIZE: u16 = 0x7ff;
+pub const PCI_MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+pub const PCI_MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+/// The BAR indicator in the low bits of the table and PBA offsets.
+pub const PCI_MSIX_BIR_MASK: u32 = 0x7;
+
+/// The size of an entry of an MSI-X table.
+pub const PCI_MSIX_ENTRY_SIZE: usize = 16;
+pub const PCI_MSIX_ENTRY_LOWER_ADDR: usize = 0x0;
+pub const PCI_MSIX_ENTRY_UPPER_ADDR: usize = 0x4;
+pub const PCI_MSIX_ENTRY_DATA: usize = 0x8;
+pub const PCI_MSIX_ENTRY_VECTOR_CTRL: usize = 0xc;
+pub const PCI_MSIX_ENTRY_CTRL_MASKBIT: u32 = 1 << 0;
+
+/// An MSI capability with a single vector and no per-vector masking, emulated in the
+/// configuration space of a function.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    offset: usize,
+    is_64: bool,
+}
+
+impl MsiCapability {
+    /// Emulates the registers of an MSI capability at `offset` of `config`, whose ID and next
+    /// pointer are set by the caller, with a 64-bit address if `is_64`.
+    pub fn emulate(config: &mut ConfigSpace, offset: usize, is_64: bool) -> Self {
+        let cap = Self { offset, is_64 };
+        let flags = if is_64 { PCI_MSI_FLAGS_64BIT } else { 0 };
+        config.set(offset + PCI_MSI_FLAGS, &flags.to_le_bytes());
+        config.set_write_mask(offset + PCI_MSI_FLAGS, &[PCI_MSI_FLAGS_ENABLE as u8, 0]);
+        config.set_write_mask(offset + PCI_MSI_ADDRESS_LO, &0xffff_fffcu32.to_le_bytes());
+        if is_64 {
+            config.set_write_mask(offset + PCI_MSI_ADDRESS_HI, &[0xff; 4]);
+        }
+        config.set_write_mask(offset + cap.data_offset(), &[0xff; 2]);
+        cap
+    }
+
+    fn data_offset(&self) -> usize {
+        if self.is_64 {
+            PCI_MSI_DATA_64
+        } else {
+            PCI_MSI_DATA_32
+        }
+    }
+
+    /// Returns the registers of the capability, from its ID.
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.data_offset() + 2
+    }
+
+    /// Returns the message programmed by the guest, if it enabled MSI.
+    pub fn message(&self, config: &ConfigSpace) -> Option<MsiMessage> {
+        if config.read_u16(self.offset + PCI_MSI_FLAGS) & PCI_MSI_FLAGS_ENABLE == 0 {
+            return None;
+        }
+        let mut address = config.read_u32(self.offset + PCI_MSI_ADDRESS_LO) as u64;
+        if self.is_64 {
+            address |= (config.read_u32(self.offset + PCI_MSI_ADDRESS_HI) as u64) << 32;
+        }
+        let data = config.read_u16(self.offset + self.data_offset()) as u32;
+        Some(MsiMessage { address, data })
+    }
+}
+
+/// An MSI-X capability emulated in the configuration space of a function, whose table and
+/// PBA are in its BARs.
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    offset: usize,
+}
+
+impl MsixCapability {
+    /// Adds an MSI-X capability of `size` vectors to `config`, whose table and PBA are at
+    /// `(bar, offset)` in the BARs of the function.
+    pub fn add(
+        config: &mut ConfigSpace,
+        size: usize,
+        (table_bar, table_offset): (usize, usize),
+        (pba_bar, pba_offset): (usize, usize),
+    ) -> Self {
+        let flags = (size - 1) as u16 & PCI_MSIX_FLAGS_QSIZE;
+        let mut body = Vec::new();
+        body.extend_from_slice(&flags.to_le_bytes());
+        body.extend_from_slice(&((table_offset | table_bar) as u32).to_le_bytes());
+        body.extend_from_slice(&((pba_offset | pba_bar) as u32).to_le_bytes());
+        let offset = config.add_capability(PCI_CAP_ID_MSIX, &body);
+        let control_mask = PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL;
+        config.set_write_mask(offset + PCI_MSIX_FLAGS, &control_mask.to_le_bytes());
+        Self { offset }
+    }
+
+    /// Returns whether the guest enabled MSI-X, and whether it masked all the vectors.
+    pub fn control(&self, config: &ConfigSpace) -> (bool, bool) {
+        let flags = config.read_u16(self.offset + PCI_MSIX_FLAGS);
+        (
+            flags & PCI_MSIX_FLAGS_ENABLE != 0,
+            flags & PCI_MSIX_FLAGS_MASKALL != 0,
+        )
+    }
+}
+
+/// An entry of an MSI-X table.
+#[derive(Debug, Clone, Copy)]
+struct MsixEntry {
+    address: u64,
+    data: u32,
+    masked: bool,
+    pending: bool,
+}
+
+/// An emulated MSI-X table, and its pending bit array.
+pub struct MsixTable {
+    entries: Vec<MsixEntry>,
+}
+
+impl MsixTable {
+    /// Creates a table of `size` vectors, all masked.
+    pub fn new(size: usize) -> Self {
+        let entry = MsixEntry {
+            address: 0,
+            data: 0,
+            masked: true,
+            pending: false,
+        };
+        Self {
+            entries: vec![entry; size],
+        }
+    }
+
+    /// Returns the number of vectors of the table.
+    pub fn num_vectors(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Handles a guest read of `width` bytes at `offset` of the table.
+    pub fn read(&self, offset: usize, width: usize) -> u64 {
+        if width == 8 {
+            return self.read(offset, 4) | (self.read(offset + 4, 4) << 32);
+        }
+        let Some(entry) = self.entries.get(offset / PCI_MSIX_ENTRY_SIZE) else {
+            return 0;
+        };
+        let value = match (offset % PCI_MSIX_ENTRY_SIZE) & !0x3 {
+            PCI_MSIX_ENTRY_LOWER_ADDR => entry.address as u32,
+            PCI_MSIX_ENTRY_UPPER_ADDR => (entry.address >> 32) as u32,
+            PCI_MSIX_ENTRY_DATA => entry.data,
+            _ => entry.masked as u32,
+        };
+        (value >> ((offset % 4) * 8)) as u64
+    }
+
+    /// Handles a guest write of `width` bytes at `offset` of the table, only complete dwords
+    /// being written.
+    ///
+    /// Returns the index of the entry written.
+    pub fn write(&mut self, offset: usize, width: usize, value: u64) -> Option<usize> {
+        if width == 8 {
+            self.write(offset, 4, value & 0xffff_ffff);
+            return self.write(offset + 4, 4, value >> 32);
+        }
+        let index = offset / PCI_MSIX_ENTRY_SIZE;
+        let entry = self.entries.get_mut(index)?;
+        if width != 4 {
+            return None;
+        }
+        let value = value as u32;
+        match offset % PCI_MSIX_ENTRY_SIZE {
+            PCI_MSIX_ENTRY_LOWER_ADDR => {
+                entry.address = (entry.address & !0xffff_ffff) | (value & !0x3) as u64;
+            }
+            PCI_MSIX_ENTRY_UPPER_ADDR => {
+                entry.address = (entry.address & 0xffff_ffff) | ((value as u64) << 32);
+            }
+            PCI_MSIX_ENTRY_DATA => entry.data = value,
+            _ => entry.masked = value & PCI_MSIX_ENTRY_CTRL_MASKBIT != 0,
+        }
+        Some(index)
+    }
+
+    /// Handles a guest read of `width` bytes at `offset` of the pending bit array.
+    pub fn read_pba(&self, offset: usize, width: usize) -> u64 {
+        let first = offset * 8;
+        let mut bits = 0;
+        for bit in 0..(width * 8).min(64) {
+            if self
+                .entries
+                .get(first + bit)
+                .is_some_and(|entry| entry.pending)
+            {
+                bits |= 1 << bit;
+            }
+        }
+        bits
+    }
+
+    /// Returns the message of the vector `index`, whether it is masked or not.
+    pub fn message(&self, index: usize) -> Option<MsiMessage> {
+        let entry = self.entries.get(index)?;
+        Some(MsiMessage {
+            address: entry.address,
+            data: entry.data,
+        })
+    }
+
+    /// Returns whether the vector `index` is masked.
+    pub fn is_masked(&self, index: usize) -> bool {
+        self.entries.get(index).is_none_or(|entry| entry.masked)
+    }
+
+    /// Signals the vector `index`, returning its message to be delivered, or `None` if it is
+    /// masked, on its own or with all the vectors if `all_masked`, and then left pending.
+    pub fn signal(&mut self, index: usize, all_masked: bool) -> Option<MsiMessage> {
+        let entry = self.entries.get_mut(index)?;
+        if entry.masked || all_masked {
+            entry.pending = true;
+            return None;
+        }
+        self.message(index)
+    }
+
+    /// Returns the messages of the pending vectors which are no longer masked, which are no
+    /// longer pending.
+    pub fn take_unmasked(&mut self, all_masked: bool) -> Vec<MsiMessage> {
+        if all_masked {
+            return Vec::new();
+        }
+        self.entries
+            .iter_mut()
+            .filter(|entry| entry.pending && !entry.masked)
+            .map(|entry| {
+                entry.pending = false;
+                MsiMessage {
+                    address: entry.address,
+                    data: entry.data,
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,787 @@
+//! Passthrough of a physical PCI function of the host to a VM.
+//!
+//! The guest sees a virtualized configuration space: the identity registers, the command and
+//! status registers and the kept capabilities are those of the physical function, while its
+//! BARs and interrupt line are emulated. The capabilities only meaningful to the host, such
+//! as all the extended ones, are hidden from the guest.
+//!
+//! The MSI and MSI-X capabilities are kept if the entry reserves physical interrupts for
+//! them, in its `EmuConfig` `[bus, device, function, msi_irq_base, msi_irq_count]`, and both
+//! the host and the guest can receive MSIs, see [`irq`]. They are hidden otherwise, and the
+//! guest then falls back to INTx. The guest programs an emulated MSI capability, with a
+//! single vector, and an emulated MSI-X table, trapped even if its BAR is mapped, while the
+//! function is programmed to raise the physical interrupts from `msi_irq_base` on, each of
+//! them delivered to the guest as the message it programmed for the matching vector. The
+//! MSI-X vectors are capped to `msi_irq_count`, and the per-vector masks and the enable and
+//! mask-all bits of the guest are applied to the function itself.
+//!
+//! The memory BARs whose host address and size are page aligned are mapped into the guest at
+//! the addresses it programs, while the other BARs trap into the host bridge and are
+//! forwarded to the function, which requires them to be in the MMIO regions of the platform
+//! config.
+//!
+//! With an IOMMU, the DMA of the function is translated through the stage-2 page table of the
+//! VM, see [`iommu`]. Without one, the function does DMA to host physical addresses, so the
+//! memory of the VM must be identity mapped, and nothing prevents it from reaching the memory
+//! of the host or of the other VMs.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::{GuestPhysAddr, HostPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+use spin::Mutex;
+
+use super::config::{
+    PCI_BASE_ADDRESS_0, PCI_CACHE_LINE_SIZE, PCI_CAPABILITY_LIST, PCI_COMMAND,
+    PCI_COMMAND_INTX_DISABLE, PCI_COMMAND_IO, PCI_COMMAND_MASTER, PCI_COMMAND_MEMORY,
+    PCI_HEADER_TYPE, PCI_INTERRUPT_LINE, PCI_INTERRUPT_PIN, PCI_NUM_BARS, PCI_STATUS,
+    PCI_STATUS_CAP_LIST, PCI_SUBSYSTEM_VENDOR_ID,
+};
+use super::msi::{
+    MsiCapability, MsixTable, PCI_CAP_ID_MSI, PCI_CAP_ID_MSIX, PCI_MSI_ADDRESS_HI,
+    PCI_MSI_ADDRESS_LO, PCI_MSI_DATA_32, PCI_MSI_DATA_64, PCI_MSI_FLAGS, PCI_MSI_FLAGS_64BIT,
+    PCI_MSI_FLAGS_ENABLE, PCI_MSI_FLAGS_MASKBIT, PCI_MSI_MASK_32, PCI_MSI_MASK_64,
+    PCI_MSIX_BIR_MASK, PCI_MSIX_ENTRY_CTRL_MASKBIT, PCI_MSIX_ENTRY_DATA, PCI_MSIX_ENTRY_LOWER_ADDR,
+    PCI_MSIX_ENTRY_SIZE, PCI_MSIX_ENTRY_UPPER_ADDR, PCI_MSIX_ENTRY_VECTOR_CTRL, PCI_MSIX_FLAGS,
+    PCI_MSIX_FLAGS_ENABLE, PCI_MSIX_FLAGS_MASKALL, PCI_MSIX_FLAGS_QSIZE, PCI_MSIX_TABLE,
+};
+use super::phys::{Bdf, PhysBar, PhysFunction};
+use super::{BarKind, BarRegion, ConfigSpace, PciFunction};
+use crate::vmm::irq::MsiMessage;
+use crate::vmm::{VMRef, iommu, irq, vm_list};
+
+/// The capabilities kept visible to the guest: power management, vendor-specific and PCI
+/// Express, along with MSI and MSI-X if they are forwarded.
+const KEPT_CAPABILITIES: &[u8] = &[0x01, 0x09, 0x10];
+
+/// The end of the header, where the capabilities start.
+const HEADER_SIZE: usize = 0x40;
+/// The end of the configuration space of conventional PCI, the extended capabilities
+/// following it.
+const LEGACY_CONFIG_SPACE_SIZE: usize = 0x100;
+const PCI_BASE_ADDRESS_END: usize = PCI_BASE_ADDRESS_0 + PCI_NUM_BARS * 4;
+
+/// The bits of the command register the guest controls.
+const GUEST_COMMAND_MASK: u16 =
+    PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE;
+
+/// A capability of the physical function kept visible to the guest.
+struct Capability {
+    id: u8,
+    /// Its registers, from its ID to the next capability of the physical function.
+    range: Range<usize>,
+    /// The offset of the next kept capability, 0 for the last one.
+    next: u8,
+}
+
+/// The MSI capability of the physical function, emulated for the guest.
+struct Msi {
+    /// The capability emulated in the shadow configuration space, at the same offset.
+    cap: MsiCapability,
+    offset: usize,
+    is_64: bool,
+    per_vector_mask: bool,
+}
+
+/// The MSI-X capability of the physical function, and its table.
+struct Msix {
+    offset: usize,
+    /// The BAR of the table, and its offset in it.
+    table_bar: usize,
+    table_offset: usize,
+    /// The size of the table of the physical function.
+    table_size: usize,
+    /// The number of vectors forwarded to the guest, each on its own physical interrupt.
+    vectors: usize,
+}
+
+impl Msix {
+    /// Returns the pages of its BAR holding the table, never mapped into the guest.
+    fn table_pages(&self) -> Range<usize> {
+        let end = self.table_offset + self.table_size * PCI_MSIX_ENTRY_SIZE;
+        (self.table_offset & !(PAGE_SIZE_4K - 1))..end.next_multiple_of(PAGE_SIZE_4K)
+    }
+}
+
+struct PassthroughState {
+    /// The emulated BARs, interrupt line and MSI capability, and a copy of the command
+    /// register.
+    shadow: ConfigSpace,
+    /// The BARs mapped into the guest, by index.
+    mapped: BTreeMap<usize, Range<GuestPhysAddr>>,
+    /// The MSI-X table programmed by the guest.
+    msix_table: MsixTable,
+}
+
+/// A physical PCI function passed through to a VM.
+pub struct PciPassthrough {
+    vm_id: usize,
+    name: String,
+    vm: VMRef,
+    phys: PhysFunction,
+    bars: [Option<PhysBar>; PCI_NUM_BARS],
+    capabilities: Vec<Capability>,
+    /// The physical interrupts the MSIs are raised on.
+    msi_irqs: Range<usize>,
+    msi: Option<Msi>,
+    msix: Option<Msix>,
+    state: Mutex<PassthroughState>,
+}
+
+/// What a guest write to the configuration space changed, the later variants requiring
+/// more work than the earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Changed {
+    Nothing,
+    /// The MSI capability.
+    Msi,
+    /// The BARs or the command register.
+    Mappings,
+}
+
+/// Returns whether a BAR can be mapped into the guest, rather than trapped.
+fn is_mappable(bar: &PhysBar) -> bool {
+    bar.bar.kind != BarKind::Io
+        && bar.address % PAGE_SIZE_4K as u64 == 0
+        && bar.bar.size % PAGE_SIZE_4K as u64 == 0
+}
+
+impl PciPassthrough {
+    /// Opens the physical function `bdf` for the guest of `vm_id`, whose MSIs are raised on
+    /// the physical interrupts `msi_irqs`, if any.
+    pub fn new(
+        vm_id: usize,
+        config: &EmulatedDeviceConfig,
+        bdf: Bdf,
+        msi_irqs: Range<usize>,
+    ) -> AxResult<Self> {
+        let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+            return ax_err!(NotFound, "VM not found");
+        };
+        let phys = PhysFunction::open(bdf)?;
+        if phys.read_u8(PCI_HEADER_TYPE) & 0x7f != 0 {
+            return ax_err!(Unsupported, "only PCI endpoints can be passed through");
+        }
+        // The function is quiesced until the guest enables it.
+        let command = phys.read_u16(PCI_COMMAND);
+        phys.write_u16(
+            PCI_COMMAND,
+            (command & !GUEST_COMMAND_MASK) | PCI_COMMAND_INTX_DISABLE,
+        );
+        let bars = phys.probe_bars()?;
+
+        let mut shadow = ConfigSpace::new(0, 0, 0, 0);
+        for (index, bar) in bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                shadow.add_bar(index, bar.bar);
+            }
+        }
+        let pin = match config.irq_id {
+            0 => 0,
+            _ => phys.read_u8(PCI_INTERRUPT_PIN),
+        };
+        if config.irq_id != 0 && pin == 0 {
+            warn!("{}: PCI {} has no INTx", config.name, bdf);
+        }
+        shadow.set_interrupt(pin, config.irq_id as u8);
+
+        let all = phys.capabilities();
+        let find = |id| {
+            all.iter()
+                .find(|(_, other)| *other == id)
+                .map(|(offset, _)| *offset)
+        };
+        let (msi, msix) = if msi_irqs.is_empty() {
+            (None, None)
+        } else {
+            let msi = find(PCI_CAP_ID_MSI).map(|offset| {
+                let flags = phys.read_u16(offset + PCI_MSI_FLAGS);
+                let is_64 = flags & PCI_MSI_FLAGS_64BIT != 0;
+                Msi {
+                    cap: MsiCapability::emulate(&mut shadow, offset, is_64),
+                    offset,
+                    is_64,
+                    per_vector_mask: flags & PCI_MSI_FLAGS_MASKBIT != 0,
+                }
+            });
+            let msix = find(PCI_CAP_ID_MSIX).map(|offset| {
+                let table = phys.read_u32(offset + PCI_MSIX_TABLE);
+                let table_size =
+                    (phys.read_u16(offset + PCI_MSIX_FLAGS) & PCI_MSIX_FLAGS_QSIZE) as usize + 1;
+                Msix {
+                    offset,
+                    table_bar: (table & PCI_MSIX_BIR_MASK) as usize,
+                    table_offset: (table & !PCI_MSIX_BIR_MASK) as usize,
+                    table_size,
+                    vectors: table_size.min(msi_irqs.len()),
+                }
+            });
+            (msi, msix)
+        };
+        if let Some(msix) = &msix {
+            if bars.get(msix.table_bar).is_none_or(|bar| bar.is_none()) {
+                return ax_err!(BadState, "PCI MSI-X table not in a BAR");
+            }
+        }
+        let mut kept_ids = KEPT_CAPABILITIES.to_vec();
+        if msi.is_some() {
+            kept_ids.push(PCI_CAP_ID_MSI);
+        }
+        if msix.is_some() {
+            kept_ids.push(PCI_CAP_ID_MSIX);
+        }
+
+        let passthrough = Self {
+            vm_id,
+            name: config.name.clone(),
+            vm,
+            capabilities: Self::kept_capabilities(&all, &kept_ids),
+            phys,
+            bars,
+            msi_irqs,
+            state: Mutex::new(PassthroughState {
+                shadow,
+                mapped: BTreeMap::new(),
+                msix_table: MsixTable::new(msix.as_ref().map_or(0, |msix| msix.vectors)),
+            }),
+            msi,
+            msix,
+        };
+        passthrough.init_msi();
+        Ok(passthrough)
+    }
+
+    /// Links the capabilities of the physical function whose IDs are in `kept_ids` together.
+    fn kept_capabilities(all: &[(usize, u8)], kept_ids: &[u8]) -> Vec<Capability> {
+        let mut offsets: Vec<usize> = all.iter().map(|(offset, _)| *offset).collect();
+        offsets.sort_unstable();
+
+        let mut kept: Vec<Capability> = all
+            .iter()
+            .filter(|(_, id)| kept_ids.contains(id))
+            .map(|&(offset, id)| {
+                let end = offsets
+                    .iter()
+                    .copied()
+                    .find(|other| *other > offset)
+                    .unwrap_or(LEGACY_CONFIG_SPACE_SIZE);
+                Capability {
+                    id,
+                    range: offset..end,
+                    next: 0,
+                }
+            })
+            .collect();
+        for i in 1..kept.len() {
+            kept[i - 1].next = kept[i].range.start as u8;
+        }
+        kept
+    }
+
+    /// Disables MSI and MSI-X on the function, and programs the entries of its MSI-X table
+    /// forwarded to the guest with the physical interrupts they raise, all masked.
+    fn init_msi(&self) {
+        if let Some(msi) = &self.msi {
+            let flags = self.phys.read_u16(msi.offset + PCI_MSI_FLAGS);
+            self.phys
+                .write_u16(msi.offset + PCI_MSI_FLAGS, flags & !PCI_MSI_FLAGS_ENABLE);
+        }
+        let Some(msix) = &self.msix else {
+            return;
+        };
+        let flags = self.phys.read_u16(msix.offset + PCI_MSIX_FLAGS);
+        self.phys
+            .write_u16(msix.offset + PCI_MSIX_FLAGS, flags & !PCI_MSIX_FLAGS_ENABLE);
+        for index in 0..msix.table_size {
+            self.write_msix_entry(
+                index,
+                PCI_MSIX_ENTRY_VECTOR_CTRL,
+                PCI_MSIX_ENTRY_CTRL_MASKBIT,
+            );
+            if index >= msix.vectors {
+                continue;
+            }
+            let Some(message) = self.host_message(index) else {
+                continue;
+            };
+            self.write_msix_entry(index, PCI_MSIX_ENTRY_LOWER_ADDR, message.address as u32);
+            self.write_msix_entry(
+                index,
+                PCI_MSIX_ENTRY_UPPER_ADDR,
+                (message.address >> 32) as u32,
+            );
+            self.write_msix_entry(index, PCI_MSIX_ENTRY_DATA, message.data);
+        }
+    }
+
+    /// Returns the message raising the physical interrupt of the vector `index`.
+    fn host_message(&self, index: usize) -> Option<MsiMessage> {
+        irq::host_msi_message(self.msi_irqs.start + index)
+    }
+
+    /// Writes a field of the entry `index` of the physical MSI-X table.
+    fn write_msix_entry(&self, index: usize, field: usize, value: u32) {
+        let Some(msix) = &self.msix else {
+            return;
+        };
+        let offset = msix.table_offset + index * PCI_MSIX_ENTRY_SIZE + field;
+        let Some(address) = self.host_address(msix.table_bar, offset) else {
+            return;
+        };
+        let vaddr = axhal::mem::phys_to_virt(PhysAddr::from(address)).as_usize();
+        // SAFETY: the address is within the MSI-X table of the function, in the MMIO regions
+        // of the platform.
+        unsafe { (vaddr as *mut u32).write_volatile(value) }
+    }
+
+    /// Applies the MSI capability programmed by the guest to the function.
+    fn update_msi(&self, state: &PassthroughState) {
+        let (Some(msi), Some(host)) = (&self.msi, self.host_message(0)) else {
+            return;
+        };
+        let message = msi.cap.message(&state.shadow);
+        irq::set_msi_message(self.msi_irqs.start, message);
+        let flags = self.phys.read_u16(msi.offset + PCI_MSI_FLAGS);
+        if message.is_none() {
+            self.phys
+                .write_u16(msi.offset + PCI_MSI_FLAGS, flags & !PCI_MSI_FLAGS_ENABLE);
+            return;
+        }
+        let (data, mask) = if msi.is_64 {
+            (PCI_MSI_DATA_64, PCI_MSI_MASK_64)
+        } else {
+            (PCI_MSI_DATA_32, PCI_MSI_MASK_32)
+        };
+        self.phys
+            .write_u32(msi.offset + PCI_MSI_ADDRESS_LO, host.address as u32);
+        if msi.is_64 {
+            self.phys
+                .write_u32(msi.offset + PCI_MSI_ADDRESS_HI, (host.address >> 32) as u32);
+        }
+        self.phys.write_u16(msi.offset + data, host.data as u16);
+        if msi.per_vector_mask {
+            self.phys.write_u32(msi.offset + mask, 0);
+        }
+        // A single vector, in the multiple message enable field.
+        self.phys.write_u16(
+            msi.offset + PCI_MSI_FLAGS,
+            (flags & !0x70) | PCI_MSI_FLAGS_ENABLE,
+        );
+    }
+
+    /// Applies the entry `index` of the MSI-X table programmed by the guest to the function.
+    fn update_msix_entry(&self, state: &PassthroughState, index: usize) {
+        let masked = state.msix_table.is_masked(index);
+        if masked {
+            self.write_msix_entry(
+                index,
+                PCI_MSIX_ENTRY_VECTOR_CTRL,
+                PCI_MSIX_ENTRY_CTRL_MASKBIT,
+            );
+        }
+        irq::set_msi_message(self.msi_irqs.start + index, state.msix_table.message(index));
+        if !masked {
+            self.write_msix_entry(index, PCI_MSIX_ENTRY_VECTOR_CTRL, 0);
+        }
+    }
+
+    /// Returns the offset of `offset` of the BAR `index` in the MSI-X table, if it is in it.
+    fn msix_table_offset(&self, index: usize, offset: usize) -> Option<usize> {
+        let msix = self.msix.as_ref()?;
+        let table = msix.table_offset..msix.table_offset + msix.table_size * PCI_MSIX_ENTRY_SIZE;
+        (index == msix.table_bar && table.contains(&offset)).then(|| offset - table.start)
+    }
+
+    fn capability(&self, offset: usize) -> Option<&Capability> {
+        self.capabilities
+            .iter()
+            .find(|cap| cap.range.contains(&offset))
+    }
+
+    /// Returns the byte at `offset` of the configuration space seen by the guest.
+    fn read_byte(&self, state: &PassthroughState, offset: usize) -> u8 {
+        match offset {
+            PCI_STATUS => {
+                let status = self.phys.read_u8(offset) & !(PCI_STATUS_CAP_LIST as u8);
+                if self.capabilities.is_empty() {
+                    status
+                } else {
+                    status | PCI_STATUS_CAP_LIST as u8
+                }
+            }
+            // A single-function device, without BIST.
+            PCI_HEADER_TYPE => 0,
+            0x0f => 0,
+            PCI_BASE_ADDRESS_0..PCI_BASE_ADDRESS_END => state.shadow.get(offset, 1)[0],
+            // The CardBus CIS pointer and the expansion ROM are hidden.
+            PCI_BASE_ADDRESS_END..PCI_SUBSYSTEM_VENDOR_ID => 0,
+            0x30..PCI_CAPABILITY_LIST => 0,
+            PCI_CAPABILITY_LIST => self
+                .capabilities
+                .first()
+                .map_or(0, |cap| cap.range.start as u8),
+            0x35..PCI_INTERRUPT_LINE => 0,
+            PCI_INTERRUPT_LINE | PCI_INTERRUPT_PIN => state.shadow.get(offset, 1)[0],
+            0..HEADER_SIZE => self.phys.read_u8(offset),
+            _ => match self.capability(offset) {
+                Some(cap) if offset == cap.range.start => cap.id,
+                Some(cap) if offset == cap.range.start + 1 => cap.next,
+                Some(cap) if cap.id == PCI_CAP_ID_MSI => match &self.msi {
+                    Some(msi) if msi.cap.range().contains(&offset) => {
+                        state.shadow.get(offset, 1)[0]
+                    }
+                    // The per-vector masks are hidden.
+                    _ => 0,
+                },
+                Some(cap) if cap.id == PCI_CAP_ID_MSIX => self.read_msix_byte(offset),
+                Some(_) => self.phys.read_u8(offset),
+                None => 0,
+            },
+        }
+    }
+
+    /// Returns the byte at `offset` of the MSI-X capability, whose table size is capped to the
+    /// vectors forwarded to the guest.
+    fn read_msix_byte(&self, offset: usize) -> u8 {
+        let Some(msix) = &self.msix else {
+            return 0;
+        };
+        let flags = msix.offset + PCI_MSIX_FLAGS;
+        if offset != flags && offset != flags + 1 {
+            return self.phys.read_u8(offset);
+        }
+        let value = (self.phys.read_u16(flags) & !PCI_MSIX_FLAGS_QSIZE) | (msix.vectors - 1) as u16;
+        value.to_le_bytes()[offset - flags]
+    }
+
+    /// Handles a guest write of the byte at `offset`, returning what it changed.
+    fn write_byte(&self, state: &mut PassthroughState, offset: usize, value: u8) -> Changed {
+        match offset {
+            PCI_COMMAND | 0x05 => {
+                let mask = GUEST_COMMAND_MASK.to_le_bytes()[offset - PCI_COMMAND];
+                let old = self.phys.read_u8(offset);
+                self.phys.write_u8(offset, (old & !mask) | (value & mask));
+                state.shadow.write(offset, 1, value as u32);
+                Changed::Mappings
+            }
+            // The error bits of the status register are cleared by writing ones.
+            PCI_STATUS | 0x07 => {
+                self.phys.write_u8(offset, value);
+                Changed::Nothing
+            }
+            PCI_CACHE_LINE_SIZE | 0x0d => {
+                self.phys.write_u8(offset, value);
+                Changed::Nothing
+            }
+            PCI_BASE_ADDRESS_0..PCI_BASE_ADDRESS_END => {
+                state.shadow.write(offset, 1, value as u32);
+                Changed::Mappings
+            }
+            PCI_INTERRUPT_LINE => {
+                state.shadow.write(offset, 1, value as u32);
+                Changed::Nothing
+            }
+            HEADER_SIZE.. => match self.capability(offset) {
+                Some(cap) if offset <= cap.range.start + 1 => Changed::Nothing,
+                Some(cap) if cap.id == PCI_CAP_ID_MSI => {
+                    state.shadow.write(offset, 1, value as u32);
+                    Changed::Msi
+                }
+                Some(cap) if cap.id == PCI_CAP_ID_MSIX => {
+                    // Only the enable and mask-all bits are writable.
+                    if offset == cap.range.start + PCI_MSIX_FLAGS + 1 {
+                        let mask = ((PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL) >> 8) as u8;
+                        let old = self.phys.read_u8(offset);
+                        self.phys.write_u8(offset, (old & !mask) | (value & mask));
+                    }
+                    Changed::Nothing
+                }
+                Some(_) => {
+                    self.phys.write_u8(offset, value);
+                    Changed::Nothing
+                }
+                None => Changed::Nothing,
+            },
+            _ => Changed::Nothing,
+        }
+    }
+
+    /// Maps the mappable BARs into the guest at the addresses it programmed, if their
+    /// decoding is enabled, and unmaps the stale ones.
+    fn update_mappings(&self, state: &mut PassthroughState) {
+        let mut wanted = BTreeMap::new();
+        for (index, region) in state.shadow.bar_regions() {
+            match (region, &self.bars[index]) {
+                (BarRegion::Mem(range), Some(bar)) if is_mappable(bar) => {
+                    wanted.insert(index, range);
+                }
+                _ => {}
+            }
+        }
+        let stale: Vec<usize> = state
+            .mapped
+            .iter()
+            .filter(|(index, range)| wanted.get(index) != Some(range))
+            .map(|(index, _)| *index)
+            .collect();
+        for index in stale {
+            let range = state.mapped.remove(&index).unwrap();
+            for (start, size, _) in self.mapped_pieces(index, &range) {
+                if let Err(err) = self.vm.unmap_region(start, size) {
+                    warn!("{}: failed to unmap BAR {}: {:?}", self.name, index, err);
+                }
+            }
+            iommu::flush_vm(self.vm_id);
+        }
+        for (index, range) in wanted {
+            if state.mapped.contains_key(&index) {
+                continue;
+            }
+            let Some(bar) = &self.bars[index] else {
+                continue;
+            };
+            let flags = MappingFlags::READ
+                | MappingFlags::WRITE
+                | MappingFlags::DEVICE
+                | MappingFlags::USER;
+            let result = self.mapped_pieces(index, &range).into_iter().try_for_each(
+                |(start, size, offset)| {
+                    let hpa = HostPhysAddr::from(bar.address as usize + offset);
+                    self.vm.map_region(start, hpa, size, flags)
+                },
+            );
+            match result {
+                Ok(()) => {
+                    debug!(
+                        "VM[{}] {}: BAR {} at {:?} -> {:#x}",
+                        self.vm_id, self.name, index, range.start, bar.address
+                    );
+                    state.mapped.insert(index, range);
+                }
+                Err(err) => warn!("{}: failed to map BAR {}: {:?}", self.name, index, err),
+            }
+        }
+    }
+
+    /// Returns the pieces of the BAR `index` at `range` which are mapped into the guest, as
+    /// their guest address, size and offset in the BAR: all of it, except for the pages of
+    /// the MSI-X table.
+    fn mapped_pieces(
+        &self,
+        index: usize,
+        range: &Range<GuestPhysAddr>,
+    ) -> Vec<(GuestPhysAddr, usize, usize)> {
+        let size = range.end - range.start;
+        let table = match &self.msix {
+            Some(msix) if msix.table_bar == index => msix.table_pages(),
+            _ => return vec![(range.start, size, 0)],
+        };
+        [(0, table.start), (table.end, size)]
+            .into_iter()
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| (range.start + start, end - start, start))
+            .collect()
+    }
+
+    /// Returns the host address of `offset` in the BAR `index`.
+    fn host_address(&self, index: usize, offset: usize) -> Option<usize> {
+        let bar = self.bars[index].as_ref()?;
+        if offset as u64 >= bar.bar.size {
+            return None;
+        }
+        Some(bar.address as usize + offset)
+    }
+}
+
+impl PciFunction for PciPassthrough {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn config_read(&self, offset: usize, width: usize) -> u32 {
+        let state = self.state.lock();
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes[..width].iter_mut().enumerate() {
+            *byte = self.read_byte(&state, offset + i);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    fn config_write(&self, offset: usize, width: usize, value: u32) {
+        let mut state = self.state.lock();
+        let mut changed = Changed::Nothing;
+        for (i, byte) in value.to_le_bytes()[..width].iter().enumerate() {
+            changed = changed.max(self.write_byte(&mut state, offset + i, *byte));
+        }
+        match changed {
+            Changed::Mappings => self.update_mappings(&mut state),
+            Changed::Msi => self.update_msi(&state),
+            Changed::Nothing => {}
+        }
+    }
+
+    fn bar_regions(&self) -> Vec<(usize, BarRegion)> {
+        self.state.lock().shadow.bar_regions()
+    }
+
+    fn bar_read(&self, _vcpu_id: usize, bar: usize, offset: usize, width: usize) -> usize {
+        if let Some(offset) = self.msix_table_offset(bar, offset) {
+            return self.state.lock().msix_table.read(offset, width) as usize;
+        }
+        let Some(address) = self.host_address(bar, offset) else {
+            return 0;
+        };
+        if self.bars[bar].is_some_and(|bar| bar.bar.kind == BarKind::Io) {
+            return io::read(address as u16, width);
+        }
+        let vaddr = axhal::mem::phys_to_virt(PhysAddr::from(address)).as_usize();
+        // SAFETY: the address is within a BAR of the function, in the MMIO regions of the
+        // platform.
+        unsafe {
+            match width {
+                1 => (vaddr as *const u8).read_volatile() as usize,
+                2 => (vaddr as *const u16).read_volatile() as usize,
+                4 => (vaddr as *const u32).read_volatile() as usize,
+                _ => (vaddr as *const u64).read_volatile() as usize,
+            }
+        }
+    }
+
+    fn bar_write(&self, _vcpu_id: usize, bar: usize, offset: usize, width: usize, value: usize) {
+        if let Some(offset) = self.msix_table_offset(bar, offset) {
+            let mut state = self.state.lock();
+            if let Some(index) = state.msix_table.write(offset, width, value as u64) {
+                self.update_msix_entry(&state, index);
+            }
+            return;
+        }
+        let Some(address) = self.host_address(bar, offset) else {
+            return;
+        };
+        if self.bars[bar].is_some_and(|bar| bar.bar.kind == BarKind::Io) {
+            io::write(address as u16, width, value);
+            return;
+        }
+        let vaddr = axhal::mem::phys_to_virt(PhysAddr::from(address)).as_usize();
+        // SAFETY: see `bar_read`.
+        unsafe {
+            match width {
+                1 => (vaddr as *mut u8).write_volatile(value as u8),
+                2 => (vaddr as *mut u16).write_volatile(value as u16),
+                4 => (vaddr as *mut u32).write_volatile(value as u32),
+                _ => (vaddr as *mut u64).write_volatile(value as u64),
+            }
+        }
+    }
+}
+
+/// Forwarding of the accesses to I/O port BARs, which only exist on x86_64.
+mod io {
+    #[cfg(target_arch = "x86_64")]
+    pub fn read(port: u16, width: usize) -> usize {
+        use core::arch::asm;
+        let value: u32;
+        // SAFETY: the port is within an I/O BAR of a function owned by the guest.
+        unsafe {
+            match width {
+                1 => {
+                    let byte: u8;
+                    asm!("in al, dx", out("al") byte, in("dx") port);
+                    value = byte as u32;
+                }
+                2 => {
+                    let word: u16;
+                    asm!("in ax, dx", out("ax") word, in("dx") port);
+                    value = word as u32;
+                }
+                _ => asm!("in eax, dx", out("eax") value, in("dx") port),
+            }
+        }
+        value as usize
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn write(port: u16, width: usize, value: usize) {
+        use core::arch::asm;
+        // SAFETY: see `read`.
+        unsafe {
+            match width {
+                1 => asm!("out dx, al", in("dx") port, in("al") value as u8),
+                2 => asm!("out dx, ax", in("dx") port, in("ax") value as u16),
+                _ => asm!("out dx, eax", in("dx") port, in("eax") value as u32),
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn read(_port: u16, _width: usize) -> usize {
+        0
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn write(_port: u16, _width: usize, _value: usize) {}
+}
+
+/// Creates the passthrough of a physical PCI function from its `emu_devices` entry, and adds
+/// it to the PCI bus of the VM.
+pub fn create_passthrough(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult {
+    let (bus, device, function, msi_irqs) = match *config.cfg_list.as_slice() {
+        [bus, device, function] => (bus, device, function, 0..0),
+        [bus, device, function, base, count] => (bus, device, function, base..base + count),
+        _ => {
+            return ax_err!(
+                InvalidInput,
+                "PCI passthrough EmuConfig must be [bus, device, function] or [bus, device, \
+                 function, msi_irq_base, msi_irq_count]"
+            );
+        }
+    };
+    if bus >= 256 || device >= 32 || function >= 8 {
+        return ax_err!(InvalidInput, "PCI passthrough function out of range");
+    }
+    let bdf = Bdf {
+        bus: bus as u8,
+        device: device as u8,
+        function: function as u8,
+    };
+    let msi_irqs = if msi_irqs.is_empty() {
+        msi_irqs
+    } else if irq::host_msi_message(msi_irqs.start).is_none() || !irq::msi_supported(vm_id) {
+        warn!(
+            "{}: MSIs cannot be forwarded, PCI {} falls back to INTx",
+            config.name, bdf
+        );
+        0..0
+    } else {
+        for irq in msi_irqs.clone() {
+            irq::route_msi(vm_id, irq)?;
+        }
+        msi_irqs
+    };
+    let device = super::device_number(config)?;
+    let function = PciPassthrough::new(vm_id, config, bdf, msi_irqs)?;
+    if iommu::is_present() {
+        iommu::attach_device(vm_id, bdf.requester_id())?;
+    } else {
+        warn!(
+            "{}: no IOMMU, the DMA of PCI {} is not isolated",
+            config.name, bdf
+        );
+    }
+    if config.irq_id != 0 {
+        irq::route_irq(vm_id, config.irq_id)?;
+    }
+    let device = super::add_function(vm_id, device, Arc::new(function))?;
+    info!("VM[{}] PCI 00:{:02x}.0 is host PCI {}", vm_id, device, bdf);
+    Ok(())
+}
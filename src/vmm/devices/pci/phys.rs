@@ -0,0 +1,179 @@
+//! Access to the physical PCI functions of the host, through the ECAM window of the platform
+//! config (`pci-ecam-base` and `pci-bus-end`).
+
+extern crate alloc;
+
+use core::fmt;
+
+use std::os::arceos::modules::{axconfig, axhal};
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use memory_addr::PhysAddr;
+
+use super::config::{
+    PCI_BASE_ADDRESS_0, PCI_CAPABILITY_LIST, PCI_COMMAND, PCI_COMMAND_IO, PCI_COMMAND_MEMORY,
+    PCI_NUM_BARS, PCI_STATUS, PCI_STATUS_CAP_LIST, PCI_VENDOR_ID,
+};
+use super::{Bar, BarKind};
+
+/// The bus, device and function numbers of a physical PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bdf {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Bdf {
+    /// Returns the 16-bit requester ID of the function, `bus << 8 | device << 3 | function`.
+    pub fn requester_id(&self) -> u16 {
+        ((self.bus as u16) << 8) | ((self.device as u16) << 3) | self.function as u16
+    }
+}
+
+impl fmt::Display for Bdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A BAR of a physical function, with its host address.
+#[derive(Debug, Clone, Copy)]
+pub struct PhysBar {
+    pub bar: Bar,
+    pub address: u64,
+}
+
+/// A physical PCI function of the host.
+pub struct PhysFunction {
+    bdf: Bdf,
+    /// The virtual address of its configuration space in the ECAM window.
+    config_base: usize,
+}
+
+impl PhysFunction {
+    /// Opens the physical function `bdf`, which must exist.
+    pub fn open(bdf: Bdf) -> AxResult<Self> {
+        if bdf.bus as usize > axconfig::devices::PCI_BUS_END
+            || bdf.device >= 32
+            || bdf.function >= 8
+        {
+            return ax_err!(InvalidInput, "PCI function out of the host ECAM window");
+        }
+        let offset = (bdf.requester_id() as usize) << 12;
+        let config_base =
+            axhal::mem::phys_to_virt(PhysAddr::from(axconfig::devices::PCI_ECAM_BASE + offset))
+                .as_usize();
+        let function = Self { bdf, config_base };
+        if function.read_u16(PCI_VENDOR_ID) == u16::MAX {
+            return ax_err!(NotFound, "no such PCI function on the host");
+        }
+        Ok(function)
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        // SAFETY: the offset is within the 4K configuration space of the function, mapped by
+        // the host as device memory.
+        unsafe { ((self.config_base + (offset & 0xfff)) as *const u8).read_volatile() }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        // SAFETY: see `read_u8`, for an aligned offset.
+        unsafe { ((self.config_base + (offset & 0xffe)) as *const u16).read_volatile() }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        // SAFETY: see `read_u8`, for an aligned offset.
+        unsafe { ((self.config_base + (offset & 0xffc)) as *const u32).read_volatile() }
+    }
+
+    pub fn write_u8(&self, offset: usize, value: u8) {
+        // SAFETY: see `read_u8`.
+        unsafe { ((self.config_base + (offset & 0xfff)) as *mut u8).write_volatile(value) }
+    }
+
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        // SAFETY: see `read_u16`.
+        unsafe { ((self.config_base + (offset & 0xffe)) as *mut u16).write_volatile(value) }
+    }
+
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        // SAFETY: see `read_u32`.
+        unsafe { ((self.config_base + (offset & 0xffc)) as *mut u32).write_volatile(value) }
+    }
+
+    /// Returns the capabilities of the function, as their offsets and IDs, in list order.
+    pub fn capabilities(&self) -> Vec<(usize, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST != 0 {
+            let mut offset = self.read_u8(PCI_CAPABILITY_LIST) as usize & !0x3;
+            // The list is bounded, in case the function loops it.
+            while offset >= 0x40 && capabilities.len() < 48 {
+                capabilities.push((offset, self.read_u8(offset)));
+                offset = self.read_u8(offset + 1) as usize & !0x3;
+            }
+        }
+        capabilities
+    }
+
+    /// Sizes the BARs of the function, with its decoding disabled meanwhile, and returns them
+    /// with their host addresses, by index.
+    ///
+    /// Fails if a BAR has not been assigned an address by the firmware or the host.
+    pub fn probe_bars(&self) -> AxResult<[Option<PhysBar>; PCI_NUM_BARS]> {
+        let command = self.read_u16(PCI_COMMAND);
+        self.write_u16(
+            PCI_COMMAND,
+            command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY),
+        );
+        let result = self.size_bars();
+        self.write_u16(PCI_COMMAND, command);
+        result
+    }
+
+    fn size_bars(&self) -> AxResult<[Option<PhysBar>; PCI_NUM_BARS]> {
+        let mut bars = [None; PCI_NUM_BARS];
+        let mut index = 0;
+        while index < PCI_NUM_BARS {
+            let offset = PCI_BASE_ADDRESS_0 + index * 4;
+            let low = self.read_u32(offset);
+            self.write_u32(offset, u32::MAX);
+            let probe = self.read_u32(offset);
+            self.write_u32(offset, low);
+            if probe == 0 {
+                index += 1;
+                continue;
+            }
+            let (kind, address, mask, slots) = if probe & 0x1 != 0 {
+                let mask = 0xffff_ffff_ffff_0000 | (probe & !0x3) as u64;
+                (BarKind::Io, (low & !0x3) as u64, mask, 1)
+            } else if probe & 0x6 == 0x4 {
+                let high = self.read_u32(offset + 4);
+                self.write_u32(offset + 4, u32::MAX);
+                let probe_high = self.read_u32(offset + 4);
+                self.write_u32(offset + 4, high);
+                let mask = ((probe_high as u64) << 32) | (probe & !0xf) as u64;
+                let address = ((high as u64) << 32) | (low & !0xf) as u64;
+                (BarKind::Mem64, address, mask, 2)
+            } else {
+                let mask = 0xffff_ffff_0000_0000 | (probe & !0xf) as u64;
+                (BarKind::Mem32, (low & !0xf) as u64, mask, 1)
+            };
+            if address == 0 {
+                return ax_err!(BadState, "PCI BAR not assigned on the host");
+            }
+            bars[index] = Some(PhysBar {
+                bar: Bar {
+                    kind,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: kind != BarKind::Io && probe & 0x8 != 0,
+                },
+                address,
+            });
+            index += slots;
+        }
+        Ok(bars)
+    }
+}
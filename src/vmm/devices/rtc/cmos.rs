@@ -0,0 +1,299 @@
+//! A CMOS RTC model, the MC146818 of the PC, with its 128 bytes of NVRAM.
+//!
+//! The RTC is accessed through an index port and the data port following it. Its alarm and
+//! its periodic and update-ended interrupts are not emulated, register C always reads as no
+//! interrupt pending.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+
+use super::{NANOS_PER_SEC, set_vm_time_nanos, vm_time_nanos};
+use crate::vmm::devices::EmuDevice;
+
+const RTC_SECONDS: usize = 0x00;
+const RTC_MINUTES: usize = 0x02;
+const RTC_HOURS: usize = 0x04;
+const RTC_DAY_OF_WEEK: usize = 0x06;
+const RTC_DAY_OF_MONTH: usize = 0x07;
+const RTC_MONTH: usize = 0x08;
+const RTC_YEAR: usize = 0x09;
+const RTC_REG_A: usize = 0x0a;
+const RTC_REG_B: usize = 0x0b;
+const RTC_REG_C: usize = 0x0c;
+const RTC_REG_D: usize = 0x0d;
+const RTC_CENTURY: usize = 0x32;
+
+/// The registers holding the time.
+const TIME_REGS: [usize; 8] = [
+    RTC_SECONDS,
+    RTC_MINUTES,
+    RTC_HOURS,
+    RTC_DAY_OF_WEEK,
+    RTC_DAY_OF_MONTH,
+    RTC_MONTH,
+    RTC_YEAR,
+    RTC_CENTURY,
+];
+
+/// The 32.768 kHz time base and a 1024 Hz periodic rate.
+const REG_A_DEFAULT: u8 = 0x26;
+/// The update is inhibited while the guest sets the time.
+const REG_B_SET: u8 = 1 << 7;
+/// Binary instead of BCD.
+const REG_B_DM: u8 = 1 << 2;
+/// 24-hour instead of 12-hour.
+const REG_B_24H: u8 = 1 << 1;
+/// The RAM and the time are valid.
+const REG_D_VRT: u8 = 1 << 7;
+/// The PM flag of the hours in 12-hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// The index port masks the NMIs with its bit 7.
+const INDEX_MASK: u8 = 0x7f;
+
+/// A broken-down UTC time.
+#[derive(Clone, Copy)]
+struct DateTime {
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+impl DateTime {
+    /// Converts seconds since the epoch, using the days from civil algorithm.
+    fn from_secs(secs: u64) -> Self {
+        let days = secs / 86400;
+        let secs_of_day = secs % 86400;
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+        }
+    }
+
+    /// Converts to seconds since the epoch, saturating at the epoch.
+    fn to_secs(self) -> u64 {
+        let month = self.month.clamp(1, 12);
+        let year = self.year.max(1970) - (month <= 2) as u64;
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day.max(1) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146_097 + doe).saturating_sub(719_468);
+        days * 86400 + self.hour * 3600 + self.minute * 60 + self.second
+    }
+
+    /// Returns the day of the week, 1 for Sunday.
+    fn day_of_week(self) -> u64 {
+        // The epoch was a Thursday.
+        (self.to_secs() / 86400 + 4) % 7 + 1
+    }
+}
+
+struct CmosState {
+    index: u8,
+    /// The registers and the NVRAM, where the time registers are only meaningful while the
+    /// guest sets the time.
+    ram: [u8; 128],
+}
+
+impl CmosState {
+    fn encode(&self, value: u64) -> u8 {
+        let value = value as u8;
+        if self.ram[RTC_REG_B] & REG_B_DM != 0 {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u64 {
+        let value = if self.ram[RTC_REG_B] & REG_B_DM != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        };
+        value as u64
+    }
+
+    fn encode_hours(&self, hour: u64) -> u8 {
+        if self.ram[RTC_REG_B] & REG_B_24H != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { HOURS_PM } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        self.encode(hour) | pm
+    }
+
+    fn decode_hours(&self, value: u8) -> u64 {
+        if self.ram[RTC_REG_B] & REG_B_24H != 0 {
+            return self.decode(value);
+        }
+        let hour = self.decode(value & !HOURS_PM) % 12;
+        if value & HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// Loads the time registers from `time`.
+    fn store_time(&mut self, time: DateTime) {
+        self.ram[RTC_SECONDS] = self.encode(time.second);
+        self.ram[RTC_MINUTES] = self.encode(time.minute);
+        self.ram[RTC_HOURS] = self.encode_hours(time.hour);
+        self.ram[RTC_DAY_OF_WEEK] = self.encode(time.day_of_week());
+        self.ram[RTC_DAY_OF_MONTH] = self.encode(time.day);
+        self.ram[RTC_MONTH] = self.encode(time.month);
+        self.ram[RTC_YEAR] = self.encode(time.year % 100);
+        self.ram[RTC_CENTURY] = self.encode(time.year / 100);
+    }
+
+    /// Returns the time in the time registers.
+    fn load_time(&self) -> DateTime {
+        DateTime {
+            year: self.decode(self.ram[RTC_CENTURY]) * 100 + self.decode(self.ram[RTC_YEAR]),
+            month: self.decode(self.ram[RTC_MONTH]),
+            day: self.decode(self.ram[RTC_DAY_OF_MONTH]),
+            hour: self.decode_hours(self.ram[RTC_HOURS]),
+            minute: self.decode(self.ram[RTC_MINUTES]),
+            second: self.decode(self.ram[RTC_SECONDS]),
+        }
+    }
+}
+
+/// A CMOS RTC.
+pub struct CmosRtc {
+    vm_id: usize,
+    name: String,
+    /// The index port, followed by the data port.
+    base: u16,
+    state: Mutex<CmosState>,
+}
+
+impl CmosRtc {
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> Self {
+        let mut ram = [0; 128];
+        ram[RTC_REG_A] = REG_A_DEFAULT;
+        ram[RTC_REG_B] = REG_B_24H;
+        ram[RTC_REG_D] = REG_D_VRT;
+        Self {
+            vm_id,
+            name: config.name.clone(),
+            base: config.base_gpa as u16,
+            state: Mutex::new(CmosState { index: 0, ram }),
+        }
+    }
+
+    fn now(&self) -> DateTime {
+        DateTime::from_secs(vm_time_nanos(self.vm_id) / NANOS_PER_SEC)
+    }
+
+    fn commit(&self, state: &CmosState) {
+        set_vm_time_nanos(self.vm_id, state.load_time().to_secs() * NANOS_PER_SEC);
+    }
+
+    fn read_data(&self, state: &mut CmosState) -> u8 {
+        let index = state.index as usize;
+        let setting = state.ram[RTC_REG_B] & REG_B_SET != 0;
+        match index {
+            _ if TIME_REGS.contains(&index) && !setting => {
+                let now = self.now();
+                state.store_time(now);
+                state.ram[index]
+            }
+            RTC_REG_C => 0,
+            _ => state.ram[index],
+        }
+    }
+
+    fn write_data(&self, state: &mut CmosState, value: u8) {
+        let index = state.index as usize;
+        let setting = state.ram[RTC_REG_B] & REG_B_SET != 0;
+        match index {
+            _ if TIME_REGS.contains(&index) => {
+                if !setting {
+                    let now = self.now();
+                    state.store_time(now);
+                }
+                state.ram[index] = value;
+                if !setting {
+                    self.commit(state);
+                }
+            }
+            RTC_REG_A => {
+                // UIP is read-only, and never set.
+                state.ram[RTC_REG_A] = value & 0x7f;
+            }
+            RTC_REG_B => {
+                if value & REG_B_SET != 0 && !setting {
+                    // The guest now sets the time, starting from the current one.
+                    let now = self.now();
+                    state.store_time(now);
+                }
+                let committed = setting && value & REG_B_SET == 0;
+                if committed {
+                    self.commit(state);
+                }
+                state.ram[RTC_REG_B] = value;
+            }
+            // Registers C and D are read-only.
+            RTC_REG_C | RTC_REG_D => {}
+            _ => state.ram[index] = value,
+        }
+    }
+}
+
+impl EmuDevice for CmosRtc {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn pio_ports(&self) -> Vec<Range<u16>> {
+        vec![self.base..self.base + 2]
+    }
+
+    fn handle_pio_read(&self, _vcpu_id: usize, port: u16, _width: usize) -> usize {
+        let mut state = self.state.lock();
+        if port == self.base {
+            // The index port is write-only.
+            0xff
+        } else {
+            self.read_data(&mut state) as usize
+        }
+    }
+
+    fn handle_pio_write(&self, _vcpu_id: usize, port: u16, _width: usize, value: usize) {
+        let mut state = self.state.lock();
+        if port == self.base {
+            state.index = value as u8 & INDEX_MASK;
+        } else {
+            self.write_data(&mut state, value as u8);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! A Goldfish RTC model, counting nanoseconds, as found on the QEMU riscv64 `virt` machine.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axvm::config::EmulatedDeviceConfig;
+use kspin::SpinNoIrq;
+
+use super::{RtcAlarm, RtcIrq, set_vm_time_nanos, vm_time_nanos};
+use crate::vmm::devices::EmuDevice;
+
+/// Reading `TIME_LOW` latches the high half of the time in `TIME_HIGH`, and writing it sets
+/// the time from the value written to `TIME_HIGH` before.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+/// Writing `ALARM_LOW` arms the alarm, with the value written to `ALARM_HIGH` before.
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+struct GoldfishState {
+    /// The high half of the time, latched by a read of `TIME_LOW` or written by the guest.
+    time_high: u32,
+    /// The time of the alarm, in nanoseconds since the epoch.
+    alarm_at: u64,
+    alarm_armed: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    alarm: RtcAlarm,
+    irq: RtcIrq,
+}
+
+/// A Goldfish RTC.
+pub struct GoldfishRtc {
+    vm_id: usize,
+    name: String,
+    base: GuestPhysAddr,
+    size: usize,
+    this: Weak<GoldfishRtc>,
+    state: SpinNoIrq<GoldfishState>,
+}
+
+impl GoldfishRtc {
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            vm_id,
+            name: config.name.clone(),
+            base: GuestPhysAddr::from(config.base_gpa),
+            size: config.length,
+            this: this.clone(),
+            state: SpinNoIrq::new(GoldfishState {
+                time_high: 0,
+                alarm_at: 0,
+                alarm_armed: false,
+                irq_enabled: false,
+                irq_pending: false,
+                alarm: RtcAlarm::new(vm_id),
+                irq: RtcIrq::new(vm_id, config.irq_id),
+            }),
+        })
+    }
+
+    fn update_irq(state: &mut GoldfishState) {
+        let level = state.irq_enabled && state.irq_pending;
+        state.irq.set(level);
+    }
+
+    /// Arms the alarm at `alarm_at`, firing it at once if it is already due.
+    fn arm(&self, state: &mut GoldfishState) {
+        state.alarm_armed = true;
+        if state.alarm_at <= vm_time_nanos(self.vm_id) {
+            Self::fire(state);
+            return;
+        }
+        let this = self.this.clone();
+        state.alarm.arm(state.alarm_at, move || {
+            if let Some(rtc) = this.upgrade() {
+                rtc.alarm_fired();
+            }
+        });
+    }
+
+    fn fire(state: &mut GoldfishState) {
+        state.alarm.disarm();
+        state.alarm_armed = false;
+        state.irq_pending = true;
+    }
+
+    fn alarm_fired(&self) {
+        let mut state = self.state.lock();
+        if state.alarm_armed && state.alarm_at <= vm_time_nanos(self.vm_id) {
+            Self::fire(&mut state);
+            Self::update_irq(&mut state);
+        }
+    }
+}
+
+impl EmuDevice for GoldfishRtc {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + self.size]
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        let offset = (addr - self.base) & !0x3;
+        let mut state = self.state.lock();
+        let value = match offset {
+            TIME_LOW => {
+                let now = vm_time_nanos(self.vm_id);
+                state.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => state.time_high,
+            ALARM_LOW => state.alarm_at as u32,
+            ALARM_HIGH => (state.alarm_at >> 32) as u32,
+            IRQ_ENABLED => state.irq_enabled as u32,
+            ALARM_STATUS => state.alarm_armed as u32,
+            _ => {
+                debug!("{}: read of unknown register {:#x}", self.name, offset);
+                0
+            }
+        };
+        value as usize
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        let offset = (addr - self.base) & !0x3;
+        let value = value as u32;
+        let mut state = self.state.lock();
+        match offset {
+            TIME_LOW => {
+                let time = ((state.time_high as u64) << 32) | value as u64;
+                set_vm_time_nanos(self.vm_id, time);
+                if state.alarm_armed {
+                    self.arm(&mut state);
+                }
+            }
+            TIME_HIGH => state.time_high = value,
+            ALARM_LOW => {
+                state.alarm_at = (state.alarm_at & !0xffff_ffff) | value as u64;
+                self.arm(&mut state);
+            }
+            ALARM_HIGH => {
+                state.alarm_at = ((value as u64) << 32) | (state.alarm_at & 0xffff_ffff);
+            }
+            IRQ_ENABLED => state.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => {
+                state.alarm.disarm();
+                state.alarm_armed = false;
+            }
+            CLEAR_INTERRUPT => state.irq_pending = false,
+            _ => debug!(
+                "{}: write of unknown register {:#x}: {:#x}",
+                self.name, offset, value
+            ),
+        }
+        Self::update_irq(&mut state);
+    }
+}
@@ -0,0 +1,156 @@
+//! Emulated real-time clocks, a PL031 on aarch64, a Goldfish RTC on riscv64 and a CMOS RTC
+//! on x86_64.
+//!
+//! Each VM has its own clock, the time of the host plus an offset set when the guest sets its
+//! RTC, so that the guests never change the time of the host or of each other. The offset is
+//! kept when the devices of the VM are removed, so the time set by a guest survives its
+//! reboot.
+
+extern crate alloc;
+
+#[cfg(target_arch = "x86_64")]
+mod cmos;
+mod goldfish;
+mod pl031;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use axerrno::AxResult;
+#[cfg(target_arch = "x86_64")]
+use axerrno::ax_err;
+use axvm::AxVMHal;
+use axvm::config::EmulatedDeviceConfig;
+use spin::{Mutex, Once};
+use std::os::arceos::modules::axhal;
+
+use crate::hal::AxVMHalImpl;
+use crate::vmm::devices::EmuDevice;
+use crate::vmm::{irq, timer};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The offsets of the clocks of the VMs from the clock of the host, in nanoseconds, stored in
+/// a BTreeMap where the key is the VM ID.
+static VM_TIME_OFFSETS: Mutex<BTreeMap<usize, i64>> = Mutex::new(BTreeMap::new());
+
+/// Returns the time of the host since the epoch, in nanoseconds.
+pub fn host_time_nanos() -> u64 {
+    // The wall time of the host is its monotonic time plus the epoch of its boot.
+    static BOOT_EPOCH_NANOS: Once<u64> = Once::new();
+    let boot_epoch = BOOT_EPOCH_NANOS
+        .call_once(|| axhal::time::wall_time_nanos() - axhal::time::monotonic_time_nanos());
+    AxVMHalImpl::current_time_nanos() + boot_epoch
+}
+
+/// Returns the time of the clock of `vm_id` since the epoch, in nanoseconds.
+pub fn vm_time_nanos(vm_id: usize) -> u64 {
+    let offset = VM_TIME_OFFSETS.lock().get(&vm_id).copied().unwrap_or(0);
+    host_time_nanos().saturating_add_signed(offset)
+}
+
+/// Sets the clock of `vm_id` to `nanos` since the epoch.
+pub fn set_vm_time_nanos(vm_id: usize, nanos: u64) {
+    let offset = nanos as i64 - host_time_nanos() as i64;
+    debug!(
+        "VM[{}] RTC set, {}s from the host",
+        vm_id,
+        offset / NANOS_PER_SEC as i64
+    );
+    VM_TIME_OFFSETS.lock().insert(vm_id, offset);
+}
+
+/// Resets the clock of `vm_id` to the time of the host, through the `rtc` command of the
+/// [hypervisor shell](crate::vmm::shell).
+pub fn reset_vm_time(vm_id: usize) {
+    VM_TIME_OFFSETS.lock().remove(&vm_id);
+}
+
+/// The interrupt line of an RTC.
+pub struct RtcIrq {
+    vm_id: usize,
+    /// The guest interrupt of the RTC, 0 if none.
+    irq: usize,
+    /// Whether the interrupt line is asserted.
+    asserted: bool,
+}
+
+impl RtcIrq {
+    pub fn new(vm_id: usize, irq: usize) -> Self {
+        Self {
+            vm_id,
+            irq,
+            asserted: false,
+        }
+    }
+
+    /// Sets the level of the interrupt line, raising the interrupt in the guest when it gets
+    /// asserted.
+    pub fn set(&mut self, level: bool) {
+        if level && !self.asserted && self.irq != 0 {
+            irq::inject_guest_irq(self.vm_id, self.irq);
+        }
+        self.asserted = level;
+    }
+}
+
+/// A one-shot alarm at a time of the clock of a VM.
+pub struct RtcAlarm {
+    vm_id: usize,
+    /// The token of the pending timer event, if the alarm is armed.
+    token: Option<usize>,
+}
+
+impl RtcAlarm {
+    pub fn new(vm_id: usize) -> Self {
+        Self { vm_id, token: None }
+    }
+
+    /// Arms the alarm to call `fire` at `at` nanoseconds since the epoch on the clock of the
+    /// VM, replacing the pending one. A stale call may still happen right after re-arming,
+    /// so `fire` must check that the alarm is due.
+    pub fn arm<F>(&mut self, at: u64, fire: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.disarm();
+        let delay = at.saturating_sub(vm_time_nanos(self.vm_id));
+        let deadline = AxVMHalImpl::current_time_nanos() + delay;
+        self.token = Some(timer::register_timer(deadline, move |_now| fire()));
+    }
+
+    /// Cancels the pending alarm, if any.
+    pub fn disarm(&mut self) {
+        if let Some(token) = self.token.take() {
+            timer::cancel_timer(token);
+        }
+    }
+}
+
+impl Drop for RtcAlarm {
+    fn drop(&mut self) {
+        self.disarm();
+    }
+}
+
+/// Creates a PL031 RTC from its `emu_devices` entry.
+pub fn create_pl031(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(pl031::Pl031::new(vm_id, config))
+}
+
+/// Creates a Goldfish RTC from its `emu_devices` entry.
+pub fn create_goldfish(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(goldfish::GoldfishRtc::new(vm_id, config))
+}
+
+/// Creates a CMOS RTC from its `emu_devices` entry.
+#[cfg(target_arch = "x86_64")]
+pub fn create_cmos(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    if config.base_gpa > u16::MAX as usize - 1 {
+        return ax_err!(InvalidInput, "CMOS RTC ports out of the I/O port space");
+    }
+    Ok(Arc::new(cmos::CmosRtc::new(vm_id, config)))
+}
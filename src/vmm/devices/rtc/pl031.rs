@@ -0,0 +1,158 @@
+//! An Arm PrimeCell PL031 RTC model, counting seconds.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axvm::config::EmulatedDeviceConfig;
+use kspin::SpinNoIrq;
+
+use super::{NANOS_PER_SEC, RtcAlarm, RtcIrq, set_vm_time_nanos, vm_time_nanos};
+use crate::vmm::devices::EmuDevice;
+
+const RTCDR: usize = 0x00;
+const RTCMR: usize = 0x04;
+const RTCLR: usize = 0x08;
+const RTCCR: usize = 0x0c;
+const RTCIMSC: usize = 0x10;
+const RTCRIS: usize = 0x14;
+const RTCMIS: usize = 0x18;
+const RTCICR: usize = 0x1c;
+/// The peripheral and PrimeCell identification registers, `RTCPeriphID0` to `RTCPCellID3`.
+const RTCPERIPHID0: usize = 0xfe0;
+
+/// The identification registers of a PL031, as seen by the AMBA bus probing.
+const ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// The alarm interrupt, raised when the counter reaches the match register.
+const INT_ALARM: u32 = 1 << 0;
+
+struct Pl031State {
+    mr: u32,
+    imsc: u32,
+    ris: u32,
+    alarm: RtcAlarm,
+    irq: RtcIrq,
+}
+
+/// A PL031 RTC.
+pub struct Pl031 {
+    vm_id: usize,
+    name: String,
+    base: GuestPhysAddr,
+    size: usize,
+    this: Weak<Pl031>,
+    state: SpinNoIrq<Pl031State>,
+}
+
+impl Pl031 {
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            vm_id,
+            name: config.name.clone(),
+            base: GuestPhysAddr::from(config.base_gpa),
+            size: config.length,
+            this: this.clone(),
+            state: SpinNoIrq::new(Pl031State {
+                mr: 0,
+                imsc: 0,
+                ris: 0,
+                alarm: RtcAlarm::new(vm_id),
+                irq: RtcIrq::new(vm_id, config.irq_id),
+            }),
+        })
+    }
+
+    /// Returns the counter, the seconds since the epoch on the clock of the VM.
+    fn counter(&self) -> u32 {
+        (vm_time_nanos(self.vm_id) / NANOS_PER_SEC) as u32
+    }
+
+    fn update_irq(state: &mut Pl031State) {
+        let level = state.ris & state.imsc != 0;
+        state.irq.set(level);
+    }
+
+    /// Arms the alarm for when the counter reaches the match register.
+    fn rearm(&self, state: &mut Pl031State) {
+        if state.mr <= self.counter() {
+            state.alarm.disarm();
+            return;
+        }
+        let this = self.this.clone();
+        state.alarm.arm(state.mr as u64 * NANOS_PER_SEC, move || {
+            if let Some(rtc) = this.upgrade() {
+                rtc.alarm_fired();
+            }
+        });
+    }
+
+    fn alarm_fired(&self) {
+        let mut state = self.state.lock();
+        if self.counter() >= state.mr {
+            state.ris |= INT_ALARM;
+            Self::update_irq(&mut state);
+        }
+    }
+}
+
+impl EmuDevice for Pl031 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + self.size]
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        let offset = (addr - self.base) & !0x3;
+        let state = self.state.lock();
+        let value = match offset {
+            RTCDR => self.counter(),
+            RTCMR => state.mr,
+            // The load register reads back as the counter.
+            RTCLR => self.counter(),
+            // The counter always runs.
+            RTCCR => 1,
+            RTCIMSC => state.imsc,
+            RTCRIS => state.ris,
+            RTCMIS => state.ris & state.imsc,
+            RTCPERIPHID0..=0xffc => ID[(offset - RTCPERIPHID0) / 4] as u32,
+            _ => {
+                debug!("{}: read of unknown register {:#x}", self.name, offset);
+                0
+            }
+        };
+        value as usize
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        let offset = (addr - self.base) & !0x3;
+        let value = value as u32;
+        let mut state = self.state.lock();
+        match offset {
+            RTCMR => {
+                state.mr = value;
+                self.rearm(&mut state);
+            }
+            RTCLR => {
+                set_vm_time_nanos(self.vm_id, value as u64 * NANOS_PER_SEC);
+                self.rearm(&mut state);
+            }
+            // The counter cannot be stopped.
+            RTCCR => {}
+            RTCIMSC => state.imsc = value & INT_ALARM,
+            RTCICR => state.ris &= !value,
+            _ => debug!(
+                "{}: write of unknown register {:#x}: {:#x}",
+                self.name, offset, value
+            ),
+        }
+        Self::update_irq(&mut state);
+    }
+}
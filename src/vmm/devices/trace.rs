@@ -0,0 +1,280 @@
+//! Tracing of the guest accesses to passthrough MMIO ranges, to see what a guest touches
+//! when bringing it up on a new board.
+//!
+//! An `emu_devices` entry of `Emu-Type` [`EmuType::MmioTrace`] covers a page-aligned range of
+//! a `passthrough_devices` entry, which is then unmapped from the guest so that its accesses
+//! trap into the hypervisor. Each access is recorded, with the vCPU, the guest physical
+//! address, the width, the value and the direction, in a ring buffer of the VM keeping the
+//! last [`TRACE_CAPACITY`] ones, and is then forwarded to the physical device. The records
+//! are also logged at the debug level as they happen, read through [`take_mmio_trace`], e.g.
+//! by the `trace` command of the [hypervisor shell](crate::vmm::shell), and the ones left are
+//! printed by [`dump_mmio_trace`] when the VM is torn down.
+//!
+//! Its `EmuConfig` is `[]`, or `[host_pa]` for a range not identity mapped, the host physical
+//! address of the range being its `Base-Ipa` by default. The host range must lie in one of the
+//! `mmio-regions` of the platform config, the only device memory the hypervisor maps.
+//!
+//! [`EmuType::MmioTrace`]: super::EmuType::MmioTrace
+
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use std::os::arceos::modules::{axconfig, axhal};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+use spin::Mutex;
+
+use crate::vmm::devices::EmuDevice;
+use crate::vmm::vm_list;
+
+/// The maximum number of accesses kept in the ring buffer of a VM.
+pub const TRACE_CAPACITY: usize = 4096;
+
+/// The direction of a traced access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDirection {
+    Read,
+    Write,
+}
+
+/// A traced guest access to a passthrough MMIO range.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// The number of the access among all the traced accesses of the VM, counting the ones
+    /// dropped from its ring buffer.
+    pub seq: u64,
+    pub vcpu_id: usize,
+    pub addr: GuestPhysAddr,
+    pub width: usize,
+    /// The value read from or written to the device.
+    pub value: usize,
+    pub direction: AccessDirection,
+}
+
+impl fmt::Display for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (direction, arrow) = match self.direction {
+            AccessDirection::Read => ("R", "->"),
+            AccessDirection::Write => ("W", "<-"),
+        };
+        write!(
+            f,
+            "#{} vCPU {} {}{} {:#x} {} {:#0width$x}",
+            self.seq,
+            self.vcpu_id,
+            direction,
+            self.width * 8,
+            self.addr.as_usize(),
+            arrow,
+            self.value,
+            width = self.width * 2 + 2
+        )
+    }
+}
+
+/// The traced accesses of a VM.
+#[derive(Default)]
+struct TraceBuffer {
+    next_seq: u64,
+    accesses: VecDeque<MmioAccess>,
+}
+
+/// The traced accesses of all VMs, stored in a BTreeMap where the key is the VM ID.
+static VM_MMIO_TRACES: Mutex<BTreeMap<usize, TraceBuffer>> = Mutex::new(BTreeMap::new());
+
+/// Records an access of a VM, dropping its oldest one if its ring buffer is full.
+fn record(
+    vm_id: usize,
+    vcpu_id: usize,
+    addr: GuestPhysAddr,
+    width: usize,
+    value: usize,
+    direction: AccessDirection,
+) {
+    let mut traces = VM_MMIO_TRACES.lock();
+    let trace = traces.entry(vm_id).or_default();
+    let access = MmioAccess {
+        seq: trace.next_seq,
+        vcpu_id,
+        addr,
+        width,
+        value,
+        direction,
+    };
+    trace.next_seq += 1;
+    if trace.accesses.len() == TRACE_CAPACITY {
+        trace.accesses.pop_front();
+    }
+    trace.accesses.push_back(access);
+    debug!("VM[{}] MMIO {}", vm_id, access);
+}
+
+/// Takes the traced accesses of `vm_id` kept in its ring buffer, oldest first.
+pub fn take_mmio_trace(vm_id: usize) -> Vec<MmioAccess> {
+    VM_MMIO_TRACES
+        .lock()
+        .get_mut(&vm_id)
+        .map(|trace| trace.accesses.drain(..).collect())
+        .unwrap_or_default()
+}
+
+/// Removes the traced accesses of a VM, called when the VM is torn down.
+pub fn remove_vm_mmio_trace(vm_id: usize) {
+    VM_MMIO_TRACES.lock().remove(&vm_id);
+}
+
+/// Prints the traced accesses kept in the ring buffer of a VM.
+pub fn dump_mmio_trace(vm_id: usize) {
+    let traces = VM_MMIO_TRACES.lock();
+    let Some(trace) = traces.get(&vm_id) else {
+        return;
+    };
+    info!(
+        "VM[{}] MMIO trace: {} accesses, last {} kept",
+        vm_id,
+        trace.next_seq,
+        trace.accesses.len()
+    );
+    for access in &trace.accesses {
+        info!("    {}", access);
+    }
+}
+
+/// Returns whether the host physical range `start..start + len` lies in one of the
+/// `mmio-regions` of the platform, which the hypervisor maps.
+fn in_host_mmio_regions(start: usize, len: usize) -> bool {
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    axconfig::devices::MMIO_REGIONS
+        .iter()
+        .any(|&(base, size)| start >= base && end <= base + size)
+}
+
+/// A traced passthrough MMIO range.
+pub struct MmioTrace {
+    vm_id: usize,
+    name: String,
+    range: Range<GuestPhysAddr>,
+    host_base: usize,
+}
+
+impl MmioTrace {
+    /// Returns the host virtual address of `addr`, in the range.
+    fn host_vaddr(&self, addr: GuestPhysAddr) -> usize {
+        let paddr = self.host_base + (addr - self.range.start);
+        axhal::mem::phys_to_virt(PhysAddr::from(paddr)).as_usize()
+    }
+}
+
+impl EmuDevice for MmioTrace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.range.clone()]
+    }
+
+    fn handle_mmio_read(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize) -> usize {
+        let vaddr = self.host_vaddr(addr);
+        // SAFETY: the range is the one of a passthrough device, in the MMIO regions of the
+        // platform, as checked by `create_mmio_trace`.
+        let value = unsafe {
+            match width {
+                1 => (vaddr as *const u8).read_volatile() as usize,
+                2 => (vaddr as *const u16).read_volatile() as usize,
+                4 => (vaddr as *const u32).read_volatile() as usize,
+                _ => (vaddr as *const u64).read_volatile() as usize,
+            }
+        };
+        record(
+            self.vm_id,
+            vcpu_id,
+            addr,
+            width,
+            value,
+            AccessDirection::Read,
+        );
+        value
+    }
+
+    fn handle_mmio_write(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize, value: usize) {
+        record(
+            self.vm_id,
+            vcpu_id,
+            addr,
+            width,
+            value,
+            AccessDirection::Write,
+        );
+        let vaddr = self.host_vaddr(addr);
+        // SAFETY: see `handle_mmio_read`.
+        unsafe {
+            match width {
+                1 => (vaddr as *mut u8).write_volatile(value as u8),
+                2 => (vaddr as *mut u16).write_volatile(value as u16),
+                4 => (vaddr as *mut u32).write_volatile(value as u32),
+                _ => (vaddr as *mut u64).write_volatile(value as u64),
+            }
+        }
+    }
+}
+
+/// Creates a traced passthrough MMIO range from its `emu_devices` entry, and unmaps it from
+/// the guest.
+pub fn create_mmio_trace(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<dyn EmuDevice>> {
+    let host_base = match *config.cfg_list.as_slice() {
+        [] => config.base_gpa,
+        [host_pa] => host_pa,
+        _ => return ax_err!(InvalidInput, "MMIO trace EmuConfig must be [] or [host_pa]"),
+    };
+    if config.length == 0
+        || config.base_gpa % PAGE_SIZE_4K != 0
+        || config.length % PAGE_SIZE_4K != 0
+        || host_base % PAGE_SIZE_4K != 0
+    {
+        return ax_err!(InvalidInput, "MMIO trace range not page aligned");
+    }
+    if !in_host_mmio_regions(host_base, config.length) {
+        return ax_err!(
+            InvalidInput,
+            "MMIO trace range not in the mmio-regions of the platform"
+        );
+    }
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return ax_err!(NotFound, "VM not found");
+    };
+    let start = GuestPhysAddr::from(config.base_gpa);
+    if vm.unmap_region(start, config.length).is_err() {
+        return ax_err!(
+            InvalidInput,
+            "MMIO trace range not mapped by a passthrough_devices entry"
+        );
+    }
+    info!(
+        "VM[{}] tracing MMIO {:#x}..{:#x} -> {:#x}",
+        vm_id,
+        config.base_gpa,
+        config.base_gpa + config.length,
+        host_base
+    );
+    Ok(Arc::new(MmioTrace {
+        vm_id,
+        name: config.name.clone(),
+        range: start..start + config.length,
+        host_base,
+    }))
+}
@@ -0,0 +1,186 @@
+//! Emulated UARTs, a PL011 and a 16550.
+//!
+//! The `EmuConfig` of the `emu_devices` entry of a UART starts with its backend, what its
+//! output goes to and its input comes from: [`BACKEND_CONSOLE`], the default, for the
+//! [hypervisor console multiplexer](crate::vmm::console), [`BACKEND_BUFFER`] for a buffer of
+//! the VM, read through [`take_uart_output`], e.g. by the `uart` command of the
+//! [hypervisor shell](crate::vmm::shell), or [`BACKEND_NULL`] to discard the output.
+//!
+//! The interrupt of a UART, its `Alloc-Irq`, is raised in the guest each time its line gets
+//! asserted.
+
+extern crate alloc;
+
+mod ns16550;
+mod pl011;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::{Mutex, Once};
+
+use crate::vmm::devices::EmuDevice;
+use crate::vmm::{console, irq};
+
+/// The UART is connected to the hypervisor console multiplexer.
+pub const BACKEND_CONSOLE: usize = 0;
+/// The output of the UART is kept in a buffer of the VM.
+pub const BACKEND_BUFFER: usize = 1;
+/// The output of the UART is discarded, and it never receives input.
+pub const BACKEND_NULL: usize = 2;
+
+/// The maximum number of output bytes kept in the buffer of a VM.
+const MAX_BUFFERED_OUTPUT: usize = 0x1_0000;
+/// The maximum number of input bytes kept for a UART whose guest does not read them.
+const MAX_PENDING_INPUT: usize = 0x1000;
+
+/// What the output of a UART goes to.
+enum Backend {
+    /// The ID of the UART in the hypervisor console multiplexer.
+    Console(Once<usize>),
+    Buffer,
+    Null,
+}
+
+/// The output buffers of all VMs, stored in a BTreeMap where the key is the VM ID.
+static VM_UART_BUFFERS: Mutex<BTreeMap<usize, VecDeque<u8>>> = Mutex::new(BTreeMap::new());
+
+/// Takes the output of the UARTs of `vm_id` using [`BACKEND_BUFFER`].
+pub fn take_uart_output(vm_id: usize) -> Vec<u8> {
+    VM_UART_BUFFERS
+        .lock()
+        .get_mut(&vm_id)
+        .map(|buf| buf.drain(..).collect())
+        .unwrap_or_default()
+}
+
+/// The connection of a UART model to its VM: its backend and its interrupt line.
+pub struct UartPort {
+    vm_id: usize,
+    name: String,
+    backend: Backend,
+    /// The guest interrupt of the UART, 0 if none.
+    irq: usize,
+    /// Whether the interrupt line is asserted.
+    asserted: AtomicBool,
+}
+
+impl UartPort {
+    fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let backend = match config.cfg_list.first().copied().unwrap_or(BACKEND_CONSOLE) {
+            BACKEND_CONSOLE => Backend::Console(Once::new()),
+            BACKEND_BUFFER => Backend::Buffer,
+            BACKEND_NULL => Backend::Null,
+            _ => return ax_err!(InvalidInput, "unknown UART backend"),
+        };
+        Ok(Self {
+            vm_id,
+            name: config.name.clone(),
+            backend,
+            irq: config.irq_id,
+            asserted: AtomicBool::new(false),
+        })
+    }
+
+    /// Sends the output of the UART to its backend.
+    pub fn output(&self, data: &[u8]) {
+        match &self.backend {
+            Backend::Console(id) => {
+                if let Some(id) = id.get() {
+                    console::write(*id, data);
+                }
+            }
+            Backend::Buffer => {
+                let mut buffers = VM_UART_BUFFERS.lock();
+                let buf = buffers.entry(self.vm_id).or_default();
+                buf.extend(data);
+                let excess = buf.len().saturating_sub(MAX_BUFFERED_OUTPUT);
+                buf.drain(..excess);
+            }
+            Backend::Null => {}
+        }
+    }
+
+    /// Sets the level of the interrupt line, raising the interrupt in the guest when it gets
+    /// asserted.
+    pub fn set_irq(&self, level: bool) {
+        let was_asserted = self.asserted.swap(level, Ordering::AcqRel);
+        if level && !was_asserted && self.irq != 0 {
+            irq::inject_guest_irq(self.vm_id, self.irq);
+        }
+    }
+}
+
+/// A UART model.
+pub trait Uart: EmuDevice + Sized + 'static {
+    /// Returns the connection of the UART to its VM.
+    fn port(&self) -> &UartPort;
+
+    /// Receives input from the backend.
+    fn receive(&self, data: &[u8]);
+}
+
+/// Appends input to a receive FIFO, dropping what does not fit.
+pub fn push_input(rx: &mut VecDeque<u8>, data: &[u8]) {
+    let len = data.len().min(MAX_PENDING_INPUT.saturating_sub(rx.len()));
+    rx.extend(&data[..len]);
+}
+
+/// Connects a UART to its backend.
+fn connect<U: Uart>(uart: U) -> Arc<dyn EmuDevice> {
+    let uart = Arc::new(uart);
+    if let Backend::Console(id) = &uart.port().backend {
+        let weak: Weak<U> = Arc::downgrade(&uart);
+        let console_id = console::register(
+            format!("vm{}:{}", uart.port().vm_id, uart.port().name),
+            Box::new(move |data: &[u8]| {
+                if let Some(uart) = weak.upgrade() {
+                    uart.receive(data);
+                }
+            }),
+        );
+        id.call_once(|| console_id);
+    }
+    uart
+}
+
+/// Creates a PL011 UART from its `emu_devices` entry.
+pub fn create_pl011(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    let port = UartPort::new(vm_id, config)?;
+    Ok(connect(pl011::Pl011::new(port, config)))
+}
+
+/// Creates a 16550 UART accessed through MMIO from its `emu_devices` entry.
+pub fn create_ns16550_mmio(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<dyn EmuDevice>> {
+    let port = UartPort::new(vm_id, config)?;
+    let reg_shift = config.cfg_list.get(1).copied().unwrap_or(0);
+    if reg_shift > 3 {
+        return ax_err!(InvalidInput, "16550 register shift must be at most 3");
+    }
+    Ok(connect(ns16550::Ns16550::new_mmio(port, config, reg_shift)))
+}
+
+/// Creates a 16550 UART accessed through I/O ports from its `emu_devices` entry.
+#[cfg(target_arch = "x86_64")]
+pub fn create_ns16550_pio(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<dyn EmuDevice>> {
+    let port = UartPort::new(vm_id, config)?;
+    if config.base_gpa + ns16550::NR_REGS > u16::MAX as usize + 1 {
+        return ax_err!(InvalidInput, "16550 ports out of the I/O port space");
+    }
+    Ok(connect(ns16550::Ns16550::new_pio(
+        port,
+        config.base_gpa as u16,
+    )))
+}
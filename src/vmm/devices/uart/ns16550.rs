@@ -0,0 +1,312 @@
+//! A 16550A UART model, accessed through MMIO or through I/O ports.
+//!
+//! Transmitted characters go out at once, so the transmitter is always empty. The input not
+//! yet read by the guest is kept in the receive FIFO, whose trigger level is honored once the
+//! guest enables it.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+
+use super::{Uart, UartPort, push_input};
+use crate::vmm::devices::EmuDevice;
+
+/// The number of registers of the UART.
+pub const NR_REGS: usize = 8;
+
+/// RBR on read, THR on write, DLL when DLAB is set.
+const UART_RX_TX: usize = 0;
+/// IER, DLM when DLAB is set.
+const UART_IER: usize = 1;
+/// IIR on read, FCR on write.
+const UART_IIR_FCR: usize = 2;
+const UART_LCR: usize = 3;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+const UART_MSR: usize = 6;
+const UART_SCR: usize = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_RCVR: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_DCD: u8 = 1 << 7;
+
+/// How the guest accesses the registers.
+enum Access {
+    /// Through MMIO, with the offsets of the registers shifted by `reg_shift`.
+    Mmio {
+        base: GuestPhysAddr,
+        size: usize,
+        reg_shift: usize,
+    },
+    /// Through the I/O ports starting at `base`.
+    #[cfg_attr(not(target_arch = "x86_64"), allow(unused))]
+    Pio { base: u16 },
+}
+
+/// The registers of the UART.
+struct Ns16550State {
+    rx: VecDeque<u8>,
+    dll: u8,
+    dlm: u8,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    /// Whether the transmitter holding register empty interrupt is pending, set when THR
+    /// gets empty and cleared by reading IIR or writing THR.
+    thre_pending: bool,
+}
+
+impl Ns16550State {
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_ENABLE_FIFO != 0
+    }
+
+    /// Returns the number of received characters raising the receive interrupt.
+    fn rx_trigger(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        // FCR bits 7:6.
+        [1, 4, 8, 14][(self.fcr >> 6) as usize]
+    }
+
+    /// Returns the pending interrupt of highest priority, as reported by IIR.
+    fn interrupt_id(&self) -> u8 {
+        let id = if self.ier & IER_RDI != 0 && self.rx.len() >= self.rx_trigger() {
+            IIR_RDI
+        } else if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            // The characters below the trigger level are reported as a timeout.
+            IIR_RX_TIMEOUT
+        } else if self.ier & IER_THRI != 0 && self.thre_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        };
+        if self.fifo_enabled() {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = LSR_THRE | LSR_TEMT;
+        if !self.rx.is_empty() {
+            lsr |= LSR_DR;
+        }
+        lsr
+    }
+
+    fn modem_status(&self) -> u8 {
+        if self.mcr & MCR_LOOP != 0 {
+            // DTR, RTS, OUT1 and OUT2 are looped back to DSR, CTS, RI and DCD.
+            let mcr = self.mcr;
+            ((mcr & 0x1) << 5) | ((mcr & 0x2) << 3) | ((mcr & 0x4) << 4) | ((mcr & 0x8) << 4)
+        } else {
+            MSR_DCD | MSR_DSR | MSR_CTS
+        }
+    }
+}
+
+/// A 16550A UART.
+pub struct Ns16550 {
+    port: UartPort,
+    access: Access,
+    state: Mutex<Ns16550State>,
+}
+
+impl Ns16550 {
+    fn new(port: UartPort, access: Access) -> Self {
+        Self {
+            port,
+            access,
+            state: Mutex::new(Ns16550State {
+                rx: VecDeque::new(),
+                dll: 0,
+                dlm: 0,
+                ier: 0,
+                fcr: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                thre_pending: false,
+            }),
+        }
+    }
+
+    pub fn new_mmio(port: UartPort, config: &EmulatedDeviceConfig, reg_shift: usize) -> Self {
+        let access = Access::Mmio {
+            base: GuestPhysAddr::from(config.base_gpa),
+            size: config.length,
+            reg_shift,
+        };
+        Self::new(port, access)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn new_pio(port: UartPort, base: u16) -> Self {
+        Self::new(port, Access::Pio { base })
+    }
+
+    fn update_irq(&self, state: &Ns16550State) {
+        self.port.set_irq(state.interrupt_id() & IIR_NO_INT == 0);
+    }
+
+    fn read(&self, reg: usize) -> u8 {
+        let mut state = self.state.lock();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        let value = match reg {
+            UART_RX_TX if dlab => state.dll,
+            UART_RX_TX => state.rx.pop_front().unwrap_or(0),
+            UART_IER if dlab => state.dlm,
+            UART_IER => state.ier,
+            UART_IIR_FCR => {
+                let iir = state.interrupt_id();
+                if iir & 0xf == IIR_THRI {
+                    state.thre_pending = false;
+                }
+                iir
+            }
+            UART_LCR => state.lcr,
+            UART_MCR => state.mcr,
+            UART_LSR => state.line_status(),
+            UART_MSR => state.modem_status(),
+            UART_SCR => state.scr,
+            _ => 0,
+        };
+        self.update_irq(&state);
+        value
+    }
+
+    fn write(&self, reg: usize, value: u8) {
+        let mut state = self.state.lock();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        match reg {
+            UART_RX_TX if dlab => state.dll = value,
+            UART_RX_TX => {
+                if state.mcr & MCR_LOOP != 0 {
+                    push_input(&mut state.rx, &[value]);
+                } else {
+                    self.port.output(&[value]);
+                }
+                // The character is transmitted at once, so THR is empty again.
+                state.thre_pending = true;
+            }
+            UART_IER if dlab => state.dlm = value,
+            UART_IER => {
+                // Enabling the interrupt while THR is empty raises it.
+                if value & IER_THRI != 0 && state.ier & IER_THRI == 0 {
+                    state.thre_pending = true;
+                }
+                state.ier = value & 0x0f;
+            }
+            UART_IIR_FCR => {
+                if value & FCR_CLEAR_RCVR != 0 {
+                    state.rx.clear();
+                }
+                state.fcr = value & 0xc1;
+            }
+            UART_LCR => state.lcr = value,
+            UART_MCR => state.mcr = value & 0x1f,
+            // LSR and MSR are read-only.
+            UART_LSR | UART_MSR => {}
+            UART_SCR => state.scr = value,
+            _ => {}
+        }
+        self.update_irq(&state);
+    }
+}
+
+impl Uart for Ns16550 {
+    fn port(&self) -> &UartPort {
+        &self.port
+    }
+
+    fn receive(&self, data: &[u8]) {
+        let mut state = self.state.lock();
+        push_input(&mut state.rx, data);
+        self.update_irq(&state);
+    }
+}
+
+impl EmuDevice for Ns16550 {
+    fn name(&self) -> &str {
+        &self.port.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        match self.access {
+            Access::Mmio { base, size, .. } => vec![base..base + size],
+            Access::Pio { .. } => Vec::new(),
+        }
+    }
+
+    fn pio_ports(&self) -> Vec<Range<u16>> {
+        match self.access {
+            Access::Mmio { .. } => Vec::new(),
+            Access::Pio { base } => vec![base..base + NR_REGS as u16],
+        }
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        let Access::Mmio {
+            base, reg_shift, ..
+        } = self.access
+        else {
+            return 0;
+        };
+        self.read(((addr - base) >> reg_shift) % NR_REGS) as usize
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        let Access::Mmio {
+            base, reg_shift, ..
+        } = self.access
+        else {
+            return;
+        };
+        self.write(((addr - base) >> reg_shift) % NR_REGS, value as u8);
+    }
+
+    fn handle_pio_read(&self, _vcpu_id: usize, port: u16, _width: usize) -> usize {
+        let Access::Pio { base } = self.access else {
+            return 0;
+        };
+        self.read((port - base) as usize) as usize
+    }
+
+    fn handle_pio_write(&self, _vcpu_id: usize, port: u16, _width: usize, value: usize) {
+        let Access::Pio { base } = self.access else {
+            return;
+        };
+        self.write((port - base) as usize, value as u8);
+    }
+}
@@ -0,0 +1,241 @@
+//! An Arm PrimeCell PL011 UART model.
+//!
+//! Transmitted characters go out at once, so the transmit FIFO is always empty. The receive
+//! FIFO is 16 characters deep when enabled, with the input not fitting in it kept behind it.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+
+use super::{Uart, UartPort, push_input};
+use crate::vmm::devices::EmuDevice;
+
+const UARTDR: usize = 0x000;
+const UARTRSR: usize = 0x004;
+const UARTFR: usize = 0x018;
+const UARTILPR: usize = 0x020;
+const UARTIBRD: usize = 0x024;
+const UARTFBRD: usize = 0x028;
+const UARTLCR_H: usize = 0x02c;
+const UARTCR: usize = 0x030;
+const UARTIFLS: usize = 0x034;
+const UARTIMSC: usize = 0x038;
+const UARTRIS: usize = 0x03c;
+const UARTMIS: usize = 0x040;
+const UARTICR: usize = 0x044;
+const UARTDMACR: usize = 0x048;
+/// The peripheral and PrimeCell identification registers, `UARTPeriphID0` to `UARTPCellID3`.
+const UARTPERIPHID0: usize = 0xfe0;
+
+/// The identification registers of a PL011 r1p4, as seen by the AMBA bus probing.
+const ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+const FR_RXFE: u32 = 1 << 4;
+const FR_RXFF: u32 = 1 << 6;
+const FR_TXFE: u32 = 1 << 7;
+
+const LCR_H_FEN: u32 = 1 << 4;
+
+const CR_LBE: u32 = 1 << 7;
+
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+const INT_RT: u32 = 1 << 6;
+const INT_MASK: u32 = 0x7ff;
+
+const FIFO_DEPTH: usize = 16;
+
+/// The registers of the UART.
+struct Pl011State {
+    rx: VecDeque<u8>,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    /// The raw interrupts not derived from the receive FIFO, i.e. the transmit interrupt.
+    ris: u32,
+    dmacr: u32,
+}
+
+impl Pl011State {
+    fn fifo_depth(&self) -> usize {
+        if self.lcr_h & LCR_H_FEN != 0 {
+            FIFO_DEPTH
+        } else {
+            1
+        }
+    }
+
+    /// Returns the number of received characters raising the receive interrupt.
+    fn rx_trigger(&self) -> usize {
+        if self.lcr_h & LCR_H_FEN == 0 {
+            return 1;
+        }
+        // UARTIFLS.RXIFLSEL: 1/8, 1/4, 1/2, 3/4 or 7/8 full.
+        match (self.ifls >> 3) & 0x7 {
+            0 => 2,
+            1 => 4,
+            2 => 8,
+            3 => 12,
+            _ => 14,
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        // The transmit FIFO is never full.
+        let mut flags = FR_TXFE;
+        if self.rx.is_empty() {
+            flags |= FR_RXFE;
+        }
+        if self.rx.len() >= self.fifo_depth() {
+            flags |= FR_RXFF;
+        }
+        flags
+    }
+
+    fn raw_interrupts(&self) -> u32 {
+        let mut ris = self.ris;
+        if self.rx.len() >= self.rx_trigger() {
+            ris |= INT_RX;
+        }
+        if !self.rx.is_empty() {
+            ris |= INT_RT;
+        }
+        ris
+    }
+
+    fn masked_interrupts(&self) -> u32 {
+        self.raw_interrupts() & self.imsc
+    }
+}
+
+/// A PL011 UART.
+pub struct Pl011 {
+    port: UartPort,
+    base: GuestPhysAddr,
+    size: usize,
+    state: Mutex<Pl011State>,
+}
+
+impl Pl011 {
+    pub fn new(port: UartPort, config: &EmulatedDeviceConfig) -> Self {
+        Self {
+            port,
+            base: GuestPhysAddr::from(config.base_gpa),
+            size: config.length,
+            state: Mutex::new(Pl011State {
+                rx: VecDeque::new(),
+                ilpr: 0,
+                ibrd: 0,
+                fbrd: 0,
+                lcr_h: 0,
+                // UARTEN is left to the guest, TXE and RXE are set on reset.
+                cr: 0x300,
+                // Both FIFO levels at 1/2 full.
+                ifls: 0x12,
+                imsc: 0,
+                ris: 0,
+                dmacr: 0,
+            }),
+        }
+    }
+
+    fn update_irq(&self, state: &Pl011State) {
+        self.port.set_irq(state.masked_interrupts() != 0);
+    }
+}
+
+impl Uart for Pl011 {
+    fn port(&self) -> &UartPort {
+        &self.port
+    }
+
+    fn receive(&self, data: &[u8]) {
+        let mut state = self.state.lock();
+        push_input(&mut state.rx, data);
+        self.update_irq(&state);
+    }
+}
+
+impl EmuDevice for Pl011 {
+    fn name(&self) -> &str {
+        &self.port.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + self.size]
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        let offset = (addr - self.base) & !0x3;
+        let mut state = self.state.lock();
+        let value = match offset {
+            UARTDR => {
+                let value = state.rx.pop_front().unwrap_or(0) as u32;
+                self.update_irq(&state);
+                value
+            }
+            // No framing, parity, break or overrun errors.
+            UARTRSR => 0,
+            UARTFR => state.flags(),
+            UARTILPR => state.ilpr,
+            UARTIBRD => state.ibrd,
+            UARTFBRD => state.fbrd,
+            UARTLCR_H => state.lcr_h,
+            UARTCR => state.cr,
+            UARTIFLS => state.ifls,
+            UARTIMSC => state.imsc,
+            UARTRIS => state.raw_interrupts(),
+            UARTMIS => state.masked_interrupts(),
+            UARTDMACR => state.dmacr,
+            UARTPERIPHID0..=0xffc => ID[(offset - UARTPERIPHID0) / 4] as u32,
+            _ => {
+                debug!("{}: read of unknown register {:#x}", self.port.name, offset);
+                0
+            }
+        };
+        value as usize
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        let offset = (addr - self.base) & !0x3;
+        let value = value as u32;
+        let mut state = self.state.lock();
+        match offset {
+            UARTDR => {
+                let byte = value as u8;
+                if state.cr & CR_LBE != 0 {
+                    push_input(&mut state.rx, &[byte]);
+                } else {
+                    self.port.output(&[byte]);
+                }
+                state.ris |= INT_TX;
+            }
+            // Clears the receive errors, there are none.
+            UARTRSR => {}
+            UARTILPR => state.ilpr = value & 0xff,
+            UARTIBRD => state.ibrd = value & 0xffff,
+            UARTFBRD => state.fbrd = value & 0x3f,
+            UARTLCR_H => state.lcr_h = value & 0xff,
+            UARTCR => state.cr = value & 0xffff,
+            UARTIFLS => state.ifls = value & 0x3f,
+            UARTIMSC => state.imsc = value & INT_MASK,
+            UARTICR => state.ris &= !value,
+            UARTDMACR => state.dmacr = value & 0x7,
+            _ => debug!(
+                "{}: write of unknown register {:#x}: {:#x}",
+                self.port.name, offset, value
+            ),
+        }
+        self.update_irq(&state);
+    }
+}
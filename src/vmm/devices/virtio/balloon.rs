@@ -0,0 +1,367 @@
+//! virtio-balloon, to reclaim memory from idle guests.
+//!
+//! The hypervisor asks the guest for a number of pages through [`set_balloon_target`], which
+//! the guest gives back by inflating its balloon. The pages inflated in the `MAP_ALLOC` memory
+//! regions of the VM are unmapped from its address space, which returns their frames to the
+//! host allocator, and are mapped again to fresh frames when the guest deflates its balloon,
+//! or accesses them anyway, see [`handle_balloon_fault`].
+//! The pages of the other regions are only accounted for. The `EmuConfig` of the
+//! `emu_devices` entry may be `[pages]`, the initial target, 0 by default.
+//!
+//! The guest memory statistics are requested through [`request_balloon_stats`], and read
+//! through [`balloon_stats`] once the guest has answered.
+
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use std::os::arceos::modules::{axalloc, axhal};
+
+use axaddrspace::{GuestPhysAddr, MappingFlags};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvm::config::{EmulatedDeviceConfig, VmMemConfig, VmMemMappingType};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use super::{VirtioDevice, VirtioMmio, Virtqueue};
+use crate::vmm::devices::GuestMemory;
+use crate::vmm::{VMRef, iommu, vm_list};
+
+/// The virtio device ID of a memory balloon.
+const VIRTIO_ID_BALLOON: u32 = 5;
+
+/// The guest tells the device before using the pages it deflates.
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+/// The device has a statistics virtqueue.
+const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+
+/// The page frame numbers in the inflate and deflate virtqueues are 4K ones.
+const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const STATS_QUEUE: usize = 2;
+
+/// The size of a statistic, a `u16` tag followed by a `u64` value.
+const STAT_SIZE: usize = 10;
+
+#[derive(Default)]
+struct BalloonState {
+    /// The number of pages requested from the guest.
+    target_pages: u32,
+    /// The number of pages in the balloon, as reported by the guest.
+    actual_pages: u32,
+    /// The guest physical addresses of the inflated pages.
+    inflated: BTreeSet<usize>,
+    /// The host virtual addresses of the frames mapped again on deflate, by guest address.
+    remapped: BTreeMap<usize, usize>,
+    /// The last statistics reported by the guest, by tag.
+    stats: BTreeMap<u16, u64>,
+    /// The buffer of the statistics virtqueue, held until the next request.
+    stats_head: Option<u16>,
+    stats_requested: bool,
+}
+
+/// A virtio-balloon device model.
+pub struct VirtioBalloon {
+    vm_id: usize,
+    vm: VMRef,
+    /// The `MAP_ALLOC` memory regions of the VM, along with their mapping flags.
+    alloc_regions: Vec<(Range<usize>, MappingFlags)>,
+    state: Mutex<BalloonState>,
+}
+
+/// The virtio-balloons of all VMs, stored in a BTreeMap where the key is the VM ID.
+static VM_BALLOONS: Mutex<BTreeMap<usize, Weak<VirtioMmio<VirtioBalloon>>>> =
+    Mutex::new(BTreeMap::new());
+
+impl VirtioBalloon {
+    fn from_config(
+        vm_id: usize,
+        config: &EmulatedDeviceConfig,
+        memory_regions: &[VmMemConfig],
+    ) -> AxResult<Self> {
+        let target_pages = match config.cfg_list.as_slice() {
+            [] => 0,
+            [pages] => *pages as u32,
+            _ => return ax_err!(InvalidInput, "virtio-balloon EmuConfig must be [pages]"),
+        };
+        let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+            return ax_err!(NotFound, "VM not found");
+        };
+        let alloc_regions = memory_regions
+            .iter()
+            .filter(|region| matches!(region.map_type, VmMemMappingType::MapAlloc))
+            .map(|region| {
+                (
+                    region.gpa..region.gpa + region.size,
+                    MappingFlags::from_bits_truncate(region.flags),
+                )
+            })
+            .collect();
+        Ok(Self {
+            vm_id,
+            vm,
+            alloc_regions,
+            state: Mutex::new(BalloonState {
+                target_pages,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Returns the mapping flags of a page, if it is in a `MAP_ALLOC` region.
+    fn alloc_flags(&self, gpa: usize) -> Option<MappingFlags> {
+        self.alloc_regions
+            .iter()
+            .find(|(range, _)| range.contains(&gpa))
+            .map(|(_, flags)| *flags)
+    }
+
+    /// Takes a page from the guest.
+    fn inflate_page(&self, state: &mut BalloonState, gpa: usize) -> AxResult {
+        if state.inflated.contains(&gpa) {
+            return Ok(());
+        }
+        if self.alloc_flags(gpa).is_some() {
+            self.vm
+                .unmap_region(GuestPhysAddr::from(gpa), PAGE_SIZE_4K)?;
+            // The passthrough devices of the VM may have cached the translation.
+            iommu::flush_vm(self.vm.id());
+            // A frame mapped again on deflate is not owned by the address space.
+            if let Some(vaddr) = state.remapped.remove(&gpa) {
+                axalloc::global_allocator().dealloc_pages(vaddr, 1);
+            }
+        }
+        state.inflated.insert(gpa);
+        Ok(())
+    }
+
+    /// Gives a page back to the guest.
+    fn deflate_page(&self, state: &mut BalloonState, gpa: usize) -> AxResult {
+        if !state.inflated.contains(&gpa) {
+            return Ok(());
+        }
+        if let Some(flags) = self.alloc_flags(gpa) {
+            let vaddr = axalloc::global_allocator()
+                .alloc_pages(1, PAGE_SIZE_4K)
+                .map_err(|_| ax_err_type!(NoMemory, "no page to deflate the balloon"))?;
+            // SAFETY: the page has just been allocated, and is not mapped by anyone else.
+            unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, PAGE_SIZE_4K) };
+            let hpa = axhal::mem::virt_to_phys(vaddr.into());
+            if let Err(err) = self
+                .vm
+                .map_region(GuestPhysAddr::from(gpa), hpa, PAGE_SIZE_4K, flags)
+            {
+                axalloc::global_allocator().dealloc_pages(vaddr, 1);
+                return Err(err);
+            }
+            state.remapped.insert(gpa, vaddr);
+        }
+        state.inflated.remove(&gpa);
+        Ok(())
+    }
+
+    /// Handles the page frame numbers of an inflate or deflate request.
+    fn handle_pfns(&self, inflate: bool, pfns: &[u8]) {
+        let mut state = self.state.lock();
+        for pfn in pfns.chunks_exact(4) {
+            let pfn = u32::from_le_bytes([pfn[0], pfn[1], pfn[2], pfn[3]]) as usize;
+            let gpa = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+            let result = if inflate {
+                self.inflate_page(&mut state, gpa)
+            } else {
+                self.deflate_page(&mut state, gpa)
+            };
+            if let Err(err) = result {
+                warn!(
+                    "VM[{}] virtio-balloon failed to {} page {:#x}: {:?}",
+                    self.vm_id,
+                    if inflate { "inflate" } else { "deflate" },
+                    gpa,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Handles the statistics virtqueue: records the statistics of the new buffer, and holds
+    /// it until the next request, on which it is given back to the guest to be refilled.
+    fn process_stats(&self, queue: &mut Virtqueue, mem: &GuestMemory) -> AxResult<bool> {
+        let mut state = self.state.lock();
+        if let Some(chain) = queue.pop(mem)? {
+            if let Some(head) = state.stats_head.replace(chain.head) {
+                // The driver should not add a buffer while one is held, give back the old one.
+                queue.push_used(mem, head, 0)?;
+            }
+            for stat in chain.read_all(mem)?.chunks_exact(STAT_SIZE) {
+                let tag = u16::from_le_bytes([stat[0], stat[1]]);
+                let mut value = [0u8; 8];
+                value.copy_from_slice(&stat[2..]);
+                state.stats.insert(tag, u64::from_le_bytes(value));
+            }
+        }
+        if state.stats_requested {
+            if let Some(head) = state.stats_head.take() {
+                state.stats_requested = false;
+                queue.push_used(mem, head, 0)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for VirtioBalloon {
+    fn drop(&mut self) {
+        for vaddr in self.state.get_mut().remapped.values() {
+            axalloc::global_allocator().dealloc_pages(*vaddr, 1);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BALLOON_F_MUST_TELL_HOST | VIRTIO_BALLOON_F_STATS_VQ
+    }
+
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    /// The configuration space holds the target and the actual number of pages.
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let state = self.state.lock();
+        let mut config = [0u8; 8];
+        config[..4].copy_from_slice(&state.target_pages.to_le_bytes());
+        config[4..].copy_from_slice(&state.actual_pages.to_le_bytes());
+        let end = (offset + data.len()).min(config.len());
+        if offset < end {
+            data[..end - offset].copy_from_slice(&config[offset..end]);
+        }
+    }
+
+    /// The guest reports the actual number of pages.
+    fn write_config(&self, offset: usize, data: &[u8]) {
+        if offset == 4 && data.len() == 4 {
+            self.state.lock().actual_pages =
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        }
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        match index {
+            INFLATE_QUEUE | DEFLATE_QUEUE => {
+                let mut used = false;
+                while let Some(chain) = queue.pop(mem)? {
+                    self.handle_pfns(index == INFLATE_QUEUE, &chain.read_all(mem)?);
+                    queue.push_used(mem, chain.head, 0)?;
+                    used = true;
+                }
+                Ok(used)
+            }
+            STATS_QUEUE => self.process_stats(queue, mem),
+            _ => Ok(false),
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.stats_head = None;
+        state.stats_requested = false;
+    }
+}
+
+/// Creates a virtio-balloon device from its `emu_devices` entry.
+pub fn create_balloon(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+    memory_regions: &[VmMemConfig],
+) -> AxResult<Arc<VirtioMmio<VirtioBalloon>>> {
+    let device = VirtioBalloon::from_config(vm_id, config, memory_regions)?;
+    let transport = Arc::new(VirtioMmio::new(vm_id, config, device)?);
+    let mut balloons = VM_BALLOONS.lock();
+    if balloons.contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "VM already has a virtio-balloon");
+    }
+    balloons.insert(vm_id, Arc::downgrade(&transport));
+    Ok(transport)
+}
+
+fn vm_balloon(vm_id: usize) -> AxResult<Arc<VirtioMmio<VirtioBalloon>>> {
+    match VM_BALLOONS.lock().get(&vm_id).and_then(Weak::upgrade) {
+        Some(balloon) => Ok(balloon),
+        None => ax_err!(NotFound, "VM has no virtio-balloon"),
+    }
+}
+
+/// Asks the guest of `vm_id` to inflate or deflate its balloon to `pages` 4K pages.
+pub fn set_balloon_target(vm_id: usize, pages: u32) -> AxResult {
+    let balloon = vm_balloon(vm_id)?;
+    balloon.device().state.lock().target_pages = pages;
+    balloon.config_changed();
+    Ok(())
+}
+
+/// Returns the number of pages in the balloon of the guest of `vm_id`, as reported by it.
+pub fn balloon_pages(vm_id: usize) -> AxResult<u32> {
+    Ok(vm_balloon(vm_id)?.device().state.lock().actual_pages)
+}
+
+/// Asks the guest of `vm_id` to update its memory statistics.
+pub fn request_balloon_stats(vm_id: usize) -> AxResult {
+    let balloon = vm_balloon(vm_id)?;
+    balloon.device().state.lock().stats_requested = true;
+    balloon.notify_queue(STATS_QUEUE);
+    Ok(())
+}
+
+/// Returns the last memory statistics reported by the guest of `vm_id`, as `(tag, value)`
+/// pairs, e.g. tag 4 for the free memory in bytes.
+pub fn balloon_stats(vm_id: usize) -> AxResult<Vec<(u16, u64)>> {
+    let balloon = vm_balloon(vm_id)?;
+    let state = balloon.device().state.lock();
+    Ok(state
+        .stats
+        .iter()
+        .map(|(tag, value)| (*tag, *value))
+        .collect())
+}
+
+/// Gives the page at `gpa` back to the guest of `vm_id`, which accessed it while it was in its
+/// balloon, as if the guest had deflated it.
+///
+/// Returns whether the page was in the balloon, and could be mapped again.
+pub fn handle_balloon_fault(vm_id: usize, gpa: GuestPhysAddr) -> bool {
+    let Ok(balloon) = vm_balloon(vm_id) else {
+        return false;
+    };
+    let device = balloon.device();
+    let gpa = gpa.as_usize() & !(PAGE_SIZE_4K - 1);
+    let mut state = device.state.lock();
+    if !state.inflated.contains(&gpa) {
+        return false;
+    }
+    match device.deflate_page(&mut state, gpa) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!(
+                "VM[{}] virtio-balloon failed to give page {:#x} back: {:?}",
+                vm_id, gpa, err
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! virtio-blk, a block device backed by a file of the host filesystem.
+//!
+//! The `Name` of the `emu_devices` entry is the path of the backing file, e.g. a raw disk
+//! image. Its optional `EmuConfig` `[offset, length]`, in bytes, restricts the disk to a range
+//! of the file, so that several guests can each get a partition of the same host disk.
+//!
+//! The data of a request is transferred one buffer at a time, the device advertising the
+//! maximum size of a buffer and their maximum number in a request, [`SIZE_MAX`] and
+//! [`SEG_MAX`]. A request beyond these limits or beyond the end of the disk fails.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use core::mem::size_of;
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+
+use super::{DescChain, VirtioDevice, Virtqueue};
+use crate::vmm::devices::GuestMemory;
+
+/// The virtio device ID of a block device.
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+/// The maximum size of a buffer is in `size_max`.
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+/// The maximum number of data buffers in a request is in `seg_max`.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device supports cache flushes.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of a sector, the unit of the requests and of the capacity.
+const SECTOR_SIZE: u64 = 512;
+/// The length of the ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const ID_BYTES: usize = 20;
+/// The maximum size of a buffer of a request, i.e. of the data transferred at once.
+const SIZE_MAX: u32 = 0x1_0000;
+/// The maximum number of data buffers of a request, besides the header and the status.
+const SEG_MAX: u32 = 128;
+
+/// The header of a request, in its first readable buffer.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BlkReqHeader {
+    req_type: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+/// The storage behind a virtio-blk device.
+pub trait BlockBackend: Send + Sync {
+    /// Returns the size of the disk, in bytes.
+    fn size(&self) -> u64;
+
+    /// Returns whether the disk cannot be written.
+    fn read_only(&self) -> bool;
+
+    /// Reads `buf.len()` bytes at `offset` into `buf`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> AxResult;
+
+    /// Writes `buf` at `offset`.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> AxResult;
+
+    /// Flushes the written data to the storage.
+    fn flush(&self) -> AxResult;
+}
+
+#[cfg(feature = "fs")]
+mod file {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use axerrno::{AxResult, ax_err, ax_err_type};
+    use spin::Mutex;
+
+    use super::BlockBackend;
+
+    /// A range of a file of the host filesystem.
+    pub struct FileBackend {
+        file: Mutex<File>,
+        offset: u64,
+        size: u64,
+        read_only: bool,
+    }
+
+    impl FileBackend {
+        /// Opens the file `path`, read-write if possible, restricted to `range` if any.
+        pub fn open(path: &str, range: Option<(u64, u64)>) -> AxResult<Self> {
+            let (file, read_only) = match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => (file, false),
+                Err(_) => (
+                    File::open(path).map_err(|err| {
+                        ax_err_type!(NotFound, format!("Failed to open {}, err {:?}", path, err))
+                    })?,
+                    true,
+                ),
+            };
+            let file_size = file
+                .metadata()
+                .map_err(|err| {
+                    ax_err_type!(
+                        Io,
+                        format!("Failed to get metadata of file {}, err {:?}", path, err)
+                    )
+                })?
+                .size();
+            let (offset, size) = range.unwrap_or((0, file_size));
+            if offset.checked_add(size).is_none_or(|end| end > file_size) {
+                return ax_err!(InvalidInput, "virtio-blk range beyond the end of the file");
+            }
+            Ok(Self {
+                file: Mutex::new(file),
+                offset,
+                size,
+                read_only,
+            })
+        }
+
+        fn check_range(&self, offset: u64, len: usize) -> AxResult {
+            if offset
+                .checked_add(len as u64)
+                .is_none_or(|end| end > self.size)
+            {
+                return ax_err!(InvalidInput, "virtio-blk access beyond the end of the disk");
+            }
+            Ok(())
+        }
+    }
+
+    impl BlockBackend for FileBackend {
+        fn size(&self) -> u64 {
+            self.size
+        }
+
+        fn read_only(&self) -> bool {
+            self.read_only
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> AxResult {
+            self.check_range(offset, buf.len())?;
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(self.offset + offset))
+                .and_then(|_| file.read_exact(buf))
+                .map_err(|err| ax_err_type!(Io, format!("virtio-blk read failed, err {:?}", err)))
+        }
+
+        fn write_at(&self, offset: u64, buf: &[u8]) -> AxResult {
+            self.check_range(offset, buf.len())?;
+            let mut file = self.file.lock();
+            file.seek(SeekFrom::Start(self.offset + offset))
+                .and_then(|_| file.write_all(buf))
+                .map_err(|err| ax_err_type!(Io, format!("virtio-blk write failed, err {:?}", err)))
+        }
+
+        fn flush(&self) -> AxResult {
+            self.file
+                .lock()
+                .flush()
+                .map_err(|err| ax_err_type!(Io, format!("virtio-blk flush failed, err {:?}", err)))
+        }
+    }
+}
+
+/// A virtio-blk device model.
+pub struct VirtioBlk {
+    backend: Box<dyn BlockBackend>,
+    /// The ID string of the device, padded with zeros.
+    id: [u8; ID_BYTES],
+}
+
+impl VirtioBlk {
+    #[cfg_attr(not(feature = "fs"), allow(unused))]
+    pub fn new(name: &str, backend: Box<dyn BlockBackend>) -> Self {
+        let mut id = [0; ID_BYTES];
+        let len = name.len().min(ID_BYTES);
+        id[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Self { backend, id }
+    }
+
+    /// Creates the device of an `emu_devices` entry, backed by the file named by the entry.
+    #[cfg(feature = "fs")]
+    pub fn from_config(config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let range = match config.cfg_list.as_slice() {
+            [] => None,
+            [offset, length] => Some((*offset as u64, *length as u64)),
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "virtio-blk EmuConfig must be [offset, length]"
+                );
+            }
+        };
+        let backend = file::FileBackend::open(&config.name, range)?;
+        info!(
+            "virtio-blk {}: {} bytes{}",
+            config.name,
+            backend.size(),
+            if backend.read_only() {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+        Ok(Self::new(&config.name, Box::new(backend)))
+    }
+
+    /// Creates the device of an `emu_devices` entry, which needs the `fs` feature.
+    #[cfg(not(feature = "fs"))]
+    pub fn from_config(_config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        ax_err!(Unsupported, "virtio-blk needs the `fs` feature")
+    }
+
+    /// Returns whether a request transferring `data_len` bytes at `offset` of the disk stays
+    /// within the disk and the limits advertised by the device.
+    fn is_valid_transfer(&self, chain: &DescChain, offset: u64, data_len: usize) -> bool {
+        chain.descs.len() <= SEG_MAX as usize + 2
+            && chain.descs.iter().all(|desc| desc.len <= SIZE_MAX)
+            && offset
+                .checked_add(data_len as u64)
+                .is_some_and(|end| end <= self.backend.size())
+    }
+
+    /// Executes a request, returning its status and the number of bytes written into it.
+    fn handle_request(
+        &self,
+        header: &BlkReqHeader,
+        chain: &DescChain,
+        mem: &GuestMemory,
+    ) -> AxResult<(u8, usize)> {
+        let offset = header.sector.saturating_mul(SECTOR_SIZE);
+        match header.req_type {
+            VIRTIO_BLK_T_IN => {
+                // The last writable byte is the status, written by the caller.
+                let data_len = chain.writable_len().saturating_sub(1);
+                if !self.is_valid_transfer(chain, offset, data_len) {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                let mut written = 0;
+                for (addr, len) in chain.writable_segments(0, data_len) {
+                    let mut data = vec![0; len];
+                    if self
+                        .backend
+                        .read_at(offset + written as u64, &mut data)
+                        .is_err()
+                    {
+                        return Ok((VIRTIO_BLK_S_IOERR, written));
+                    }
+                    mem.write(addr, &data)?;
+                    written += len;
+                }
+                Ok((VIRTIO_BLK_S_OK, written))
+            }
+            VIRTIO_BLK_T_OUT => {
+                let header_len = size_of::<BlkReqHeader>();
+                let data_len = chain.readable_len().saturating_sub(header_len);
+                if self.backend.read_only() || !self.is_valid_transfer(chain, offset, data_len) {
+                    return Ok((VIRTIO_BLK_S_IOERR, 0));
+                }
+                let mut done = 0;
+                for (addr, len) in chain.readable_segments(header_len, data_len) {
+                    let mut data = vec![0; len];
+                    mem.read(addr, &mut data)?;
+                    if self.backend.write_at(offset + done as u64, &data).is_err() {
+                        return Ok((VIRTIO_BLK_S_IOERR, 0));
+                    }
+                    done += len;
+                }
+                Ok((VIRTIO_BLK_S_OK, 0))
+            }
+            VIRTIO_BLK_T_FLUSH => match self.backend.flush() {
+                Ok(()) => Ok((VIRTIO_BLK_S_OK, 0)),
+                Err(_) => Ok((VIRTIO_BLK_S_IOERR, 0)),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let len = data_len.min(ID_BYTES);
+                Ok((VIRTIO_BLK_S_OK, chain.write_all(mem, &self.id[..len])?))
+            }
+            _ => Ok((VIRTIO_BLK_S_UNSUPP, 0)),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH;
+        if self.backend.read_only() {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        features
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// The configuration space starts with the capacity, in sectors, `size_max` and `seg_max`.
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; 16];
+        config[..8].copy_from_slice(&(self.backend.size() / SECTOR_SIZE).to_le_bytes());
+        config[8..12].copy_from_slice(&SIZE_MAX.to_le_bytes());
+        config[12..].copy_from_slice(&SEG_MAX.to_le_bytes());
+        let end = (offset + data.len()).min(config.len());
+        if offset < end {
+            data[..end - offset].copy_from_slice(&config[offset..end]);
+        }
+    }
+
+    fn process_queue(
+        &self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            let Some(first) = chain.readable().next() else {
+                return ax_err!(InvalidData, "virtio-blk request without header");
+            };
+            let Some(status) = chain.writable().last().filter(|desc| desc.len > 0) else {
+                return ax_err!(InvalidData, "virtio-blk request without status");
+            };
+            let header: BlkReqHeader = mem.read_obj(first.addr)?;
+            let (status_code, written) = self.handle_request(&header, &chain, mem)?;
+            mem.write_obj(status.addr + (status.len as usize - 1), &status_code)?;
+            queue.push_used(mem, chain.head, written as u32 + 1)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
@@ -0,0 +1,409 @@
+//! virtio-console, a paravirtual console with multiple ports.
+//!
+//! Each element of the `EmuConfig` of the `emu_devices` entry is a port: `0` connects it to
+//! the [hypervisor console multiplexer](crate::vmm::console), and a VM ID connects it to the
+//! port with the same number of the virtio-console of that VM, which must be connected back.
+//! Without `EmuConfig`, the device has a single port on the hypervisor console. Port 0, when
+//! on the hypervisor console, is the console of the guest (`hvc0` on Linux). The other ports
+//! are named after the peer VM, `vm<ID>`, or `vm<ID>:console<N>` on the hypervisor console,
+//! see `/dev/virtio-ports/` in the guest.
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::{Mutex, Once};
+
+use super::{VirtioDevice, VirtioMmio, Virtqueue};
+use crate::vmm::console;
+use crate::vmm::devices::GuestMemory;
+
+/// The virtio device ID of a console.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// The device supports multiple ports, and has control virtqueues.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// The device supports emergency writes through its configuration space.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The virtqueues of the control messages, for the driver and for the device.
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+/// The maximum number of ports of a device.
+const MAX_PORTS: usize = 16;
+/// The maximum number of input bytes kept for a port whose driver has no buffer available.
+const MAX_PENDING_INPUT: usize = 0x1_0000;
+
+/// The offset of `emerg_wr` in the configuration space.
+const CONFIG_EMERG_WR: usize = 8;
+
+/// A control message, followed by the name of the port for `VIRTIO_CONSOLE_PORT_NAME`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ControlMsg {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+/// What a port is connected to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PortKind {
+    /// The hypervisor console multiplexer.
+    Host,
+    /// The port with the same number of the virtio-console of another VM.
+    Peer(usize),
+}
+
+/// The state of the ports, shared by the virtqueues.
+#[derive(Default)]
+struct ConsoleState {
+    /// The input of each port, not yet received by the driver.
+    input: Vec<VecDeque<u8>>,
+    /// The control messages not yet received by the driver.
+    control: VecDeque<Vec<u8>>,
+}
+
+/// A virtio-console device model.
+pub struct VirtioConsole {
+    vm_id: usize,
+    ports: Vec<PortKind>,
+    /// The IDs of the host ports in the hypervisor console multiplexer.
+    console_ids: Once<Vec<Option<usize>>>,
+    state: Mutex<ConsoleState>,
+    /// The transport of the device, to notify the receive virtqueues.
+    transport: Once<Weak<VirtioMmio<VirtioConsole>>>,
+}
+
+/// The virtio-consoles connectable to other VMs, stored in a BTreeMap where the key is the
+/// VM ID.
+static VM_CONSOLES: Mutex<BTreeMap<usize, Weak<VirtioMmio<VirtioConsole>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Returns the receive virtqueue of a port, followed by its transmit virtqueue.
+fn rx_queue(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 + 2 * port }
+}
+
+/// Returns the port of a data virtqueue, and whether it is a receive one.
+fn queue_port(index: usize) -> (usize, bool) {
+    match index {
+        0 | 1 => (0, index == 0),
+        _ => ((index - 2) / 2, index % 2 == 0),
+    }
+}
+
+fn control_msg(id: usize, event: u16, value: u16) -> Vec<u8> {
+    let msg = ControlMsg {
+        id: id as u32,
+        event,
+        value,
+    };
+    let mut bytes = Vec::with_capacity(size_of::<ControlMsg>());
+    bytes.extend_from_slice(&msg.id.to_le_bytes());
+    bytes.extend_from_slice(&msg.event.to_le_bytes());
+    bytes.extend_from_slice(&msg.value.to_le_bytes());
+    bytes
+}
+
+impl VirtioConsole {
+    fn from_config(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let ports: Vec<PortKind> = if config.cfg_list.is_empty() {
+            vec![PortKind::Host]
+        } else {
+            config
+                .cfg_list
+                .iter()
+                .map(|&peer| match peer {
+                    0 => PortKind::Host,
+                    peer => PortKind::Peer(peer),
+                })
+                .collect()
+        };
+        if ports.len() > MAX_PORTS {
+            return ax_err!(InvalidInput, "virtio-console has too many ports");
+        }
+        if ports.contains(&PortKind::Peer(vm_id)) {
+            return ax_err!(InvalidInput, "virtio-console port connected to its own VM");
+        }
+        Ok(Self {
+            vm_id,
+            state: Mutex::new(ConsoleState {
+                input: ports.iter().map(|_| VecDeque::new()).collect(),
+                control: VecDeque::new(),
+            }),
+            ports,
+            console_ids: Once::new(),
+            transport: Once::new(),
+        })
+    }
+
+    /// Returns the name of a port in the guest and in the hypervisor console multiplexer.
+    fn port_name(&self, port: usize) -> String {
+        match self.ports[port] {
+            PortKind::Host => format!("vm{}:console{}", self.vm_id, port),
+            PortKind::Peer(peer) => format!("vm{}", peer),
+        }
+    }
+
+    fn notify(&self, index: usize) {
+        if let Some(transport) = self.transport.get().and_then(Weak::upgrade) {
+            transport.notify_queue(index);
+        }
+    }
+
+    /// Receives input on a port, from the hypervisor console or from a peer.
+    fn receive(&self, port: usize, data: &[u8]) {
+        {
+            let mut state = self.state.lock();
+            let input = &mut state.input[port];
+            let len = data
+                .len()
+                .min(MAX_PENDING_INPUT.saturating_sub(input.len()));
+            if len < data.len() {
+                warn!(
+                    "VM[{}] virtio-console port {} input overflow, {} bytes dropped",
+                    self.vm_id,
+                    port,
+                    data.len() - len
+                );
+            }
+            input.extend(&data[..len]);
+        }
+        self.notify(rx_queue(port));
+    }
+
+    /// Sends the output of a port to what it is connected to.
+    fn transmit(&self, port: usize, data: &[u8]) {
+        match self.ports[port] {
+            PortKind::Host => {
+                if let Some(Some(id)) = self.console_ids.get().map(|ids| ids[port]) {
+                    console::write(id, data);
+                }
+            }
+            PortKind::Peer(peer) => {
+                let peer_console = VM_CONSOLES.lock().get(&peer).and_then(Weak::upgrade);
+                let Some(peer_console) = peer_console else {
+                    trace!("VM[{}] has no virtio-console, output dropped", peer);
+                    return;
+                };
+                let peer_device = peer_console.device();
+                if peer_device.ports.get(port) != Some(&PortKind::Peer(self.vm_id)) {
+                    trace!(
+                        "VM[{}] virtio-console port {} is not connected back, output dropped",
+                        peer, port
+                    );
+                    return;
+                }
+                peer_device.receive(port, data);
+            }
+        }
+    }
+
+    /// Handles a control message from the driver.
+    fn handle_control(&self, msg: &ControlMsg) {
+        let port = msg.id as usize;
+        let mut replies = Vec::new();
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_READY if msg.value == 1 => {
+                for port in 0..self.ports.len() {
+                    replies.push(control_msg(port, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if msg.value == 1 && port < self.ports.len() => {
+                if port == 0 && self.ports[port] == PortKind::Host {
+                    replies.push(control_msg(port, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                } else {
+                    let mut name = control_msg(port, VIRTIO_CONSOLE_PORT_NAME, 0);
+                    name.extend_from_slice(self.port_name(port).as_bytes());
+                    replies.push(name);
+                }
+                replies.push(control_msg(port, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            // The ports are always open on the device side, the driver opening or closing them
+            // has no effect.
+            _ => {}
+        }
+        if !replies.is_empty() {
+            self.state.lock().control.extend(replies);
+            self.notify(CONTROL_RX_QUEUE);
+        }
+    }
+
+    /// Fills the buffers of a receive virtqueue with the pending input of a port.
+    fn fill_rx(&self, port: usize, queue: &mut Virtqueue, mem: &GuestMemory) -> AxResult<bool> {
+        let mut used = false;
+        loop {
+            let mut state = self.state.lock();
+            if state.input[port].is_empty() {
+                break;
+            }
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let len = chain.writable_len().min(state.input[port].len());
+            let data: Vec<u8> = state.input[port].drain(..len).collect();
+            drop(state);
+            let written = chain.write_all(mem, &data)?;
+            queue.push_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Fills the buffers of the control receive virtqueue with the pending control messages.
+    fn fill_control_rx(&self, queue: &mut Virtqueue, mem: &GuestMemory) -> AxResult<bool> {
+        let mut used = false;
+        loop {
+            let mut state = self.state.lock();
+            if state.control.is_empty() {
+                break;
+            }
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let msg = state.control.pop_front().unwrap_or_default();
+            drop(state);
+            let written = chain.write_all(mem, &msg)?;
+            queue.push_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    /// The configuration space holds `cols` and `rows`, unused, and `max_nr_ports`.
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        let end = (offset + data.len()).min(config.len());
+        if offset < end {
+            data[..end - offset].copy_from_slice(&config[offset..end]);
+        }
+    }
+
+    /// Emergency writes go to port 0, before the virtqueues are set up.
+    fn write_config(&self, offset: usize, data: &[u8]) {
+        if offset == CONFIG_EMERG_WR && !data.is_empty() {
+            self.transmit(0, &data[..1]);
+        }
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        match index {
+            CONTROL_RX_QUEUE => self.fill_control_rx(queue, mem),
+            CONTROL_TX_QUEUE => {
+                let mut used = false;
+                while let Some(chain) = queue.pop(mem)? {
+                    let data = chain.read_all(mem)?;
+                    queue.push_used(mem, chain.head, 0)?;
+                    used = true;
+                    if data.len() >= size_of::<ControlMsg>() {
+                        self.handle_control(&ControlMsg {
+                            id: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                            event: u16::from_le_bytes([data[4], data[5]]),
+                            value: u16::from_le_bytes([data[6], data[7]]),
+                        });
+                    }
+                }
+                Ok(used)
+            }
+            _ => {
+                let (port, is_rx) = queue_port(index);
+                if is_rx {
+                    return self.fill_rx(port, queue, mem);
+                }
+                let mut used = false;
+                while let Some(chain) = queue.pop(mem)? {
+                    let data = chain.read_all(mem)?;
+                    queue.push_used(mem, chain.head, 0)?;
+                    used = true;
+                    self.transmit(port, &data);
+                }
+                Ok(used)
+            }
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.control.clear();
+        for input in state.input.iter_mut() {
+            input.clear();
+        }
+    }
+}
+
+/// Creates a virtio-console device from its `emu_devices` entry, and connects its ports.
+pub fn create_console(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<VirtioMmio<VirtioConsole>>> {
+    let device = VirtioConsole::from_config(vm_id, config)?;
+    let connectable = device
+        .ports
+        .iter()
+        .any(|kind| matches!(kind, PortKind::Peer(_)));
+    if connectable && VM_CONSOLES.lock().contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "VM already has a connectable virtio-console");
+    }
+    let transport = Arc::new(VirtioMmio::new(vm_id, config, device)?);
+    let device = transport.device();
+    device.transport.call_once(|| Arc::downgrade(&transport));
+
+    let console_ids = (0..device.ports.len())
+        .map(|port| {
+            if device.ports[port] != PortKind::Host {
+                return None;
+            }
+            let weak = Arc::downgrade(&transport);
+            Some(console::register(
+                device.port_name(port),
+                Box::new(move |data| {
+                    if let Some(transport) = weak.upgrade() {
+                        transport.device().receive(port, data);
+                    }
+                }),
+            ))
+        })
+        .collect();
+    device.console_ids.call_once(|| console_ids);
+
+    if connectable {
+        VM_CONSOLES.lock().insert(vm_id, Arc::downgrade(&transport));
+    }
+    Ok(transport)
+}
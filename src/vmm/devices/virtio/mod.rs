@@ -0,0 +1,442 @@
+//! Virtio devices over the virtio-mmio transport, or the virtio-pci one.
+//!
+//! Each device is an `emu_devices` entry covering its virtio-mmio register window (version 2,
+//! i.e. non-legacy, with split virtqueues), whose `Alloc-Irq` is the guest interrupt raised
+//! when the device has used buffers or its configuration changed. The guest finds it like the
+//! `virtio_mmio` windows of QEMU, through its device tree.
+//!
+//! A device may instead be a function on the PCI bus of the VM, with the `Emu-Type` of its
+//! virtio-mmio variant plus 0x10, e.g. [`EmuType::VirtioPciBlk`] for virtio-blk. Its
+//! `Base-Ipa` is then its device number on the bus, 0 for the first free one, see [`pci`].
+//!
+//! Queue notifications are processed synchronously, on the task of the notifying vCPU.
+//!
+//! [`EmuType::VirtioPciBlk`]: super::EmuType::VirtioPciBlk
+
+extern crate alloc;
+
+mod balloon;
+mod blk;
+mod console;
+mod net;
+mod pci;
+mod queue;
+mod rng;
+mod vsock;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::{EmulatedDeviceConfig, VmMemConfig};
+use spin::{Mutex, Once};
+
+use crate::vmm::devices::{EmuDevice, GuestMemory};
+use crate::vmm::{irq, vm_list};
+pub use balloon::{
+    balloon_pages, balloon_stats, handle_balloon_fault, request_balloon_stats, set_balloon_target,
+};
+pub use pci::create_pci;
+pub use queue::{DescChain, MAX_READ_LEN, Virtqueue};
+
+/// The size of the virtio-mmio register window, followed by the device configuration space.
+const VIRTIO_MMIO_CONFIG: usize = 0x100;
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: usize = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: usize = 0x064;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: usize = 0x0fc;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x1af4;
+
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+/// The device has used buffers.
+const INTERRUPT_VRING: u32 = 1 << 0;
+/// The configuration of the device changed.
+const INTERRUPT_CONFIG: u32 = 1 << 1;
+
+/// Compliance with the virtio 1.x specification, required by the non-legacy transport.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The default maximum number of descriptors of a virtqueue.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+
+/// The interrupts of a device, when raised by a transport wrapping its virtio-mmio one, such as
+/// the MSI-X vectors of virtio-pci.
+pub trait VirtioInterrupt: Send + Sync {
+    /// Raises the interrupt of the virtqueue `queue`, or of a configuration change if `None`.
+    ///
+    /// Returns `false` if the transport does not handle it, and it is then raised on the
+    /// `Alloc-Irq` of the device.
+    fn raise(&self, queue: Option<usize>) -> bool;
+}
+
+/// A virtio device model, behind the virtio-mmio transport.
+pub trait VirtioDevice: Send + Sync {
+    /// Returns the virtio device ID, e.g. 2 for a block device.
+    fn device_id(&self) -> u32;
+
+    /// Returns the device-specific feature bits. [`VIRTIO_F_VERSION_1`] is added by the
+    /// transport.
+    fn features(&self) -> u64;
+
+    /// Returns the number of virtqueues of the device.
+    fn num_queues(&self) -> usize;
+
+    /// Returns the maximum number of descriptors of each virtqueue.
+    fn queue_max_size(&self) -> u16 {
+        DEFAULT_QUEUE_SIZE
+    }
+
+    /// Reads the device configuration space at `offset` into `data`.
+    fn read_config(&self, offset: usize, data: &mut [u8]);
+
+    /// Writes `data` into the device configuration space at `offset`.
+    fn write_config(&self, _offset: usize, _data: &[u8]) {}
+
+    /// Processes the buffers made available by the driver on the virtqueue `index`.
+    ///
+    /// Returns whether buffers have been used, which interrupts the driver.
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool>;
+
+    /// Resets the device, when the driver writes 0 to the status register.
+    fn reset(&self) {}
+}
+
+/// The registers of the virtio-mmio transport.
+struct TransportState {
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    status: u32,
+    config_generation: u32,
+}
+
+/// A virtio device behind the virtio-mmio transport.
+pub struct VirtioMmio<D> {
+    vm_id: usize,
+    name: String,
+    base: GuestPhysAddr,
+    size: usize,
+    /// The guest interrupt of the device, 0 if none.
+    irq: usize,
+    mem: GuestMemory,
+    device: D,
+    interrupt_status: AtomicU32,
+    /// The interrupts of the transport wrapping this one, if any.
+    interrupt: Once<Arc<dyn VirtioInterrupt>>,
+    /// The registers, locked before any of the queues.
+    state: Mutex<TransportState>,
+    /// The virtqueues, each locked on its own so that a device can process one of its queues
+    /// while another one is notified, e.g. by a peer device.
+    queues: Vec<Mutex<Virtqueue>>,
+}
+
+fn set_half(value: &mut u64, high: bool, half: u32) {
+    if high {
+        *value = (*value & 0xffff_ffff) | ((half as u64) << 32);
+    } else {
+        *value = (*value & !0xffff_ffff) | half as u64;
+    }
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Creates the transport of `device`, from its `emu_devices` entry.
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig, device: D) -> AxResult<Self> {
+        if config.length < VIRTIO_MMIO_CONFIG {
+            return ax_err!(InvalidInput, "virtio-mmio window too small");
+        }
+        let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+            return ax_err!(NotFound, "VM not found");
+        };
+        let queues = (0..device.num_queues())
+            .map(|_| Mutex::new(Virtqueue::new(device.queue_max_size())))
+            .collect();
+        Ok(Self {
+            vm_id,
+            name: config.name.clone(),
+            base: GuestPhysAddr::from(config.base_gpa),
+            size: config.length,
+            irq: config.irq_id,
+            mem: GuestMemory::new(vm),
+            device,
+            interrupt_status: AtomicU32::new(0),
+            interrupt: Once::new(),
+            state: Mutex::new(TransportState {
+                device_features_sel: 0,
+                driver_features_sel: 0,
+                driver_features: 0,
+                queue_sel: 0,
+                status: 0,
+                config_generation: 0,
+            }),
+            queues,
+        })
+    }
+
+    /// Returns the device model.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Raises the interrupts of the device through `interrupt`, for the transport wrapping
+    /// this one.
+    pub fn set_interrupt(&self, interrupt: Arc<dyn VirtioInterrupt>) {
+        self.interrupt.call_once(|| interrupt);
+    }
+
+    /// Sets interrupt status bits, and raises the interrupt of the virtqueue `queue`, or of a
+    /// configuration change if `None`.
+    fn raise_interrupt(&self, bits: u32, queue: Option<usize>) {
+        self.interrupt_status.fetch_or(bits, Ordering::AcqRel);
+        if self
+            .interrupt
+            .get()
+            .is_some_and(|interrupt| interrupt.raise(queue))
+        {
+            return;
+        }
+        if self.irq != 0 {
+            irq::inject_guest_irq(self.vm_id, self.irq);
+        }
+    }
+
+    /// Notifies the driver that the configuration space of the device changed.
+    pub fn config_changed(&self) {
+        let mut state = self.state.lock();
+        state.config_generation = state.config_generation.wrapping_add(1);
+        self.raise_interrupt(INTERRUPT_CONFIG, None);
+    }
+
+    /// Processes the virtqueue `index`, on a notification from the driver or when the backend
+    /// has new data for it.
+    pub fn notify_queue(&self, index: usize) {
+        let Some(queue) = self.queues.get(index) else {
+            return;
+        };
+        let result = self
+            .device
+            .process_queue(index, &mut queue.lock(), &self.mem);
+        match result {
+            Ok(true) => self.raise_interrupt(INTERRUPT_VRING, Some(index)),
+            Ok(false) => {}
+            Err(err) => {
+                warn!(
+                    "VM[{}] {} queue {} failed: {:?}",
+                    self.vm_id, self.name, index, err
+                );
+                self.state.lock().status |= STATUS_DEVICE_NEEDS_RESET;
+                self.raise_interrupt(INTERRUPT_CONFIG, None);
+            }
+        }
+    }
+
+    fn reset(&self, state: &mut TransportState) {
+        state.device_features_sel = 0;
+        state.driver_features_sel = 0;
+        state.driver_features = 0;
+        state.queue_sel = 0;
+        state.status = 0;
+        self.interrupt_status.store(0, Ordering::Release);
+        for queue in &self.queues {
+            queue.lock().reset();
+        }
+        self.device.reset();
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let state = self.state.lock();
+        let queue = self.queues.get(state.queue_sel as usize).map(|q| q.lock());
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_id(),
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => match state.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map_or(0, |q| q.max_size as u32),
+            // Write-only for virtio-mmio, read back by the virtio-pci transport.
+            VIRTIO_MMIO_QUEUE_NUM => queue.map_or(0, |q| q.size as u32),
+            VIRTIO_MMIO_QUEUE_READY => queue.is_some_and(|q| q.ready) as u32,
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::Acquire),
+            VIRTIO_MMIO_STATUS => state.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => state.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        if offset == VIRTIO_MMIO_QUEUE_NOTIFY {
+            self.notify_queue(value as usize);
+            return;
+        }
+        let mut state = self.state.lock();
+        let queue_sel = state.queue_sel as usize;
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => state.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let sel = state.driver_features_sel;
+                if sel < 2 {
+                    set_half(&mut state.driver_features, sel == 1, value);
+                }
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => state.driver_features_sel = value,
+            VIRTIO_MMIO_QUEUE_SEL => state.queue_sel = value,
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!value, Ordering::AcqRel);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if value == 0 {
+                    self.reset(&mut state);
+                    return;
+                }
+                let mut status = value;
+                // Features the device does not offer, or a legacy driver, fail the negotiation.
+                if status & STATUS_FEATURES_OK != 0
+                    && (state.driver_features & !self.device_features() != 0
+                        || state.driver_features & VIRTIO_F_VERSION_1 == 0)
+                {
+                    status &= !STATUS_FEATURES_OK;
+                }
+                state.status = status | (state.status & STATUS_DEVICE_NEEDS_RESET);
+            }
+            _ => {
+                let Some(queue) = self.queues.get(queue_sel) else {
+                    return;
+                };
+                let mut queue = queue.lock();
+                match offset {
+                    VIRTIO_MMIO_QUEUE_NUM => {
+                        if value != 0 && value <= queue.max_size as u32 && value.is_power_of_two() {
+                            queue.size = value as u16;
+                        }
+                    }
+                    VIRTIO_MMIO_QUEUE_READY => queue.ready = value & 1 != 0,
+                    VIRTIO_MMIO_QUEUE_DESC_LOW => set_half(&mut queue.desc_table, false, value),
+                    VIRTIO_MMIO_QUEUE_DESC_HIGH => set_half(&mut queue.desc_table, true, value),
+                    VIRTIO_MMIO_QUEUE_DRIVER_LOW => set_half(&mut queue.avail_ring, false, value),
+                    VIRTIO_MMIO_QUEUE_DRIVER_HIGH => set_half(&mut queue.avail_ring, true, value),
+                    VIRTIO_MMIO_QUEUE_DEVICE_LOW => set_half(&mut queue.used_ring, false, value),
+                    VIRTIO_MMIO_QUEUE_DEVICE_HIGH => set_half(&mut queue.used_ring, true, value),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl<D: VirtioDevice> EmuDevice for VirtioMmio<D> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + self.size]
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, width: usize) -> usize {
+        let offset = addr - self.base;
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let mut data = [0u8; 8];
+            let width = width.min(data.len());
+            self.device
+                .read_config(offset - VIRTIO_MMIO_CONFIG, &mut data[..width]);
+            return u64::from_le_bytes(data) as usize;
+        }
+        if width != 4 {
+            return 0;
+        }
+        self.read_reg(offset) as usize
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, width: usize, value: usize) {
+        let offset = addr - self.base;
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let data = (value as u64).to_le_bytes();
+            self.device
+                .write_config(offset - VIRTIO_MMIO_CONFIG, &data[..width.min(data.len())]);
+            return;
+        }
+        if width == 4 {
+            self.write_reg(offset, value as u32);
+        }
+    }
+}
+
+/// Creates a virtio-blk device from its `emu_devices` entry.
+pub fn create_blk(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    let device = blk::VirtioBlk::from_config(config)?;
+    Ok(Arc::new(VirtioMmio::new(vm_id, config, device)?))
+}
+
+/// Creates a virtio-net device from its `emu_devices` entry.
+pub fn create_net(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(net::create_net(vm_id, config)?)
+}
+
+/// Creates a virtio-console device from its `emu_devices` entry.
+pub fn create_console(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(console::create_console(vm_id, config)?)
+}
+
+/// Creates a virtio-vsock device from its `emu_devices` entry.
+pub fn create_vsock(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(vsock::create_vsock(vm_id, config)?)
+}
+
+/// Creates a virtio-rng device from its `emu_devices` entry.
+pub fn create_rng(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    let device = rng::VirtioRng::from_config(config)?;
+    Ok(Arc::new(VirtioMmio::new(vm_id, config, device)?))
+}
+
+/// Creates a virtio-balloon device from its `emu_devices` entry, reclaiming the pages of the
+/// `MAP_ALLOC` regions among `memory_regions`.
+pub fn create_balloon(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+    memory_regions: &[VmMemConfig],
+) -> AxResult<Arc<dyn EmuDevice>> {
+    Ok(balloon::create_balloon(vm_id, config, memory_regions)?)
+}
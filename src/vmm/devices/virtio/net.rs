@@ -0,0 +1,196 @@
+//! virtio-net, a NIC attached to an in-hypervisor [L2 switch](crate::vmm::vswitch).
+//!
+//! The `EmuConfig` of the `emu_devices` entry is `[switch]` or `[switch, mac]`: the ID of the
+//! switch the NIC is attached to, 0 by default, and its MAC address as a 48-bit number,
+//! `52:54:00:12:<VM ID>:<switch>` by default.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::{Mutex, Once};
+
+use super::{VirtioDevice, VirtioMmio, Virtqueue};
+use crate::vmm::devices::GuestMemory;
+use crate::vmm::vswitch::{self, MacAddr, SwitchPort};
+
+/// The virtio device ID of a network card.
+const VIRTIO_ID_NET: u32 = 1;
+
+/// The device has a MAC address, in its configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// The device reports its link status, in its configuration space.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// The size of `virtio_net_hdr`, preceding each frame, with `VIRTIO_F_VERSION_1`.
+const NET_HDR_SIZE: usize = 12;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The maximum number of frames kept for a driver which has no receive buffer available.
+const MAX_PENDING_FRAMES: usize = 256;
+
+/// A virtio-net device model.
+pub struct VirtioNet {
+    vm_id: usize,
+    mac: MacAddr,
+    switch_id: usize,
+    /// The ID of the device in its switch.
+    port_id: Once<usize>,
+    /// The frames forwarded by the switch, not yet received by the driver.
+    rx_frames: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl VirtioNet {
+    fn from_config(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let (switch_id, mac) = match config.cfg_list.as_slice() {
+            [] => (0, None),
+            [switch_id] => (*switch_id, None),
+            [switch_id, mac] => (*switch_id, Some(*mac as u64)),
+            _ => return ax_err!(InvalidInput, "virtio-net EmuConfig must be [switch, mac]"),
+        };
+        let mac = match mac {
+            Some(mac) => {
+                let bytes = mac.to_be_bytes();
+                let mut mac = MacAddr::default();
+                mac.copy_from_slice(&bytes[2..]);
+                mac
+            }
+            None => [0x52, 0x54, 0x00, 0x12, vm_id as u8, switch_id as u8],
+        };
+        Ok(Self {
+            vm_id,
+            mac,
+            switch_id,
+            port_id: Once::new(),
+            rx_frames: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Fills the buffers of the receive virtqueue with the pending frames.
+    fn fill_rx(&self, queue: &mut Virtqueue, mem: &GuestMemory) -> AxResult<bool> {
+        let mut used = false;
+        loop {
+            let mut rx_frames = self.rx_frames.lock();
+            if rx_frames.is_empty() {
+                break;
+            }
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let frame = rx_frames.pop_front().unwrap_or_default();
+            drop(rx_frames);
+            if chain.writable_len() < NET_HDR_SIZE + frame.len() {
+                warn!(
+                    "VM[{}] virtio-net receive buffer too small, frame dropped",
+                    self.vm_id
+                );
+                queue.push_used(mem, chain.head, 0)?;
+                used = true;
+                continue;
+            }
+            // A zeroed header, apart from `num_buffers`: no offload, and a single buffer.
+            let mut packet = vec![0; NET_HDR_SIZE];
+            packet[10..12].copy_from_slice(&1u16.to_le_bytes());
+            packet.extend_from_slice(&frame);
+            let written = chain.write_all(mem, &packet)?;
+            queue.push_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    /// The configuration space holds the MAC address, then the link status.
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let mut config = [0u8; 8];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        let end = (offset + data.len()).min(config.len());
+        if offset < end {
+            data[..end - offset].copy_from_slice(&config[offset..end]);
+        }
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        match index {
+            RX_QUEUE => self.fill_rx(queue, mem),
+            TX_QUEUE => {
+                let mut used = false;
+                while let Some(chain) = queue.pop(mem)? {
+                    let packet = chain.read_all(mem)?;
+                    queue.push_used(mem, chain.head, 0)?;
+                    used = true;
+                    if let (Some(frame), Some(port_id)) =
+                        (packet.get(NET_HDR_SIZE..), self.port_id.get())
+                    {
+                        vswitch::transmit(self.switch_id, *port_id, frame);
+                    }
+                }
+                Ok(used)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn reset(&self) {
+        self.rx_frames.lock().clear();
+    }
+}
+
+impl SwitchPort for VirtioMmio<VirtioNet> {
+    fn deliver(&self, frame: &[u8]) {
+        {
+            let mut rx_frames = self.device().rx_frames.lock();
+            if rx_frames.len() >= MAX_PENDING_FRAMES {
+                trace!("VM[{}] virtio-net receive queue full", self.device().vm_id);
+                return;
+            }
+            rx_frames.push_back(frame.to_vec());
+        }
+        self.notify_queue(RX_QUEUE);
+    }
+}
+
+/// Creates a virtio-net device from its `emu_devices` entry, and attaches it to its switch.
+pub fn create_net(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<VirtioMmio<VirtioNet>>> {
+    let device = VirtioNet::from_config(vm_id, config)?;
+    let transport = Arc::new(VirtioMmio::new(vm_id, config, device)?);
+    let device = transport.device();
+    let port: Weak<dyn SwitchPort> = Arc::downgrade(&transport) as _;
+    let port_id = vswitch::attach(device.switch_id, port);
+    device.port_id.call_once(|| port_id);
+    info!(
+        "VM[{}] virtio-net {:02x?} on vswitch {}",
+        vm_id, device.mac, device.switch_id
+    );
+    Ok(transport)
+}
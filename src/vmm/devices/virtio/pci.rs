@@ -0,0 +1,528 @@
+//! The virtio-pci transport, with the modern layout of virtio 1.x.
+//!
+//! A virtio-pci device wraps the virtio-mmio transport of the same device model, which never
+//! appears on the bus of the VM, and maps the structures of its capabilities in BAR 0 to the
+//! virtio-mmio registers, so that all the device models work over both transports. Its
+//! `Alloc-Irq` is its INTx interrupt, on pin INTA.
+//!
+//! If the guest can receive MSIs, the device also has an MSI-X capability, with a vector for
+//! its configuration changes and one per virtqueue, whose table and PBA follow the virtio
+//! structures in BAR 0. Once the guest enables MSI-X, the interrupts of the device are raised
+//! on the vectors it assigned through the common configuration instead of INTx.
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::{EmulatedDeviceConfig, VmMemConfig};
+use spin::{Mutex, MutexGuard};
+
+use super::{
+    VENDOR_ID, VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_DEVICE_FEATURES,
+    VIRTIO_MMIO_DEVICE_FEATURES_SEL, VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES,
+    VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS,
+    VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+    VIRTIO_MMIO_QUEUE_DEVICE_LOW, VIRTIO_MMIO_QUEUE_DRIVER_HIGH, VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+    VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_STATUS, VirtioDevice,
+    VirtioInterrupt, VirtioMmio, balloon, blk, console, net, rng, vsock,
+};
+use crate::vmm::devices::pci::{
+    Bar, BarKind, BarRegion, ConfigSpace, MsixCapability, MsixTable, PciFunction,
+};
+use crate::vmm::devices::{self, EmuDevice, EmuType};
+use crate::vmm::irq;
+
+/// The PCI device ID of a modern virtio device is this plus its virtio device ID.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// The vendor-specific capability, holding the virtio structures.
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// The layout of BAR 0.
+const COMMON_CFG_OFFSET: usize = 0x0000;
+const COMMON_CFG_SIZE: usize = 0x38;
+const ISR_CFG_OFFSET: usize = 0x1000;
+const DEVICE_CFG_OFFSET: usize = 0x2000;
+const DEVICE_CFG_SIZE: usize = 0x1000;
+const NOTIFY_CFG_OFFSET: usize = 0x3000;
+const MSIX_TABLE_OFFSET: usize = 0x4000;
+const MSIX_PBA_OFFSET: usize = 0xc000;
+const BAR_SIZE: u64 = 0x10000;
+/// The notifications of queue N are written at `NOTIFY_CFG_OFFSET` plus N times this.
+const NOTIFY_OFF_MULTIPLIER: usize = 4;
+
+/// The fields of the common configuration structure.
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0c;
+const COMMON_MSIX_CONFIG: usize = 0x10;
+const COMMON_NUM_QUEUES: usize = 0x12;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1a;
+const COMMON_QUEUE_ENABLE: usize = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const COMMON_QUEUE_DESC_LOW: usize = 0x20;
+const COMMON_QUEUE_DESC_HIGH: usize = 0x24;
+const COMMON_QUEUE_DRIVER_LOW: usize = 0x28;
+const COMMON_QUEUE_DRIVER_HIGH: usize = 0x2c;
+const COMMON_QUEUE_DEVICE_LOW: usize = 0x30;
+const COMMON_QUEUE_DEVICE_HIGH: usize = 0x34;
+
+/// No MSI-X vector is used.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// The maximum number of virtqueues of a device, when counting them.
+const MAX_QUEUES: u32 = 1024;
+
+/// The registers of the common configuration which are not forwarded to virtio-mmio.
+struct PciTransportState {
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u16,
+}
+
+/// The MSI-X vectors of a device, and the ones assigned to its configuration changes and
+/// virtqueues.
+struct MsixState {
+    /// Whether the guest enabled MSI-X, and whether it masked all the vectors, mirrored from
+    /// the capability.
+    enabled: bool,
+    all_masked: bool,
+    table: MsixTable,
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+}
+
+impl MsixState {
+    fn new(num_queues: usize) -> Self {
+        Self {
+            enabled: false,
+            all_masked: false,
+            table: MsixTable::new(num_queues + 1),
+            config_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; num_queues],
+        }
+    }
+
+    /// Returns `vector` if the table has it, or [`VIRTIO_MSI_NO_VECTOR`], which the driver
+    /// reads back when a vector could not be assigned.
+    fn checked(&self, vector: u16) -> u16 {
+        if (vector as usize) < self.table.num_vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+}
+
+/// The interrupts of a virtio-pci device, raised by its virtio-mmio transport.
+struct PciInterrupts {
+    vm_id: usize,
+    msix: Mutex<MsixState>,
+}
+
+impl VirtioInterrupt for PciInterrupts {
+    fn raise(&self, queue: Option<usize>) -> bool {
+        let mut msix = self.msix.lock();
+        // INTx is used until MSI-X is enabled.
+        if !msix.enabled {
+            return false;
+        }
+        let vector = match queue {
+            Some(queue) => msix
+                .queue_vectors
+                .get(queue)
+                .copied()
+                .unwrap_or(VIRTIO_MSI_NO_VECTOR),
+            None => msix.config_vector,
+        };
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            return true;
+        }
+        let all_masked = msix.all_masked;
+        if let Some(message) = msix.table.signal(vector as usize, all_masked) {
+            drop(msix);
+            irq::deliver_msi(self.vm_id, message);
+        }
+        true
+    }
+}
+
+/// A virtio device behind the virtio-pci transport.
+pub struct VirtioPci {
+    name: String,
+    /// The virtio-mmio transport of the device, at guest physical address 0.
+    mmio: Arc<dyn EmuDevice>,
+    num_queues: u16,
+    config: Mutex<ConfigSpace>,
+    /// The MSI-X capability, if the guest can receive MSIs.
+    msix_cap: Option<MsixCapability>,
+    state: Mutex<PciTransportState>,
+    interrupts: Arc<PciInterrupts>,
+}
+
+/// Returns the body of a virtio capability, following its ID and next pointer.
+fn virtio_cap(cfg_type: u8, offset: usize, length: usize, extra: &[u8]) -> Vec<u8> {
+    let cap_len = 16 + extra.len() as u8;
+    let mut body = vec![cap_len, cfg_type, 0, 0, 0, 0];
+    body.extend_from_slice(&(offset as u32).to_le_bytes());
+    body.extend_from_slice(&(length as u32).to_le_bytes());
+    body.extend_from_slice(extra);
+    body
+}
+
+impl VirtioPci {
+    /// Creates the virtio-pci transport of the device behind the virtio-mmio transport `mmio`,
+    /// which raises its interrupts through `interrupts`.
+    fn new(
+        vm_id: usize,
+        config: &EmulatedDeviceConfig,
+        mmio: Arc<dyn EmuDevice>,
+        interrupts: Arc<PciInterrupts>,
+    ) -> Self {
+        let read = |offset| mmio.handle_mmio_read(0, GuestPhysAddr::from(offset), 4) as u32;
+        let device_id = read(VIRTIO_MMIO_DEVICE_ID);
+        let mut num_queues = 0;
+        while num_queues < MAX_QUEUES {
+            mmio.handle_mmio_write(
+                0,
+                GuestPhysAddr::from(VIRTIO_MMIO_QUEUE_SEL),
+                4,
+                num_queues as usize,
+            );
+            if read(VIRTIO_MMIO_QUEUE_NUM_MAX) == 0 {
+                break;
+            }
+            num_queues += 1;
+        }
+        mmio.handle_mmio_write(0, GuestPhysAddr::from(VIRTIO_MMIO_QUEUE_SEL), 4, 0);
+
+        let class_code = match device_id {
+            // Network controller, SCSI storage controller and other communication controller.
+            1 => 0x02_00_00,
+            2 => 0x01_00_00,
+            3 => 0x07_80_00,
+            _ => 0xff_00_00,
+        };
+        let mut pci_config = ConfigSpace::new(
+            VENDOR_ID as u16,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_id as u16,
+            class_code,
+            1,
+        );
+        pci_config.set_subsystem(
+            VENDOR_ID as u16,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_id as u16,
+        );
+        pci_config.set_interrupt(1, config.irq_id as u8);
+        pci_config.add_bar(0, Bar {
+            kind: BarKind::Mem32,
+            size: BAR_SIZE,
+            prefetchable: false,
+        });
+        let caps = [
+            virtio_cap(
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CFG_OFFSET,
+                COMMON_CFG_SIZE,
+                &[],
+            ),
+            virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG_OFFSET, 4, &[]),
+            virtio_cap(
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_CFG_OFFSET,
+                DEVICE_CFG_SIZE,
+                &[],
+            ),
+            virtio_cap(
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                NOTIFY_CFG_OFFSET,
+                (num_queues as usize).max(1) * NOTIFY_OFF_MULTIPLIER,
+                &(NOTIFY_OFF_MULTIPLIER as u32).to_le_bytes(),
+            ),
+        ];
+        for cap in caps {
+            pci_config.add_capability(PCI_CAP_ID_VNDR, &cap);
+        }
+        let msix = MsixState::new(num_queues as usize);
+        let msix_cap = irq::msi_supported(vm_id).then(|| {
+            MsixCapability::add(
+                &mut pci_config,
+                msix.table.num_vectors(),
+                (0, MSIX_TABLE_OFFSET),
+                (0, MSIX_PBA_OFFSET),
+            )
+        });
+        *interrupts.msix.lock() = msix;
+
+        Self {
+            name: config.name.clone(),
+            mmio,
+            num_queues: num_queues as u16,
+            config: Mutex::new(pci_config),
+            msix_cap,
+            state: Mutex::new(PciTransportState {
+                device_feature_select: 0,
+                driver_feature_select: 0,
+                queue_select: 0,
+            }),
+            interrupts,
+        }
+    }
+
+    fn mmio_read(&self, vcpu_id: usize, offset: usize) -> u32 {
+        self.mmio
+            .handle_mmio_read(vcpu_id, GuestPhysAddr::from(offset), 4) as u32
+    }
+
+    fn mmio_write(&self, vcpu_id: usize, offset: usize, value: u32) {
+        self.mmio
+            .handle_mmio_write(vcpu_id, GuestPhysAddr::from(offset), 4, value as usize);
+    }
+
+    fn common_read(&self, vcpu_id: usize, offset: usize) -> usize {
+        let state = self.state.lock();
+        let queue = state.queue_select as usize;
+        let value = match offset {
+            COMMON_DEVICE_FEATURE_SELECT => state.device_feature_select,
+            COMMON_DEVICE_FEATURE => self.mmio_read(vcpu_id, VIRTIO_MMIO_DEVICE_FEATURES),
+            COMMON_DRIVER_FEATURE_SELECT => state.driver_feature_select,
+            COMMON_MSIX_CONFIG => self.interrupts.msix.lock().config_vector as u32,
+            COMMON_NUM_QUEUES => self.num_queues as u32,
+            COMMON_DEVICE_STATUS => self.mmio_read(vcpu_id, VIRTIO_MMIO_STATUS) & 0xff,
+            COMMON_CONFIG_GENERATION => {
+                self.mmio_read(vcpu_id, VIRTIO_MMIO_CONFIG_GENERATION) & 0xff
+            }
+            COMMON_QUEUE_SELECT => state.queue_select as u32,
+            COMMON_QUEUE_SIZE => self.mmio_read(vcpu_id, VIRTIO_MMIO_QUEUE_NUM),
+            COMMON_QUEUE_MSIX_VECTOR => {
+                self.interrupts
+                    .msix
+                    .lock()
+                    .queue_vectors
+                    .get(queue)
+                    .map_or(VIRTIO_MSI_NO_VECTOR, |vector| *vector) as u32
+            }
+            COMMON_QUEUE_ENABLE => self.mmio_read(vcpu_id, VIRTIO_MMIO_QUEUE_READY),
+            COMMON_QUEUE_NOTIFY_OFF => state.queue_select as u32,
+            // The driver features and the queue addresses are write-only for virtio-mmio.
+            _ => 0,
+        };
+        value as usize
+    }
+
+    fn common_write(&self, vcpu_id: usize, offset: usize, width: usize, value: usize) {
+        // The 64-bit fields may be written at once.
+        if width == 8 {
+            self.common_write(vcpu_id, offset, 4, value & 0xffff_ffff);
+            self.common_write(vcpu_id, offset + 4, 4, value >> 32);
+            return;
+        }
+        let value = value as u32;
+        let mut state = self.state.lock();
+        let mmio_offset = match offset {
+            COMMON_DEVICE_FEATURE_SELECT => {
+                state.device_feature_select = value;
+                VIRTIO_MMIO_DEVICE_FEATURES_SEL
+            }
+            COMMON_DRIVER_FEATURE_SELECT => {
+                state.driver_feature_select = value;
+                VIRTIO_MMIO_DRIVER_FEATURES_SEL
+            }
+            COMMON_DRIVER_FEATURE => VIRTIO_MMIO_DRIVER_FEATURES,
+            COMMON_MSIX_CONFIG => {
+                let mut msix = self.interrupts.msix.lock();
+                msix.config_vector = msix.checked(value as u16);
+                return;
+            }
+            COMMON_DEVICE_STATUS => {
+                if value & 0xff == 0 {
+                    let mut msix = self.interrupts.msix.lock();
+                    msix.config_vector = VIRTIO_MSI_NO_VECTOR;
+                    msix.queue_vectors.fill(VIRTIO_MSI_NO_VECTOR);
+                }
+                self.mmio_write(vcpu_id, VIRTIO_MMIO_STATUS, value & 0xff);
+                return;
+            }
+            COMMON_QUEUE_SELECT => {
+                state.queue_select = value as u16;
+                VIRTIO_MMIO_QUEUE_SEL
+            }
+            COMMON_QUEUE_SIZE => VIRTIO_MMIO_QUEUE_NUM,
+            COMMON_QUEUE_MSIX_VECTOR => {
+                let queue = state.queue_select as usize;
+                let mut msix = self.interrupts.msix.lock();
+                let vector = msix.checked(value as u16);
+                if let Some(queue_vector) = msix.queue_vectors.get_mut(queue) {
+                    *queue_vector = vector;
+                }
+                return;
+            }
+            COMMON_QUEUE_ENABLE => VIRTIO_MMIO_QUEUE_READY,
+            COMMON_QUEUE_DESC_LOW => VIRTIO_MMIO_QUEUE_DESC_LOW,
+            COMMON_QUEUE_DESC_HIGH => VIRTIO_MMIO_QUEUE_DESC_HIGH,
+            COMMON_QUEUE_DRIVER_LOW => VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+            COMMON_QUEUE_DRIVER_HIGH => VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+            COMMON_QUEUE_DEVICE_LOW => VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+            COMMON_QUEUE_DEVICE_HIGH => VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+            _ => return,
+        };
+        self.mmio_write(vcpu_id, mmio_offset, value);
+    }
+
+    /// Delivers the pending MSI-X vectors the guest unmasked.
+    fn deliver_unmasked(&self, mut msix: MutexGuard<MsixState>) {
+        let all_masked = msix.all_masked;
+        let messages = msix.table.take_unmasked(all_masked);
+        drop(msix);
+        for message in messages {
+            irq::deliver_msi(self.interrupts.vm_id, message);
+        }
+    }
+}
+
+impl PciFunction for VirtioPci {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn config_read(&self, offset: usize, width: usize) -> u32 {
+        self.config.lock().read(offset, width)
+    }
+
+    fn config_write(&self, offset: usize, width: usize, value: u32) {
+        let mut config = self.config.lock();
+        config.write(offset, width, value);
+        if let Some(cap) = &self.msix_cap {
+            let (enabled, all_masked) = cap.control(&config);
+            let mut msix = self.interrupts.msix.lock();
+            msix.enabled = enabled;
+            msix.all_masked = all_masked;
+            if enabled {
+                self.deliver_unmasked(msix);
+            }
+        }
+    }
+
+    fn bar_regions(&self) -> Vec<(usize, BarRegion)> {
+        self.config.lock().bar_regions()
+    }
+
+    fn bar_read(&self, vcpu_id: usize, _bar: usize, offset: usize, width: usize) -> usize {
+        match offset {
+            COMMON_CFG_OFFSET..ISR_CFG_OFFSET => self.common_read(vcpu_id, offset),
+            // Reading the ISR status acknowledges the interrupt.
+            ISR_CFG_OFFSET => {
+                let status = self.mmio_read(vcpu_id, VIRTIO_MMIO_INTERRUPT_STATUS);
+                self.mmio_write(vcpu_id, VIRTIO_MMIO_INTERRUPT_ACK, status);
+                status as usize
+            }
+            DEVICE_CFG_OFFSET..NOTIFY_CFG_OFFSET => self.mmio.handle_mmio_read(
+                vcpu_id,
+                GuestPhysAddr::from(VIRTIO_MMIO_CONFIG + offset - DEVICE_CFG_OFFSET),
+                width,
+            ),
+            MSIX_TABLE_OFFSET..MSIX_PBA_OFFSET if self.msix_cap.is_some() => {
+                let msix = self.interrupts.msix.lock();
+                msix.table.read(offset - MSIX_TABLE_OFFSET, width) as usize
+            }
+            MSIX_PBA_OFFSET.. if self.msix_cap.is_some() => {
+                let msix = self.interrupts.msix.lock();
+                msix.table.read_pba(offset - MSIX_PBA_OFFSET, width) as usize
+            }
+            _ => 0,
+        }
+    }
+
+    fn bar_write(&self, vcpu_id: usize, _bar: usize, offset: usize, width: usize, value: usize) {
+        match offset {
+            COMMON_CFG_OFFSET..ISR_CFG_OFFSET => self.common_write(vcpu_id, offset, width, value),
+            DEVICE_CFG_OFFSET..NOTIFY_CFG_OFFSET => self.mmio.handle_mmio_write(
+                vcpu_id,
+                GuestPhysAddr::from(VIRTIO_MMIO_CONFIG + offset - DEVICE_CFG_OFFSET),
+                width,
+                value,
+            ),
+            NOTIFY_CFG_OFFSET..MSIX_TABLE_OFFSET => {
+                let queue = (offset - NOTIFY_CFG_OFFSET) / NOTIFY_OFF_MULTIPLIER;
+                self.mmio_write(vcpu_id, VIRTIO_MMIO_QUEUE_NOTIFY, queue as u32);
+            }
+            MSIX_TABLE_OFFSET..MSIX_PBA_OFFSET if self.msix_cap.is_some() => {
+                let mut msix = self.interrupts.msix.lock();
+                msix.table
+                    .write(offset - MSIX_TABLE_OFFSET, width, value as u64);
+                self.deliver_unmasked(msix);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Raises the interrupts of `transport` through `interrupts`.
+fn wrap<D: VirtioDevice + 'static>(
+    transport: Arc<VirtioMmio<D>>,
+    interrupts: &Arc<PciInterrupts>,
+) -> Arc<dyn EmuDevice> {
+    transport.set_interrupt(interrupts.clone());
+    transport
+}
+
+/// Creates a virtio device behind the virtio-pci transport from its `emu_devices` entry,
+/// and adds it to the PCI bus of the VM.
+pub fn create_pci(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+    memory_regions: &[VmMemConfig],
+) -> AxResult {
+    let Some(emu_type) = EmuType::of(config).and_then(EmuType::virtio_mmio_variant) else {
+        return ax_err!(InvalidInput, "not a virtio-pci Emu-Type");
+    };
+    let mmio_config = EmulatedDeviceConfig {
+        base_gpa: 0,
+        length: VIRTIO_MMIO_CONFIG + DEVICE_CFG_SIZE,
+        emu_type: emu_type as usize as _,
+        ..config.clone()
+    };
+    let interrupts = Arc::new(PciInterrupts {
+        vm_id,
+        msix: Mutex::new(MsixState::new(0)),
+    });
+    let mmio = match emu_type {
+        EmuType::VirtioBlk => {
+            let device = blk::VirtioBlk::from_config(&mmio_config)?;
+            let transport = VirtioMmio::new(vm_id, &mmio_config, device)?;
+            wrap(Arc::new(transport), &interrupts)
+        }
+        EmuType::VirtioNet => wrap(net::create_net(vm_id, &mmio_config)?, &interrupts),
+        EmuType::VirtioConsole => wrap(console::create_console(vm_id, &mmio_config)?, &interrupts),
+        EmuType::VirtioVsock => wrap(vsock::create_vsock(vm_id, &mmio_config)?, &interrupts),
+        EmuType::VirtioRng => {
+            let device = rng::VirtioRng::from_config(&mmio_config)?;
+            let transport = VirtioMmio::new(vm_id, &mmio_config, device)?;
+            wrap(Arc::new(transport), &interrupts)
+        }
+        EmuType::VirtioBalloon => wrap(
+            balloon::create_balloon(vm_id, &mmio_config, memory_regions)?,
+            &interrupts,
+        ),
+        _ => return ax_err!(InvalidInput, "not a virtio-pci Emu-Type"),
+    };
+    let device = devices::pci::device_number(config)?;
+    let function = Arc::new(VirtioPci::new(vm_id, config, mmio, interrupts));
+    devices::pci::add_function(vm_id, device, function)?;
+    Ok(())
+}
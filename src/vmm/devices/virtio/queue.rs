@@ -0,0 +1,246 @@
+//! Split virtqueues, as laid out in the guest memory by the driver.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{Ordering, fence};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+
+use crate::vmm::devices::GuestMemory;
+
+/// The descriptor continues through its `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer of the descriptor is write-only for the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// The maximum size of the readable buffers gathered by [`DescChain::read_all`], which bounds
+/// the memory allocated for a request whose buffer sizes are chosen by the guest.
+pub const MAX_READ_LEN: usize = 1 << 20;
+
+/// A descriptor of the descriptor table.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// An element of the used ring.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Descriptor {
+    pub addr: GuestPhysAddr,
+    pub len: u32,
+    /// Whether the buffer is written by the device, read otherwise.
+    pub writable: bool,
+}
+
+/// A chain of descriptors made available by the driver, i.e. a request to the device.
+pub struct DescChain {
+    /// The index of the head descriptor, identifying the chain in the used ring.
+    pub head: u16,
+    pub descs: Vec<Descriptor>,
+}
+
+impl DescChain {
+    /// Returns the buffers read by the device, which precede the written ones.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|desc| !desc.writable)
+    }
+
+    /// Returns the buffers written by the device.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|desc| desc.writable)
+    }
+
+    /// Returns the total size of the buffers written by the device.
+    pub fn writable_len(&self) -> usize {
+        self.writable().map(|desc| desc.len as usize).sum()
+    }
+
+    /// Returns the total size of the buffers read by the device.
+    pub fn readable_len(&self) -> usize {
+        self.readable().map(|desc| desc.len as usize).sum()
+    }
+
+    /// Returns the guest memory segments holding the bytes `skip..skip + len` of the readable
+    /// buffers, as if they were contiguous.
+    pub fn readable_segments(
+        &self,
+        skip: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (GuestPhysAddr, usize)> + '_ {
+        segments(self.readable(), skip, len)
+    }
+
+    /// Returns the guest memory segments holding the bytes `skip..skip + len` of the writable
+    /// buffers, as if they were contiguous.
+    pub fn writable_segments(
+        &self,
+        skip: usize,
+        len: usize,
+    ) -> impl Iterator<Item = (GuestPhysAddr, usize)> + '_ {
+        segments(self.writable(), skip, len)
+    }
+
+    /// Gathers the contents of all the readable buffers.
+    ///
+    /// Fails if they hold more than [`MAX_READ_LEN`] bytes.
+    pub fn read_all(&self, mem: &GuestMemory) -> AxResult<Vec<u8>> {
+        if self.readable_len() > MAX_READ_LEN {
+            return ax_err!(InvalidData, "virtqueue descriptor chain too large");
+        }
+        let mut data = Vec::new();
+        for desc in self.readable() {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            mem.read(desc.addr, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Scatters `data` into the writable buffers, as much as they can hold.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_all(&self, mem: &GuestMemory, data: &[u8]) -> AxResult<usize> {
+        let mut pos = 0;
+        for desc in self.writable() {
+            if pos == data.len() {
+                break;
+            }
+            let len = (desc.len as usize).min(data.len() - pos);
+            mem.write(desc.addr, &data[pos..pos + len])?;
+            pos += len;
+        }
+        Ok(pos)
+    }
+}
+
+/// Returns the guest memory segments holding the bytes `skip..skip + len` of the buffers of
+/// `descs`, as if they were contiguous.
+fn segments<'a>(
+    descs: impl Iterator<Item = &'a Descriptor> + 'a,
+    skip: usize,
+    len: usize,
+) -> impl Iterator<Item = (GuestPhysAddr, usize)> + 'a {
+    let end = skip.saturating_add(len);
+    let mut pos = 0;
+    descs.filter_map(move |desc| {
+        let desc_start = pos;
+        pos += desc.len as usize;
+        let start = desc_start.max(skip);
+        let stop = pos.min(end);
+        (start < stop).then(|| (desc.addr + (start - desc_start), stop - start))
+    })
+}
+
+/// The state of a split virtqueue, as configured by the driver through the transport.
+pub struct Virtqueue {
+    /// The maximum number of descriptors supported by the device.
+    pub max_size: u16,
+    /// The number of descriptors chosen by the driver.
+    pub size: u16,
+    pub ready: bool,
+    /// The guest physical addresses of the descriptor table, the available (driver) ring and
+    /// the used (device) ring.
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    /// The next index of the available ring to be processed.
+    last_avail_idx: u16,
+    /// The next index of the used ring to be filled.
+    used_idx: u16,
+}
+
+impl Virtqueue {
+    pub fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            last_avail_idx: 0,
+            used_idx: 0,
+        }
+    }
+
+    /// Resets the queue to its initial state, on a device reset.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    fn avail_idx(&self, mem: &GuestMemory) -> AxResult<u16> {
+        mem.read_obj(GuestPhysAddr::from(self.avail_ring as usize + 2))
+    }
+
+    /// Takes the next chain made available by the driver, if any.
+    pub fn pop(&mut self, mem: &GuestMemory) -> AxResult<Option<DescChain>> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        if self.avail_idx(mem)? == self.last_avail_idx {
+            return Ok(None);
+        }
+        // Read the ring entry only after having seen the index.
+        fence(Ordering::Acquire);
+        let slot = (self.last_avail_idx % self.size) as usize;
+        let head: u16 =
+            mem.read_obj(GuestPhysAddr::from(self.avail_ring as usize + 4 + slot * 2))?;
+        self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+
+        let mut descs = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.size || descs.len() >= self.size as usize {
+                return ax_err!(InvalidData, "malformed virtqueue descriptor chain");
+            }
+            let desc: VirtqDesc = mem.read_obj(GuestPhysAddr::from(
+                self.desc_table as usize + index as usize * size_of::<VirtqDesc>(),
+            ))?;
+            descs.push(Descriptor {
+                addr: GuestPhysAddr::from(desc.addr as usize),
+                len: desc.len,
+                writable: desc.flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = desc.next;
+        }
+        Ok(Some(DescChain { head, descs }))
+    }
+
+    /// Returns a chain to the driver, `len` being the number of bytes written into it.
+    pub fn push_used(&mut self, mem: &GuestMemory, head: u16, len: u32) -> AxResult {
+        let slot = (self.used_idx % self.size) as usize;
+        let elem = VirtqUsedElem {
+            id: head as u32,
+            len,
+        };
+        mem.write_obj(
+            GuestPhysAddr::from(self.used_ring as usize + 4 + slot * size_of::<VirtqUsedElem>()),
+            &elem,
+        )?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // Publish the element before the index.
+        fence(Ordering::Release);
+        mem.write_obj(
+            GuestPhysAddr::from(self.used_ring as usize + 2),
+            &self.used_idx,
+        )
+    }
+}
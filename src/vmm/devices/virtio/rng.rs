@@ -0,0 +1,219 @@
+//! virtio-rng, an entropy source for the guests.
+//!
+//! The random bytes come from a ChaCha20 generator of each device, seeded from the hardware
+//! random number generator of the host if it has one (`RDRAND` on x86_64, `RNDR` on aarch64),
+//! mixed with the timer. The `EmuConfig` of the `emu_devices` entry may be `[seed]`, to seed
+//! the generator deterministically instead, e.g. to reproduce a guest boot.
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::Mutex;
+use std::os::arceos::modules::axhal;
+
+use super::{MAX_READ_LEN, VirtioDevice, Virtqueue};
+use crate::vmm::devices::GuestMemory;
+
+/// The virtio device ID of an entropy source.
+const VIRTIO_ID_RNG: u32 = 4;
+
+/// A ChaCha20 keystream, used as a CSPRNG.
+struct ChaCha20 {
+    key: [u32; 8],
+    counter: u64,
+    block: [u8; 64],
+    /// The bytes of `block` already used.
+    used: usize,
+}
+
+impl ChaCha20 {
+    fn new(seed: [u64; 4]) -> Self {
+        let mut key = [0u32; 8];
+        for (i, word) in seed.iter().enumerate() {
+            key[2 * i] = *word as u32;
+            key[2 * i + 1] = (*word >> 32) as u32;
+        }
+        Self {
+            key,
+            counter: 0,
+            block: [0; 64],
+            used: 64,
+        }
+    }
+
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    fn refill(&mut self) {
+        // "expand 32-byte k", the key, the block counter and a zero nonce.
+        let mut input = [0u32; 16];
+        input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut state = input;
+        for _ in 0..10 {
+            Self::quarter_round(&mut state, 0, 4, 8, 12);
+            Self::quarter_round(&mut state, 1, 5, 9, 13);
+            Self::quarter_round(&mut state, 2, 6, 10, 14);
+            Self::quarter_round(&mut state, 3, 7, 11, 15);
+            Self::quarter_round(&mut state, 0, 5, 10, 15);
+            Self::quarter_round(&mut state, 1, 6, 11, 12);
+            Self::quarter_round(&mut state, 2, 7, 8, 13);
+            Self::quarter_round(&mut state, 3, 4, 9, 14);
+        }
+        for (i, word) in state.iter().enumerate() {
+            let word = word.wrapping_add(input[i]);
+            self.block[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        self.used = 0;
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        let mut pos = 0;
+        while pos < buf.len() {
+            if self.used == self.block.len() {
+                self.refill();
+            }
+            let len = (self.block.len() - self.used).min(buf.len() - pos);
+            buf[pos..pos + len].copy_from_slice(&self.block[self.used..self.used + len]);
+            self.used += len;
+            pos += len;
+        }
+    }
+}
+
+/// Returns a random number from the hardware of the host, if it has a generator.
+#[cfg(target_arch = "x86_64")]
+fn hw_random() -> Option<u64> {
+    use core::arch::x86_64::{__cpuid, _rdrand64_step};
+
+    #[target_feature(enable = "rdrand")]
+    unsafe fn rdrand() -> Option<u64> {
+        let mut value = 0;
+        // The generator may transiently run out of entropy.
+        for _ in 0..10 {
+            if unsafe { _rdrand64_step(&mut value) } == 1 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    // CPUID.01H:ECX.RDRAND[bit 30].
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) == 0 {
+        return None;
+    }
+    unsafe { rdrand() }
+}
+
+/// Returns a random number from the hardware of the host, if it has a generator.
+#[cfg(target_arch = "aarch64")]
+fn hw_random() -> Option<u64> {
+    use core::arch::asm;
+
+    let isar0: u64;
+    unsafe { asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0) };
+    // ID_AA64ISAR0_EL1.RNDR, bits [63:60].
+    if isar0 >> 60 == 0 {
+        return None;
+    }
+    let value: u64;
+    let failed: u64;
+    // RNDR is s3_3_c2_c4_0, and sets NZCV to 0b0100 on failure.
+    unsafe {
+        asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "cset {failed}, eq",
+            value = out(reg) value,
+            failed = out(reg) failed,
+        )
+    };
+    (failed == 0).then_some(value)
+}
+
+/// Returns a random number from the hardware of the host, if it has a generator.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn hw_random() -> Option<u64> {
+    None
+}
+
+/// Returns a seed from the host, mixing its hardware generator, if any, with the timer.
+fn host_seed() -> [u64; 4] {
+    let mut seed = [0u64; 4];
+    let mut hw_available = false;
+    for word in seed.iter_mut() {
+        let hw = hw_random();
+        hw_available |= hw.is_some();
+        *word = hw.unwrap_or(0) ^ axhal::time::monotonic_time_nanos().rotate_left(17);
+    }
+    if !hw_available {
+        warn!("The host has no hardware random number generator, virtio-rng seeded from time");
+    }
+    seed
+}
+
+/// A virtio-rng device model.
+pub struct VirtioRng {
+    rng: Mutex<ChaCha20>,
+}
+
+impl VirtioRng {
+    pub fn from_config(config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let seed = match config.cfg_list.as_slice() {
+            [] => host_seed(),
+            [seed] => [*seed as u64, 0, 0, 0],
+            _ => return ax_err!(InvalidInput, "virtio-rng EmuConfig must be [seed]"),
+        };
+        Ok(Self {
+            rng: Mutex::new(ChaCha20::new(seed)),
+        })
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    /// The device has no configuration space.
+    fn read_config(&self, _offset: usize, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn process_queue(
+        &self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(mem)? {
+            // Bounded like the requests read by the devices, the rest of the buffers being left
+            // unused.
+            let mut data = vec![0; chain.writable_len().min(MAX_READ_LEN)];
+            self.rng.lock().fill(&mut data);
+            let written = chain.write_all(mem, &data)?;
+            queue.push_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
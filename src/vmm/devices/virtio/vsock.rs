@@ -0,0 +1,455 @@
+//! virtio-vsock, stream sockets between a guest and the [hypervisor](crate::vmm::vsock).
+//!
+//! The `EmuConfig` of the `emu_devices` entry is `[cid]`, the CID of the guest, which is the
+//! VM ID plus 3 by default, CIDs 0 to 2 being reserved. Only the connections with the
+//! hypervisor, CID 2, are supported: the packets to other CIDs are reset.
+
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use spin::{Mutex, Once};
+
+use super::{VirtioDevice, VirtioMmio, Virtqueue};
+use crate::vmm::devices::GuestMemory;
+use crate::vmm::vsock::{self, HOST_CID, VsockConn, VsockEndpoint, VsockHandler};
+
+/// The virtio device ID of a socket device.
+const VIRTIO_ID_VSOCK: u32 = 19;
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The peer will neither receive nor send any more data.
+const VIRTIO_VSOCK_SHUTDOWN_BOTH: u32 = 3;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+/// The receive buffer advertised for each connection on the hypervisor side, whose data is
+/// consumed as soon as it is received.
+const HOST_BUF_ALLOC: u32 = 0x1_0000;
+/// The largest payload of the packets sent to the guest, which fits its receive buffers.
+const MAX_PAYLOAD: usize = 4096 - size_of::<VsockHdr>();
+
+/// The header of a packet.
+#[derive(Clone, Copy, Default)]
+#[repr(C, packed)]
+struct VsockHdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+/// A connection with the hypervisor.
+struct Conn {
+    handler: Arc<dyn VsockHandler>,
+    /// Whether the connection, requested by the hypervisor, awaits the response of the guest.
+    connecting: bool,
+    /// The receive buffer of the guest, and the data it has consumed from it.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// The data sent to the guest.
+    tx_cnt: u32,
+    /// The data received from the guest, and the part of it reported to the guest.
+    fwd_cnt: u32,
+    reported_fwd_cnt: u32,
+    /// The data to be sent once the guest has room for it.
+    tx_pending: VecDeque<u8>,
+}
+
+impl Conn {
+    fn new(handler: Arc<dyn VsockHandler>, connecting: bool) -> Self {
+        Self {
+            handler,
+            connecting,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            tx_pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the room left in the receive buffer of the guest.
+    fn peer_credit(&self) -> usize {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt)) as usize
+    }
+}
+
+/// A callback to the service of a connection, invoked once the device state is unlocked.
+enum Event {
+    Connected(Arc<dyn VsockHandler>, VsockConn),
+    Received(Arc<dyn VsockHandler>, VsockConn, Vec<u8>),
+    Closed(Arc<dyn VsockHandler>, VsockConn),
+}
+
+impl Event {
+    fn dispatch(self) {
+        match self {
+            Event::Connected(handler, conn) => handler.connected(&conn),
+            Event::Received(handler, conn, data) => handler.received(&conn, &data),
+            Event::Closed(handler, conn) => handler.closed(&conn),
+        }
+    }
+}
+
+#[derive(Default)]
+struct VsockState {
+    /// The connections, keyed by their guest and hypervisor ports.
+    conns: BTreeMap<(u32, u32), Conn>,
+    /// The packets not yet received by the driver.
+    rx_packets: VecDeque<Vec<u8>>,
+}
+
+/// A virtio-vsock device model.
+pub struct VirtioVsock {
+    vm_id: usize,
+    guest_cid: u64,
+    state: Mutex<VsockState>,
+    /// The transport of the device, to notify the receive virtqueue.
+    transport: Once<Weak<VirtioMmio<VirtioVsock>>>,
+}
+
+impl VirtioVsock {
+    fn from_config(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let guest_cid = match config.cfg_list.as_slice() {
+            [] => vm_id as u64 + 3,
+            [cid] if *cid as u64 > HOST_CID => *cid as u64,
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "virtio-vsock EmuConfig must be [cid], cid > 2"
+                );
+            }
+        };
+        Ok(Self {
+            vm_id,
+            guest_cid,
+            state: Mutex::new(VsockState::default()),
+            transport: Once::new(),
+        })
+    }
+
+    fn conn(&self, guest_port: u32, host_port: u32) -> VsockConn {
+        VsockConn {
+            vm_id: self.vm_id,
+            host_port,
+            guest_port,
+        }
+    }
+
+    /// Queues a packet from the hypervisor to the guest.
+    fn queue_packet(
+        &self,
+        state: &mut VsockState,
+        (guest_port, host_port): (u32, u32),
+        op: u16,
+        flags: u32,
+        payload: &[u8],
+    ) {
+        let fwd_cnt = match state.conns.get_mut(&(guest_port, host_port)) {
+            Some(conn) => {
+                conn.reported_fwd_cnt = conn.fwd_cnt;
+                conn.fwd_cnt
+            }
+            None => 0,
+        };
+        let hdr = VsockHdr {
+            src_cid: HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: host_port,
+            dst_port: guest_port,
+            len: payload.len() as u32,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: HOST_BUF_ALLOC,
+            fwd_cnt,
+        };
+        // SAFETY: the header is a packed plain struct, viewed as its bytes.
+        let hdr_bytes = unsafe {
+            core::slice::from_raw_parts(
+                (&hdr as *const VsockHdr).cast::<u8>(),
+                size_of::<VsockHdr>(),
+            )
+        };
+        let mut packet = Vec::with_capacity(hdr_bytes.len() + payload.len());
+        packet.extend_from_slice(hdr_bytes);
+        packet.extend_from_slice(payload);
+        state.rx_packets.push_back(packet);
+    }
+
+    /// Sends as much of the pending data of a connection as the guest has room for.
+    fn flush_conn(&self, state: &mut VsockState, key: (u32, u32)) {
+        loop {
+            let Some(conn) = state.conns.get_mut(&key) else {
+                return;
+            };
+            let len = conn
+                .peer_credit()
+                .min(conn.tx_pending.len())
+                .min(MAX_PAYLOAD);
+            if conn.connecting || len == 0 {
+                return;
+            }
+            let data: Vec<u8> = conn.tx_pending.drain(..len).collect();
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+            self.queue_packet(state, key, VIRTIO_VSOCK_OP_RW, 0, &data);
+        }
+    }
+
+    fn notify_rx(&self) {
+        if let Some(transport) = self.transport.get().and_then(Weak::upgrade) {
+            transport.notify_queue(RX_QUEUE);
+        }
+    }
+
+    /// Handles a packet from the guest, returning the callbacks to invoke.
+    fn handle_packet(&self, state: &mut VsockState, hdr: &VsockHdr, payload: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        let key = (hdr.src_port, hdr.dst_port);
+        let conn = self.conn(hdr.src_port, hdr.dst_port);
+        let (op, dst_cid, type_) = (hdr.op, hdr.dst_cid, hdr.type_);
+        if dst_cid != HOST_CID || type_ != VIRTIO_VSOCK_TYPE_STREAM {
+            if op != VIRTIO_VSOCK_OP_RST {
+                self.queue_packet(state, key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+            }
+            return events;
+        }
+
+        if op == VIRTIO_VSOCK_OP_REQUEST {
+            match vsock::listener(hdr.dst_port) {
+                Some(handler) if !state.conns.contains_key(&key) => {
+                    let mut new_conn = Conn::new(handler.clone(), false);
+                    new_conn.peer_buf_alloc = hdr.buf_alloc;
+                    new_conn.peer_fwd_cnt = hdr.fwd_cnt;
+                    state.conns.insert(key, new_conn);
+                    self.queue_packet(state, key, VIRTIO_VSOCK_OP_RESPONSE, 0, &[]);
+                    events.push(Event::Connected(handler, conn));
+                }
+                _ => self.queue_packet(state, key, VIRTIO_VSOCK_OP_RST, 0, &[]),
+            }
+            return events;
+        }
+
+        let Some(entry) = state.conns.get_mut(&key) else {
+            if op != VIRTIO_VSOCK_OP_RST {
+                self.queue_packet(state, key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+            }
+            return events;
+        };
+        entry.peer_buf_alloc = hdr.buf_alloc;
+        entry.peer_fwd_cnt = hdr.fwd_cnt;
+        let handler = entry.handler.clone();
+        match op {
+            VIRTIO_VSOCK_OP_RESPONSE if entry.connecting => {
+                entry.connecting = false;
+                events.push(Event::Connected(handler, conn));
+            }
+            VIRTIO_VSOCK_OP_RW => {
+                entry.fwd_cnt = entry.fwd_cnt.wrapping_add(payload.len() as u32);
+                let unreported = entry.fwd_cnt.wrapping_sub(entry.reported_fwd_cnt);
+                if unreported >= HOST_BUF_ALLOC / 2 {
+                    self.queue_packet(state, key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+                }
+                events.push(Event::Received(handler, conn, payload.to_vec()));
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                self.queue_packet(state, key, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, &[]);
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN | VIRTIO_VSOCK_OP_RST => {
+                state.conns.remove(&key);
+                if op == VIRTIO_VSOCK_OP_SHUTDOWN {
+                    self.queue_packet(state, key, VIRTIO_VSOCK_OP_RST, 0, &[]);
+                }
+                events.push(Event::Closed(handler, conn));
+                return events;
+            }
+            _ => {}
+        }
+        // The guest may have made room for the pending data.
+        self.flush_conn(state, key);
+        events
+    }
+
+    /// Fills the buffers of the receive virtqueue with the pending packets.
+    fn fill_rx(&self, queue: &mut Virtqueue, mem: &GuestMemory) -> AxResult<bool> {
+        let mut used = false;
+        loop {
+            let mut state = self.state.lock();
+            if state.rx_packets.is_empty() {
+                break;
+            }
+            let Some(chain) = queue.pop(mem)? else {
+                break;
+            };
+            let packet = state.rx_packets.pop_front().unwrap_or_default();
+            drop(state);
+            if chain.writable_len() < packet.len() {
+                warn!(
+                    "VM[{}] virtio-vsock receive buffer too small, packet dropped",
+                    self.vm_id
+                );
+            }
+            let written = chain.write_all(mem, &packet)?;
+            queue.push_used(mem, chain.head, written as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// The receive, transmit and event virtqueues.
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    /// The configuration space holds the CID of the guest.
+    fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let config = self.guest_cid.to_le_bytes();
+        let end = (offset + data.len()).min(config.len());
+        if offset < end {
+            data[..end - offset].copy_from_slice(&config[offset..end]);
+        }
+    }
+
+    fn process_queue(
+        &self,
+        index: usize,
+        queue: &mut Virtqueue,
+        mem: &GuestMemory,
+    ) -> AxResult<bool> {
+        match index {
+            RX_QUEUE => self.fill_rx(queue, mem),
+            TX_QUEUE => {
+                let mut used = false;
+                let mut events = Vec::new();
+                while let Some(chain) = queue.pop(mem)? {
+                    let packet = chain.read_all(mem)?;
+                    queue.push_used(mem, chain.head, 0)?;
+                    used = true;
+                    if packet.len() < size_of::<VsockHdr>() {
+                        continue;
+                    }
+                    // SAFETY: the header is a packed plain struct, read from its bytes.
+                    let hdr = unsafe { packet.as_ptr().cast::<VsockHdr>().read_unaligned() };
+                    let payload_len = (hdr.len as usize).min(packet.len() - size_of::<VsockHdr>());
+                    let payload = &packet[size_of::<VsockHdr>()..][..payload_len];
+                    events.extend(self.handle_packet(&mut self.state.lock(), &hdr, payload));
+                }
+                self.notify_rx();
+                for event in events {
+                    event.dispatch();
+                }
+                Ok(used)
+            }
+            // The event virtqueue only carries transport resets, never sent.
+            _ => Ok(false),
+        }
+    }
+
+    fn reset(&self) {
+        let conns = core::mem::take(&mut self.state.lock().conns);
+        self.state.lock().rx_packets.clear();
+        for ((guest_port, host_port), conn) in conns {
+            Event::Closed(conn.handler, self.conn(guest_port, host_port)).dispatch();
+        }
+    }
+}
+
+impl VsockEndpoint for VirtioMmio<VirtioVsock> {
+    fn send(&self, conn: &VsockConn, data: &[u8]) -> AxResult {
+        let device = self.device();
+        {
+            let mut state = device.state.lock();
+            let key = (conn.guest_port, conn.host_port);
+            let Some(entry) = state.conns.get_mut(&key) else {
+                return ax_err!(NotConnected, "vsock connection closed");
+            };
+            entry.tx_pending.extend(data);
+            device.flush_conn(&mut state, key);
+        }
+        self.notify_queue(RX_QUEUE);
+        Ok(())
+    }
+
+    fn close(&self, conn: &VsockConn) {
+        let device = self.device();
+        {
+            let mut state = device.state.lock();
+            let key = (conn.guest_port, conn.host_port);
+            if state.conns.remove(&key).is_none() {
+                return;
+            }
+            device.queue_packet(
+                &mut state,
+                key,
+                VIRTIO_VSOCK_OP_SHUTDOWN,
+                VIRTIO_VSOCK_SHUTDOWN_BOTH,
+                &[],
+            );
+        }
+        self.notify_queue(RX_QUEUE);
+    }
+
+    fn connect(&self, conn: &VsockConn, handler: Arc<dyn VsockHandler>) -> AxResult {
+        let device = self.device();
+        {
+            let mut state = device.state.lock();
+            let key = (conn.guest_port, conn.host_port);
+            if state.conns.contains_key(&key) {
+                return ax_err!(AlreadyExists, "vsock connection already exists");
+            }
+            state.conns.insert(key, Conn::new(handler, true));
+            device.queue_packet(&mut state, key, VIRTIO_VSOCK_OP_REQUEST, 0, &[]);
+        }
+        self.notify_queue(RX_QUEUE);
+        Ok(())
+    }
+}
+
+/// Creates a virtio-vsock device from its `emu_devices` entry.
+pub fn create_vsock(
+    vm_id: usize,
+    config: &EmulatedDeviceConfig,
+) -> AxResult<Arc<VirtioMmio<VirtioVsock>>> {
+    let device = VirtioVsock::from_config(vm_id, config)?;
+    let transport = Arc::new(VirtioMmio::new(vm_id, config, device)?);
+    let device = transport.device();
+    device.transport.call_once(|| Arc::downgrade(&transport));
+    let endpoint: Weak<dyn VsockEndpoint> = Arc::downgrade(&transport) as _;
+    vsock::register_endpoint(vm_id, endpoint)?;
+    info!("VM[{}] virtio-vsock CID {}", vm_id, device.guest_cid);
+    Ok(transport)
+}
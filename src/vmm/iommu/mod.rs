@@ -0,0 +1,156 @@
+//! The IOMMU of the host, isolating the DMA of the PCI functions passed through to the VMs.
+//!
+//! The IOMMU is found at `iommu-paddr` in the platform config, 0 if there is none: an SMMUv3
+//! on aarch64, a VT-d remapping unit on x86_64, or a RISC-V IOMMU on riscv64. The functions of
+//! the host keep bypassing translation, while a function attached to a VM translates its DMA
+//! through the stage-2 page table of the VM itself, so that it only reaches the memory of the
+//! VM, at the guest physical addresses the guest programs into it.
+//!
+//! A function is identified by its PCI requester ID, `bus << 8 | device << 3 | function`,
+//! which is also its stream or device ID, as on the QEMU machines.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+#[cfg(target_arch = "riscv64")]
+mod riscv;
+#[cfg(target_arch = "aarch64")]
+mod smmuv3;
+#[cfg(target_arch = "x86_64")]
+mod vtd;
+
+use std::os::arceos::modules::{axalloc, axconfig, axhal};
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err, ax_err_type};
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+use spin::{Mutex, Once};
+
+use crate::vmm::vm_list;
+#[cfg(target_arch = "riscv64")]
+use riscv::RiscvIommu as Iommu;
+#[cfg(target_arch = "aarch64")]
+use smmuv3::SmmuV3 as Iommu;
+#[cfg(target_arch = "x86_64")]
+use vtd::Vtd as Iommu;
+
+/// The number of requester IDs of the buses of the host.
+fn num_requester_ids() -> usize {
+    (axconfig::devices::PCI_BUS_END + 1) * 256
+}
+
+/// A zeroed table of the IOMMU, in pages owned until the hypervisor stops.
+struct Table {
+    vaddr: usize,
+    paddr: HostPhysAddr,
+}
+
+impl Table {
+    fn alloc(pages: usize) -> AxResult<Self> {
+        let vaddr = axalloc::global_allocator()
+            .alloc_pages(pages, PAGE_SIZE_4K)
+            .map_err(|_| ax_err_type!(NoMemory, "no memory for the IOMMU tables"))?;
+        // SAFETY: the pages have just been allocated.
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, pages * PAGE_SIZE_4K) };
+        Ok(Self {
+            vaddr,
+            paddr: axhal::mem::virt_to_phys(vaddr.into()),
+        })
+    }
+
+    /// Writes the 64-bit word `index` of the table.
+    fn write(&self, index: usize, value: u64) {
+        // SAFETY: the caller stays within the pages of the table.
+        unsafe { (self.vaddr as *mut u64).add(index).write_volatile(value) }
+    }
+}
+
+/// The IOMMU of the host, if it has one.
+static IOMMU: Once<Option<Mutex<Iommu>>> = Once::new();
+
+/// The requester IDs of the functions attached to the VMs, stored in a BTreeMap where the key
+/// is the VM ID.
+static VM_REQUESTER_IDS: Mutex<BTreeMap<usize, Vec<u16>>> = Mutex::new(BTreeMap::new());
+
+/// Initializes the IOMMU of the host, before the VMs are created.
+pub fn init() {
+    IOMMU.call_once(|| {
+        let paddr = axconfig::devices::IOMMU_PADDR;
+        if paddr == 0 {
+            return None;
+        }
+        let base = axhal::mem::phys_to_virt(PhysAddr::from(paddr)).as_usize();
+        match Iommu::new(base) {
+            Ok(iommu) => {
+                info!("IOMMU at {:#x} enabled", paddr);
+                Some(Mutex::new(iommu))
+            }
+            Err(err) => {
+                warn!("IOMMU at {:#x} not enabled: {:?}", paddr, err);
+                None
+            }
+        }
+    });
+}
+
+fn iommu() -> Option<&'static Mutex<Iommu>> {
+    IOMMU.get().and_then(|iommu| iommu.as_ref())
+}
+
+/// Returns whether the host has an IOMMU.
+pub fn is_present() -> bool {
+    iommu().is_some()
+}
+
+/// Translates the DMA of the function `requester_id` through the stage-2 page table of
+/// `vm_id`.
+pub fn attach_device(vm_id: usize, requester_id: u16) -> AxResult {
+    let Some(iommu) = iommu() else {
+        return ax_err!(Unsupported, "no IOMMU");
+    };
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        return ax_err!(NotFound, "VM not found");
+    };
+    if requester_id as usize >= num_requester_ids() {
+        return ax_err!(InvalidInput, "PCI requester ID out of the host buses");
+    }
+    iommu.lock().attach(requester_id, vm_id, vm.ept_root())?;
+    VM_REQUESTER_IDS
+        .lock()
+        .entry(vm_id)
+        .or_default()
+        .push(requester_id);
+    debug!(
+        "IOMMU: requester {:#06x} attached to VM[{}]",
+        requester_id, vm_id
+    );
+    Ok(())
+}
+
+/// Blocks the DMA of the functions attached to `vm_id`, called when the VM is torn down,
+/// before its memory is freed.
+pub fn detach_vm_devices(vm_id: usize) {
+    let Some(requester_ids) = VM_REQUESTER_IDS.lock().remove(&vm_id) else {
+        return;
+    };
+    if let Some(iommu) = iommu() {
+        let mut iommu = iommu.lock();
+        for requester_id in requester_ids {
+            iommu.detach(requester_id);
+            debug!(
+                "IOMMU: requester {:#06x} detached from VM[{}]",
+                requester_id, vm_id
+            );
+        }
+    }
+}
+
+/// Invalidates the translations of `vm_id` cached by the IOMMU, after mappings have been
+/// removed from its stage-2 page table.
+pub fn flush_vm(vm_id: usize) {
+    if let Some(iommu) = iommu() {
+        iommu.lock().flush(vm_id);
+    }
+}
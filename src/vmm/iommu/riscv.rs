@@ -0,0 +1,216 @@
+//! A RISC-V IOMMU, with a three-level device directory and a command queue, in G-stage only.
+//!
+//! The G-stage translation of a device shares the page table of the VM, in the Sv39x4 mode of
+//! the `hgatp` of its vCPUs. The functions of the host are in bare mode.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{Ordering, fence};
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+
+use super::{Table, num_requester_ids};
+
+const IOMMU_CAPABILITIES: usize = 0x00;
+const IOMMU_DDTP: usize = 0x10;
+const IOMMU_CQB: usize = 0x18;
+const IOMMU_CQH: usize = 0x20;
+const IOMMU_CQT: usize = 0x24;
+const IOMMU_CQCSR: usize = 0x48;
+
+const CAPABILITIES_SV39X4: u64 = 1 << 17;
+/// The device contexts have the extended format, twice as large.
+const CAPABILITIES_MSI_FLAT: u64 = 1 << 22;
+
+const DDTP_MODE_3LVL: u64 = 4;
+const DDTP_BUSY: u64 = 1 << 4;
+const PPN_SHIFT: u64 = 10;
+
+/// The command queue has 256 commands of 16 bytes, in a page.
+const CQ_LOG2SZ: u64 = 8;
+const CQCSR_CQEN: u32 = 1 << 0;
+const CQCSR_ERRORS: u32 = (1 << 8) | (1 << 9) | (1 << 10);
+const CQCSR_CQON: u32 = 1 << 16;
+
+const CMD_IOTINVAL_GVMA: u64 = 1 | (1 << 7);
+const CMD_IOFENCE_C: u64 = 2;
+const CMD_IODIR_INVAL_DDT: u64 = 3;
+/// The GSCID field of `IOTINVAL.GVMA` is valid.
+const CMD_GV: u64 = 1 << 33;
+/// The device ID field of `IODIR.INVAL_DDT` is valid.
+const CMD_DV: u64 = 1 << 33;
+
+const DDT_VALID: u64 = 1 << 0;
+/// A non-leaf directory page has 512 entries.
+const DDT_NON_LEAF_ENTRIES: usize = 512;
+const DC_TC_VALID: u64 = 1 << 0;
+const IOHGATP_MODE_SV39X4: u64 = 8 << 60;
+const IOHGATP_GSCID_SHIFT: u64 = 44;
+
+fn read_reg(base: usize, offset: usize) -> u64 {
+    // SAFETY: the registers of the IOMMU are mapped by the host as device memory.
+    unsafe { ((base + offset) as *const u64).read_volatile() }
+}
+
+fn ppn(paddr: usize) -> u64 {
+    (paddr / PAGE_SIZE_4K) as u64
+}
+
+/// A RISC-V IOMMU.
+pub struct RiscvIommu {
+    base: usize,
+    /// The device contexts of all the requester IDs, in leaf directory pages.
+    contexts: Table,
+    /// The number of 64-bit words of a device context.
+    context_words: usize,
+    cq: Table,
+    cq_tail: u32,
+}
+
+impl RiscvIommu {
+    pub fn new(base: usize) -> AxResult<Self> {
+        let capabilities = read_reg(base, IOMMU_CAPABILITIES);
+        if capabilities & CAPABILITIES_SV39X4 == 0 {
+            return ax_err!(Unsupported, "IOMMU without Sv39x4");
+        }
+        if read_reg(base, IOMMU_DDTP) & DDTP_BUSY != 0 {
+            return ax_err!(BadState, "IOMMU busy");
+        }
+        let context_words = if capabilities & CAPABILITIES_MSI_FLAT != 0 {
+            8
+        } else {
+            4
+        };
+        let contexts_per_page = PAGE_SIZE_4K / (context_words * 8);
+        let leaf_pages = num_requester_ids().div_ceil(contexts_per_page);
+        let mid_pages = leaf_pages.div_ceil(DDT_NON_LEAF_ENTRIES);
+        let root = Table::alloc(1)?;
+        let mids = Table::alloc(mid_pages)?;
+        let contexts = Table::alloc(leaf_pages)?;
+        for mid in 0..mid_pages {
+            let paddr = mids.paddr.as_usize() + mid * PAGE_SIZE_4K;
+            root.write(mid, (ppn(paddr) << PPN_SHIFT) | DDT_VALID);
+        }
+        for leaf in 0..leaf_pages {
+            let paddr = contexts.paddr.as_usize() + leaf * PAGE_SIZE_4K;
+            mids.write(leaf, (ppn(paddr) << PPN_SHIFT) | DDT_VALID);
+        }
+        // The functions of the host are in bare mode, with both stages off.
+        for requester_id in 0..num_requester_ids() {
+            contexts.write(requester_id * context_words, DC_TC_VALID);
+        }
+        let cq = Table::alloc(1)?;
+
+        let mut iommu = Self {
+            base,
+            contexts,
+            context_words,
+            cq,
+            cq_tail: 0,
+        };
+        iommu.write_u64(
+            IOMMU_CQB,
+            (ppn(iommu.cq.paddr.as_usize()) << PPN_SHIFT) | (CQ_LOG2SZ - 1),
+        );
+        iommu.write_u32(IOMMU_CQT, 0);
+        iommu.write_u32(IOMMU_CQCSR, CQCSR_CQEN);
+        iommu.wait(|iommu| iommu.read_u32(IOMMU_CQCSR) & CQCSR_CQON != 0)?;
+        iommu.write_u64(
+            IOMMU_DDTP,
+            (ppn(root.paddr.as_usize()) << PPN_SHIFT) | DDTP_MODE_3LVL,
+        );
+        iommu.wait(|iommu| read_reg(iommu.base, IOMMU_DDTP) & DDTP_BUSY == 0)?;
+        iommu.command(CMD_IODIR_INVAL_DDT, 0);
+        iommu.fence()?;
+        Ok(iommu)
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+
+    fn wait(&self, done: impl Fn(&Self) -> bool) -> AxResult {
+        for _ in 0..1_000_000 {
+            if done(self) {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        ax_err!(TimedOut, "IOMMU not responding")
+    }
+
+    /// Appends a command to the command queue.
+    fn command(&mut self, word0: u64, word1: u64) {
+        let index = self.cq_tail as usize;
+        self.cq.write(index * 2, word0);
+        self.cq.write(index * 2 + 1, word1);
+        fence(Ordering::SeqCst);
+        self.cq_tail = (self.cq_tail + 1) % (1 << CQ_LOG2SZ);
+        self.write_u32(IOMMU_CQT, self.cq_tail);
+    }
+
+    /// Waits for the commands queued so far to complete.
+    fn fence(&mut self) -> AxResult {
+        self.command(CMD_IOFENCE_C, 0);
+        self.wait(|iommu| {
+            iommu.read_u32(IOMMU_CQCSR) & CQCSR_ERRORS != 0
+                || iommu.read_u32(IOMMU_CQH) == iommu.cq_tail
+        })?;
+        if self.read_u32(IOMMU_CQCSR) & CQCSR_ERRORS != 0 {
+            return ax_err!(BadState, "IOMMU command queue error");
+        }
+        Ok(())
+    }
+
+    /// Writes the device context of `device_id`, its first word last since it holds the
+    /// valid bit.
+    fn write_context(&mut self, device_id: u16, words: [u64; 4]) -> AxResult {
+        let index = device_id as usize * self.context_words;
+        self.contexts.write(index, 0);
+        for (i, word) in words.iter().enumerate().skip(1) {
+            self.contexts.write(index + i, *word);
+        }
+        fence(Ordering::SeqCst);
+        self.contexts.write(index, words[0]);
+        self.command(CMD_IODIR_INVAL_DDT | CMD_DV | ((device_id as u64) << 40), 0);
+        self.fence()
+    }
+
+    pub fn attach(&mut self, device_id: u16, vm_id: usize, root: HostPhysAddr) -> AxResult {
+        let gscid = vm_id as u64 & 0xffff;
+        let iohgatp = IOHGATP_MODE_SV39X4 | (gscid << IOHGATP_GSCID_SHIFT) | ppn(root.as_usize());
+        self.write_context(device_id, [DC_TC_VALID, iohgatp, 0, 0])?;
+        self.flush(vm_id);
+        Ok(())
+    }
+
+    pub fn detach(&mut self, device_id: u16) {
+        // A device context which is not valid blocks the DMA of the device.
+        if let Err(err) = self.write_context(device_id, [0; 4]) {
+            warn!("IOMMU: failed to detach {:#06x}: {:?}", device_id, err);
+        }
+    }
+
+    pub fn flush(&mut self, vm_id: usize) {
+        let gscid = vm_id as u64 & 0xffff;
+        self.command(
+            CMD_IOTINVAL_GVMA | CMD_GV | (gscid << IOHGATP_GSCID_SHIFT),
+            0,
+        );
+        if let Err(err) = self.fence() {
+            warn!("IOMMU: failed to flush VM[{}]: {:?}", vm_id, err);
+        }
+    }
+}
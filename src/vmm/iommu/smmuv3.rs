@@ -0,0 +1,247 @@
+//! An Arm SMMUv3, with a linear stream table and a command queue, in stage-2 only.
+//!
+//! The stage-2 translation of a stream shares the page table of the VM, with the same
+//! configuration as the `VTCR_EL2` of its vCPUs: a 39-bit IPA space starting at level 1, with
+//! 4K granules and a 40-bit output. The event queue is not used, so translation faults are
+//! silently aborted.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{Ordering, fence};
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+
+use super::{Table, num_requester_ids};
+
+const SMMU_IDR0: usize = 0x00;
+const SMMU_IDR1: usize = 0x04;
+const SMMU_CR0: usize = 0x20;
+const SMMU_CR0ACK: usize = 0x24;
+const SMMU_CR1: usize = 0x28;
+const SMMU_CR2: usize = 0x2c;
+const SMMU_STRTAB_BASE: usize = 0x80;
+const SMMU_STRTAB_BASE_CFG: usize = 0x88;
+const SMMU_CMDQ_BASE: usize = 0x90;
+const SMMU_CMDQ_PROD: usize = 0x98;
+const SMMU_CMDQ_CONS: usize = 0x9c;
+
+/// Stage 2 translation is supported.
+const IDR0_S2P: u32 = 1 << 0;
+/// Coherent access to the tables and the queues.
+const IDR0_COHACC: u32 = 1 << 4;
+const IDR1_SIDSIZE_MASK: u32 = 0x3f;
+
+const CR0_SMMUEN: u32 = 1 << 0;
+const CR0_CMDQEN: u32 = 1 << 3;
+/// Inner shareable, write-back cacheable queues and tables.
+const CR1_WB_INNER_SHAREABLE: u32 =
+    (1 << 0) | (1 << 2) | (3 << 4) | (1 << 6) | (1 << 8) | (3 << 10);
+/// Invalid stream IDs are recorded rather than silently aborted.
+const CR2_RECINVSID: u32 = 1 << 1;
+
+/// Read-allocate hint of the table and queue base registers.
+const BASE_RA: u64 = 1 << 62;
+const BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_ffe0;
+
+/// The command queue has 256 commands of 16 bytes, in a page.
+const CMDQ_LOG2SIZE: u32 = 8;
+const CMDQ_WRAP: u32 = 1 << CMDQ_LOG2SIZE;
+const CMDQ_ERR_MASK: u32 = 0x7f << 24;
+
+const CMD_CFGI_STE: u64 = 0x03;
+const CMD_CFGI_ALL: u64 = 0x04;
+const CMD_TLBI_S12_VMALL: u64 = 0x28;
+const CMD_TLBI_NSNH_ALL: u64 = 0x30;
+const CMD_SYNC: u64 = 0x46;
+
+/// An STE is 8 64-bit words.
+const STE_WORDS: usize = 8;
+const STE_VALID: u64 = 1 << 0;
+const STE_CONFIG_ABORT: u64 = 0b000 << 1;
+const STE_CONFIG_BYPASS: u64 = 0b100 << 1;
+const STE_CONFIG_S2_TRANSLATE: u64 = 0b110 << 1;
+/// Use the incoming shareability.
+const STE_SHCFG_INCOMING: u64 = 1 << 44;
+
+/// The stage-2 translation of the VMs, see the module documentation.
+const S2_T0SZ: u64 = 64 - 39;
+const S2_SL0: u64 = 0b01;
+/// Inner and outer write-back cacheable, inner shareable walks.
+const S2_IR0: u64 = 0b01;
+const S2_OR0: u64 = 0b01;
+const S2_SH0: u64 = 0b11;
+const S2_TG_4K: u64 = 0b00;
+const S2_PS_40BIT: u64 = 0b010;
+const S2_AA64: u64 = 1 << 51;
+
+fn read_reg(base: usize, offset: usize) -> u32 {
+    // SAFETY: the registers of the SMMU are mapped by the host as device memory.
+    unsafe { ((base + offset) as *const u32).read_volatile() }
+}
+
+/// An SMMUv3.
+pub struct SmmuV3 {
+    base: usize,
+    stream_table: Table,
+    cmdq: Table,
+    cmdq_prod: u32,
+}
+
+impl SmmuV3 {
+    pub fn new(base: usize) -> AxResult<Self> {
+        let read = |offset| read_reg(base, offset);
+        let idr0 = read(SMMU_IDR0);
+        if idr0 & IDR0_S2P == 0 {
+            return ax_err!(Unsupported, "SMMU without stage 2");
+        }
+        if idr0 & IDR0_COHACC == 0 {
+            return ax_err!(Unsupported, "SMMU not coherent");
+        }
+        let log2size = num_requester_ids().next_power_of_two().trailing_zeros();
+        if log2size > read(SMMU_IDR1) & IDR1_SIDSIZE_MASK {
+            return ax_err!(
+                Unsupported,
+                "SMMU stream IDs narrower than the requester IDs"
+            );
+        }
+        let ste_size = STE_WORDS * 8;
+        let stream_table = Table::alloc(((1 << log2size) * ste_size).div_ceil(PAGE_SIZE_4K))?;
+        // The functions of the host bypass translation.
+        for sid in 0..1 << log2size {
+            stream_table.write(sid * STE_WORDS, STE_VALID | STE_CONFIG_BYPASS);
+        }
+        let cmdq = Table::alloc(1)?;
+
+        let mut smmu = Self {
+            base,
+            stream_table,
+            cmdq,
+            cmdq_prod: 0,
+        };
+        smmu.write(SMMU_CR0, 0);
+        smmu.wait_cr0(0)?;
+        smmu.write(SMMU_CR1, CR1_WB_INNER_SHAREABLE);
+        smmu.write(SMMU_CR2, CR2_RECINVSID);
+        smmu.write_u64(
+            SMMU_STRTAB_BASE,
+            BASE_RA | (smmu.stream_table.paddr.as_usize() as u64 & BASE_ADDR_MASK),
+        );
+        // A linear table of 2^log2size entries.
+        smmu.write(SMMU_STRTAB_BASE_CFG, log2size);
+        smmu.write_u64(
+            SMMU_CMDQ_BASE,
+            BASE_RA | (smmu.cmdq.paddr.as_usize() as u64 & BASE_ADDR_MASK) | CMDQ_LOG2SIZE as u64,
+        );
+        smmu.write(SMMU_CMDQ_PROD, 0);
+        smmu.write(SMMU_CMDQ_CONS, 0);
+        smmu.write(SMMU_CR0, CR0_CMDQEN);
+        smmu.wait_cr0(CR0_CMDQEN)?;
+        smmu.command([CMD_CFGI_ALL, 31]);
+        smmu.command([CMD_TLBI_NSNH_ALL, 0]);
+        smmu.sync()?;
+        smmu.write(SMMU_CR0, CR0_CMDQEN | CR0_SMMUEN);
+        smmu.wait_cr0(CR0_CMDQEN | CR0_SMMUEN)?;
+        Ok(smmu)
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        read_reg(self.base, offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+
+    fn wait_cr0(&self, value: u32) -> AxResult {
+        for _ in 0..1_000_000 {
+            if self.read(SMMU_CR0ACK) == value {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        ax_err!(TimedOut, "SMMU CR0 not acknowledged")
+    }
+
+    /// Appends a command to the command queue.
+    fn command(&mut self, command: [u64; 2]) {
+        let index = (self.cmdq_prod & (CMDQ_WRAP - 1)) as usize;
+        self.cmdq.write(index * 2, command[0]);
+        self.cmdq.write(index * 2 + 1, command[1]);
+        fence(Ordering::SeqCst);
+        self.cmdq_prod = (self.cmdq_prod + 1) & (CMDQ_WRAP * 2 - 1);
+        self.write(SMMU_CMDQ_PROD, self.cmdq_prod);
+    }
+
+    /// Waits for the commands queued so far to complete.
+    fn sync(&mut self) -> AxResult {
+        self.command([CMD_SYNC, 0]);
+        for _ in 0..1_000_000 {
+            let cons = self.read(SMMU_CMDQ_CONS);
+            if cons & CMDQ_ERR_MASK != 0 {
+                return ax_err!(BadState, "SMMU command error");
+            }
+            if cons & (CMDQ_WRAP * 2 - 1) == self.cmdq_prod {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        ax_err!(TimedOut, "SMMU command queue stuck")
+    }
+
+    /// Writes the STE of `sid`, its first word last since it holds the valid bit.
+    fn write_ste(&mut self, sid: u16, words: [u64; 4]) -> AxResult {
+        let index = sid as usize * STE_WORDS;
+        self.stream_table.write(index, 0);
+        self.command([CMD_CFGI_STE | ((sid as u64) << 32), 1]);
+        self.sync()?;
+        for (i, word) in words.iter().enumerate().skip(1) {
+            self.stream_table.write(index + i, *word);
+        }
+        fence(Ordering::SeqCst);
+        self.stream_table.write(index, words[0]);
+        self.command([CMD_CFGI_STE | ((sid as u64) << 32), 1]);
+        self.sync()
+    }
+
+    pub fn attach(&mut self, sid: u16, vm_id: usize, root: HostPhysAddr) -> AxResult {
+        let vmid = vm_id as u64 & 0xffff;
+        let s2 = vmid
+            | (S2_T0SZ << 32)
+            | (S2_SL0 << 38)
+            | (S2_IR0 << 40)
+            | (S2_OR0 << 42)
+            | (S2_SH0 << 44)
+            | (S2_TG_4K << 46)
+            | (S2_PS_40BIT << 48)
+            | S2_AA64;
+        let ttb = root.as_usize() as u64 & 0x000f_ffff_ffff_fff0;
+        self.write_ste(sid, [
+            STE_VALID | STE_CONFIG_S2_TRANSLATE,
+            STE_SHCFG_INCOMING,
+            s2,
+            ttb,
+        ])?;
+        self.flush(vm_id);
+        Ok(())
+    }
+
+    pub fn detach(&mut self, sid: u16) {
+        if let Err(err) = self.write_ste(sid, [STE_VALID | STE_CONFIG_ABORT, 0, 0, 0]) {
+            warn!("SMMU: failed to detach stream {:#x}: {:?}", sid, err);
+        }
+    }
+
+    pub fn flush(&mut self, vm_id: usize) {
+        self.command([CMD_TLBI_S12_VMALL | ((vm_id as u64 & 0xffff) << 32), 0]);
+        if let Err(err) = self.sync() {
+            warn!("SMMU: failed to flush VM[{}]: {:?}", vm_id, err);
+        }
+    }
+}
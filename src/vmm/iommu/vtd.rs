@@ -0,0 +1,213 @@
+//! An Intel VT-d DMA remapping unit, with legacy root and context tables and register-based
+//! invalidation.
+//!
+//! The second-level translation of a function shares the EPT of the VM, whose 4-level format
+//! the second-level page tables have been designed to match. The functions of the host are
+//! in pass-through.
+
+use core::hint::spin_loop;
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+
+use super::{Table, num_requester_ids};
+
+const DMAR_CAP: usize = 0x08;
+const DMAR_ECAP: usize = 0x10;
+const DMAR_GCMD: usize = 0x18;
+const DMAR_GSTS: usize = 0x1c;
+const DMAR_RTADDR: usize = 0x20;
+const DMAR_CCMD: usize = 0x28;
+
+/// Write buffer flushing is required.
+const CAP_RWBF: u64 = 1 << 4;
+/// 4-level page tables, for a 48-bit guest address width, like the EPT.
+const CAP_SAGAW_48BIT: u64 = 1 << 10;
+const CAP_ND_MASK: u64 = 0x7;
+/// Coherent page walks.
+const ECAP_C: u64 = 1 << 0;
+/// Pass-through translation.
+const ECAP_PT: u64 = 1 << 6;
+const ECAP_IRO_SHIFT: u64 = 8;
+const ECAP_IRO_MASK: u64 = 0x3ff;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_WBF: u32 = 1 << 27;
+/// The bits of `GSTS` reflecting persistent commands, to be kept when writing `GCMD`.
+const GSTS_PERSISTENT: u32 = 0x96ff_ffff;
+
+/// Global context cache invalidation.
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_CIRG_GLOBAL: u64 = 0b01 << 61;
+/// IOTLB invalidation, globally or for a domain, draining the reads and writes.
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_IIRG_GLOBAL: u64 = 0b01 << 60;
+const IOTLB_IIRG_DOMAIN: u64 = 0b10 << 60;
+const IOTLB_DR: u64 = 1 << 49;
+const IOTLB_DW: u64 = 1 << 48;
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CONTEXT_TT_UNTRANSLATED: u64 = 0b00 << 2;
+const CONTEXT_TT_PASS_THROUGH: u64 = 0b10 << 2;
+/// A 48-bit address width.
+const CONTEXT_AW_48BIT: u64 = 0b010;
+const CONTEXT_DID_SHIFT: u64 = 8;
+
+/// The domain of the functions of the host, the ones of the VMs following it.
+const HOST_DOMAIN: u64 = 1;
+
+fn read_reg(base: usize, offset: usize) -> u64 {
+    // SAFETY: the registers of the remapping unit are mapped by the host as device memory.
+    unsafe { ((base + offset) as *const u64).read_volatile() }
+}
+
+/// A VT-d DMA remapping unit.
+pub struct Vtd {
+    base: usize,
+    iotlb: usize,
+    root_table: Table,
+    /// The context tables of all the buses, a page each.
+    context_tables: Table,
+    num_domains: u64,
+    rwbf: bool,
+}
+
+impl Vtd {
+    pub fn new(base: usize) -> AxResult<Self> {
+        let cap = read_reg(base, DMAR_CAP);
+        let ecap = read_reg(base, DMAR_ECAP);
+        if cap & CAP_SAGAW_48BIT == 0 {
+            return ax_err!(Unsupported, "VT-d without 4-level page tables");
+        }
+        if ecap & ECAP_C == 0 || ecap & ECAP_PT == 0 {
+            return ax_err!(Unsupported, "VT-d without coherency or pass-through");
+        }
+        let num_buses = num_requester_ids() / 256;
+        let root_table = Table::alloc(1)?;
+        let context_tables = Table::alloc(num_buses)?;
+        for bus in 0..num_buses {
+            let context_table = context_tables.paddr.as_usize() + bus * PAGE_SIZE_4K;
+            root_table.write(bus * 2, context_table as u64 | ENTRY_PRESENT);
+        }
+        for requester_id in 0..num_buses * 256 {
+            context_tables.write(requester_id * 2, CONTEXT_TT_PASS_THROUGH | ENTRY_PRESENT);
+            context_tables.write(
+                requester_id * 2 + 1,
+                (HOST_DOMAIN << CONTEXT_DID_SHIFT) | CONTEXT_AW_48BIT,
+            );
+        }
+
+        let vtd = Self {
+            base,
+            iotlb: (((ecap >> ECAP_IRO_SHIFT) & ECAP_IRO_MASK) as usize) * 16 + 8,
+            root_table,
+            context_tables,
+            num_domains: 1 << (4 + 2 * (cap & CAP_ND_MASK)),
+            rwbf: cap & CAP_RWBF != 0,
+        };
+        vtd.write_u64(DMAR_RTADDR, vtd.root_table.paddr.as_usize() as u64);
+        vtd.command(GCMD_SRTP)?;
+        vtd.invalidate_context()?;
+        vtd.invalidate_iotlb(IOTLB_IIRG_GLOBAL)?;
+        vtd.command(GCMD_TE)?;
+        Ok(vtd)
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        // SAFETY: see `read_reg`.
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) }
+    }
+
+    /// Waits for `done` to hold.
+    fn wait(&self, done: impl Fn() -> bool) -> AxResult {
+        for _ in 0..1_000_000 {
+            if done() {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        ax_err!(TimedOut, "VT-d command not completed")
+    }
+
+    /// Issues the global command `command`, and waits for its status.
+    fn command(&self, command: u32) -> AxResult {
+        let status = self.read_u32(DMAR_GSTS) & GSTS_PERSISTENT;
+        self.write_u32(DMAR_GCMD, status | command);
+        // The write buffer flush status is cleared once done, the others are set.
+        if command == GCMD_WBF {
+            self.wait(|| self.read_u32(DMAR_GSTS) & GCMD_WBF == 0)
+        } else {
+            self.wait(|| self.read_u32(DMAR_GSTS) & command != 0)
+        }
+    }
+
+    fn invalidate_context(&self) -> AxResult {
+        if self.rwbf {
+            self.command(GCMD_WBF)?;
+        }
+        self.write_u64(DMAR_CCMD, CCMD_ICC | CCMD_CIRG_GLOBAL);
+        self.wait(|| read_reg(self.base, DMAR_CCMD) & CCMD_ICC == 0)
+    }
+
+    fn invalidate_iotlb(&self, granularity: u64) -> AxResult {
+        self.write_u64(self.iotlb, IOTLB_IVT | granularity | IOTLB_DR | IOTLB_DW);
+        self.wait(|| read_reg(self.base, self.iotlb) & IOTLB_IVT == 0)
+    }
+
+    fn domain(&self, vm_id: usize) -> AxResult<u64> {
+        let domain = HOST_DOMAIN + 1 + vm_id as u64;
+        if domain >= self.num_domains {
+            return ax_err!(Unsupported, "no VT-d domain left for the VM");
+        }
+        Ok(domain)
+    }
+
+    /// Writes the context entry of `requester_id`, its low word last since it holds the
+    /// present bit.
+    fn write_context(&self, requester_id: u16, low: u64, high: u64) -> AxResult {
+        let index = requester_id as usize * 2;
+        self.context_tables.write(index, 0);
+        self.invalidate_context()?;
+        self.context_tables.write(index + 1, high);
+        self.context_tables.write(index, low);
+        self.invalidate_context()?;
+        self.invalidate_iotlb(IOTLB_IIRG_GLOBAL)
+    }
+
+    pub fn attach(&mut self, requester_id: u16, vm_id: usize, root: HostPhysAddr) -> AxResult {
+        let domain = self.domain(vm_id)?;
+        self.write_context(
+            requester_id,
+            root.as_usize() as u64 | CONTEXT_TT_UNTRANSLATED | ENTRY_PRESENT,
+            (domain << CONTEXT_DID_SHIFT) | CONTEXT_AW_48BIT,
+        )
+    }
+
+    pub fn detach(&mut self, requester_id: u16) {
+        // A context entry which is not present blocks the DMA of the function.
+        if let Err(err) = self.write_context(requester_id, 0, 0) {
+            warn!("VT-d: failed to detach {:#06x}: {:?}", requester_id, err);
+        }
+    }
+
+    pub fn flush(&mut self, vm_id: usize) {
+        let Ok(domain) = self.domain(vm_id) else {
+            return;
+        };
+        if let Err(err) = self.invalidate_iotlb(IOTLB_IIRG_DOMAIN | (domain << 32)) {
+            warn!("VT-d: failed to flush VM[{}]: {:?}", vm_id, err);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Delivery of interrupts to vCPUs running on other physical CPUs.
+//!
+//! A virtual interrupt raised for a vCPU, such as a guest IPI (a GIC SGI, an SBI IPI or a write
+//! to the LAPIC ICR), is first recorded as pending for the target vCPU, in its interrupt queue
+//! or its virtual interrupt controller, and is injected by the vCPU's own task right before it
+//! enters the guest. To get it taken without delay:
+//! * a halted target vCPU is woken up through the wait queue of its VM;
+//! * a target vCPU running in the guest on another physical CPU is kicked out of it with a
+//!   physical IPI, the resulting VM exit injecting the interrupt on re-entry.
+//!
+//! The running vCPU publishes its physical CPU before checking its pending interrupts, and the
+//! sender records the interrupt before looking the physical CPU up, so that either the vCPU
+//! sees the interrupt before entering the guest, or the sender sees the vCPU running and
+//! kicks it.
+
+#[cfg(target_arch = "riscv64")]
+use alloc::vec::Vec;
+
+use std::os::arceos::modules::axhal;
+
+use axhal::irq::{IPI_IRQ, IpiTarget};
+
+use crate::vmm::timer;
+#[cfg(target_arch = "riscv64")]
+use crate::vmm::vcpus;
+
+/// The SBI IPI extension ID ("sPI").
+#[cfg(target_arch = "riscv64")]
+pub const SBI_EXT_IPI: u64 = 0x73_5049;
+
+/// Supervisor software interrupt.
+#[cfg(target_arch = "riscv64")]
+const IRQ_S_SOFT: usize = 1;
+
+/// Registers the host handler of the IPIs used to kick vCPUs.
+///
+/// They also kick the CPUs whose earliest timer event was registered by another CPU, so that
+/// they re-program their hardware timer.
+pub fn init() {
+    // Taking the interrupt is what makes the vCPU exit.
+    if !axhal::irq::register_handler(IPI_IRQ, timer::scheduler_next_event) {
+        warn!("Failed to register the vCPU kick IPI handler");
+    }
+}
+
+/// Forces the vCPU running on the physical CPU `cpu_id` to exit the guest, or the CPU to
+/// re-program its hardware timer, unless it is the current CPU, which is not running a vCPU
+/// since the hypervisor is.
+pub fn kick_cpu(cpu_id: usize) {
+    if cpu_id == axhal::cpu::this_cpu_id() {
+        return;
+    }
+    trace!("Kick CPU {}", cpu_id);
+    axhal::irq::send_ipi(IPI_IRQ, IpiTarget::Other { cpu_id });
+}
+
+/// Handles an SBI `send_ipi` call, raising a supervisor software interrupt on the harts, i.e.
+/// the vCPUs, of `hart_mask` offset by `hart_mask_base`, or on all of them if `hart_mask_base`
+/// is `usize::MAX`.
+///
+/// # Returns
+///
+/// The SBI error code, 0 on success.
+#[cfg(target_arch = "riscv64")]
+pub fn handle_sbi_send_ipi(
+    vm_id: usize,
+    vcpu_num: usize,
+    hart_mask: usize,
+    hart_mask_base: usize,
+) -> isize {
+    /// `SBI_ERR_INVALID_PARAM`.
+    const SBI_ERR_INVALID_PARAM: isize = -3;
+
+    let Some(targets) = sbi_ipi_targets(vcpu_num, hart_mask, hart_mask_base) else {
+        return SBI_ERR_INVALID_PARAM;
+    };
+    for vcpu_id in targets {
+        vcpus::inject_interrupt(vm_id, vcpu_id, IRQ_S_SOFT);
+    }
+    0
+}
+
+/// Returns the vCPUs targeted by an SBI `send_ipi` call, or `None` if one of the harts is out
+/// of range, including past `usize::MAX`.
+#[cfg(target_arch = "riscv64")]
+fn sbi_ipi_targets(vcpu_num: usize, hart_mask: usize, hart_mask_base: usize) -> Option<Vec<usize>> {
+    if hart_mask_base == usize::MAX {
+        return Some((0..vcpu_num).collect());
+    }
+    (0..usize::BITS as usize)
+        .filter(|bit| hart_mask & (1 << bit) != 0)
+        .map(|bit| {
+            hart_mask_base
+                .checked_add(bit)
+                .filter(|&vcpu_id| vcpu_id < vcpu_num)
+        })
+        .collect()
+}
@@ -0,0 +1,349 @@
+//! Routing of physical interrupts to the VMs owning the devices which raise them.
+//!
+//! A passthrough device entry of a VM config may name the physical interrupt of the device in
+//! its `Alloc-Irq` field (0 if none). That interrupt is then owned by the VM: when it fires, it
+//! is injected into the VM under the same number, through [`vcpus::inject_interrupt`], which
+//! also wakes the target vCPU up if it is halted. All other interrupts are handled by the host.
+//!
+//! A routed interrupt is forwarded by its host handler, whether it is taken while a vCPU is
+//! running or while the host is, e.g. idle with every vCPU of the VM halted. The handlers
+//! registered through `axhal` get no argument, so each route takes one of the
+//! [`MAX_ROUTED_IRQS`] handler slots, whose handler forwards the interrupt held by its slot.
+//!
+//! A forwarded interrupt is acknowledged on the host right away, and its physical line is
+//! masked until the guest completes the virtual interrupt (see [`complete_irq`]), so that a
+//! level-triggered device does not raise it again before the guest has serviced it.
+//!
+//! On x86_64, a VM with an emulated I/O APIC receives a forwarded interrupt on the GSI
+//! matching its host vector instead, and routes it through its own redirection table.
+//! Likewise on riscv64, a VM with an emulated PLIC receives it on the source of the same number.
+//!
+//! The message signalled interrupts of the PCI functions of a VM, emulated or passed through,
+//! are delivered through [`deliver_msi`], according to the message the guest programmed into
+//! the function: to the local APICs its address targets on x86_64, or as the SPI written to
+//! the `MSI_SETSPI_NS` register of an emulated GICv2m frame on aarch64. There is no virtual
+//! ITS nor IMSIC, so the guests do not get LPIs, and riscv64 guests do not get MSIs at all.
+//!
+//! A passthrough function raises its MSIs on physical interrupts of the host routed through
+//! [`route_msi`]: it writes them to the `msi-doorbell-paddr` of the platform config, with the
+//! host interrupt as data, and each of them is then delivered as the message the guest
+//! programmed for it, set through [`set_msi_message`].
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::os::arceos::modules::{axconfig, axhal};
+
+use axerrno::{AxResult, ax_err};
+use kspin::SpinNoIrq;
+
+#[cfg(target_arch = "x86_64")]
+use crate::vmm::vapic;
+use crate::vmm::vcpus;
+#[cfg(target_arch = "aarch64")]
+use crate::vmm::vgic;
+#[cfg(target_arch = "riscv64")]
+use crate::vmm::vplic;
+
+/// The first interrupt which may be owned by a device, SGIs and PPIs being per-CPU.
+#[cfg(target_arch = "aarch64")]
+const FIRST_DEVICE_IRQ: usize = 32;
+/// The first interrupt which may be owned by a device, PLIC source 0 being reserved.
+#[cfg(target_arch = "riscv64")]
+const FIRST_DEVICE_IRQ: usize = 1;
+/// The first interrupt which may be owned by a device, the lower vectors being exceptions.
+/// It is also the host vector of GSI 0.
+#[cfg(target_arch = "x86_64")]
+pub const FIRST_DEVICE_IRQ: usize = 32;
+
+/// A message signalled interrupt, as written by a PCI function to the address it was
+/// programmed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// How a physical interrupt is delivered to the VM owning it.
+enum Delivery {
+    /// Injected under the same number, its line masked while it is in service, i.e. injected
+    /// and not yet completed by the guest.
+    Line { in_service: bool },
+    /// Delivered as the message the guest programmed for it, if it has enabled it.
+    Msi(Option<MsiMessage>),
+}
+
+/// The owner of a physical interrupt.
+struct IrqRoute {
+    vm_id: usize,
+    delivery: Delivery,
+}
+
+/// The routed physical interrupts, stored in a BTreeMap where the key is the interrupt number.
+///
+/// Also accessed from interrupt context, hence the IRQ-safe lock.
+static IRQ_ROUTES: SpinNoIrq<BTreeMap<usize, IrqRoute>> = SpinNoIrq::new(BTreeMap::new());
+
+/// The maximum number of physical interrupts routed to the VMs at once.
+pub const MAX_ROUTED_IRQS: usize = 64;
+
+/// The value of a free slot of [`HANDLER_SLOTS`].
+const FREE_SLOT: usize = usize::MAX;
+
+/// The physical interrupt forwarded by the host handler of each slot, [`FREE_SLOT`] if none.
+///
+/// A slot is taken while its interrupt is routed, and freed along with the host handler once
+/// the VM owning the interrupt is torn down.
+static HANDLER_SLOTS: [AtomicUsize; MAX_ROUTED_IRQS] =
+    [const { AtomicUsize::new(FREE_SLOT) }; MAX_ROUTED_IRQS];
+
+/// The host handler of the slot `SLOT` of [`HANDLER_SLOTS`].
+fn slot_handler<const SLOT: usize>() {
+    let irq = HANDLER_SLOTS[SLOT].load(Ordering::Acquire);
+    if irq != FREE_SLOT {
+        forward_irq(irq);
+    }
+}
+
+macro_rules! slot_handlers {
+    ($($slot:literal)*) => {
+        [$(slot_handler::<$slot> as fn()),*]
+    };
+}
+
+/// The host handlers of the slots of [`HANDLER_SLOTS`].
+static SLOT_HANDLERS: [fn(); MAX_ROUTED_IRQS] = slot_handlers!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61
+    62 63
+);
+
+/// Registers the host handler forwarding `irq`, which also enables it on the host.
+///
+/// Fails if the interrupt is used by the host, or if all the slots are taken.
+fn register_forward_handler(irq: usize) -> AxResult {
+    let Some(slot) = HANDLER_SLOTS.iter().position(|slot| {
+        slot.compare_exchange(FREE_SLOT, irq, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }) else {
+        return ax_err!(NoMemory, "too many routed interrupts");
+    };
+    if !axhal::irq::register_handler(irq, SLOT_HANDLERS[slot]) {
+        HANDLER_SLOTS[slot].store(FREE_SLOT, Ordering::Release);
+        return ax_err!(AlreadyExists, "interrupt already used by the host");
+    }
+    Ok(())
+}
+
+/// Routes the physical interrupt `irq` to the VM `vm_id`.
+///
+/// Fails if the interrupt cannot be owned by a device, or is already owned by another VM.
+pub fn route_irq(vm_id: usize, irq: usize) -> AxResult {
+    add_route(vm_id, irq, Delivery::Line { in_service: false })
+}
+
+/// Routes the physical interrupt `irq`, an MSI of a passthrough PCI function, to the VM
+/// `vm_id`. It is dropped until the guest programs its message, see [`set_msi_message`].
+pub fn route_msi(vm_id: usize, irq: usize) -> AxResult {
+    add_route(vm_id, irq, Delivery::Msi(None))
+}
+
+fn add_route(vm_id: usize, irq: usize, delivery: Delivery) -> AxResult {
+    if irq < FIRST_DEVICE_IRQ {
+        return ax_err!(InvalidInput, "not a device interrupt");
+    }
+    {
+        let mut routes = IRQ_ROUTES.lock();
+        match routes.get(&irq) {
+            Some(route) if route.vm_id != vm_id => {
+                return ax_err!(AlreadyExists, "interrupt already routed to another VM");
+            }
+            Some(_) => return Ok(()),
+            None => {
+                routes.insert(irq, IrqRoute { vm_id, delivery });
+            }
+        }
+    }
+    if let Err(err) = register_forward_handler(irq) {
+        IRQ_ROUTES.lock().remove(&irq);
+        return Err(err);
+    }
+    info!("VM[{}] owns physical irq {}", vm_id, irq);
+    Ok(())
+}
+
+/// Sets the message delivered to its VM when the physical interrupt `irq`, routed through
+/// [`route_msi`], fires, or drops it if `None`.
+pub fn set_msi_message(irq: usize, message: Option<MsiMessage>) {
+    if let Some(route) = IRQ_ROUTES.lock().get_mut(&irq) {
+        if let Delivery::Msi(msi) = &mut route.delivery {
+            *msi = message;
+        }
+    }
+}
+
+/// Returns the message a passthrough PCI function must write to raise the physical interrupt
+/// `irq`, if the host can receive MSIs.
+pub fn host_msi_message(irq: usize) -> Option<MsiMessage> {
+    match axconfig::devices::MSI_DOORBELL_PADDR {
+        0 => None,
+        address => Some(MsiMessage {
+            address: address as u64,
+            data: irq as u32,
+        }),
+    }
+}
+
+/// Unregisters the host handler forwarding `irq` and frees its slot, the interrupt being left
+/// masked.
+fn unregister_forward_handler(irq: usize) {
+    axhal::irq::set_enable(irq, false);
+    axhal::irq::unregister_handler(irq);
+    if let Some(slot) = HANDLER_SLOTS
+        .iter()
+        .find(|slot| slot.load(Ordering::Acquire) == irq)
+    {
+        slot.store(FREE_SLOT, Ordering::Release);
+    }
+}
+
+/// Removes the routes of all the interrupts owned by a VM, called when the VM
+/// is torn down. The interrupts are left masked, without any host handler.
+pub fn remove_vm_irqs(vm_id: usize) {
+    let mut removed = Vec::new();
+    IRQ_ROUTES.lock().retain(|&irq, route| {
+        if route.vm_id == vm_id {
+            removed.push(irq);
+        }
+        route.vm_id != vm_id
+    });
+    for irq in removed {
+        unregister_forward_handler(irq);
+    }
+}
+
+/// Forwards the physical interrupt `irq` to the VM owning it, from its host handler.
+fn forward_irq(irq: usize) {
+    let owner = IRQ_ROUTES
+        .lock()
+        .get_mut(&irq)
+        .map(|route| match &mut route.delivery {
+            Delivery::Line { in_service } => {
+                *in_service = true;
+                (route.vm_id, None)
+            }
+            Delivery::Msi(message) => (route.vm_id, Some(*message)),
+        });
+    match owner {
+        Some((vm_id, None)) => {
+            axhal::irq::set_enable(irq, false);
+            trace!("Forward irq {} to VM[{}]", irq, vm_id);
+            if !inject_guest_irq(vm_id, irq) || !tracks_completion(vm_id) {
+                complete_irq(vm_id, irq);
+            }
+        }
+        Some((vm_id, Some(Some(message)))) => {
+            trace!("Forward MSI irq {} to VM[{}] as {:x?}", irq, vm_id, message);
+            deliver_msi(vm_id, message);
+        }
+        Some((vm_id, Some(None))) => {
+            trace!("Drop MSI irq {} of VM[{}], not enabled", irq, vm_id);
+        }
+        None => {}
+    }
+}
+
+/// Raises the interrupt `irq` in the guest of `vm_id`, through its emulated interrupt
+/// controller if it has one. The interrupt is numbered like the physical ones, so that an
+/// emulated device can raise it in place of a passthrough one.
+///
+/// Returns whether the interrupt was delivered.
+pub fn inject_guest_irq(vm_id: usize, irq: usize) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(vapic) = vapic::get_vm_vapic(vm_id) {
+        let Some(gsi) = irq.checked_sub(FIRST_DEVICE_IRQ) else {
+            warn!("VM[{}] irq {} has no vIOAPIC pin", vm_id, irq);
+            return false;
+        };
+        vapic.raise_gsi(gsi);
+        return true;
+    }
+    #[cfg(target_arch = "riscv64")]
+    if let Some(vplic) = vplic::get_vm_vplic(vm_id) {
+        vplic.raise(irq);
+        return true;
+    }
+    #[cfg(target_arch = "aarch64")]
+    if let Some(vgic) = vgic::get_vm_vgic(vm_id) {
+        return match vgic.spi_target_or_latch(irq) {
+            Some(vcpu_id) => vcpus::inject_interrupt(vm_id, vcpu_id, irq),
+            None => true,
+        };
+    }
+    vcpus::inject_interrupt(vm_id, 0, irq)
+}
+
+/// Unmasks the physical interrupt `irq` once its virtual counterpart has been completed by
+/// the guest of `vm_id`. Does nothing if the interrupt is not forwarded to that VM.
+pub fn complete_irq(vm_id: usize, irq: usize) {
+    let mut routes = IRQ_ROUTES.lock();
+    if let Some(route) = routes.get_mut(&irq) {
+        if let Delivery::Line { in_service } = &mut route.delivery {
+            if route.vm_id == vm_id && *in_service {
+                *in_service = false;
+                axhal::irq::set_enable(irq, true);
+            }
+        }
+    }
+}
+
+/// Returns whether the guest of `vm_id` has an interrupt controller receiving MSIs, so that
+/// its PCI functions can offer them.
+pub fn msi_supported(_vm_id: usize) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    if vapic::get_vm_vapic(_vm_id).is_some() {
+        return true;
+    }
+    #[cfg(target_arch = "aarch64")]
+    if vgic::has_v2m(_vm_id) {
+        return true;
+    }
+    false
+}
+
+/// Delivers an MSI written by a PCI function of `vm_id`, according to its message.
+///
+/// Returns whether the message targets an interrupt controller of the guest.
+pub fn deliver_msi(vm_id: usize, message: MsiMessage) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(vapic) = vapic::get_vm_vapic(vm_id) {
+        return vapic.deliver_msi(message.address, message.data);
+    }
+    #[cfg(target_arch = "aarch64")]
+    if let Some(spi) = vgic::v2m_spi(vm_id, message.address, message.data) {
+        return inject_guest_irq(vm_id, spi);
+    }
+    warn!("VM[{}] MSI {:x?} has no target", vm_id, message);
+    false
+}
+
+/// Returns whether the VM has an emulated interrupt controller reporting the completion of
+/// forwarded interrupts. If not, the physical line is unmasked as soon as it is injected.
+fn tracks_completion(_vm_id: usize) -> bool {
+    #[cfg(target_arch = "aarch64")]
+    if vgic::get_vm_vgic(_vm_id).is_some() {
+        return true;
+    }
+    #[cfg(target_arch = "x86_64")]
+    if vapic::get_vm_vapic(_vm_id).is_some() {
+        return true;
+    }
+    #[cfg(target_arch = "riscv64")]
+    if vplic::get_vm_vplic(_vm_id).is_some() {
+        return true;
+    }
+    false
+}
@@ -1,8 +1,23 @@
 mod config;
+mod console;
+mod devices;
 mod images;
+mod iommu;
+mod ipi;
+mod irq;
+mod shell;
 mod timer;
+#[cfg(target_arch = "x86_64")]
+mod vapic;
 mod vcpus;
+#[cfg(target_arch = "aarch64")]
+mod vgic;
 mod vm_list;
+#[cfg(target_arch = "riscv64")]
+mod vplic;
+mod vsock;
+mod vswitch;
+mod vtimer;
 
 use std::os::arceos::api::task::{self, AxWaitQueueHandle};
 
@@ -11,6 +26,9 @@ use core::sync::atomic::Ordering;
 
 use crate::hal::{AxVCpuHalImpl, AxVMHalImpl};
 pub use timer::init_percpu as init_timer_percpu;
+#[cfg(target_arch = "aarch64")]
+pub use vgic::init_percpu as init_vgic_percpu;
+pub use vtimer::VirtTimer;
 
 pub type VM = axvm::AxVM<AxVMHalImpl, AxVCpuHalImpl>;
 pub type VMRef = axvm::AxVMRef<AxVMHalImpl, AxVCpuHalImpl>;
@@ -22,14 +40,48 @@ static VMM: AxWaitQueueHandle = AxWaitQueueHandle::new();
 static RUNNING_VM_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub fn init() {
+    // Take over the IOMMU before the passthrough devices are attached to the VMs.
+    iommu::init();
     // Initialize guest VM according to config file.
     config::init_guest_vms();
+    ipi::init();
+    timer::init();
+    timer::start_stats_dump();
 
     // Setup vcpus, spawn axtask for primary VCpu.
     info!("Setting up vcpus...");
     for vm in vm_list::get_vm_list() {
         vcpus::setup_vm_primary_vcpu(vm);
     }
+
+    shell::init();
+}
+
+/// Releases everything held for a VM whose vCPUs have all stopped, called by its last vCPU
+/// task, and lets [`start`] return once no VM is running anymore.
+fn teardown_vm(vm_id: usize) {
+    info!("VM[{}] stopped, tearing it down", vm_id);
+    // Block the DMA of the passthrough functions before the memory of the VM is freed.
+    iommu::detach_vm_devices(vm_id);
+    irq::remove_vm_irqs(vm_id);
+    devices::remove_vm_devices(vm_id);
+    devices::dump_mmio_trace(vm_id);
+    devices::remove_vm_mmio_trace(vm_id);
+    devices::remove_vm_pci(vm_id);
+    #[cfg(target_arch = "aarch64")]
+    {
+        vgic::remove_vm_vgic(vm_id);
+        vgic::remove_vm_v2m(vm_id);
+    }
+    #[cfg(target_arch = "x86_64")]
+    vapic::remove_vm_vapic(vm_id);
+    #[cfg(target_arch = "riscv64")]
+    vplic::remove_vm_vplic(vm_id);
+    vcpus::remove_vm_vcpus(vm_id);
+    vm_list::remove_vm(vm_id);
+
+    RUNNING_VM_COUNT.fetch_sub(1, Ordering::Release);
+    task::ax_wait_queue_wake(&VMM, 1);
 }
 
 pub fn start() {
@@ -37,8 +89,8 @@ pub fn start() {
     for vm in vm_list::get_vm_list() {
         match vm.boot() {
             Ok(_) => {
-                vcpus::notify_primary_vcpu(vm.id());
                 RUNNING_VM_COUNT.fetch_add(1, Ordering::Release);
+                vcpus::notify_primary_vcpu(vm.id());
                 info!("VM[{}] boot success", vm.id())
             }
             Err(err) => warn!("VM[{}] boot failed, error {:?}", vm.id(), err),
@@ -47,4 +99,6 @@ pub fn start() {
 
     // Do not exit until all VMs are stopped.
     task::ax_wait_queue_wait_until(&VMM, || RUNNING_VM_COUNT.load(Ordering::Acquire) == 0, None);
+
+    timer::dump_stats();
 }
@@ -0,0 +1,340 @@
+//! The hypervisor shell, running the commands of the operators of the hypervisor.
+//!
+//! A command is a line of words, whose output is returned by [`execute`]. The shell is
+//! reached through the `hv` console of the [console multiplexer](super::console), registered
+//! by [`init`] if the multiplexer reads the host console input, i.e. if some guest console is
+//! emulated: the host console is otherwise left to the guest it is passed through to. The
+//! timer statistics are also dumped to the log periodically, see [`timer::start_stats_dump`].
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use std::os::arceos::modules::axconfig;
+
+use spin::{Mutex, Once};
+
+use crate::vmm::devices::{
+    balloon_pages, balloon_stats, host_time_nanos, request_balloon_stats, reset_vm_time,
+    set_balloon_target, take_mmio_trace, take_uart_output, vm_time_nanos,
+};
+use crate::vmm::vsock::{self, VsockConn, VsockHandler};
+use crate::vmm::{console, timer, vm_list};
+
+/// The maximum length of a command line typed on the `hv` console.
+const MAX_LINE_LEN: usize = 256;
+
+/// The usage and the description of the commands.
+const COMMANDS: &[(&str, &str)] = &[
+    ("help", "lists the commands"),
+    ("vms", "lists the VMs"),
+    (
+        "timer [reset]",
+        "prints the timer statistics of the CPUs, or resets them",
+    ),
+    ("balloon <vm>", "prints the size of the balloon of a guest"),
+    (
+        "balloon <vm> <pages>",
+        "sets the target size of the balloon",
+    ),
+    (
+        "balloon <vm> stats",
+        "prints the memory statistics of a guest, and requests new ones",
+    ),
+    ("rtc <vm>", "prints the time of the clock of a guest"),
+    (
+        "rtc <vm> reset",
+        "resets the clock of a guest to the time of the hypervisor",
+    ),
+    (
+        "trace <vm>",
+        "prints the MMIO accesses traced for a guest since the last time",
+    ),
+    (
+        "uart <vm>",
+        "prints the output buffered by the UARTs of a guest",
+    ),
+    ("vsock", "lists the vsock connections of the shell"),
+    (
+        "vsock listen <port>",
+        "accepts the guest connections to a hypervisor port",
+    ),
+    ("vsock unlisten <port>", "stops accepting them"),
+    ("vsock connect <vm> <port>", "connects to a port of a guest"),
+    (
+        "vsock send <conn> <text>",
+        "sends a line of text on a connection",
+    ),
+    ("vsock close <conn>", "closes a connection"),
+];
+
+/// The names of the memory statistics of the virtio-balloon, indexed by their tags.
+const BALLOON_STATS: &[&str] = &[
+    "swap-in (pages)",
+    "swap-out (pages)",
+    "major faults",
+    "minor faults",
+    "free memory (bytes)",
+    "total memory (bytes)",
+    "available memory (bytes)",
+    "disk caches (bytes)",
+    "hugetlb allocations",
+    "hugetlb failures",
+];
+
+/// The ID of the `hv` console, if registered.
+static CONSOLE_ID: Once<usize> = Once::new();
+/// The command line being typed on the `hv` console.
+static CONSOLE_LINE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// The vsock connections of the shell, stored in a BTreeMap where the key is the connection
+/// number shown to the operator.
+static VSOCK_CONNS: Mutex<BTreeMap<usize, VsockConn>> = Mutex::new(BTreeMap::new());
+static NEXT_VSOCK_CONN: AtomicUsize = AtomicUsize::new(0);
+
+/// Executes a command line, returning its output, made of complete lines.
+pub fn execute(line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    run(&words).unwrap_or_else(|err| format!("Error: {}\n", err))
+}
+
+fn run(words: &[&str]) -> Result<String, String> {
+    match words {
+        [] => Ok(String::new()),
+        ["help"] => Ok(help()),
+        ["vms"] => Ok(vms()),
+        ["timer"] => Ok(timer::stats_report()),
+        ["timer", "reset"] => {
+            for cpu_id in 0..axconfig::SMP {
+                timer::reset_stats(cpu_id);
+            }
+            Ok("Timer statistics reset\n".into())
+        }
+        ["balloon", vm_id] => balloon_pages(parse(vm_id, "VM ID")?)
+            .map(|pages| format!("VM[{}] balloon: {} pages\n", vm_id, pages))
+            .map_err(|err| format!("{:?}", err)),
+        ["balloon", vm_id, "stats"] => balloon_stats_report(parse(vm_id, "VM ID")?),
+        ["balloon", vm_id, pages] => {
+            set_balloon_target(parse(vm_id, "VM ID")?, parse(pages, "page count")?)
+                .map(|_| format!("VM[{}] balloon target: {} pages\n", vm_id, pages))
+                .map_err(|err| format!("{:?}", err))
+        }
+        ["rtc", vm_id] => Ok(rtc(parse(vm_id, "VM ID")?)),
+        ["rtc", vm_id, "reset"] => {
+            reset_vm_time(parse(vm_id, "VM ID")?);
+            Ok(format!("VM[{}] RTC reset\n", vm_id))
+        }
+        ["trace", vm_id] => Ok(mmio_trace(parse(vm_id, "VM ID")?)),
+        ["uart", vm_id] => Ok(uart_output(parse(vm_id, "VM ID")?)),
+        ["vsock"] => Ok(vsock_conns()),
+        ["vsock", "listen", port] => vsock::listen(parse(port, "port")?, Arc::new(ShellVsock))
+            .map(|_| format!("Listening on vsock port {}\n", port))
+            .map_err(|err| format!("{:?}", err)),
+        ["vsock", "unlisten", port] => {
+            vsock::unlisten(parse(port, "port")?);
+            Ok(format!("Stopped listening on vsock port {}\n", port))
+        }
+        ["vsock", "connect", vm_id, port] => vsock::connect(
+            parse(vm_id, "VM ID")?,
+            parse(port, "port")?,
+            Arc::new(ShellVsock),
+        )
+        .map(|_| format!("Connecting to port {} of VM[{}]\n", port, vm_id))
+        .map_err(|err| format!("{:?}", err)),
+        ["vsock", "send", conn, text @ ..] if !text.is_empty() => {
+            let conn = vsock_conn(parse(conn, "connection")?)?;
+            let mut data = text.join(" ");
+            data.push('\n');
+            conn.send(data.as_bytes())
+                .map(|_| String::new())
+                .map_err(|err| format!("{:?}", err))
+        }
+        ["vsock", "close", conn] => {
+            let number = parse(conn, "connection")?;
+            let conn = vsock_conn(number)?;
+            VSOCK_CONNS.lock().remove(&number);
+            conn.close();
+            Ok(format!("vsock #{} closed\n", number))
+        }
+        [command, ..] => Err(format!("unknown command {}, see help", command)),
+    }
+}
+
+fn parse<T: FromStr>(word: &str, what: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("invalid {} {}", what, word))
+}
+
+fn help() -> String {
+    let mut output = String::new();
+    for (usage, description) in COMMANDS {
+        let _ = writeln!(output, "{:<24} {}", usage, description);
+    }
+    output
+}
+
+fn vms() -> String {
+    let mut output = String::new();
+    for vm in vm_list::get_vm_list() {
+        let state = if vm.running() { "running" } else { "stopped" };
+        let _ = writeln!(output, "VM[{}] {} vCPUs, {}", vm.id(), vm.vcpu_num(), state);
+    }
+    output
+}
+
+fn balloon_stats_report(vm_id: usize) -> Result<String, String> {
+    let stats = balloon_stats(vm_id).map_err(|err| format!("{:?}", err))?;
+    request_balloon_stats(vm_id).map_err(|err| format!("{:?}", err))?;
+    if stats.is_empty() {
+        return Ok(format!("VM[{}] has not reported statistics yet\n", vm_id));
+    }
+    let mut output = String::new();
+    for (tag, value) in stats {
+        let _ = match BALLOON_STATS.get(tag as usize) {
+            Some(name) => writeln!(output, "{:<24} {}", name, value),
+            None => writeln!(output, "tag {:<20} {}", tag, value),
+        };
+    }
+    Ok(output)
+}
+
+fn rtc(vm_id: usize) -> String {
+    const NANOS_PER_SEC: i64 = 1_000_000_000;
+    let vm_time = vm_time_nanos(vm_id) as i64;
+    let offset = vm_time - host_time_nanos() as i64;
+    format!(
+        "VM[{}] RTC: {}s since the epoch, {:+}s from the hypervisor\n",
+        vm_id,
+        vm_time / NANOS_PER_SEC,
+        offset / NANOS_PER_SEC
+    )
+}
+
+fn mmio_trace(vm_id: usize) -> String {
+    let mut output = String::new();
+    for access in take_mmio_trace(vm_id) {
+        let _ = writeln!(output, "{}", access);
+    }
+    output
+}
+
+fn uart_output(vm_id: usize) -> String {
+    let mut output = String::from_utf8_lossy(&take_uart_output(vm_id)).into_owned();
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+fn vsock_conns() -> String {
+    let mut output = String::new();
+    for (number, conn) in VSOCK_CONNS.lock().iter() {
+        let _ = writeln!(output, "#{} {}", number, describe(conn));
+    }
+    output
+}
+
+fn vsock_conn(number: usize) -> Result<VsockConn, String> {
+    VSOCK_CONNS
+        .lock()
+        .get(&number)
+        .copied()
+        .ok_or_else(|| format!("no vsock connection #{}", number))
+}
+
+fn describe(conn: &VsockConn) -> String {
+    format!(
+        "VM[{}] port {} - hypervisor port {}",
+        conn.vm_id, conn.guest_port, conn.host_port
+    )
+}
+
+/// The vsock service of the shell, printing on the `hv` console the text received on the
+/// connections opened by the `vsock` commands.
+struct ShellVsock;
+
+impl VsockHandler for ShellVsock {
+    fn connected(&self, conn: &VsockConn) {
+        let number = NEXT_VSOCK_CONN.fetch_add(1, Ordering::Relaxed);
+        VSOCK_CONNS.lock().insert(number, *conn);
+        print(&format!(
+            "vsock #{} connected: {}\n",
+            number,
+            describe(conn)
+        ));
+    }
+
+    fn received(&self, conn: &VsockConn, data: &[u8]) {
+        let Some(number) = find_vsock_conn(conn) else {
+            return;
+        };
+        let mut output = String::new();
+        for line in String::from_utf8_lossy(data).lines() {
+            let _ = writeln!(output, "vsock #{} < {}", number, line);
+        }
+        print(&output);
+    }
+
+    fn closed(&self, conn: &VsockConn) {
+        if let Some(number) = find_vsock_conn(conn) {
+            VSOCK_CONNS.lock().remove(&number);
+            print(&format!("vsock #{} closed by the guest\n", number));
+        }
+    }
+}
+
+fn find_vsock_conn(conn: &VsockConn) -> Option<usize> {
+    VSOCK_CONNS
+        .lock()
+        .iter()
+        .find_map(|(&number, c)| (c == conn).then_some(number))
+}
+
+/// Prints output of the shell not answering a command line, on the `hv` console.
+fn print(output: &str) {
+    if let Some(&id) = CONSOLE_ID.get() {
+        console::write(id, output.as_bytes());
+    }
+}
+
+/// Receives the input of the `hv` console, executing each line once complete.
+fn console_input(data: &[u8]) {
+    for &byte in data {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut *CONSOLE_LINE.lock());
+                if line.is_empty() {
+                    continue;
+                }
+                let line = String::from_utf8_lossy(&line);
+                print(&format!("> {}\n{}", line, execute(&line)));
+            }
+            // Backspace and delete.
+            0x08 | 0x7f => {
+                CONSOLE_LINE.lock().pop();
+            }
+            _ => {
+                let mut line = CONSOLE_LINE.lock();
+                if line.len() < MAX_LINE_LEN {
+                    line.push(byte);
+                }
+            }
+        }
+    }
+}
+
+/// Registers the `hv` console of the shell, once the guest consoles are registered.
+pub fn init() {
+    if !console::is_active() {
+        return;
+    }
+    let id = console::register("hv".into(), Box::new(console_input));
+    CONSOLE_ID.call_once(|| id);
+}
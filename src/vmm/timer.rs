@@ -1,52 +1,268 @@
 extern crate alloc;
 
-use core::sync::atomic::AtomicUsize;
+use core::fmt::Write;
 use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
 
-use std::os::arceos::modules::{axconfig, axhal};
+use std::os::arceos::modules::{axconfig, axhal, axtask};
 
 use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
 use kspin::SpinNoIrq;
 use lazyinit::LazyInit;
-use timer_list::{TimeValue, TimerEvent, TimerList};
+use timer_list::TimeValue;
+
+use crate::vmm::ipi;
 
 static TOKEN: AtomicUsize = AtomicUsize::new(0);
 const PERIODIC_INTERVAL_NANOS: u64 = axhal::time::NANOS_PER_SEC / axconfig::TICKS_PER_SEC as u64;
 
+/// The interval between two dumps of the timer statistics to the log, see [`start_stats_dump`].
+const STATS_DUMP_INTERVAL_NANOS: u64 = 60 * axhal::time::NANOS_PER_SEC;
+
+/// The bit position of the owning CPU ID in a timer token,
+/// the bits below it hold a global sequence number.
+const TOKEN_CPU_SHIFT: u32 = usize::BITS - 16;
+const TOKEN_SEQ_MASK: usize = (1 << TOKEN_CPU_SHIFT) - 1;
+
+/// Tokens of the timer events that have been migrated away from the CPU encoded in them,
+/// mapped to the CPU whose timer list currently holds them.
+///
+/// Its lock is also held by [`cancel_timer`] and [`migrate_vcpu_timers`] while they access
+/// the timer lists, before locking any of them, so that an event is never canceled while it
+/// is in flight between two lists.
+static MIGRATED_TOKENS: SpinNoIrq<BTreeMap<usize, usize>> = SpinNoIrq::new(BTreeMap::new());
+
 /// Represents a timer event in the virtual machine monitor (VMM).
 ///
 /// This struct holds a unique token for the timer and a callback function
 /// that will be executed when the timer expires.
 pub struct VmmTimerEvent {
-    // Unique identifier for the timer event
+    // Unique identifier for the timer event, encoding the CPU it was registered on
     token: usize,
+    // The (VM ID, vCPU ID) pair owning the timer event, if any
+    owner: Option<(usize, usize)>,
+    // Whether the timer event has been migrated from the CPU encoded in its token
+    migrated: bool,
     // Callback function to be executed when the timer expires
     timer_callback: Box<dyn FnOnce(TimeValue) + Send + 'static>,
 }
 
 impl VmmTimerEvent {
-    fn new<F>(token: usize, f: F) -> Self
+    fn new<F>(token: usize, owner: Option<(usize, usize)>, f: F) -> Self
     where
         F: FnOnce(TimeValue) + Send + 'static,
     {
         Self {
-            token: token,
+            token,
+            owner,
+            migrated: false,
             timer_callback: Box::new(f),
         }
     }
 }
 
-impl TimerEvent for VmmTimerEvent {
+impl VmmTimerEvent {
     fn callback(self, now: TimeValue) {
+        if self.migrated {
+            MIGRATED_TOKENS.lock().remove(&self.token);
+        }
         (self.timer_callback)(now)
     }
 }
 
+/// The pending timer events of a CPU.
+///
+/// The events are ordered by deadline, and indexed by token and by owning vCPU, so that
+/// canceling an event or migrating the ones of a vCPU does not walk through the others.
+struct TimerList {
+    /// The pending events, by deadline then token.
+    events: BTreeMap<(TimeValue, usize), VmmTimerEvent>,
+    /// The deadline of each pending event, by token.
+    deadlines: BTreeMap<usize, TimeValue>,
+    /// The tokens of the pending events owned by each (VM ID, vCPU ID) pair.
+    owned: BTreeMap<(usize, usize), BTreeSet<usize>>,
+}
+
+impl TimerList {
+    fn new() -> Self {
+        Self {
+            events: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            owned: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an event expiring at `deadline`.
+    fn set(&mut self, deadline: TimeValue, event: VmmTimerEvent) {
+        self.deadlines.insert(event.token, deadline);
+        if let Some(owner) = event.owner {
+            self.owned.entry(owner).or_default().insert(event.token);
+        }
+        self.events.insert((deadline, event.token), event);
+    }
+
+    /// Removes the event of `token`, returning it along with its deadline if it was pending.
+    fn remove(&mut self, token: usize) -> Option<(TimeValue, VmmTimerEvent)> {
+        let deadline = self.deadlines.remove(&token)?;
+        let event = self.events.remove(&(deadline, token))?;
+        if let Some(owner) = event.owner {
+            if let Some(tokens) = self.owned.get_mut(&owner) {
+                tokens.remove(&token);
+                if tokens.is_empty() {
+                    self.owned.remove(&owner);
+                }
+            }
+        }
+        Some((deadline, event))
+    }
+
+    /// Removes the earliest event if its deadline is not after `now`.
+    fn expire_one(&mut self, now: TimeValue) -> Option<(TimeValue, VmmTimerEvent)> {
+        let &(deadline, token) = self.events.first_key_value()?.0;
+        if deadline > now {
+            return None;
+        }
+        self.remove(token)
+    }
+
+    /// Returns the deadline of the earliest event.
+    fn next_deadline(&self) -> Option<TimeValue> {
+        self.events
+            .first_key_value()
+            .map(|(&(deadline, _), _)| deadline)
+    }
+
+    /// Takes the events owned by the `(VM ID, vCPU ID)` pair `owner` out of the list.
+    fn take_owned(&mut self, owner: (usize, usize)) -> Vec<(TimeValue, VmmTimerEvent)> {
+        let tokens = self.owned.remove(&owner).unwrap_or_default();
+        tokens
+            .into_iter()
+            .filter_map(|token| self.remove(token))
+            .collect()
+    }
+}
+
+#[percpu::def_percpu]
+static TIMER_LIST: LazyInit<SpinNoIrq<TimerList>> = LazyInit::new();
+
+/// Whether the scheduler tick is stopped on the CPU, i.e. its hardware timer was last
+/// programmed while it was idle, see [`scheduler_next_event`].
+#[percpu::def_percpu]
+static TICK_STOPPED: bool = false;
+
+/// The number of buckets in the lateness histogram.
+///
+/// Bucket `0` counts events fired less than 1 us after their deadline, bucket `i` counts events
+/// fired within `[2^(i-1), 2^i)` us after their deadline, and the last bucket counts the rest.
+pub const LATENESS_BUCKETS: usize = 16;
+
+/// Timer statistics of a CPU.
+///
+/// Counters are atomics since events can be registered and canceled from remote CPUs.
+struct TimerStats {
+    registered: AtomicU64,
+    fired: AtomicU64,
+    canceled: AtomicU64,
+    max_lateness_ns: AtomicU64,
+    lateness_histogram: [AtomicU64; LATENESS_BUCKETS],
+}
+
+impl TimerStats {
+    const fn new() -> Self {
+        Self {
+            registered: AtomicU64::new(0),
+            fired: AtomicU64::new(0),
+            canceled: AtomicU64::new(0),
+            max_lateness_ns: AtomicU64::new(0),
+            lateness_histogram: [const { AtomicU64::new(0) }; LATENESS_BUCKETS],
+        }
+    }
+
+    /// Records an event fired `lateness_ns` nanoseconds after its deadline.
+    fn record_fired(&self, lateness_ns: u64) {
+        let lateness_us = lateness_ns / 1000;
+        let bucket = if lateness_us == 0 {
+            0
+        } else {
+            (lateness_us.ilog2() as usize + 1).min(LATENESS_BUCKETS - 1)
+        };
+        self.fired.fetch_add(1, Ordering::Relaxed);
+        self.lateness_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+        self.max_lateness_ns
+            .fetch_max(lateness_ns, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TimerStatsSnapshot {
+        TimerStatsSnapshot {
+            registered: self.registered.load(Ordering::Relaxed),
+            fired: self.fired.load(Ordering::Relaxed),
+            canceled: self.canceled.load(Ordering::Relaxed),
+            max_lateness_ns: self.max_lateness_ns.load(Ordering::Relaxed),
+            lateness_histogram: core::array::from_fn(|i| {
+                self.lateness_histogram[i].load(Ordering::Relaxed)
+            }),
+        }
+    }
+
+    fn reset(&self) {
+        self.registered.store(0, Ordering::Relaxed);
+        self.fired.store(0, Ordering::Relaxed);
+        self.canceled.store(0, Ordering::Relaxed);
+        self.max_lateness_ns.store(0, Ordering::Relaxed);
+        for bucket in &self.lateness_histogram {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A point-in-time copy of the timer statistics of a CPU, returned by [`stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct TimerStatsSnapshot {
+    /// The number of timer events registered on the CPU.
+    pub registered: u64,
+    /// The number of timer events fired on the CPU.
+    pub fired: u64,
+    /// The number of timer events canceled before firing.
+    pub canceled: u64,
+    /// The largest delay observed between a deadline and the firing of its event.
+    pub max_lateness_ns: u64,
+    /// The histogram of the delays between deadlines and the firing of their events,
+    /// see [`LATENESS_BUCKETS`] for the bucket layout.
+    pub lateness_histogram: [u64; LATENESS_BUCKETS],
+}
+
 #[percpu::def_percpu]
-static TIMER_LIST: LazyInit<SpinNoIrq<TimerList<VmmTimerEvent>>> = LazyInit::new();
+static TIMER_STATS: TimerStats = TimerStats::new();
+
+/// Returns the timer statistics of the specified CPU.
+fn stats_of(cpu_id: usize) -> &'static TimerStats {
+    // All the counters are atomics, so it is safe to access them remotely.
+    unsafe { TIMER_STATS.remote_ref_raw(cpu_id) }
+}
+
+/// Returns the timer list of the specified CPU.
+fn timer_list_of(cpu_id: usize) -> &'static SpinNoIrq<TimerList> {
+    // The timer list is only mutated behind its lock, so it is safe to access remotely.
+    unsafe { TIMER_LIST.remote_ref_raw(cpu_id) }
+}
+
+/// Returns the ID of the CPU that a timer token was registered on.
+pub fn token_cpu(token: usize) -> usize {
+    token >> TOKEN_CPU_SHIFT
+}
+
+fn this_cpu_id() -> usize {
+    axhal::cpu::this_cpu_id()
+}
 
 /// Registers a new timer that will execute at the specified deadline
 ///
+/// If the new timer is the earliest one on the current CPU, the one-shot hardware timer
+/// is re-programmed so that it is not delayed until the next scheduler tick.
+///
 /// # Arguments
 /// - `deadline`: The absolute time in nanoseconds when the timer should trigger
 /// - `handler`: The callback function to execute when the timer expires
@@ -57,47 +273,290 @@ pub fn register_timer<F>(deadline: u64, handler: F) -> usize
 where
     F: FnOnce(TimeValue) + Send + 'static,
 {
-    let timer_list = unsafe { TIMER_LIST.current_ref_mut_raw() };
-    let mut timers = timer_list.lock();
-    let token = TOKEN.fetch_add(1, Ordering::Release);
-    let event = VmmTimerEvent::new(token, handler);
-    timers.set(TimeValue::from_nanos(deadline as u64), event);
+    register_timer_on(this_cpu_id(), None, deadline, handler)
+}
+
+/// Registers a new timer owned by a vCPU on the current CPU,
+/// which follows the vCPU when it migrates, see [`migrate_vcpu_timers`].
+///
+/// # Arguments
+/// - `vm_id`: The ID of the VM owning the timer
+/// - `vcpu_id`: The ID of the vCPU owning the timer
+/// - `deadline`: The absolute time in nanoseconds when the timer should trigger
+/// - `handler`: The callback function to execute when the timer expires
+///
+/// # Returns
+/// A unique token that can be used to cancel this timer later
+pub fn register_vcpu_timer<F>(vm_id: usize, vcpu_id: usize, deadline: u64, handler: F) -> usize
+where
+    F: FnOnce(TimeValue) + Send + 'static,
+{
+    register_timer_on(this_cpu_id(), Some((vm_id, vcpu_id)), deadline, handler)
+}
+
+/// Registers a new timer on the specified CPU.
+///
+/// When registering the earliest timer of a remote CPU, that CPU is kicked so that it
+/// re-programs its hardware timer, see [`ipi`].
+///
+/// # Arguments
+/// - `cpu_id`: The ID of the CPU whose timer list holds the timer
+/// - `owner`: The (VM ID, vCPU ID) pair owning the timer, if any
+/// - `deadline`: The absolute time in nanoseconds when the timer should trigger
+/// - `handler`: The callback function to execute when the timer expires
+///
+/// # Returns
+/// A unique token that can be used to cancel this timer later, from any CPU
+pub fn register_timer_on<F>(
+    cpu_id: usize,
+    owner: Option<(usize, usize)>,
+    deadline: u64,
+    handler: F,
+) -> usize
+where
+    F: FnOnce(TimeValue) + Send + 'static,
+{
+    let mut timers = timer_list_of(cpu_id).lock();
+    let seq = TOKEN.fetch_add(1, Ordering::Release) & TOKEN_SEQ_MASK;
+    let token = (cpu_id << TOKEN_CPU_SHIFT) | seq;
+    let event = VmmTimerEvent::new(token, owner, handler);
+    let deadline = TimeValue::from_nanos(deadline);
+    let is_earliest = timers.next_deadline().is_none_or(|next| deadline < next);
+    timers.set(deadline, event);
+    drop(timers);
+    stats_of(cpu_id).registered.fetch_add(1, Ordering::Relaxed);
+
+    if is_earliest {
+        if cpu_id == this_cpu_id() {
+            scheduler_next_event();
+        } else {
+            ipi::kick_cpu(cpu_id);
+        }
+    }
     token
 }
 
 /// Cancels a timer with the specified token.
 ///
+/// The timer can be canceled from any CPU, including after it has been migrated.
+///
 /// # Parameters
 /// - `token`: The unique token of the timer to cancel.
 pub fn cancel_timer(token: usize) {
-    let timer_list = unsafe { TIMER_LIST.current_ref_mut_raw() };
-    let mut timers = timer_list.lock();
-    timers.cancel(|event| event.token == token);
+    let mut tokens = MIGRATED_TOKENS.lock();
+    let cpu_id = tokens.remove(&token).unwrap_or_else(|| token_cpu(token));
+    let found = timer_list_of(cpu_id).lock().remove(token);
+    drop(tokens);
+
+    // Canceling an event which has already fired is a no-op, and is not counted.
+    if found.is_some() {
+        stats_of(cpu_id).canceled.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
-/// Check and process any pending timer events
+/// Moves the pending timer events owned by a vCPU from the timer list of `from_cpu`
+/// to the timer list of `to_cpu`, generally called when the vCPU is scheduled on a new CPU.
+///
+/// The tokens of the migrated timer events are kept, so they can still be canceled.
+pub fn migrate_vcpu_timers(vm_id: usize, vcpu_id: usize, from_cpu: usize, to_cpu: usize) {
+    if from_cpu == to_cpu {
+        return;
+    }
+
+    // Hold the migrated tokens from the drain to the insertion, so that `cancel_timer` finds
+    // the events either in the source list or in the destination list. The two lists are
+    // never locked together, since two vCPUs may migrate in opposite directions.
+    let mut tokens = MIGRATED_TOKENS.lock();
+
+    // Take the events out of the source list first and insert them into the destination list
+    // afterwards, only the events of the vCPU being moved.
+    let migrated = timer_list_of(from_cpu).lock().take_owned((vm_id, vcpu_id));
+
+    if migrated.is_empty() {
+        return;
+    }
+    debug!(
+        "Migrating {} timer events of VM[{}] VCpu[{}] from CPU {} to CPU {}",
+        migrated.len(),
+        vm_id,
+        vcpu_id,
+        from_cpu,
+        to_cpu
+    );
+
+    insert_migrated(
+        &mut timer_list_of(to_cpu).lock(),
+        &mut tokens,
+        to_cpu,
+        migrated,
+    );
+    drop(tokens);
+
+    if to_cpu == this_cpu_id() {
+        scheduler_next_event();
+    }
+}
+
+/// Inserts the events migrated to `to_cpu` into its `timers`, recording in `tokens` the CPU
+/// holding the ones registered on another CPU.
+fn insert_migrated(
+    timers: &mut TimerList,
+    tokens: &mut BTreeMap<usize, usize>,
+    to_cpu: usize,
+    migrated: Vec<(TimeValue, VmmTimerEvent)>,
+) {
+    for (deadline, mut event) in migrated {
+        if token_cpu(event.token) == to_cpu {
+            tokens.remove(&event.token);
+            event.migrated = false;
+        } else {
+            tokens.insert(event.token, to_cpu);
+            event.migrated = true;
+        }
+        timers.set(deadline, event);
+    }
+}
+
+/// Check and process any pending timer events,
+/// then program the hardware timer for the next one.
 pub fn check_events() {
-    let timer_list = unsafe { TIMER_LIST.current_ref_mut_raw() };
+    let cpu_id = this_cpu_id();
+    let timer_list = timer_list_of(cpu_id);
     loop {
-        let now = axhal::time::wall_time();
+        // Deadlines are registered in monotonic time, see `register_timer`.
+        let now = axhal::time::monotonic_time();
         let event = timer_list.lock().expire_one(now);
-        if let Some((_deadline, event)) = event {
-            trace!("pick one {:#?} to handler!!!", _deadline);
+        if let Some((deadline, event)) = event {
+            trace!("pick one {:#?} to handler!!!", deadline);
+            stats_of(cpu_id).record_fired((now - deadline).as_nanos() as u64);
             event.callback(now);
         } else {
             break;
         }
     }
+    scheduler_next_event();
+}
+
+/// Returns the deadline in nanoseconds of the earliest pending timer event on the current CPU.
+pub fn next_deadline() -> Option<u64> {
+    timer_list_of(this_cpu_id())
+        .lock()
+        .next_deadline()
+        .map(|deadline| deadline.as_nanos() as u64)
 }
 
-/// Schedule the next timer event based on the periodic interval
+/// Schedule the next timer event.
+///
+/// The timer is tickless: the one-shot hardware timer is programmed for the earliest
+/// pending timer event on the current CPU. While a task runs, it is capped by the scheduler
+/// tick so that the task can still be preempted at least every periodic interval. An idle CPU
+/// has nothing to preempt, so it is left asleep until its next timer event, if any.
 pub fn scheduler_next_event() {
-    let now_ns = axhal::time::monotonic_time_nanos();
-    let deadline = now_ns + PERIODIC_INTERVAL_NANOS;
+    let tick_deadline = (!axtask::current().is_idle())
+        .then(|| axhal::time::monotonic_time_nanos() + PERIODIC_INTERVAL_NANOS);
+    TICK_STOPPED.write_current(tick_deadline.is_none());
+    let deadline = match (next_deadline(), tick_deadline) {
+        (Some(next), Some(tick)) => next.min(tick),
+        (next, tick) => match next.or(tick) {
+            Some(deadline) => deadline,
+            None => return,
+        },
+    };
     trace!("PHY deadline {} !!!", deadline);
     axhal::time::set_oneshot_timer(deadline);
 }
 
+/// Restarts the scheduler tick of the current CPU if it was stopped while the CPU was idle,
+/// must be called by the tasks running on it, the hypervisor one being switched to once the
+/// timer interrupt returns.
+pub fn restart_tick() {
+    if TICK_STOPPED.read_current() {
+        scheduler_next_event();
+    }
+}
+
+/// The handler of the timer interrupt, which replaces the one of `axruntime` re-arming the
+/// timer every periodic interval.
+///
+/// The timers of the sleeping host tasks are only checked on the scheduler tick, so a host
+/// task must not sleep on an idle CPU: the hypervisor ones wait for their events instead.
+fn handle_timer_irq() {
+    check_events();
+    axtask::on_timer_tick();
+}
+
+/// Hooks the timer interrupt of all the CPUs, once their timer lists are initialized.
+pub fn init() {
+    let irq = axhal::time::TIMER_IRQ_NUM;
+    axhal::irq::unregister_handler(irq);
+    if !axhal::irq::register_handler(irq, handle_timer_irq) {
+        warn!("Failed to register the hypervisor timer interrupt handler");
+    }
+}
+
+/// Returns the timer statistics of the specified CPU.
+pub fn stats(cpu_id: usize) -> TimerStatsSnapshot {
+    stats_of(cpu_id).snapshot()
+}
+
+/// Resets the timer statistics of the specified CPU.
+pub fn reset_stats(cpu_id: usize) {
+    stats_of(cpu_id).reset()
+}
+
+/// Formats the timer statistics of all the CPUs, one line per CPU followed by the non-empty
+/// buckets of its lateness histogram.
+pub fn stats_report() -> String {
+    let mut report = String::new();
+    for cpu_id in 0..axconfig::SMP {
+        let stats = stats(cpu_id);
+        let _ = writeln!(
+            report,
+            "CPU {} timer events: registered {}, fired {}, canceled {}, max lateness {} ns",
+            cpu_id, stats.registered, stats.fired, stats.canceled, stats.max_lateness_ns
+        );
+        for (bucket, count) in stats.lateness_histogram.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let _ = match bucket {
+                0 => writeln!(report, "    lateness < 1 us: {}", count),
+                b if b == LATENESS_BUCKETS - 1 => {
+                    writeln!(report, "    lateness >= {} us: {}", 1u64 << (b - 1), count)
+                }
+                b => writeln!(
+                    report,
+                    "    lateness {}..{} us: {}",
+                    1u64 << (b - 1),
+                    1u64 << b,
+                    count
+                ),
+            };
+        }
+    }
+    report
+}
+
+/// Prints the timer statistics of all the CPUs.
+pub fn dump_stats() {
+    for line in stats_report().lines() {
+        info!("{}", line);
+    }
+}
+
+/// Prints the timer statistics of all the CPUs every [`STATS_DUMP_INTERVAL_NANOS`], so that
+/// they can be read from the log whether or not the hypervisor shell is reachable.
+pub fn start_stats_dump() {
+    schedule_stats_dump(axhal::time::monotonic_time_nanos() + STATS_DUMP_INTERVAL_NANOS);
+}
+
+fn schedule_stats_dump(deadline: u64) {
+    register_timer(deadline, move |_now| {
+        dump_stats();
+        schedule_stats_dump(deadline + STATS_DUMP_INTERVAL_NANOS);
+    });
+}
+
 /// Initialize the hypervisor timer system
 pub fn init_percpu() {
     info!("Initing HV Timer...");
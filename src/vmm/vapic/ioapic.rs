@@ -0,0 +1,200 @@
+//! Emulated I/O APIC of a VM.
+//!
+//! The guest accesses the redirection table indirectly, by selecting a register through
+//! `IOREGSEL` and then reading or writing it through `IOWIN`.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+const IOREDTBL: u32 = 0x10;
+
+/// The number of interrupt pins, i.e. of redirection table entries.
+pub const NR_PINS: usize = 24;
+/// Version 0x11, with [`NR_PINS`] redirection entries.
+const IOAPIC_VERSION_VALUE: u32 = 0x11 | ((NR_PINS as u32 - 1) << 16);
+
+const REDIR_VECTOR_MASK: u64 = 0xff;
+const REDIR_DELIVERY_MODE_SHIFT: u64 = 8;
+const REDIR_LOGICAL: u64 = 1 << 11;
+const REDIR_REMOTE_IRR: u64 = 1 << 14;
+const REDIR_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+const REDIR_DEST_SHIFT: u64 = 56;
+/// The bits of a redirection entry writable by the guest.
+const REDIR_WRITABLE: u64 = 0xff00_0000_0001_afff;
+
+/// An interrupt routed by a redirection table entry.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicRoute {
+    pub vector: u8,
+    pub delivery_mode: u8,
+    pub logical: bool,
+    pub level: bool,
+    pub destination: u32,
+}
+
+/// The state of an emulated I/O APIC.
+pub struct VIoApic {
+    id: u32,
+    ioregsel: u32,
+    redirection: [u64; NR_PINS],
+    /// The pins whose input is asserted, for level-triggered interrupts.
+    asserted: u32,
+}
+
+impl VIoApic {
+    /// Creates an I/O APIC with all its pins masked.
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+            ioregsel: 0,
+            redirection: [REDIR_MASKED; NR_PINS],
+            asserted: 0,
+        }
+    }
+
+    /// Handles a guest read of the I/O APIC registers.
+    pub fn handle_read(&self, offset: usize) -> u32 {
+        match offset {
+            IOREGSEL => self.ioregsel,
+            IOWIN => match self.ioregsel {
+                IOAPICID => self.id << 24,
+                IOAPICVER => IOAPIC_VERSION_VALUE,
+                IOAPICARB => self.id << 24,
+                reg if reg >= IOREDTBL && ((reg - IOREDTBL) / 2) < NR_PINS as u32 => {
+                    let entry = self.redirection[((reg - IOREDTBL) / 2) as usize];
+                    if reg % 2 == 0 {
+                        entry as u32
+                    } else {
+                        (entry >> 32) as u32
+                    }
+                }
+                _ => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    /// Handles a guest write of the I/O APIC registers.
+    ///
+    /// # Returns
+    ///
+    /// The interrupt to be delivered if unmasking a pin found its input asserted.
+    pub fn handle_write(&mut self, offset: usize, value: u32) -> Option<IoApicRoute> {
+        match offset {
+            IOREGSEL => self.ioregsel = value & 0xff,
+            IOWIN => match self.ioregsel {
+                IOAPICID => self.id = (value >> 24) & 0xf,
+                reg if reg >= IOREDTBL && ((reg - IOREDTBL) / 2) < NR_PINS as u32 => {
+                    let pin = ((reg - IOREDTBL) / 2) as usize;
+                    let entry = &mut self.redirection[pin];
+                    let new = if reg % 2 == 0 {
+                        (*entry & !0xffff_ffff) | value as u64
+                    } else {
+                        (*entry & 0xffff_ffff) | ((value as u64) << 32)
+                    };
+                    // The remote IRR and delivery status bits are read-only.
+                    *entry = (new & REDIR_WRITABLE) | (*entry & REDIR_REMOTE_IRR);
+                    if self.asserted & (1 << pin) != 0 {
+                        return self.deliver(pin);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        None
+    }
+
+    /// Sets the input level of a pin. Edge-triggered pins deliver on the rising edge,
+    /// level-triggered ones as long as the input is asserted and the guest has completed
+    /// the previous delivery.
+    ///
+    /// # Returns
+    ///
+    /// The interrupt to be delivered, if any.
+    pub fn set_irq(&mut self, pin: usize, asserted: bool) -> Option<IoApicRoute> {
+        if pin >= NR_PINS {
+            return None;
+        }
+        let was_asserted = self.asserted & (1 << pin) != 0;
+        if asserted {
+            self.asserted |= 1 << pin;
+        } else {
+            self.asserted &= !(1 << pin);
+            return None;
+        }
+        let level = self.redirection[pin] & REDIR_LEVEL != 0;
+        if was_asserted && !level {
+            return None;
+        }
+        self.deliver(pin)
+    }
+
+    /// Raises a pin whose input level is not tracked, such as one receiving a forwarded
+    /// physical interrupt. A level-triggered pin is delivered once and then deasserted, the
+    /// device raising it again if still needed once the guest has completed it.
+    ///
+    /// # Returns
+    ///
+    /// The interrupt to be delivered, if any.
+    pub fn pulse_irq(&mut self, pin: usize) -> Option<IoApicRoute> {
+        let route = self.set_irq(pin, true);
+        if route.is_some_and(|route| route.level) {
+            self.asserted &= !(1 << pin);
+        }
+        route
+    }
+
+    /// Delivers the interrupt of a pin, unless masked or awaiting its EOI.
+    fn deliver(&mut self, pin: usize) -> Option<IoApicRoute> {
+        let entry = self.redirection[pin];
+        if entry & (REDIR_MASKED | REDIR_REMOTE_IRR) != 0 {
+            return None;
+        }
+        let level = entry & REDIR_LEVEL != 0;
+        if level {
+            self.redirection[pin] |= REDIR_REMOTE_IRR;
+        } else {
+            self.asserted &= !(1 << pin);
+        }
+        Some(IoApicRoute {
+            vector: (entry & REDIR_VECTOR_MASK) as u8,
+            delivery_mode: ((entry >> REDIR_DELIVERY_MODE_SHIFT) & 0b111) as u8,
+            logical: entry & REDIR_LOGICAL != 0,
+            level,
+            destination: (entry >> REDIR_DEST_SHIFT) as u32,
+        })
+    }
+
+    /// Handles the EOI of a level-triggered interrupt broadcast by a local APIC,
+    /// clearing the remote IRR of the pins using its vector.
+    ///
+    /// # Returns
+    ///
+    /// The pins which have been completed, and the interrupts to be delivered again for
+    /// those whose input is still asserted.
+    pub fn eoi(&mut self, vector: u8) -> (Vec<usize>, Vec<IoApicRoute>) {
+        let mut completed = Vec::new();
+        let mut redeliver = Vec::new();
+        for pin in 0..NR_PINS {
+            let entry = &mut self.redirection[pin];
+            if *entry & REDIR_REMOTE_IRR == 0 || (*entry & REDIR_VECTOR_MASK) as u8 != vector {
+                continue;
+            }
+            *entry &= !REDIR_REMOTE_IRR;
+            completed.push(pin);
+            if self.asserted & (1 << pin) != 0 {
+                redeliver.extend(self.deliver(pin));
+            }
+        }
+        (completed, redeliver)
+    }
+}
@@ -0,0 +1,330 @@
+//! Emulated local APIC of a vCPU.
+//!
+//! The registers are addressed by their x2APIC MSR index. In xAPIC mode, the MMIO offset of a
+//! register in the APIC page is its index shifted left by 4, see [`mmio_offset_to_msr`].
+//!
+//! The LAPIC timer registers are not handled here but by the vCPU's
+//! [`VirtTimer`](crate::vmm::VirtTimer), see [`is_timer_msr`].
+
+/// The base MSR index of the x2APIC registers.
+const MSR_X2APIC_BASE: usize = 0x800;
+const MSR_IA32_TSC_DEADLINE: usize = 0x6e0;
+
+const APIC_ID: usize = 0x802;
+const APIC_VERSION: usize = 0x803;
+const APIC_TPR: usize = 0x808;
+const APIC_PPR: usize = 0x80a;
+const APIC_EOI: usize = 0x80b;
+const APIC_LDR: usize = 0x80d;
+const APIC_DFR: usize = 0x80e;
+const APIC_SVR: usize = 0x80f;
+const APIC_ISR: usize = 0x810;
+const APIC_TMR: usize = 0x818;
+const APIC_IRR: usize = 0x820;
+const APIC_ESR: usize = 0x828;
+const APIC_LVT_CMCI: usize = 0x82f;
+const APIC_ICR: usize = 0x830;
+/// The high half of the ICR, only in xAPIC mode.
+const APIC_ICR_HIGH: usize = 0x831;
+const APIC_LVT_TIMER: usize = 0x832;
+const APIC_LVT_THERMAL: usize = 0x833;
+const APIC_LVT_PERF: usize = 0x834;
+const APIC_LVT_LINT0: usize = 0x835;
+const APIC_LVT_LINT1: usize = 0x836;
+const APIC_LVT_ERROR: usize = 0x837;
+const APIC_TIMER_INIT_COUNT: usize = 0x838;
+const APIC_TIMER_CUR_COUNT: usize = 0x839;
+const APIC_TIMER_DIV_CONF: usize = 0x83e;
+/// Self IPI, only in x2APIC mode.
+const APIC_SELF_IPI: usize = 0x83f;
+
+/// Version 0x14, with 6 LVT entries.
+const APIC_VERSION_VALUE: u32 = 0x14 | (5 << 16);
+const APIC_SVR_ENABLE: u32 = 1 << 8;
+const APIC_LVT_MASKED: u32 = 1 << 16;
+
+/// The interrupt command of a write to the ICR.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipi {
+    pub vector: u8,
+    pub delivery_mode: u8,
+    /// Logical destination mode.
+    pub logical: bool,
+    pub level_assert: bool,
+    pub shorthand: u8,
+    pub destination: u32,
+}
+
+pub const DELIVERY_FIXED: u8 = 0b000;
+pub const DELIVERY_LOWEST_PRIORITY: u8 = 0b001;
+pub const DELIVERY_NMI: u8 = 0b100;
+pub const DELIVERY_INIT: u8 = 0b101;
+pub const DELIVERY_STARTUP: u8 = 0b110;
+
+pub const SHORTHAND_NONE: u8 = 0b00;
+pub const SHORTHAND_SELF: u8 = 0b01;
+pub const SHORTHAND_ALL_INCLUDING_SELF: u8 = 0b10;
+
+/// Converts the offset of a register in the xAPIC MMIO page into its x2APIC MSR index.
+pub fn mmio_offset_to_msr(offset: usize) -> usize {
+    MSR_X2APIC_BASE + (offset >> 4)
+}
+
+/// Returns whether an MSR is one of the LAPIC timer registers.
+pub fn is_timer_msr(msr: usize) -> bool {
+    matches!(
+        msr,
+        APIC_LVT_TIMER
+            | APIC_TIMER_INIT_COUNT
+            | APIC_TIMER_CUR_COUNT
+            | APIC_TIMER_DIV_CONF
+            | MSR_IA32_TSC_DEADLINE
+    )
+}
+
+/// A 256-bit vector register (`ISR`, `TMR`, `IRR`).
+#[derive(Default)]
+struct VectorBitmap([u32; 8]);
+
+impl VectorBitmap {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 32] |= 1 << (vector % 32);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 32] &= !(1 << (vector % 32));
+    }
+
+    fn get(&self, vector: u8) -> bool {
+        self.0[vector as usize / 32] & (1 << (vector % 32)) != 0
+    }
+
+    /// Returns the highest vector set.
+    fn highest(&self) -> Option<u8> {
+        (0..8)
+            .rev()
+            .find(|&i| self.0[i] != 0)
+            .map(|i| (i * 32 + 31 - self.0[i].leading_zeros() as usize) as u8)
+    }
+}
+
+/// The state of an emulated local APIC.
+pub struct VLapic {
+    /// The APIC ID, which is the vCPU ID.
+    id: u32,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    esr: u32,
+    icr: u64,
+    isr: VectorBitmap,
+    tmr: VectorBitmap,
+    irr: VectorBitmap,
+    lvt_cmci: u32,
+    lvt_thermal: u32,
+    lvt_perf: u32,
+    lvt_lint0: u32,
+    lvt_lint1: u32,
+    lvt_error: u32,
+}
+
+impl VLapic {
+    /// Creates a local APIC in its reset state, software disabled.
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            tpr: 0,
+            ldr: 0,
+            dfr: u32::MAX,
+            svr: 0xff,
+            esr: 0,
+            icr: 0,
+            isr: VectorBitmap::default(),
+            tmr: VectorBitmap::default(),
+            irr: VectorBitmap::default(),
+            lvt_cmci: APIC_LVT_MASKED,
+            lvt_thermal: APIC_LVT_MASKED,
+            lvt_perf: APIC_LVT_MASKED,
+            lvt_lint0: APIC_LVT_MASKED,
+            lvt_lint1: APIC_LVT_MASKED,
+            lvt_error: APIC_LVT_MASKED,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns whether the APIC matches a logical destination, in the flat model for xAPIC
+    /// and in the cluster model for x2APIC.
+    pub fn matches_logical(&self, destination: u32, x2apic: bool) -> bool {
+        if x2apic {
+            // The cluster ID is the upper half of the x2APIC ID shifted, the lower half
+            // is a bitmap of the logical IDs within the cluster.
+            let cluster = self.id >> 4;
+            destination >> 16 == cluster && destination & (1 << (self.id & 0xf)) != 0
+        } else {
+            (self.ldr >> 24) & destination != 0
+        }
+    }
+
+    /// Returns the processor priority, the higher of the task priority and the priority class
+    /// of the highest in-service interrupt.
+    fn ppr(&self) -> u32 {
+        let isrv = self.isr.highest().map_or(0, |v| v as u32);
+        if (self.tpr & 0xf0) >= (isrv & 0xf0) {
+            self.tpr & 0xff
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// Accepts an interrupt into the `IRR`. Interrupts are dropped while the APIC is software
+    /// disabled.
+    pub fn accept(&mut self, vector: u8, level: bool) {
+        if self.svr & APIC_SVR_ENABLE == 0 {
+            return;
+        }
+        self.irr.set(vector);
+        if level {
+            self.tmr.set(vector);
+        } else {
+            self.tmr.clear(vector);
+        }
+    }
+
+    /// Returns the highest interrupt in the `IRR` whose priority is above the processor
+    /// priority, if any.
+    pub fn deliverable(&self) -> Option<u8> {
+        self.irr
+            .highest()
+            .filter(|&v| (v as u32 & 0xf0) > (self.ppr() & 0xf0))
+    }
+
+    /// Moves the highest deliverable interrupt from the `IRR` to the `ISR`,
+    /// to be injected into the vCPU.
+    pub fn ack(&mut self) -> Option<u8> {
+        let vector = self.deliverable()?;
+        self.irr.clear(vector);
+        self.isr.set(vector);
+        Some(vector)
+    }
+
+    /// Completes the highest in-service interrupt.
+    ///
+    /// Returns its vector if it is level-triggered, so that the I/O APIC can be notified.
+    fn eoi(&mut self) -> Option<u8> {
+        let vector = self.isr.highest()?;
+        self.isr.clear(vector);
+        self.tmr.get(vector).then_some(vector)
+    }
+
+    /// Reads a register, in x2APIC mode if `x2apic`.
+    ///
+    /// Returns `None` for an unknown register.
+    pub fn read(&self, msr: usize, x2apic: bool) -> Option<u64> {
+        let value = match msr {
+            APIC_ID if x2apic => self.id,
+            APIC_ID => self.id << 24,
+            APIC_VERSION => APIC_VERSION_VALUE,
+            APIC_TPR => self.tpr,
+            APIC_PPR => self.ppr(),
+            APIC_LDR if x2apic => ((self.id >> 4) << 16) | (1 << (self.id & 0xf)),
+            APIC_LDR => self.ldr,
+            APIC_DFR if !x2apic => self.dfr,
+            APIC_SVR => self.svr,
+            r if (APIC_ISR..APIC_ISR + 8).contains(&r) => self.isr.0[r - APIC_ISR],
+            r if (APIC_TMR..APIC_TMR + 8).contains(&r) => self.tmr.0[r - APIC_TMR],
+            r if (APIC_IRR..APIC_IRR + 8).contains(&r) => self.irr.0[r - APIC_IRR],
+            APIC_ESR => self.esr,
+            APIC_LVT_CMCI => self.lvt_cmci,
+            APIC_ICR if x2apic => return Some(self.icr),
+            APIC_ICR => self.icr as u32,
+            APIC_ICR_HIGH if !x2apic => (self.icr >> 32) as u32,
+            APIC_LVT_THERMAL => self.lvt_thermal,
+            APIC_LVT_PERF => self.lvt_perf,
+            APIC_LVT_LINT0 => self.lvt_lint0,
+            APIC_LVT_LINT1 => self.lvt_lint1,
+            APIC_LVT_ERROR => self.lvt_error,
+            _ => return None,
+        };
+        Some(value as u64)
+    }
+
+    /// Writes a register, in x2APIC mode if `x2apic`.
+    ///
+    /// Returns `None` for an unknown or read-only register.
+    pub fn write(&mut self, msr: usize, value: u64, x2apic: bool) -> Option<LapicWrite> {
+        let value32 = value as u32;
+        match msr {
+            // The APIC ID is read-only in x2APIC mode, and kept fixed in xAPIC mode.
+            APIC_ID if !x2apic => {}
+            APIC_TPR => self.tpr = value32 & 0xff,
+            APIC_EOI => {
+                return Some(match self.eoi() {
+                    Some(vector) => LapicWrite::LevelEoi(vector),
+                    None => LapicWrite::Done,
+                });
+            }
+            APIC_LDR if !x2apic => self.ldr = value32 & 0xff00_0000,
+            APIC_DFR if !x2apic => self.dfr = value32 | 0x0fff_ffff,
+            APIC_SVR => self.svr = value32 & 0x13ff,
+            APIC_ESR => self.esr = 0,
+            APIC_LVT_CMCI => self.lvt_cmci = value32,
+            APIC_ICR if x2apic => {
+                self.icr = value;
+                return Some(LapicWrite::Ipi(self.decode_icr(true)));
+            }
+            APIC_ICR => {
+                self.icr = (self.icr & !0xffff_ffff) | value32 as u64;
+                return Some(LapicWrite::Ipi(self.decode_icr(false)));
+            }
+            APIC_ICR_HIGH if !x2apic => {
+                self.icr = (self.icr & 0xffff_ffff) | ((value32 as u64) << 32);
+            }
+            APIC_LVT_THERMAL => self.lvt_thermal = value32,
+            APIC_LVT_PERF => self.lvt_perf = value32,
+            APIC_LVT_LINT0 => self.lvt_lint0 = value32,
+            APIC_LVT_LINT1 => self.lvt_lint1 = value32,
+            APIC_LVT_ERROR => self.lvt_error = value32,
+            APIC_SELF_IPI if x2apic => {
+                return Some(LapicWrite::Ipi(Ipi {
+                    vector: value as u8,
+                    delivery_mode: DELIVERY_FIXED,
+                    logical: false,
+                    level_assert: true,
+                    shorthand: SHORTHAND_SELF,
+                    destination: 0,
+                }));
+            }
+            _ => return None,
+        }
+        Some(LapicWrite::Done)
+    }
+
+    fn decode_icr(&self, x2apic: bool) -> Ipi {
+        let icr = self.icr;
+        Ipi {
+            vector: icr as u8,
+            delivery_mode: ((icr >> 8) & 0b111) as u8,
+            logical: icr & (1 << 11) != 0,
+            level_assert: icr & (1 << 14) != 0,
+            shorthand: ((icr >> 18) & 0b11) as u8,
+            destination: if x2apic {
+                (icr >> 32) as u32
+            } else {
+                (icr >> 56) as u32
+            },
+        }
+    }
+}
+
+/// The side effect of a register write.
+pub enum LapicWrite {
+    Done,
+    /// A level-triggered interrupt has been completed.
+    LevelEoi(u8),
+    /// An IPI has been requested through the ICR.
+    Ipi(Ipi),
+}
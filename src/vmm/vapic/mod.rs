@@ -0,0 +1,367 @@
+//! Virtual local APICs and I/O APIC for x86_64 guests.
+//!
+//! A VM owning an emulated I/O APIC (an `emu_devices` entry of type
+//! [`EmuType::InterruptController`]) gets its own interrupt controllers instead of sharing the
+//! host's, so that several x86 guests can run concurrently:
+//! * a [`VLapic`] per vCPU, accessed either through the xAPIC MMIO page (an `emu_devices` entry
+//!   of type [`EmuType::LocalApic`]) or through the x2APIC MSRs, with the timer registers handled
+//!   by the vCPU's [`VirtTimer`];
+//! * a [`VIoApic`] per VM, whose redirection table routes the GSIs, including the physical
+//!   interrupts forwarded to the VM, to the local APICs.
+//!
+//! The MSIs of the PCI functions of the VM target the local APICs directly, through the
+//! destination in their address and the vector in their data, see [`VApic::deliver_msi`].
+//!
+//! Interrupts are accepted into the `IRR` of the target local APIC, and the highest deliverable
+//! one is injected into the vCPU by its own task right before it enters the guest.
+//!
+//! [`EmuType::InterruptController`]: devices::EmuType::InterruptController
+//! [`EmuType::LocalApic`]: devices::EmuType::LocalApic
+
+extern crate alloc;
+
+mod ioapic;
+mod lapic;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use std::os::arceos::modules::axtask::{self, TaskExtRef};
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
+use kspin::SpinNoIrq;
+
+use crate::vmm::devices::{self, EmuDevice};
+use crate::vmm::{VirtTimer, irq, vcpus};
+use ioapic::{IoApicRoute, VIoApic};
+use lapic::{Ipi, LapicWrite, VLapic};
+
+/// The x2APIC MSR range.
+const MSR_X2APIC_RANGE: core::ops::Range<usize> = 0x800..0x900;
+
+/// The address window of the MSIs, `0xfeeX_XXXX`.
+const MSI_ADDRESS_MASK: u64 = 0xfff0_0000;
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u64 = 12;
+const MSI_ADDRESS_DEST_MODE_LOGICAL: u64 = 1 << 2;
+const MSI_DATA_DELIVERY_MODE_SHIFT: u32 = 8;
+
+/// The physical destination broadcasting to all the local APICs, in xAPIC and x2APIC modes.
+const BROADCAST_XAPIC: u32 = 0xff;
+const BROADCAST_X2APIC: u32 = u32::MAX;
+
+/// The interrupt controllers of a VM.
+pub struct VApic {
+    vm_id: usize,
+    lapic_base: GuestPhysAddr,
+    lapic_size: usize,
+    ioapic_base: GuestPhysAddr,
+    ioapic_size: usize,
+    ioapic: SpinNoIrq<VIoApic>,
+    lapics: Vec<SpinNoIrq<VLapic>>,
+}
+
+impl VApic {
+    fn new(
+        vm_id: usize,
+        vcpu_num: usize,
+        (lapic_base, lapic_size): (GuestPhysAddr, usize),
+        (ioapic_base, ioapic_size): (GuestPhysAddr, usize),
+    ) -> Self {
+        Self {
+            vm_id,
+            lapic_base,
+            lapic_size,
+            ioapic_base,
+            ioapic_size,
+            ioapic: SpinNoIrq::new(VIoApic::new()),
+            lapics: (0..vcpu_num)
+                .map(|id| SpinNoIrq::new(VLapic::new(id as u32)))
+                .collect(),
+        }
+    }
+
+    fn in_lapic(&self, addr: GuestPhysAddr) -> bool {
+        (self.lapic_base..self.lapic_base + self.lapic_size).contains(&addr)
+    }
+
+    fn lapic_write(&self, vcpu_id: usize, msr: usize, value: u64, x2apic: bool) -> bool {
+        let result = self.lapics[vcpu_id].lock().write(msr, value, x2apic);
+        match result {
+            None => return false,
+            Some(LapicWrite::Done) => {}
+            Some(LapicWrite::LevelEoi(vector)) => self.ioapic_eoi(vector),
+            Some(LapicWrite::Ipi(ipi)) => self.send_ipi(vcpu_id, ipi, x2apic),
+        }
+        true
+    }
+
+    /// Forwards the EOI of a level-triggered interrupt to the I/O APIC.
+    fn ioapic_eoi(&self, vector: u8) {
+        let (completed, redeliver) = self.ioapic.lock().eoi(vector);
+        for pin in completed {
+            irq::complete_irq(self.vm_id, irq::FIRST_DEVICE_IRQ + pin);
+        }
+        for route in redeliver {
+            self.deliver_route(route);
+        }
+    }
+
+    /// Returns the vCPUs matching a destination.
+    fn destinations(&self, destination: u32, logical: bool, x2apic: bool) -> Vec<usize> {
+        let broadcast = if x2apic {
+            BROADCAST_X2APIC
+        } else {
+            BROADCAST_XAPIC
+        };
+        (0..self.lapics.len())
+            .filter(|&vcpu_id| {
+                let lapic = self.lapics[vcpu_id].lock();
+                if logical {
+                    lapic.matches_logical(destination, x2apic)
+                } else {
+                    destination == broadcast || lapic.id() == destination
+                }
+            })
+            .collect()
+    }
+
+    /// Sends an IPI requested by a write to the ICR of `source`.
+    fn send_ipi(&self, source: usize, ipi: Ipi, x2apic: bool) {
+        let targets: Vec<usize> = match ipi.shorthand {
+            lapic::SHORTHAND_NONE => self.destinations(ipi.destination, ipi.logical, x2apic),
+            lapic::SHORTHAND_SELF => vec![source],
+            lapic::SHORTHAND_ALL_INCLUDING_SELF => (0..self.lapics.len()).collect(),
+            // All excluding self.
+            _ => (0..self.lapics.len()).filter(|&i| i != source).collect(),
+        };
+        trace!(
+            "VM[{}] VCpu[{}] IPI {:x?} to {:?}",
+            self.vm_id, source, ipi, targets
+        );
+        match ipi.delivery_mode {
+            lapic::DELIVERY_FIXED => {
+                for vcpu_id in targets {
+                    self.deliver(vcpu_id, ipi.vector, false);
+                }
+            }
+            lapic::DELIVERY_LOWEST_PRIORITY => {
+                if let Some(&vcpu_id) = targets.first() {
+                    self.deliver(vcpu_id, ipi.vector, false);
+                }
+            }
+            // INIT is implied by the startup IPI which follows it,
+            // and its de-assert variant is a no-op on modern processors.
+            lapic::DELIVERY_INIT => {
+                if ipi.level_assert {
+                    debug!("VM[{}] VCpu[{}] INIT {:?}", self.vm_id, source, targets);
+                }
+            }
+            lapic::DELIVERY_STARTUP => {
+                for vcpu_id in targets {
+                    let entry = GuestPhysAddr::from((ipi.vector as usize) << 12);
+                    vcpus::start_vcpu(self.vm_id, vcpu_id, entry);
+                }
+            }
+            lapic::DELIVERY_NMI => {
+                warn!(
+                    "VM[{}] VCpu[{}] NMI IPIs are not supported",
+                    self.vm_id, source
+                );
+            }
+            mode => {
+                warn!(
+                    "VM[{}] VCpu[{}] unsupported IPI delivery mode {:#b}",
+                    self.vm_id, source, mode
+                );
+            }
+        }
+    }
+
+    /// Delivers an interrupt routed by the I/O APIC, or signalled by a message.
+    fn deliver_route(&self, route: IoApicRoute) {
+        let targets = self.destinations(route.destination, route.logical, false);
+        let targets = match route.delivery_mode {
+            lapic::DELIVERY_FIXED => &targets[..],
+            lapic::DELIVERY_LOWEST_PRIORITY => &targets[..targets.len().min(1)],
+            mode => {
+                warn!(
+                    "VM[{}] unsupported interrupt delivery mode {:#b}",
+                    self.vm_id, mode
+                );
+                return;
+            }
+        };
+        for &vcpu_id in targets {
+            self.deliver(vcpu_id, route.vector, route.level);
+        }
+    }
+
+    /// Accepts an interrupt into the local APIC of a vCPU, and wakes the vCPU up.
+    fn deliver(&self, vcpu_id: usize, vector: u8, level: bool) {
+        self.lapics[vcpu_id].lock().accept(vector, level);
+        vcpus::notify_vcpu(self.vm_id, vcpu_id);
+    }
+
+    /// Raises a GSI whose input level is not tracked, such as a forwarded physical interrupt.
+    pub fn raise_gsi(&self, gsi: usize) {
+        let route = self.ioapic.lock().pulse_irq(gsi);
+        if let Some(route) = route {
+            self.deliver_route(route);
+        }
+    }
+
+    /// Delivers an MSI whose message is `address` and `data`.
+    ///
+    /// Returns `false` if the address is not in the MSI window of the local APICs.
+    pub fn deliver_msi(&self, address: u64, data: u32) -> bool {
+        if address & MSI_ADDRESS_MASK != MSI_ADDRESS_BASE {
+            return false;
+        }
+        // The trigger mode is ignored: no EOI is expected for an MSI, which is accepted as
+        // edge-triggered.
+        self.deliver_route(IoApicRoute {
+            vector: data as u8,
+            delivery_mode: ((data >> MSI_DATA_DELIVERY_MODE_SHIFT) & 0x7) as u8,
+            logical: address & MSI_ADDRESS_DEST_MODE_LOGICAL != 0,
+            level: false,
+            destination: ((address >> MSI_ADDRESS_DEST_SHIFT) & 0xff) as u32,
+        });
+        true
+    }
+
+    /// Accepts the interrupts queued for a vCPU, and picks the highest deliverable one to be
+    /// injected. Must be called on the vCPU's own task, right before it enters the guest.
+    pub fn vcpu_enter(&self, vcpu_id: usize, pending: VecDeque<usize>) -> Option<usize> {
+        let mut lapic = self.lapics[vcpu_id].lock();
+        for vector in pending {
+            lapic.accept(vector as u8, false);
+        }
+        lapic.ack().map(|vector| vector as usize)
+    }
+
+    /// Returns whether an interrupt is waiting to be injected into a vCPU.
+    pub fn has_deliverable(&self, vcpu_id: usize) -> bool {
+        self.lapics[vcpu_id].lock().deliverable().is_some()
+    }
+}
+
+impl EmuDevice for VApic {
+    fn name(&self) -> &str {
+        "vAPIC"
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        let mut regions = vec![self.ioapic_base..self.ioapic_base + self.ioapic_size];
+        if self.lapic_size != 0 {
+            regions.push(self.lapic_base..self.lapic_base + self.lapic_size);
+        }
+        regions
+    }
+
+    fn sysregs(&self) -> Vec<Range<usize>> {
+        vec![MSR_X2APIC_RANGE]
+    }
+
+    fn handle_mmio_read(&self, vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        if self.in_lapic(addr) {
+            let msr = lapic::mmio_offset_to_msr(addr - self.lapic_base);
+            let value = if lapic::is_timer_msr(msr) {
+                with_current_vtimer(|vtimer| vtimer.handle_sysreg_read(msr))
+            } else {
+                self.lapics[vcpu_id].lock().read(msr, false)
+            };
+            return value.unwrap_or(0) as usize;
+        }
+        self.ioapic.lock().handle_read(addr - self.ioapic_base) as usize
+    }
+
+    fn handle_mmio_write(&self, vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        if self.in_lapic(addr) {
+            let msr = lapic::mmio_offset_to_msr(addr - self.lapic_base);
+            if lapic::is_timer_msr(msr) {
+                with_current_vtimer(|vtimer| vtimer.handle_sysreg_write(msr, value as u64));
+            } else {
+                self.lapic_write(vcpu_id, msr, value as u64, false);
+            }
+            return;
+        }
+        let route = self
+            .ioapic
+            .lock()
+            .handle_write(addr - self.ioapic_base, value as u32);
+        if let Some(route) = route {
+            self.deliver_route(route);
+        }
+    }
+
+    /// Handles a trapped guest read of an x2APIC MSR, except for the timer registers which
+    /// are handled by the vCPU's timer.
+    fn handle_sysreg_read(&self, vcpu_id: usize, msr: usize) -> Option<u64> {
+        if !MSR_X2APIC_RANGE.contains(&msr) || lapic::is_timer_msr(msr) {
+            return None;
+        }
+        self.lapics[vcpu_id].lock().read(msr, true)
+    }
+
+    /// Handles a trapped guest write of an x2APIC MSR, except for the timer registers which
+    /// are handled by the vCPU's timer.
+    fn handle_sysreg_write(&self, vcpu_id: usize, msr: usize, value: u64) -> bool {
+        if !MSR_X2APIC_RANGE.contains(&msr) || lapic::is_timer_msr(msr) {
+            return false;
+        }
+        self.lapic_write(vcpu_id, msr, value, true)
+    }
+}
+
+/// Runs `f` on the timer of the current vCPU, which emulates the LAPIC timer registers.
+fn with_current_vtimer<R>(f: impl FnOnce(&VirtTimer) -> R) -> R {
+    // Devices are accessed on the task of the vCPU.
+    f(&axtask::current().task_ext().vtimer)
+}
+
+/// The interrupt controllers of all VMs, stored in a BTreeMap where the key is the VM ID.
+///
+/// Also accessed from interrupt context, by the forwarding of routed interrupts, hence the
+/// IRQ-safe lock.
+static VM_VAPICS: SpinNoIrq<BTreeMap<usize, Arc<VApic>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Creates the interrupt controllers of a VM.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM.
+/// * `vcpu_num` - The number of vCPUs of the VM.
+/// * `lapic` - The guest physical address and size of the xAPIC MMIO page.
+/// * `ioapic` - The guest physical address and size of the I/O APIC.
+pub fn create_vm_vapic(
+    vm_id: usize,
+    vcpu_num: usize,
+    lapic: (GuestPhysAddr, usize),
+    ioapic: (GuestPhysAddr, usize),
+) -> AxResult {
+    info!(
+        "VM[{}] vIOAPIC at {:?}, vLAPIC at {:?}, {} pins",
+        vm_id,
+        ioapic.0,
+        lapic.0,
+        ioapic::NR_PINS
+    );
+    let vapic = Arc::new(VApic::new(vm_id, vcpu_num, lapic, ioapic));
+    devices::register_device(vm_id, vapic.clone())?;
+    VM_VAPICS.lock().insert(vm_id, vapic);
+    Ok(())
+}
+
+/// Removes the interrupt controllers of a VM, called when the VM is torn down.
+pub fn remove_vm_vapic(vm_id: usize) -> Option<Arc<VApic>> {
+    VM_VAPICS.lock().remove(&vm_id)
+}
+
+/// Retrieves the interrupt controllers of a VM, if it has them.
+pub fn get_vm_vapic(vm_id: usize) -> Option<Arc<VApic>> {
+    VM_VAPICS.lock().get(&vm_id).cloned()
+}
@@ -1,38 +1,138 @@
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use kspin::SpinNoIrq;
+use spin::Mutex;
 
 use std::os::arceos::api;
-use std::os::arceos::modules::axtask;
+use std::os::arceos::modules::{axhal, axtask};
 
 use axaddrspace::GuestPhysAddr;
+use axerrno::AxResult;
 use axtask::{AxTaskRef, TaskExtRef, TaskInner, WaitQueue};
 use axvcpu::{AxVCpuExitReason, VCpuState};
 
-use api::sys::ax_terminate;
 use api::task::AxCpuMask;
 
 use crate::task::TaskExt;
-use crate::vmm::{VCpuRef, VMRef};
+#[cfg(target_arch = "x86_64")]
+use crate::vmm::vapic;
+#[cfg(target_arch = "aarch64")]
+use crate::vmm::vgic;
+use crate::vmm::{VCpuRef, VMRef, devices, ipi, timer, vm_list};
+#[cfg(target_arch = "riscv64")]
+use crate::vmm::{vplic, vtimer};
 
 const KERNEL_STACK_SIZE: usize = 0x40000; // 256 KiB
 
-/// A global static BTreeMap that holds the wait queues for vCPUs
+/// A global registry that holds the wait queues for vCPUs
 /// associated with their respective VMs, identified by their VM IDs.
+static VM_VCPU_TASK_WAIT_QUEUE: VMVcpusRegistry = VMVcpusRegistry::new();
+
+/// Represents the set of [`VMVcpus`] of all VMs,
+/// stored in a BTreeMap where the key is the VM ID, protected by a mutex.
+///
+/// Entries are handed out as `Arc`s so that callers never block on a wait queue
+/// (or notify it) while holding the registry lock, which allows vCPUs of different
+/// VMs to be set up, woken and torn down concurrently from different cores.
 ///
-/// TODO: find a better data structure to replace the `static mut`, something like a contional variable.
-static mut VM_VCPU_TASK_WAIT_QUEUE: BTreeMap<usize, VMVcpus> = BTreeMap::new();
+/// Also accessed from interrupt context, by the forwarding of routed interrupts, hence the
+/// IRQ-safe lock.
+struct VMVcpusRegistry {
+    inner: SpinNoIrq<BTreeMap<usize, Arc<VMVcpus>>>,
+}
+
+impl VMVcpusRegistry {
+    /// Creates a new, empty `VMVcpusRegistry`.
+    const fn new() -> Self {
+        Self {
+            inner: SpinNoIrq::new(BTreeMap::new()),
+        }
+    }
+
+    /// Inserts the vCPUs of a VM into the registry.
+    ///
+    /// If the vCPUs of the given VM are already registered, a warning is logged, and the
+    /// registry is left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_id` - The ID of the VM.
+    /// * `vm_vcpus` - The vCPUs of the VM.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(Arc<VMVcpus>)` with the registered vCPUs, or `None` if the VM was already
+    /// registered.
+    fn insert(&self, vm_id: usize, vm_vcpus: VMVcpus) -> Option<Arc<VMVcpus>> {
+        let mut inner = self.inner.lock();
+        if inner.contains_key(&vm_id) {
+            warn!(
+                "VM[{}] vcpus already registered, insert failed, just return ...",
+                vm_id
+            );
+            return None;
+        }
+        let vm_vcpus = Arc::new(vm_vcpus);
+        inner.insert(vm_id, vm_vcpus.clone());
+        Some(vm_vcpus)
+    }
+
+    /// Retrieves the vCPUs of a VM by its ID.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(Arc<VMVcpus>)` if the VM is registered, or `None` if it is not.
+    fn get(&self, vm_id: usize) -> Option<Arc<VMVcpus>> {
+        self.inner.lock().get(&vm_id).cloned()
+    }
+
+    /// Removes the vCPUs of a VM from the registry by its ID.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Some(Arc<VMVcpus>)` if the VM was registered, or `None` if it was not.
+    fn remove(&self, vm_id: usize) -> Option<Arc<VMVcpus>> {
+        self.inner.lock().remove(&vm_id)
+    }
+}
 
 /// A structure representing the vCPUs of a specific VM, including a wait queue
 /// and a list of tasks associated with the vCPUs.
 pub struct VMVcpus {
     // The ID of the VM to which these vCPUs belong.
-    _vm_id: usize,
+    vm_id: usize,
     // A wait queue to manage task scheduling for the vCPUs.
     wait_queue: WaitQueue,
     // A list of tasks associated with the vCPUs of this VM.
-    vcpu_task_list: Vec<AxTaskRef>,
+    vcpu_task_list: Mutex<Vec<AxTaskRef>>,
+    // Virtual interrupts waiting to be injected, indexed by vCPU ID.
+    // Interrupts may be raised from timer callbacks, so IRQs are disabled while holding the lock.
+    pending_irqs: Vec<SpinNoIrq<VecDeque<usize>>>,
+    // The physical CPU each vCPU is running the guest on, `NOT_RUNNING` if none,
+    // indexed by vCPU ID.
+    running_cpus: Vec<AtomicUsize>,
+    // Whether each vCPU has been given a task, indexed by vCPU ID.
+    booted: Vec<AtomicBool>,
+    // Whether the vCPUs are stopping for good, see `stop_vm`.
+    stopping: AtomicBool,
+    // The number of vCPU tasks which have not exited yet.
+    live_tasks: AtomicUsize,
 }
 
+/// The error returned to a guest booting a vCPU which is already on, PSCI `ALREADY_ON`.
+#[cfg(target_arch = "aarch64")]
+const CPU_UP_ALREADY_ON: isize = -4;
+/// The error returned to a guest booting a vCPU which is already on,
+/// SBI `ERR_ALREADY_AVAILABLE`.
+#[cfg(not(target_arch = "aarch64"))]
+const CPU_UP_ALREADY_ON: isize = -6;
+
+/// The value of [`VMVcpus::running_cpus`] for a vCPU not in the guest.
+const NOT_RUNNING: usize = usize::MAX;
+
 impl VMVcpus {
     /// Creates a new `VMVcpus` instance for the given VM.
     ///
@@ -45,9 +145,18 @@ impl VMVcpus {
     /// A new `VMVcpus` instance with an empty task list and a fresh wait queue.
     fn new(vm: VMRef) -> Self {
         Self {
-            _vm_id: vm.id(),
+            vm_id: vm.id(),
             wait_queue: WaitQueue::new(),
-            vcpu_task_list: Vec::with_capacity(vm.vcpu_num()),
+            vcpu_task_list: Mutex::new(Vec::with_capacity(vm.vcpu_num())),
+            pending_irqs: (0..vm.vcpu_num())
+                .map(|_| SpinNoIrq::new(VecDeque::new()))
+                .collect(),
+            running_cpus: (0..vm.vcpu_num())
+                .map(|_| AtomicUsize::new(NOT_RUNNING))
+                .collect(),
+            booted: (0..vm.vcpu_num()).map(|_| AtomicBool::new(false)).collect(),
+            stopping: AtomicBool::new(false),
+            live_tasks: AtomicUsize::new(0),
         }
     }
 
@@ -56,13 +165,21 @@ impl VMVcpus {
     /// # Arguments
     ///
     /// * `vcpu_task` - A reference to the task associated with a vCPU that is to be added.
-    fn add_vcpu_task(&mut self, vcpu_task: AxTaskRef) {
-        self.vcpu_task_list.push(vcpu_task);
+    fn add_vcpu_task(&self, vcpu_task: AxTaskRef) {
+        self.vcpu_task_list.lock().push(vcpu_task);
     }
 
-    /// Blocks the current thread on the wait queue associated with the vCPUs of this VM.
-    fn wait(&self) {
-        self.wait_queue.wait()
+    /// Counts a vCPU task about to be spawned, so that the VM is not torn down before the
+    /// task exits.
+    fn count_vcpu_task(&self) {
+        self.live_tasks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Uncounts an exiting vCPU task.
+    ///
+    /// Returns whether it was the last vCPU task of the VM.
+    fn vcpu_task_exited(&self) -> bool {
+        self.live_tasks.fetch_sub(1, Ordering::SeqCst) == 1
     }
 
     /// Blocks the current thread on the wait queue associated with the vCPUs of this VM
@@ -74,53 +191,302 @@ impl VMVcpus {
         self.wait_queue.wait_until(condition)
     }
 
-    fn notify_one(&mut self) {
+    fn notify_one(&self) {
         self.wait_queue.notify_one(false);
     }
+
+    fn notify_all(&self) {
+        self.wait_queue.notify_all(false);
+    }
+
+    /// Queues a virtual interrupt for the given vCPU.
+    fn push_pending_irq(&self, vcpu_id: usize, vector: usize) {
+        let mut pending = self.pending_irqs[vcpu_id].lock();
+        if !pending.contains(&vector) {
+            pending.push_back(vector);
+        }
+    }
+
+    /// Puts back virtual interrupts which could not be injected yet, ahead of the queue.
+    #[cfg(target_arch = "aarch64")]
+    fn requeue_pending_irqs(&self, vcpu_id: usize, irqs: VecDeque<usize>) {
+        let mut pending = self.pending_irqs[vcpu_id].lock();
+        for vector in irqs.into_iter().rev() {
+            if !pending.contains(&vector) {
+                pending.push_front(vector);
+            }
+        }
+    }
+
+    /// Takes all the virtual interrupts queued for the given vCPU.
+    fn take_pending_irqs(&self, vcpu_id: usize) -> VecDeque<usize> {
+        core::mem::take(&mut *self.pending_irqs[vcpu_id].lock())
+    }
+
+    /// Records the physical CPU the given vCPU is about to run the guest on,
+    /// or `None` once it has exited the guest.
+    fn set_running_cpu(&self, vcpu_id: usize, cpu_id: Option<usize>) {
+        self.running_cpus[vcpu_id].store(cpu_id.unwrap_or(NOT_RUNNING), Ordering::SeqCst);
+    }
+
+    /// Returns the physical CPU the given vCPU is running the guest on, if any.
+    fn running_cpu(&self, vcpu_id: usize) -> Option<usize> {
+        let cpu_id = self.running_cpus[vcpu_id].load(Ordering::SeqCst);
+        (cpu_id != NOT_RUNNING).then_some(cpu_id)
+    }
+
+    /// Wakes the given vCPU up if it is halted, or kicks it out of the guest if it is running
+    /// on another physical CPU, so that it takes its pending interrupts.
+    fn kick(&self, vcpu_id: usize) {
+        match self.running_cpu(vcpu_id) {
+            Some(cpu_id) => ipi::kick_cpu(cpu_id),
+            None => self.notify_all(),
+        }
+    }
+
+    /// Returns whether the vCPUs are stopping for good.
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Marks the given vCPU as booted.
+    ///
+    /// Returns `false` if it already was, or does not exist, in which case it must not be
+    /// given another task.
+    fn set_booted(&self, vcpu_id: usize) -> bool {
+        self.booted.get(vcpu_id).is_some_and(|booted| {
+            booted
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        })
+    }
+
+    /// Returns whether any virtual interrupt is queued for the given vCPU.
+    fn has_pending_irq(&self, vcpu_id: usize) -> bool {
+        !self.pending_irqs[vcpu_id].lock().is_empty()
+    }
 }
 
-/// Blocks the current thread until it is explicitly woken up, using the wait queue
-/// associated with the vCPUs of the specified VM.
+/// Retrieves the vCPUs of the specified VM from the global registry.
+///
+/// # Panics
+///
+/// Panics if the vCPUs of the VM have not been set up by [`setup_vm_primary_vcpu`].
+fn get_vm_vcpus(vm_id: usize) -> Arc<VMVcpus> {
+    VM_VCPU_TASK_WAIT_QUEUE
+        .get(vm_id)
+        .unwrap_or_else(|| panic!("VM[{}] vcpus not found", vm_id))
+}
+
+/// Notifies the primary vCPU task associated with the specified VM to wake up and resume execution.
+/// This function is used to notify the primary vCPU of a VM to start running after the VM has been booted.
 ///
 /// # Arguments
 ///
-/// * `vm_id` - The ID of the VM whose vCPU wait queue is used to block the current thread.
+/// * `vm_id` - The ID of the VM whose vCPUs are to be notified.
 ///
-fn wait(vm_id: usize) {
-    unsafe { VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id) }
-        .unwrap()
-        .wait()
+pub(crate) fn notify_primary_vcpu(vm_id: usize) {
+    // Generally, the primary vCPU is the first and **only** vCPU in the list.
+    get_vm_vcpus(vm_id).notify_one()
 }
 
-/// Blocks the current thread until the provided condition is met, using the wait queue
-/// associated with the vCPUs of the specified VM.
+/// Queues a virtual interrupt for the target vCPU of the specified VM, and wakes the vCPU up
+/// if it is halted, or kicks it out of the guest if it is running on another physical CPU.
+///
+/// The interrupt is injected by the vCPU's own task right before it enters the guest again,
+/// so this function can be called from any CPU, including from timer callbacks.
 ///
 /// # Arguments
 ///
-/// * `vm_id` - The ID of the VM whose vCPU wait queue is used to block the current thread.
-/// * `condition` - A closure that returns a boolean value indicating whether the condition is met.
+/// * `vm_id` - The ID of the VM to which the vCPU belongs.
+/// * `vcpu_id` - The ID of the target vCPU.
+/// * `vector` - The interrupt vector to inject.
 ///
-fn wait_for<F>(vm_id: usize, condition: F)
-where
-    F: Fn() -> bool,
-{
-    unsafe { VM_VCPU_TASK_WAIT_QUEUE.get(&vm_id) }
-        .unwrap()
-        .wait_until(condition)
+/// # Returns
+///
+/// Whether the interrupt was queued, `false` if the VM has not been set up.
+///
+pub(crate) fn inject_interrupt(vm_id: usize, vcpu_id: usize, vector: usize) -> bool {
+    let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.get(vm_id) else {
+        warn!(
+            "VM[{}] not found, dropping irq {} for VCpu[{}]",
+            vm_id, vector, vcpu_id
+        );
+        return false;
+    };
+    vm_vcpus.push_pending_irq(vcpu_id, vector);
+    vm_vcpus.kick(vcpu_id);
+    true
 }
 
-/// Notifies the primary vCPU task associated with the specified VM to wake up and resume execution.
-/// This function is used to notify the primary vCPU of a VM to start running after the VM has been booted.
+/// Stops all the vCPUs of the specified VM for good, e.g. when the guest powers off.
+///
+/// The vCPUs running the guest are kicked out of it and the halted ones are woken up, then
+/// their tasks exit, the last of which tears the VM down.
+fn stop_vm(vm_id: usize) {
+    let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.get(vm_id) else {
+        return;
+    };
+    // Set before looking the running vCPUs up, the vCPUs publishing their physical CPU
+    // before checking it, as for the interrupts.
+    vm_vcpus.stopping.store(true, Ordering::SeqCst);
+    for vcpu_id in 0..vm_vcpus.running_cpus.len() {
+        vm_vcpus.kick(vcpu_id);
+    }
+    vm_vcpus.notify_all();
+}
+
+/// Boots a secondary vCPU of the specified VM on behalf of the guest, e.g. on a startup IPI.
+/// Does nothing if the vCPU has already been booted.
 ///
 /// # Arguments
 ///
-/// * `vm_id` - The ID of the VM whose vCPUs are to be notified.
+/// * `vm_id` - The ID of the VM to which the vCPU belongs.
+/// * `vcpu_id` - The ID of the vCPU to be booted.
+/// * `entry_point` - The entry point of the vCPU.
 ///
-pub(crate) fn notify_primary_vcpu(vm_id: usize) {
-    // Generally, the primary vCPU is the first and **only** vCPU in the list.
-    unsafe { VM_VCPU_TASK_WAIT_QUEUE.get_mut(&vm_id) }
-        .unwrap()
-        .notify_one()
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn start_vcpu(vm_id: usize, vcpu_id: usize, entry_point: GuestPhysAddr) {
+    let Some(vm) = vm_list::get_vm_by_id(vm_id) else {
+        warn!("VM[{}] not found, cannot boot VCpu[{}]", vm_id, vcpu_id);
+        return;
+    };
+    let vm_vcpus = get_vm_vcpus(vm_id);
+    // Several startup IPIs may be sent to the vCPU, possibly from several other vCPUs at once.
+    if !vm_vcpus.set_booted(vcpu_id) {
+        debug!("VM[{}] VCpu[{}] already booted", vm_id, vcpu_id);
+        return;
+    }
+    info!(
+        "VM[{}] boot VCpu[{}] entry_point={:?}",
+        vm_id, vcpu_id, entry_point
+    );
+    vcpu_on(&vm_vcpus, vm.clone(), vcpu_id, entry_point, 0);
+}
+
+/// Injects the virtual interrupts queued for the vCPU, must be called on the vCPU's own task.
+///
+/// For an aarch64 VM with a virtual GIC, this also loads the vCPU's list registers,
+/// which are saved again by [`save_interrupt_state`] once the vCPU exits. For an x86_64 VM
+/// with virtual APICs, the queued interrupts are accepted by the vCPU's local APIC, which
+/// then picks the one to inject. For a riscv64 VM with a virtual PLIC, the external interrupt
+/// line of the vCPU is reflected into `hvip` as well, as is its timer interrupt by the caller.
+fn inject_pending_interrupts(vm_vcpus: &VMVcpus, vcpu: &VCpuRef) {
+    let pending = vm_vcpus.take_pending_irqs(vcpu.id());
+
+    // The timer interrupt is a level reflected by `VirtTimer::vcpu_enter`, the one queued by
+    // the timer event only wakes the vCPU up.
+    #[cfg(target_arch = "riscv64")]
+    let pending: VecDeque<usize> = pending
+        .into_iter()
+        .filter(|&irq| irq != vtimer::IRQ_S_TIMER)
+        .collect();
+
+    #[cfg(target_arch = "riscv64")]
+    if let Some(vplic) = vplic::get_vm_vplic(vm_vcpus.vm_id) {
+        vplic.vcpu_enter(vcpu.id());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if let Some(vapic) = vapic::get_vm_vapic(vm_vcpus.vm_id) {
+        if let Some(vector) = vapic.vcpu_enter(vcpu.id(), pending) {
+            if let Err(err) = vcpu.inject_interrupt(vector) {
+                warn!(
+                    "VCpu[{}] failed to inject irq {}: {:?}",
+                    vcpu.id(),
+                    vector,
+                    err
+                );
+            }
+        }
+        return;
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    if let Some(vgic) = vgic::get_vm_vgic(vm_vcpus.vm_id) {
+        let left = vgic.vcpu_enter(vcpu.id(), pending);
+        vm_vcpus.requeue_pending_irqs(vcpu.id(), left);
+        return;
+    }
+
+    for vector in pending {
+        if let Err(err) = vcpu.inject_interrupt(vector) {
+            warn!(
+                "VCpu[{}] failed to inject irq {}: {:?}",
+                vcpu.id(),
+                vector,
+                err
+            );
+        }
+    }
+}
+
+/// Saves the interrupt controller state of the vCPU after it exits the guest,
+/// must be called on the vCPU's own task.
+fn save_interrupt_state(_vm_vcpus: &VMVcpus, _vcpu_id: usize) {
+    #[cfg(target_arch = "aarch64")]
+    if let Some(vgic) = vgic::get_vm_vgic(_vm_vcpus.vm_id) {
+        vgic.vcpu_exit(_vcpu_id);
+    }
+}
+
+/// Returns whether a virtual interrupt is waiting to be taken by the vCPU,
+/// either queued or already loaded in the virtual interrupt controller.
+fn has_pending_interrupt(vm_vcpus: &VMVcpus, vcpu_id: usize) -> bool {
+    if vm_vcpus.has_pending_irq(vcpu_id) {
+        return true;
+    }
+    #[cfg(target_arch = "aarch64")]
+    if let Some(vgic) = vgic::get_vm_vgic(vm_vcpus.vm_id) {
+        return vgic.has_pending_lr(vcpu_id);
+    }
+    #[cfg(target_arch = "x86_64")]
+    if let Some(vapic) = vapic::get_vm_vapic(vm_vcpus.vm_id) {
+        return vapic.has_deliverable(vcpu_id);
+    }
+    #[cfg(target_arch = "riscv64")]
+    if let Some(vplic) = vplic::get_vm_vplic(vm_vcpus.vm_id) {
+        return vplic.has_pending(vcpu_id);
+    }
+    false
+}
+
+/// Blocks the current vCPU task until a virtual interrupt is queued for it, or the VM stops.
+///
+/// The timer events raising the interrupts of the vCPU expire from the timer interrupt of the
+/// CPU holding them, even if it is idle, and wake the vCPU up, see [`timer`].
+fn wait_for_interrupt(vm_vcpus: &VMVcpus, vcpu_id: usize) {
+    vm_vcpus.wait_until(|| has_pending_interrupt(vm_vcpus, vcpu_id) || vm_vcpus.is_stopping());
+}
+
+/// Moves the pending timer events of the vCPU to the current CPU, if the vCPU task has been
+/// scheduled on another CPU of its `phys_cpu_set` since it last ran on `last_cpu_id`.
+fn follow_vcpu_timers(vm_id: usize, vcpu_id: usize, last_cpu_id: &mut usize) {
+    let cpu_id = axhal::cpu::this_cpu_id();
+    if cpu_id != *last_cpu_id {
+        timer::migrate_vcpu_timers(vm_id, vcpu_id, *last_cpu_id, cpu_id);
+        *last_cpu_id = cpu_id;
+    }
+}
+
+/// Removes the vCPUs of the specified VM from the global registry,
+/// called when the VM is torn down.
+///
+/// Tasks still blocked on the VM's wait queue keep their own reference to it,
+/// so removing the entry never invalidates a wait in progress.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM whose vCPUs are to be removed.
+///
+/// # Returns
+///
+/// Returns the list of vCPU tasks of the removed VM, empty if the VM was not registered.
+pub(crate) fn remove_vm_vcpus(vm_id: usize) -> Vec<AxTaskRef> {
+    VM_VCPU_TASK_WAIT_QUEUE
+        .remove(vm_id)
+        .map(|vm_vcpus| core::mem::take(&mut *vm_vcpus.vcpu_task_list.lock()))
+        .unwrap_or_default()
 }
 
 /// Boot target vCPU on the specified VM.
@@ -128,12 +494,13 @@ pub(crate) fn notify_primary_vcpu(vm_id: usize) {
 ///
 /// # Arguments
 ///
-/// * `vm_id` - The ID of the VM on which the vCPU is to be booted.
+/// * `vm_vcpus` - The registered vCPUs of the VM.
+/// * `vm` - The VM on which the vCPU is to be booted.
 /// * `vcpu_id` - The ID of the vCPU to be booted.
 /// * `entry_point` - The entry point of the vCPU.
 /// * `arg` - The argument to be passed to the vCPU.
 ///
-fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
+fn vcpu_on(vm_vcpus: &VMVcpus, vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
     let vcpu = vm.vcpu_list()[vcpu_id].clone();
     assert_eq!(
         vcpu.state(),
@@ -157,11 +524,7 @@ fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
         vcpu.set_gpr(1, arg);
     }
 
-    let vcpu_task = alloc_vcpu_task(vm.clone(), vcpu);
-
-    unsafe { VM_VCPU_TASK_WAIT_QUEUE.get_mut(&vm.id()) }
-        .unwrap()
-        .add_vcpu_task(vcpu_task);
+    spawn_vcpu_task(vm_vcpus, vm, vcpu);
 }
 
 /// Sets up the primary vCPU for the given VM,
@@ -175,16 +538,68 @@ fn vcpu_on(vm: VMRef, vcpu_id: usize, entry_point: GuestPhysAddr, arg: usize) {
 pub fn setup_vm_primary_vcpu(vm: VMRef) {
     info!("Initializing VM[{}]'s {} vcpus", vm.id(), vm.vcpu_num());
     let vm_id = vm.id();
-    let mut vm_vcpus = VMVcpus::new(vm.clone());
+    let vm_vcpus = VMVcpus::new(vm.clone());
 
     let primary_vcpu_id = 0;
 
     let primary_vcpu = vm.vcpu_list()[primary_vcpu_id].clone();
-    let primary_vcpu_task = alloc_vcpu_task(vm.clone(), primary_vcpu);
-    vm_vcpus.add_vcpu_task(primary_vcpu_task);
-    unsafe {
-        VM_VCPU_TASK_WAIT_QUEUE.insert(vm_id, vm_vcpus);
+    vm_vcpus.set_booted(primary_vcpu_id);
+    // The vCPU task looks the vCPUs of its VM up as soon as it runs.
+    let Some(vm_vcpus) = VM_VCPU_TASK_WAIT_QUEUE.insert(vm_id, vm_vcpus) else {
+        return;
+    };
+    spawn_vcpu_task(&vm_vcpus, vm, primary_vcpu);
+}
+
+/// Spawns the task of a vCPU of a VM whose vCPUs are already registered, counting it so that
+/// the VM is not torn down before the task exits.
+fn spawn_vcpu_task(vm_vcpus: &VMVcpus, vm: VMRef, vcpu: VCpuRef) {
+    vm_vcpus.count_vcpu_task();
+    let vcpu_task = alloc_vcpu_task(vm, vcpu);
+    vm_vcpus.add_vcpu_task(vcpu_task);
+}
+
+/// Runs the vCPU in the guest until its next VM exit, which is returned.
+///
+/// Unlike `AxVM::run_vcpu`, which handles the MMIO exits itself through the devices `axvm`
+/// creates, every exit is returned to [`vcpu_run`], so that it reaches the devices emulated by
+/// [`devices`]. The memory of the VMs is mapped when they are created, so the nested page
+/// faults, also handled by [`vcpu_run`], are only raised by the pages unmapped by a balloon.
+fn run_guest(vcpu: &VCpuRef) -> AxResult<AxVCpuExitReason> {
+    vcpu.bind()?;
+    let exit_reason = vcpu.run();
+    vcpu.unbind()?;
+    exit_reason
+}
+
+/// Extends the `width`-byte value of an MMIO read to the `reg_width`-byte register it is
+/// loaded into, sign-extending it if the load is signed.
+fn extend_mmio_read(value: usize, width: usize, reg_width: usize, signed_ext: bool) -> usize {
+    let truncate = |value: usize, bytes: usize| match bytes * 8 {
+        bits if bits < usize::BITS as usize => value & ((1 << bits) - 1),
+        _ => value,
+    };
+    let value = truncate(value, width);
+    let shift = usize::BITS as usize - (width * 8).min(usize::BITS as usize);
+    if !signed_ext || shift == 0 {
+        return value;
     }
+    truncate((((value << shift) as isize) >> shift) as usize, reg_width)
+}
+
+/// Returns the `width`-byte value of a port read in `AL`/`AX`/`EAX`. As on hardware, an 8 or
+/// 16-bit read keeps the rest of `RAX`, while a 32-bit one clears its upper half.
+fn set_pio_read_result(vcpu: &VCpuRef, _width: usize, value: usize) {
+    #[cfg(target_arch = "x86_64")]
+    let value = match _width {
+        1 | 2 => {
+            let mask = (1 << (_width * 8)) - 1;
+            let rax = vcpu.get_arch_vcpu().regs().rax as usize;
+            (rax & !mask) | (value & mask)
+        }
+        _ => value & 0xffff_ffff,
+    };
+    vcpu.set_gpr(0, value);
 }
 
 /// Allocates arceos task for vcpu, set the task's entry function to [`vcpu_run()`],
@@ -228,27 +643,138 @@ fn alloc_vcpu_task(vm: VMRef, vcpu: VCpuRef) -> AxTaskRef {
 /// This function is the entry point for the vCPU tasks, which are spawned for each vCPU of a VM.
 ///
 /// When the vCPU first starts running, it waits for the VM to be in the running state.
-/// It then enters a loop where it runs the vCPU and handles the various exit reasons.
+/// It then enters a loop where it runs the vCPU and handles the various exit reasons,
+/// until the VM is stopped. The last vCPU task of the VM to exit tears it down.
 fn vcpu_run() {
     let curr = axtask::current();
 
     let vm = curr.task_ext().vm.clone();
     let vcpu = curr.task_ext().vcpu.clone();
+    let vtimer = &curr.task_ext().vtimer;
     let vm_id = vm.id();
     let vcpu_id = vcpu.id();
+    let vm_vcpus = get_vm_vcpus(vm_id);
+    let mut last_cpu_id = axhal::cpu::this_cpu_id();
 
     info!("VM[{}] Vcpu[{}] waiting for running", vm.id(), vcpu.id());
-    wait_for(vm_id, || vm.running());
+    vm_vcpus.wait_until(|| vm.running() || vm_vcpus.is_stopping());
 
     info!("VM[{}] Vcpu[{}] running...", vm.id(), vcpu.id());
 
-    loop {
-        match vm.run_vcpu(vcpu_id) {
-            // match vcpu.run() {
+    while !vm_vcpus.is_stopping() {
+        // The vCPU task may have been woken up on an idle CPU.
+        timer::restart_tick();
+        follow_vcpu_timers(vm_id, vcpu_id, &mut last_cpu_id);
+        // Published before taking the pending interrupts, see `ipi`. The vCPU task is not
+        // migrated before `run_guest` returns, since it does not block in between.
+        vm_vcpus.set_running_cpu(vcpu_id, Some(last_cpu_id));
+        inject_pending_interrupts(&vm_vcpus, &vcpu);
+        #[cfg(target_arch = "riscv64")]
+        vtimer.vcpu_enter();
+
+        let exit_reason = run_guest(&vcpu);
+        vm_vcpus.set_running_cpu(vcpu_id, None);
+        save_interrupt_state(&vm_vcpus, vcpu_id);
+
+        match exit_reason {
             Ok(exit_reason) => match exit_reason {
+                #[cfg(target_arch = "riscv64")]
+                AxVCpuExitReason::Hypercall { nr, args } if nr == vtimer::SBI_EXT_TIME => {
+                    vtimer.handle_sbi_set_timer(args[0]);
+                    // `SBI_SUCCESS`.
+                    vcpu.set_gpr(0, 0);
+                }
+                #[cfg(target_arch = "riscv64")]
+                AxVCpuExitReason::Hypercall { nr, args } if nr == ipi::SBI_EXT_IPI => {
+                    let error = ipi::handle_sbi_send_ipi(
+                        vm_id,
+                        vm.vcpu_num(),
+                        args[0] as usize,
+                        args[1] as usize,
+                    );
+                    vcpu.set_gpr(0, error as usize);
+                }
                 AxVCpuExitReason::Hypercall { nr, args } => {
                     debug!("Hypercall [{}] args {:x?}", nr, args);
                 }
+                AxVCpuExitReason::MmioRead {
+                    addr,
+                    width,
+                    reg,
+                    reg_width,
+                    signed_ext,
+                } => match devices::handle_mmio_read(vm_id, vcpu_id, addr, width.size()) {
+                    Some(value) => vcpu.set_gpr(
+                        reg,
+                        extend_mmio_read(value, width.size(), reg_width.size(), signed_ext),
+                    ),
+                    None => warn!(
+                        "VM[{}] run VCpu[{}] unhandled MmioRead {:?}",
+                        vm_id, vcpu_id, addr
+                    ),
+                },
+                AxVCpuExitReason::MmioWrite { addr, width, data } => {
+                    if !devices::handle_mmio_write(
+                        vm_id,
+                        vcpu_id,
+                        addr,
+                        width.size(),
+                        data as usize,
+                    ) {
+                        warn!(
+                            "VM[{}] run VCpu[{}] unhandled MmioWrite {:?} value {:#x}",
+                            vm_id, vcpu_id, addr, data
+                        );
+                    }
+                }
+                AxVCpuExitReason::IoRead { port, width } => {
+                    match devices::handle_pio_read(vm_id, vcpu_id, port, width.size()) {
+                        Some(value) => set_pio_read_result(&vcpu, width.size(), value),
+                        None => warn!(
+                            "VM[{}] run VCpu[{}] unhandled IoRead {:#x}",
+                            vm_id, vcpu_id, port
+                        ),
+                    }
+                }
+                AxVCpuExitReason::IoWrite { port, width, data } => {
+                    if !devices::handle_pio_write(vm_id, vcpu_id, port, width.size(), data as usize)
+                    {
+                        warn!(
+                            "VM[{}] run VCpu[{}] unhandled IoWrite {:#x} value {:#x}",
+                            vm_id, vcpu_id, port, data
+                        );
+                    }
+                }
+                AxVCpuExitReason::NestedPageFault { addr, access_flags } => {
+                    if !devices::handle_balloon_fault(vm_id, addr) {
+                        warn!(
+                            "VM[{}] run VCpu[{}] unhandled nested page fault at {:?} {:?}, stopping the VM",
+                            vm_id, vcpu_id, addr, access_flags
+                        );
+                        stop_vm(vm_id);
+                    }
+                }
+                AxVCpuExitReason::SysRegRead { addr, reg } => {
+                    match devices::handle_sysreg_read(vm_id, vcpu_id, addr)
+                        .or_else(|| vtimer.handle_sysreg_read(addr))
+                    {
+                        Some(value) => vcpu.set_gpr(reg, value as usize),
+                        None => warn!(
+                            "VM[{}] run VCpu[{}] unhandled SysRegRead {:#x}",
+                            vm_id, vcpu_id, addr
+                        ),
+                    }
+                }
+                AxVCpuExitReason::SysRegWrite { addr, value } => {
+                    if !devices::handle_sysreg_write(vm_id, vcpu_id, addr, value)
+                        && !vtimer.handle_sysreg_write(addr, value)
+                    {
+                        warn!(
+                            "VM[{}] run VCpu[{}] unhandled SysRegWrite {:#x} value {:#x}",
+                            vm_id, vcpu_id, addr, value
+                        );
+                    }
+                }
                 AxVCpuExitReason::FailEntry {
                     hardware_entry_failure_reason,
                 } => {
@@ -262,7 +788,12 @@ fn vcpu_run() {
                 }
                 AxVCpuExitReason::Halt => {
                     debug!("VM[{}] run VCpu[{}] Halt", vm_id, vcpu_id);
-                    wait(vm_id)
+                    // The timer interrupt may still be pending from an earlier deadline.
+                    #[cfg(target_arch = "riscv64")]
+                    if vtimer.is_pending() {
+                        continue;
+                    }
+                    wait_for_interrupt(&vm_vcpus, vcpu_id)
                 }
                 AxVCpuExitReason::Nothing => {}
                 AxVCpuExitReason::CpuDown { _state } => {
@@ -270,7 +801,7 @@ fn vcpu_run() {
                         "VM[{}] run VCpu[{}] CpuDown state {:#x}",
                         vm_id, vcpu_id, _state
                     );
-                    wait(vm_id)
+                    vm_vcpus.wait_until(|| vm_vcpus.is_stopping())
                 }
                 AxVCpuExitReason::CpuUp {
                     target_cpu,
@@ -281,12 +812,23 @@ fn vcpu_run() {
                         "VM[{}]'s VCpu[{}] try to boot target_cpu [{}] entry_point={:x} arg={:#x}",
                         vm_id, vcpu_id, target_cpu, entry_point, arg
                     );
-                    vcpu_on(vm.clone(), target_cpu as _, entry_point, arg as _);
-                    vcpu.set_gpr(0, 0);
+                    if vm_vcpus.set_booted(target_cpu as _) {
+                        vcpu_on(
+                            &vm_vcpus,
+                            vm.clone(),
+                            target_cpu as _,
+                            entry_point,
+                            arg as _,
+                        );
+                        vcpu.set_gpr(0, 0);
+                    } else {
+                        warn!("VM[{}] VCpu[{}] already booted", vm_id, target_cpu);
+                        vcpu.set_gpr(0, CPU_UP_ALREADY_ON as usize);
+                    }
                 }
                 AxVCpuExitReason::SystemDown => {
                     warn!("VM[{}] run VCpu[{}] SystemDown", vm_id, vcpu_id);
-                    ax_terminate()
+                    stop_vm(vm_id)
                 }
                 _ => {
                     warn!("Unhandled VM-Exit");
@@ -294,8 +836,13 @@ fn vcpu_run() {
             },
             Err(err) => {
                 warn!("VM[{}] run VCpu[{}] get error {:?}", vm_id, vcpu_id, err);
-                wait(vm_id)
+                vm_vcpus.wait_until(|| vm_vcpus.is_stopping())
             }
         }
     }
+
+    info!("VM[{}] Vcpu[{}] stopped", vm_id, vcpu_id);
+    if vm_vcpus.vcpu_task_exited() {
+        super::teardown_vm(vm_id);
+    }
 }
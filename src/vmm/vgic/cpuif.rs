@@ -0,0 +1,110 @@
+//! Access to the hardware virtual CPU interface of the current CPU, through the memory-mapped
+//! `GICH` registers of a GICv2 or the `ICH_*_EL2` system registers of a GICv3.
+//!
+//! List register values are handled as 64-bit values, GICv2 ones being zero-extended.
+
+use super::{GicVersion, gich, ich};
+
+impl GicVersion {
+    /// Returns the number of list registers implemented.
+    pub(super) fn num_lrs(self) -> usize {
+        match self {
+            Self::V2 => gich::num_lrs(),
+            Self::V3 => ich::num_lrs(),
+        }
+    }
+
+    /// Reads the list register `index`.
+    pub(super) fn read_lr(self, index: usize) -> u64 {
+        match self {
+            Self::V2 => gich::read_lr(index) as u64,
+            Self::V3 => ich::read_lr(index),
+        }
+    }
+
+    /// Writes the list register `index`.
+    pub(super) fn write_lr(self, index: usize, value: u64) {
+        match self {
+            Self::V2 => gich::write_lr(index, value as u32),
+            Self::V3 => ich::write_lr(index, value),
+        }
+    }
+
+    /// Returns the bitmap of the empty list registers.
+    pub(super) fn empty_lrs(self) -> u64 {
+        match self {
+            Self::V2 => gich::empty_lrs(),
+            Self::V3 => ich::empty_lrs(),
+        }
+    }
+
+    pub(super) fn read_vmcr(self) -> u32 {
+        match self {
+            Self::V2 => gich::read_vmcr(),
+            Self::V3 => ich::read_vmcr(),
+        }
+    }
+
+    pub(super) fn write_vmcr(self, value: u32) {
+        match self {
+            Self::V2 => gich::write_vmcr(value),
+            Self::V3 => ich::write_vmcr(value),
+        }
+    }
+
+    pub(super) fn read_apr(self) -> u32 {
+        match self {
+            Self::V2 => gich::read_apr(),
+            Self::V3 => ich::read_apr(),
+        }
+    }
+
+    pub(super) fn write_apr(self, value: u32) {
+        match self {
+            Self::V2 => gich::write_apr(value),
+            Self::V3 => ich::write_apr(value),
+        }
+    }
+
+    /// Requests a maintenance interrupt once the list registers drain.
+    pub(super) fn set_underflow_irq(self, enable: bool) {
+        match self {
+            Self::V2 => gich::set_underflow_irq(enable),
+            Self::V3 => ich::set_underflow_irq(enable),
+        }
+    }
+
+    /// Builds a list register value for a pending software interrupt.
+    ///
+    /// The source CPU of an SGI is only encoded by a GICv2.
+    pub(super) fn make_lr(self, irq: usize, priority: u8, source: usize) -> u64 {
+        match self {
+            Self::V2 => gich::make_lr(irq, priority, source) as u64,
+            Self::V3 => ich::make_lr(irq, priority),
+        }
+    }
+
+    /// Returns the virtual interrupt ID held by a list register value.
+    pub(super) fn lr_irq(self, lr: u64) -> usize {
+        match self {
+            Self::V2 => gich::lr_irq(lr as u32),
+            Self::V3 => ich::lr_irq(lr),
+        }
+    }
+
+    /// Returns whether a list register value holds a pending interrupt.
+    pub(super) fn lr_is_pending(self, lr: u64) -> bool {
+        match self {
+            Self::V2 => gich::lr_is_pending(lr as u32),
+            Self::V3 => ich::lr_is_pending(lr),
+        }
+    }
+
+    /// Returns whether the current CPU provides this version of the virtual CPU interface.
+    pub(super) fn is_present(self) -> bool {
+        match self {
+            Self::V2 => gich::is_present(),
+            Self::V3 => ich::is_present(),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! GICv2 virtual interface control registers (`GICH`) of the current CPU.
+//!
+//! Every CPU accesses its own banked copy of the `GICH` registers at the same physical address,
+//! configured by `gich-paddr` in the platform config.
+
+use std::os::arceos::modules::{axconfig, axhal};
+
+use memory_addr::PhysAddr;
+
+const GICH_HCR: usize = 0x000;
+const GICH_VTR: usize = 0x004;
+const GICH_VMCR: usize = 0x008;
+const GICH_ELRSR0: usize = 0x030;
+const GICH_ELRSR1: usize = 0x034;
+const GICH_APR: usize = 0x0f0;
+const GICH_LR0: usize = 0x100;
+
+/// Enables the virtual CPU interface.
+const GICH_HCR_EN: u32 = 1 << 0;
+/// Raises a maintenance interrupt when at most one list register holds an interrupt.
+const GICH_HCR_UIE: u32 = 1 << 1;
+
+/// The maximum number of list registers of a GICv2.
+pub const MAX_LRS: usize = 64;
+
+const LR_VIRTUAL_ID_MASK: u32 = 0x3ff;
+const LR_CPUID_SHIFT: u32 = 10;
+const LR_PRIORITY_SHIFT: u32 = 23;
+/// Raises a maintenance interrupt once the guest completes the interrupt.
+const LR_EOI: u32 = 1 << 19;
+const LR_STATE_PENDING: u32 = 0b01 << 28;
+
+fn gich_base() -> usize {
+    axhal::mem::phys_to_virt(PhysAddr::from(axconfig::devices::GICH_PADDR)).as_usize()
+}
+
+fn read(offset: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((gich_base() + offset) as *const u32) }
+}
+
+fn write(offset: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((gich_base() + offset) as *mut u32, value) }
+}
+
+/// Returns whether the platform provides the GICv2 virtualization extensions.
+pub fn is_present() -> bool {
+    axconfig::devices::GICH_PADDR != 0
+}
+
+/// Enables the virtual CPU interface of the current CPU.
+pub fn enable() {
+    write(GICH_HCR, GICH_HCR_EN);
+}
+
+/// Returns the number of list registers implemented.
+pub fn num_lrs() -> usize {
+    ((read(GICH_VTR) & 0x3f) + 1) as usize
+}
+
+/// Reads the list register `index`.
+pub fn read_lr(index: usize) -> u32 {
+    read(GICH_LR0 + index * 4)
+}
+
+/// Writes the list register `index`.
+pub fn write_lr(index: usize, value: u32) {
+    write(GICH_LR0 + index * 4, value)
+}
+
+/// Returns the bitmap of the empty list registers.
+pub fn empty_lrs() -> u64 {
+    read(GICH_ELRSR0) as u64 | ((read(GICH_ELRSR1) as u64) << 32)
+}
+
+pub fn read_vmcr() -> u32 {
+    read(GICH_VMCR)
+}
+
+pub fn write_vmcr(value: u32) {
+    write(GICH_VMCR, value)
+}
+
+pub fn read_apr() -> u32 {
+    read(GICH_APR)
+}
+
+pub fn write_apr(value: u32) {
+    write(GICH_APR, value)
+}
+
+/// Requests a maintenance interrupt once the list registers drain,
+/// so that interrupts which did not fit can be written.
+pub fn set_underflow_irq(enable: bool) {
+    let hcr = read(GICH_HCR);
+    if enable {
+        write(GICH_HCR, hcr | GICH_HCR_UIE);
+    } else {
+        write(GICH_HCR, hcr & !GICH_HCR_UIE);
+    }
+}
+
+/// Builds a list register value for a pending software interrupt.
+///
+/// The completion of an SPI raises a maintenance interrupt, so that it is noticed while the
+/// guest keeps running.
+///
+/// # Arguments
+///
+/// * `irq` - The virtual interrupt ID.
+/// * `priority` - The 8-bit priority, of which the list register keeps the upper 5 bits.
+/// * `source` - The source CPU of an SGI, ignored for other interrupts.
+pub fn make_lr(irq: usize, priority: u8, source: usize) -> u32 {
+    let cpuid = if irq < 16 {
+        (source as u32 & 0x7) << LR_CPUID_SHIFT
+    } else {
+        0
+    };
+    let eoi = if irq >= 32 { LR_EOI } else { 0 };
+    (irq as u32 & LR_VIRTUAL_ID_MASK)
+        | cpuid
+        | (((priority >> 3) as u32) << LR_PRIORITY_SHIFT)
+        | eoi
+        | LR_STATE_PENDING
+}
+
+/// Returns whether a list register value holds a pending interrupt, active or not.
+pub fn lr_is_pending(lr: u32) -> bool {
+    lr & LR_STATE_PENDING != 0
+}
+
+/// Returns the virtual interrupt ID held by a list register value.
+pub fn lr_irq(lr: u32) -> usize {
+    (lr & LR_VIRTUAL_ID_MASK) as usize
+}
@@ -0,0 +1,155 @@
+//! GICv3 virtual interface control system registers (`ICH_*_EL2`) of the current CPU.
+
+use core::arch::asm;
+
+/// Enables the virtual CPU interface.
+const ICH_HCR_EN: u64 = 1 << 0;
+/// Raises a maintenance interrupt when at most one list register holds an interrupt.
+const ICH_HCR_UIE: u64 = 1 << 1;
+
+/// The maximum number of list registers of a GICv3.
+const MAX_LRS: usize = 16;
+
+const LR_VINTID_MASK: u64 = 0xffff_ffff;
+/// Raises a maintenance interrupt once the guest completes the interrupt.
+const LR_EOI: u64 = 1 << 41;
+const LR_PRIORITY_SHIFT: u64 = 48;
+const LR_GROUP1: u64 = 1 << 60;
+const LR_STATE_PENDING: u64 = 0b01 << 62;
+
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value) };
+        value
+    }};
+}
+
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {
+        unsafe { asm!(concat!("msr ", $reg, ", {}"), in(reg) $value) }
+    };
+}
+
+/// Returns whether the CPU implements the GICv3 system register interface.
+pub fn is_present() -> bool {
+    // ID_AA64PFR0_EL1.GIC, bits [27:24].
+    (read_sysreg!("ID_AA64PFR0_EL1") >> 24) & 0xf != 0
+}
+
+/// Enables the virtual CPU interface of the current CPU.
+pub fn enable() {
+    write_sysreg!("ICH_HCR_EL2", ICH_HCR_EN);
+}
+
+/// Returns the number of list registers implemented.
+pub fn num_lrs() -> usize {
+    (((read_sysreg!("ICH_VTR_EL2") & 0x1f) + 1) as usize).min(MAX_LRS)
+}
+
+/// Reads the list register `index`.
+pub fn read_lr(index: usize) -> u64 {
+    match index {
+        0 => read_sysreg!("ICH_LR0_EL2"),
+        1 => read_sysreg!("ICH_LR1_EL2"),
+        2 => read_sysreg!("ICH_LR2_EL2"),
+        3 => read_sysreg!("ICH_LR3_EL2"),
+        4 => read_sysreg!("ICH_LR4_EL2"),
+        5 => read_sysreg!("ICH_LR5_EL2"),
+        6 => read_sysreg!("ICH_LR6_EL2"),
+        7 => read_sysreg!("ICH_LR7_EL2"),
+        8 => read_sysreg!("ICH_LR8_EL2"),
+        9 => read_sysreg!("ICH_LR9_EL2"),
+        10 => read_sysreg!("ICH_LR10_EL2"),
+        11 => read_sysreg!("ICH_LR11_EL2"),
+        12 => read_sysreg!("ICH_LR12_EL2"),
+        13 => read_sysreg!("ICH_LR13_EL2"),
+        14 => read_sysreg!("ICH_LR14_EL2"),
+        15 => read_sysreg!("ICH_LR15_EL2"),
+        _ => 0,
+    }
+}
+
+/// Writes the list register `index`.
+pub fn write_lr(index: usize, value: u64) {
+    match index {
+        0 => write_sysreg!("ICH_LR0_EL2", value),
+        1 => write_sysreg!("ICH_LR1_EL2", value),
+        2 => write_sysreg!("ICH_LR2_EL2", value),
+        3 => write_sysreg!("ICH_LR3_EL2", value),
+        4 => write_sysreg!("ICH_LR4_EL2", value),
+        5 => write_sysreg!("ICH_LR5_EL2", value),
+        6 => write_sysreg!("ICH_LR6_EL2", value),
+        7 => write_sysreg!("ICH_LR7_EL2", value),
+        8 => write_sysreg!("ICH_LR8_EL2", value),
+        9 => write_sysreg!("ICH_LR9_EL2", value),
+        10 => write_sysreg!("ICH_LR10_EL2", value),
+        11 => write_sysreg!("ICH_LR11_EL2", value),
+        12 => write_sysreg!("ICH_LR12_EL2", value),
+        13 => write_sysreg!("ICH_LR13_EL2", value),
+        14 => write_sysreg!("ICH_LR14_EL2", value),
+        15 => write_sysreg!("ICH_LR15_EL2", value),
+        _ => {}
+    }
+}
+
+/// Returns the bitmap of the empty list registers.
+pub fn empty_lrs() -> u64 {
+    read_sysreg!("ICH_ELRSR_EL2")
+}
+
+pub fn read_vmcr() -> u32 {
+    read_sysreg!("ICH_VMCR_EL2") as u32
+}
+
+pub fn write_vmcr(value: u32) {
+    write_sysreg!("ICH_VMCR_EL2", value as u64);
+}
+
+/// Reads the Group 1 active priorities, the guest's interrupts all being Group 1.
+pub fn read_apr() -> u32 {
+    read_sysreg!("ICH_AP1R0_EL2") as u32
+}
+
+pub fn write_apr(value: u32) {
+    write_sysreg!("ICH_AP1R0_EL2", value as u64);
+}
+
+/// Requests a maintenance interrupt once the list registers drain,
+/// so that interrupts which did not fit can be written.
+pub fn set_underflow_irq(enable: bool) {
+    let hcr = read_sysreg!("ICH_HCR_EL2");
+    if enable {
+        write_sysreg!("ICH_HCR_EL2", hcr | ICH_HCR_UIE);
+    } else {
+        write_sysreg!("ICH_HCR_EL2", hcr & !ICH_HCR_UIE);
+    }
+}
+
+/// Builds a list register value for a pending Group 1 software interrupt.
+///
+/// The completion of an SPI raises a maintenance interrupt, so that it is noticed while the
+/// guest keeps running.
+///
+/// # Arguments
+///
+/// * `irq` - The virtual interrupt ID.
+/// * `priority` - The 8-bit priority, of which the CPU keeps the implemented upper bits.
+pub fn make_lr(irq: usize, priority: u8) -> u64 {
+    let eoi = if irq >= 32 { LR_EOI } else { 0 };
+    (irq as u64 & LR_VINTID_MASK)
+        | eoi
+        | ((priority as u64) << LR_PRIORITY_SHIFT)
+        | LR_GROUP1
+        | LR_STATE_PENDING
+}
+
+/// Returns whether a list register value holds a pending interrupt, active or not.
+pub fn lr_is_pending(lr: u64) -> bool {
+    lr & LR_STATE_PENDING != 0
+}
+
+/// Returns the virtual interrupt ID held by a list register value.
+pub fn lr_irq(lr: u64) -> usize {
+    (lr & LR_VINTID_MASK) as usize
+}
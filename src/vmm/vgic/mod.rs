@@ -0,0 +1,458 @@
+//! Virtual GIC for aarch64 guests.
+//!
+//! A VM owning an emulated distributor gets its own view of the interrupt controller, with
+//! accesses to its distributor trapping into [`VGicD`] through the [`EmuDevice`] of the VM:
+//! * GICv2 (an `emu_devices` entry of type [`EmuType::InterruptController`]): the guest's CPU
+//!   interface is backed by the hardware virtual CPU interface (`GICV`), mapped at the guest's
+//!   `GICC` address through a passthrough device entry.
+//! * GICv3 (entries of types [`EmuType::InterruptController`] and
+//!   [`EmuType::GicRedistributor`]): the guest also gets an emulated redistributor frame per
+//!   vCPU, and its CPU interface is the virtual `ICC_*_EL1` system register interface, with
+//!   `ICC_SGI1R_EL1` writes trapped to generate SGIs. The affinity of each vCPU is its
+//!   `MPIDR_EL1`, i.e. its entry in the VM's `phys_cpu_ids`. There is no ITS and LPIs are not
+//!   supported: `GICD_TYPER.LPIS` and `GICR_TYPER.PLPIS` read as zero, and the LPI registers
+//!   are RAZ/WI.
+//!
+//! The MSIs of the PCI functions of the VM are SPIs, raised through an emulated GICv2m frame
+//! (an `emu_devices` entry of type [`EmuType::GicV2m`]), with either version, see [`v2m`].
+//!
+//! [`EmuType::InterruptController`]: devices::EmuType::InterruptController
+//! [`EmuType::GicRedistributor`]: devices::EmuType::GicRedistributor
+//! [`EmuType::GicV2m`]: devices::EmuType::GicV2m
+//!
+//! Virtual interrupts are delivered through the list registers of the hardware virtual
+//! interface control (`GICH` or `ICH_*_EL2`). The list registers are loaded right before a
+//! vCPU enters the guest and saved right after it exits, so several vCPUs can share a
+//! physical CPU. The maintenance interrupt of the virtual interface makes the vCPU exit the
+//! guest once its list registers drain while more interrupts are queued, so that these are
+//! written in turn, and once the guest completes an SPI, so that a forwarded interrupt is
+//! unmasked without waiting for the next exit of the vCPU.
+
+extern crate alloc;
+
+mod cpuif;
+mod gich;
+mod ich;
+mod v2m;
+mod vgicd;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use std::os::arceos::modules::axhal;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+use kspin::SpinNoIrq;
+
+use crate::vmm::devices::{self, EmuDevice};
+use crate::vmm::irq;
+use v2m::GicV2m;
+use vgicd::{NR_BANKED_IRQS, NR_IRQS, NR_SGIS, VGicD};
+
+/// The maintenance interrupt of the virtual CPU interface, a PPI.
+const MAINTENANCE_IRQ: usize = 25;
+
+/// The maximum number of vCPUs addressable by a GICv2.
+const GICV2_MAX_VCPUS: usize = 8;
+/// The size of the redistributor of a vCPU, its `RD_base` and `SGI_base` frames.
+const GICR_FRAME_SIZE: usize = 0x2_0000;
+
+/// `ICC_SGI1R_EL1` (op0 3, op1 0, CRn 12, CRm 11, op2 5) in the ISS layout of an `MSR` trap.
+const ICC_SGI1R_EL1: usize = (3 << 20) | (5 << 17) | (12 << 10) | (11 << 1);
+
+/// The architecture version of a GIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    V2,
+    V3,
+}
+
+impl GicVersion {
+    /// Returns the version of the virtual CPU interface provided by the host, if any.
+    fn host() -> Option<Self> {
+        [Self::V3, Self::V2].into_iter().find(|v| v.is_present())
+    }
+}
+
+/// The list registers and virtual CPU interface state of a vCPU, saved while it is not running.
+#[derive(Default)]
+struct VGicCpuState {
+    vmcr: u32,
+    apr: u32,
+    /// The non-empty list registers.
+    lrs: Vec<u64>,
+    /// The interrupts held by the list registers when the vCPU last entered the guest.
+    loaded: Vec<usize>,
+}
+
+/// The virtual GIC of a VM.
+pub struct VGic {
+    vm_id: usize,
+    version: GicVersion,
+    gicd_base: GuestPhysAddr,
+    gicd_size: usize,
+    /// The redistributor region of a GICv3, empty for a GICv2.
+    gicr_base: GuestPhysAddr,
+    gicr_size: usize,
+    gicd: SpinNoIrq<VGicD>,
+    cpus: Vec<SpinNoIrq<VGicCpuState>>,
+}
+
+impl VGic {
+    fn new(
+        vm_id: usize,
+        version: GicVersion,
+        affinities: Vec<u64>,
+        (gicd_base, gicd_size): (GuestPhysAddr, usize),
+        (gicr_base, gicr_size): (GuestPhysAddr, usize),
+    ) -> Self {
+        let vcpu_num = affinities.len();
+        Self {
+            vm_id,
+            version,
+            gicd_base,
+            gicd_size,
+            gicr_base,
+            gicr_size,
+            gicd: SpinNoIrq::new(VGicD::new(vm_id, version, affinities)),
+            cpus: (0..vcpu_num)
+                .map(|_| SpinNoIrq::new(VGicCpuState::default()))
+                .collect(),
+        }
+    }
+
+    /// Returns whether `addr` falls into the distributor of this vGIC.
+    fn in_gicd(&self, addr: GuestPhysAddr) -> bool {
+        (self.gicd_base..self.gicd_base + self.gicd_size).contains(&addr)
+    }
+
+    /// Returns the vCPU owning the redistributor frame at `addr`, and the offset in the frame.
+    fn gicr_frame(&self, addr: GuestPhysAddr) -> (usize, usize) {
+        let offset = addr - self.gicr_base;
+        (offset / GICR_FRAME_SIZE, offset % GICR_FRAME_SIZE)
+    }
+
+    /// Returns the vCPU an SPI is currently routed to by the guest, to inject it into.
+    ///
+    /// An SPI without any target is latched as pending in the distributor instead, to be
+    /// delivered once the guest routes it to a vCPU.
+    pub fn spi_target_or_latch(&self, irq: usize) -> Option<usize> {
+        let mut gicd = self.gicd.lock();
+        let target = gicd.spi_target(irq);
+        if target.is_none() {
+            gicd.set_pending(0, irq);
+        }
+        target
+    }
+
+    /// Loads the saved state of a vCPU into the virtual interface of the current CPU,
+    /// and writes its pending interrupts into the free list registers.
+    ///
+    /// Must be called on the vCPU's own task, right before it enters the guest.
+    ///
+    /// # Returns
+    ///
+    /// The interrupts which did not fit into the list registers, to be retried later.
+    pub fn vcpu_enter(&self, vcpu_id: usize, pending: VecDeque<usize>) -> VecDeque<usize> {
+        let cpuif = self.version;
+        let num_lrs = cpuif.num_lrs();
+        let state = self.cpus[vcpu_id].lock();
+        cpuif.write_vmcr(state.vmcr);
+        cpuif.write_apr(state.apr);
+        for (index, &lr) in state.lrs.iter().enumerate() {
+            cpuif.write_lr(index, lr);
+        }
+        let mut used: Vec<usize> = state.lrs.iter().map(|&lr| cpuif.lr_irq(lr)).collect();
+        let mut next_free = state.lrs.len();
+        drop(state);
+
+        let mut gicd = self.gicd.lock();
+        let mut left = VecDeque::new();
+        for irq in pending {
+            if irq >= NR_IRQS {
+                warn!("VM[{}] vGIC drops invalid irq {}", self.vm_id, irq);
+                continue;
+            }
+            if !gicd.is_deliverable(vcpu_id, irq) {
+                gicd.set_pending(vcpu_id, irq);
+                continue;
+            }
+            // Already pending or active in a list register. Only the SGIs of a GICv2 may be
+            // held several times, once per source CPU.
+            if (irq >= NR_SGIS || cpuif == GicVersion::V3) && used.contains(&irq) {
+                continue;
+            }
+            if next_free >= num_lrs {
+                left.push_back(irq);
+                continue;
+            }
+            let source = if irq < NR_SGIS {
+                gicd.take_sgi_source(vcpu_id, irq)
+            } else {
+                0
+            };
+            cpuif.write_lr(
+                next_free,
+                cpuif.make_lr(irq, gicd.priority(vcpu_id, irq), source),
+            );
+            used.push(irq);
+            next_free += 1;
+        }
+        for index in next_free..num_lrs {
+            cpuif.write_lr(index, 0);
+        }
+        cpuif.set_underflow_irq(!left.is_empty());
+        self.cpus[vcpu_id].lock().loaded = used;
+        left
+    }
+
+    /// Saves the state of a vCPU from the virtual interface of the current CPU.
+    ///
+    /// Must be called on the vCPU's own task, right after it exits the guest.
+    pub fn vcpu_exit(&self, vcpu_id: usize) {
+        let cpuif = self.version;
+        let num_lrs = cpuif.num_lrs();
+        let empty = cpuif.empty_lrs();
+        let mut state = self.cpus[vcpu_id].lock();
+        state.vmcr = cpuif.read_vmcr();
+        state.apr = cpuif.read_apr();
+        state.lrs.clear();
+        for index in 0..num_lrs {
+            if empty & (1 << index) == 0 {
+                state.lrs.push(cpuif.read_lr(index));
+            }
+            // Also clears the completed interrupts still asserting the maintenance interrupt.
+            cpuif.write_lr(index, 0);
+        }
+        cpuif.set_underflow_irq(false);
+
+        // The SPIs which left the list registers have been completed by the guest.
+        let held: Vec<usize> = state.lrs.iter().map(|&lr| cpuif.lr_irq(lr)).collect();
+        let completed: Vec<usize> = core::mem::take(&mut state.loaded)
+            .into_iter()
+            .filter(|irq| *irq >= NR_BANKED_IRQS && !held.contains(irq))
+            .collect();
+        drop(state);
+        for irq in completed {
+            irq::complete_irq(self.vm_id, irq);
+        }
+    }
+
+    /// Returns whether an interrupt is still pending in the saved list registers of a vCPU.
+    ///
+    /// An interrupt which is only active is being handled by the guest, and does not wake the
+    /// vCPU up.
+    pub fn has_pending_lr(&self, vcpu_id: usize) -> bool {
+        let cpuif = self.version;
+        self.cpus[vcpu_id]
+            .lock()
+            .lrs
+            .iter()
+            .any(|&lr| cpuif.lr_is_pending(lr))
+    }
+}
+
+impl EmuDevice for VGic {
+    fn name(&self) -> &str {
+        match self.version {
+            GicVersion::V2 => "vGICv2",
+            GicVersion::V3 => "vGICv3",
+        }
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        let mut regions = vec![self.gicd_base..self.gicd_base + self.gicd_size];
+        if self.gicr_size != 0 {
+            regions.push(self.gicr_base..self.gicr_base + self.gicr_size);
+        }
+        regions
+    }
+
+    fn sysregs(&self) -> Vec<Range<usize>> {
+        match self.version {
+            GicVersion::V2 => Vec::new(),
+            GicVersion::V3 => vec![ICC_SGI1R_EL1..ICC_SGI1R_EL1 + 1],
+        }
+    }
+
+    fn handle_mmio_read(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize) -> usize {
+        if self.in_gicd(addr) {
+            let offset = addr - self.gicd_base;
+            return self.gicd.lock().handle_read(vcpu_id, offset, width);
+        }
+        let (owner, offset) = self.gicr_frame(addr);
+        if owner >= self.cpus.len() {
+            return 0;
+        }
+        self.gicd.lock().handle_redist_read(owner, offset, width)
+    }
+
+    fn handle_mmio_write(&self, vcpu_id: usize, addr: GuestPhysAddr, width: usize, value: usize) {
+        let deliveries = if self.in_gicd(addr) {
+            let offset = addr - self.gicd_base;
+            self.gicd.lock().handle_write(vcpu_id, offset, width, value)
+        } else {
+            let (owner, offset) = self.gicr_frame(addr);
+            if owner >= self.cpus.len() {
+                return;
+            }
+            self.gicd
+                .lock()
+                .handle_redist_write(owner, offset, width, value)
+        };
+        vgicd::deliver(self.vm_id, deliveries);
+    }
+
+    fn handle_sysreg_write(&self, vcpu_id: usize, addr: usize, value: u64) -> bool {
+        if self.version != GicVersion::V3 || addr != ICC_SGI1R_EL1 {
+            return false;
+        }
+        let deliveries = self.gicd.lock().handle_sgi1r(vcpu_id, value);
+        vgicd::deliver(self.vm_id, deliveries);
+        true
+    }
+}
+
+/// The virtual GICs of all VMs, stored in a BTreeMap where the key is the VM ID.
+///
+/// Also accessed from interrupt context, by the forwarding of routed interrupts, hence the
+/// IRQ-safe lock.
+static VM_VGICS: SpinNoIrq<BTreeMap<usize, Arc<VGic>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Creates the virtual GIC of a VM.
+///
+/// A GICv3 is emulated if a redistributor region is given, a GICv2 otherwise.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM.
+/// * `affinities` - The `MPIDR_EL1` affinity of each vCPU of the VM.
+/// * `gicd` - The guest physical address and size of the emulated distributor.
+/// * `gicr` - The guest physical address and size of the emulated redistributor region.
+pub fn create_vm_vgic(
+    vm_id: usize,
+    affinities: Vec<u64>,
+    gicd: (GuestPhysAddr, usize),
+    gicr: Option<(GuestPhysAddr, usize)>,
+) -> AxResult {
+    let version = if gicr.is_some() {
+        GicVersion::V3
+    } else {
+        GicVersion::V2
+    };
+    if GicVersion::host() != Some(version) {
+        return ax_err!(
+            Unsupported,
+            "the host does not provide the virtual CPU interface of the vGIC"
+        );
+    }
+    let vcpu_num = affinities.len();
+    let gicr = gicr.unwrap_or((GuestPhysAddr::from(0), 0));
+    match version {
+        GicVersion::V2 if vcpu_num > GICV2_MAX_VCPUS => {
+            return ax_err!(InvalidInput, "GICv2 supports at most 8 vCPUs");
+        }
+        GicVersion::V3 if gicr.1 < vcpu_num * GICR_FRAME_SIZE => {
+            return ax_err!(
+                InvalidInput,
+                "the redistributor region is too small for the vCPUs"
+            );
+        }
+        _ => {}
+    }
+    info!(
+        "VM[{}] vGIC {:?} distributor at {:?}, {} irqs",
+        vm_id, version, gicd.0, NR_IRQS
+    );
+    let vgic = Arc::new(VGic::new(vm_id, version, affinities, gicd, gicr));
+    devices::register_device(vm_id, vgic.clone())?;
+    VM_VGICS.lock().insert(vm_id, vgic);
+    Ok(())
+}
+
+/// Removes the virtual GIC of a VM, called when the VM is torn down.
+pub fn remove_vm_vgic(vm_id: usize) -> Option<Arc<VGic>> {
+    VM_VGICS.lock().remove(&vm_id)
+}
+
+/// Retrieves the virtual GIC of a VM, if it has one.
+pub fn get_vm_vgic(vm_id: usize) -> Option<Arc<VGic>> {
+    VM_VGICS.lock().get(&vm_id).cloned()
+}
+
+/// The GICv2m MSI frames of all VMs, stored in a BTreeMap where the key is the VM ID.
+///
+/// Also accessed from interrupt context, by the forwarding of routed interrupts, hence the
+/// IRQ-safe lock.
+static VM_V2M_FRAMES: SpinNoIrq<BTreeMap<usize, Arc<GicV2m>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Creates the GICv2m MSI frame of a VM from its `emu_devices` entry, which must come before
+/// the ones of its PCI functions.
+pub fn create_v2m(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Arc<dyn EmuDevice>> {
+    let mut frames = VM_V2M_FRAMES.lock();
+    if frames.contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "VM already has a GICv2m frame");
+    }
+    let frame = Arc::new(GicV2m::new(vm_id, config)?);
+    frames.insert(vm_id, frame.clone());
+    Ok(frame)
+}
+
+/// Removes the GICv2m MSI frame of a VM, called when the VM is torn down.
+pub fn remove_vm_v2m(vm_id: usize) {
+    VM_V2M_FRAMES.lock().remove(&vm_id);
+}
+
+/// Returns whether a VM has a GICv2m MSI frame.
+pub fn has_v2m(vm_id: usize) -> bool {
+    VM_V2M_FRAMES.lock().contains_key(&vm_id)
+}
+
+/// Returns the SPI raised in the guest of `vm_id` by the MSI whose message is `address` and
+/// `data`, if it targets its GICv2m MSI frame.
+pub fn v2m_spi(vm_id: usize, address: u64, data: u32) -> Option<usize> {
+    let frame = VM_V2M_FRAMES.lock().get(&vm_id).cloned()?;
+    frame.spi(address, data)
+}
+
+/// Handles the maintenance interrupt of the virtual CPU interface, taken on an exit of the
+/// vCPU running on the current CPU. It is deasserted here, the exit itself saving the list
+/// registers and then writing the queued interrupts when the vCPU enters the guest again.
+fn handle_maintenance_irq() {
+    let Some(cpuif) = GicVersion::host() else {
+        return;
+    };
+    cpuif.set_underflow_irq(false);
+    // The empty list registers which still hold a completed SPI assert it as well, and the
+    // completion is found out by `vcpu_exit` from the interrupts loaded anyway.
+    let empty = cpuif.empty_lrs();
+    for index in (0..cpuif.num_lrs()).filter(|i| empty & (1 << i) != 0) {
+        cpuif.write_lr(index, 0);
+    }
+}
+
+/// Enables the virtual CPU interface and its maintenance interrupt on the current CPU,
+/// if the platform has one.
+pub fn init_percpu() {
+    let Some(version) = GicVersion::host() else {
+        return;
+    };
+    match version {
+        GicVersion::V2 => gich::enable(),
+        GicVersion::V3 => ich::enable(),
+    }
+    // The handler is registered by the first CPU, the others only enable their own PPI.
+    if !axhal::irq::register_handler(MAINTENANCE_IRQ, handle_maintenance_irq) {
+        axhal::irq::set_enable(MAINTENANCE_IRQ, true);
+    }
+    debug!(
+        "vGIC {:?}: {} list registers, {} banked irqs",
+        version,
+        version.num_lrs(),
+        NR_BANKED_IRQS
+    );
+}
@@ -0,0 +1,114 @@
+//! An emulated GICv2m MSI frame, turning the MSIs of the PCI functions of a VM into SPIs.
+//!
+//! The frame is the `emu_devices` entry of `Emu-Type` [`EmuType::GicV2m`], whose `EmuConfig`
+//! is `[spi_base, spi_count]`, the SPIs it hands out to the guest, like the `msi-controller`
+//! frame of the QEMU `virt` machine with a GICv2. An MSI is a write of its SPI to the
+//! `MSI_SETSPI_NS` register of the frame, either by a vCPU or by a PCI function, whose message
+//! is then delivered through [`v2m_spi`](super::v2m_spi).
+//!
+//! [`EmuType::GicV2m`]: crate::vmm::devices::EmuType::GicV2m
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvm::config::EmulatedDeviceConfig;
+
+use super::NR_IRQS;
+use crate::vmm::devices::EmuDevice;
+use crate::vmm::irq;
+
+const MSI_TYPER: usize = 0x008;
+const MSI_SETSPI_NS: usize = 0x040;
+
+const MSI_TYPER_BASE_SHIFT: u32 = 16;
+/// The maximum number of SPIs of a frame, in the 10-bit count of `MSI_TYPER`.
+const MAX_SPIS: usize = 0x3ff;
+/// The first SPI.
+const FIRST_SPI: usize = 32;
+/// The size of the frame.
+const FRAME_SIZE: usize = 0x1000;
+
+/// An emulated GICv2m MSI frame.
+pub struct GicV2m {
+    vm_id: usize,
+    name: String,
+    base: GuestPhysAddr,
+    spis: Range<usize>,
+}
+
+impl GicV2m {
+    /// Creates the MSI frame of a VM from its `emu_devices` entry.
+    pub fn new(vm_id: usize, config: &EmulatedDeviceConfig) -> AxResult<Self> {
+        let (spi_base, spi_count) = match *config.cfg_list.as_slice() {
+            [spi_base, spi_count] => (spi_base, spi_count),
+            _ => {
+                return ax_err!(
+                    InvalidInput,
+                    "GICv2m EmuConfig must be [spi_base, spi_count]"
+                );
+            }
+        };
+        if spi_base < FIRST_SPI || spi_count == 0 || spi_count > MAX_SPIS {
+            return ax_err!(InvalidInput, "GICv2m SPIs out of range");
+        }
+        if spi_base + spi_count > NR_IRQS {
+            return ax_err!(InvalidInput, "GICv2m SPIs beyond the ones of the vGIC");
+        }
+        if config.length < FRAME_SIZE {
+            return ax_err!(InvalidInput, "GICv2m frame too small");
+        }
+        Ok(Self {
+            vm_id,
+            name: config.name.clone(),
+            base: GuestPhysAddr::from(config.base_gpa),
+            spis: spi_base..spi_base + spi_count,
+        })
+    }
+
+    /// Returns the SPI raised by writing `data` at `address`, if `address` is the
+    /// `MSI_SETSPI_NS` register of the frame and `data` one of its SPIs.
+    pub fn spi(&self, address: u64, data: u32) -> Option<usize> {
+        let setspi = (self.base + MSI_SETSPI_NS).as_usize() as u64;
+        let spi = data as usize;
+        (address == setspi && self.spis.contains(&spi)).then_some(spi)
+    }
+}
+
+impl EmuDevice for GicV2m {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + FRAME_SIZE]
+    }
+
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize) -> usize {
+        match addr - self.base {
+            MSI_TYPER => {
+                (self.spis.start << MSI_TYPER_BASE_SHIFT) | (self.spis.end - self.spis.start)
+            }
+            _ => 0,
+        }
+    }
+
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, _width: usize, value: usize) {
+        if addr - self.base != MSI_SETSPI_NS {
+            return;
+        }
+        let spi = value & 0x3ff;
+        if self.spis.contains(&spi) {
+            irq::inject_guest_irq(self.vm_id, spi);
+        } else {
+            warn!(
+                "VM[{}] {}: SPI {} not in the frame",
+                self.vm_id, self.name, spi
+            );
+        }
+    }
+}
@@ -0,0 +1,734 @@
+//! Emulated GIC distributor, and the redistributors of a GICv3.
+//!
+//! The distributor state is kept per VM. SGIs and PPIs are banked per vCPU, SPIs are shared
+//! by all the vCPUs of the VM and routed through `GICD_ITARGETSR` on a GICv2, or through
+//! `GICD_IROUTER` on a GICv3.
+//!
+//! On a GICv3 the distributor operates with affinity routing enabled (`GICD_CTLR.ARE`), so the
+//! banked registers are only reachable through the `SGI_base` frame of each vCPU's
+//! redistributor, and the SGIs are generated by the guest through `ICC_SGI1R_EL1`.
+//!
+//! Interrupts are not delivered by the distributor itself: an interrupt which is both enabled
+//! and forwarded (`GICD_CTLR.EnableGrp0`/`EnableGrp1`) is handed to the target vCPU through
+//! [`vcpus::inject_interrupt`], and written into a list register right before the vCPU enters
+//! the guest. Interrupts raised while disabled are latched as pending here, and delivered
+//! as soon as the guest enables them.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use super::GicVersion;
+use crate::vmm::vcpus;
+
+/// The number of interrupts supported by the emulated distributor,
+/// 16 SGIs, 16 PPIs and 224 SPIs.
+pub const NR_IRQS: usize = 256;
+/// The number of banked interrupts (SGIs and PPIs).
+pub const NR_BANKED_IRQS: usize = 32;
+/// The number of SGIs.
+pub const NR_SGIS: usize = 16;
+
+const NR_WORDS: usize = NR_IRQS / 32;
+
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IIDR: usize = 0x008;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ISPENDR: usize = 0x200;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_ISACTIVER: usize = 0x300;
+const GICD_ICACTIVER: usize = 0x380;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+const GICD_SGIR: usize = 0xf00;
+const GICD_CPENDSGIR: usize = 0xf10;
+const GICD_SPENDSGIR: usize = 0xf20;
+const GICD_PIDR2: usize = 0xfe8;
+const GICD_IROUTER: usize = 0x6000;
+const GICD_PIDR2_V3: usize = 0xffe8;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_IIDR: usize = 0x0004;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_PIDR2: usize = 0xffe8;
+/// The offset of the `SGI_base` frame in the redistributor of a vCPU.
+const GICR_SGI_BASE: usize = 0x1_0000;
+
+const GICD_CTLR_ENABLE: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+/// Affinity routing, always enabled on a GICv3.
+const GICD_CTLR_ARE: u32 = 1 << 4;
+/// Single security state, always reported on a GICv3.
+const GICD_CTLR_DS: u32 = 1 << 6;
+/// Implementer ARM, as reported by a GIC-400.
+const GICD_IIDR_VALUE: u32 = 0x0200_143b;
+/// Architecture revision GICv2.
+const GICD_PIDR2_VALUE: u32 = 0x2 << 4;
+/// Architecture revision GICv3.
+const GICD_PIDR2_VALUE_V3: u32 = 0x3 << 4;
+/// The number of interrupt ID bits minus one reported in `GICD_TYPER.IDbits`.
+const GICD_TYPER_IDBITS: u32 = 9;
+
+/// Routes an SPI to any participating vCPU, instead of the one matching its affinity.
+const GICD_IROUTER_IRM: u64 = 1 << 31;
+/// The writable bits of `GICD_IROUTER`: `Aff3`, `IRM`, `Aff2`, `Aff1` and `Aff0`.
+const GICD_IROUTER_MASK: u64 = 0xff_8000_0000 | 0xff_ffff;
+
+const GICR_TYPER_LAST: u32 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Targets all the vCPUs but the requesting one in `ICC_SGI1R_EL1`.
+const ICC_SGI1R_IRM: u64 = 1 << 40;
+
+/// The state of the SGIs and PPIs banked for a vCPU.
+#[derive(Clone, Default)]
+struct BankedState {
+    enabled: u32,
+    pending: u32,
+    active: u32,
+    priority: [u8; NR_BANKED_IRQS],
+    /// The bitmap of the source vCPUs of each pending SGI.
+    sgi_sources: [u8; NR_SGIS],
+    /// Whether the guest has woken up the redistributor through `GICR_WAKER`.
+    awake: bool,
+}
+
+/// Selects one of the set/clear pairs of bitmap registers.
+#[derive(Clone, Copy)]
+enum Bitmap {
+    Enabled,
+    Pending,
+    Active,
+}
+
+/// An interrupt to be delivered to a vCPU, produced by a distributor register write.
+#[derive(Debug, Clone, Copy)]
+pub struct Delivery {
+    pub vcpu_id: usize,
+    pub irq: usize,
+}
+
+/// The state of an emulated distributor.
+pub struct VGicD {
+    vm_id: usize,
+    version: GicVersion,
+    vcpu_num: usize,
+    /// The affinity of each vCPU, in the layout of `GICD_IROUTER`.
+    affinities: Vec<u64>,
+    ctlr: u32,
+    /// SPI state, indexed by interrupt ID, the banked words are unused.
+    enabled: [u32; NR_WORDS],
+    pending: [u32; NR_WORDS],
+    active: [u32; NR_WORDS],
+    priority: [u8; NR_IRQS],
+    targets: [u8; NR_IRQS],
+    routes: [u64; NR_IRQS],
+    config: [u32; NR_IRQS / 16],
+    banked: Vec<BankedState>,
+}
+
+impl VGicD {
+    /// Creates a new distributor with all interrupts disabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_id` - The ID of the VM.
+    /// * `version` - The architecture version of the emulated GIC.
+    /// * `affinities` - The `MPIDR_EL1` affinity of each vCPU of the VM.
+    pub fn new(vm_id: usize, version: GicVersion, affinities: Vec<u64>) -> Self {
+        let vcpu_num = affinities.len();
+        Self {
+            vm_id,
+            version,
+            vcpu_num,
+            affinities: affinities.into_iter().map(mpidr_to_route).collect(),
+            ctlr: 0,
+            enabled: [0; NR_WORDS],
+            pending: [0; NR_WORDS],
+            active: [0; NR_WORDS],
+            priority: [0; NR_IRQS],
+            targets: [0; NR_IRQS],
+            routes: [0; NR_IRQS],
+            config: [0; NR_IRQS / 16],
+            banked: vec![BankedState::default(); vcpu_num],
+        }
+    }
+
+    /// Returns the priority of an interrupt as seen by a vCPU.
+    pub fn priority(&self, vcpu_id: usize, irq: usize) -> u8 {
+        if irq < NR_BANKED_IRQS {
+            self.banked[vcpu_id].priority[irq]
+        } else {
+            self.priority[irq]
+        }
+    }
+
+    /// Returns whether the distributor forwards interrupts to the CPU interfaces.
+    fn is_enabled(&self) -> bool {
+        self.ctlr & (GICD_CTLR_ENABLE | GICD_CTLR_ENABLE_GRP1) != 0
+    }
+
+    /// Returns whether an interrupt is enabled for a vCPU and forwarded by the distributor.
+    pub fn is_deliverable(&self, vcpu_id: usize, irq: usize) -> bool {
+        if !self.is_enabled() || irq >= NR_IRQS {
+            return false;
+        }
+        if irq < NR_BANKED_IRQS {
+            self.banked[vcpu_id].enabled & (1 << irq) != 0
+        } else {
+            self.enabled[irq / 32] & (1 << (irq % 32)) != 0
+        }
+    }
+
+    /// Latches an interrupt as pending in the distributor, to be delivered
+    /// once the guest enables it.
+    pub fn set_pending(&mut self, vcpu_id: usize, irq: usize) {
+        if irq < NR_BANKED_IRQS {
+            self.banked[vcpu_id].pending |= 1 << irq;
+        } else if irq < NR_IRQS {
+            self.pending[irq / 32] |= 1 << (irq % 32);
+        }
+    }
+
+    /// Takes the source vCPU of a pending SGI, encoded in the `CPUID` field of list registers.
+    pub fn take_sgi_source(&mut self, vcpu_id: usize, sgi: usize) -> usize {
+        let sources = &mut self.banked[vcpu_id].sgi_sources[sgi];
+        if *sources == 0 {
+            return 0;
+        }
+        let source = sources.trailing_zeros() as usize;
+        *sources &= !(1 << source);
+        source
+    }
+
+    /// Returns the vCPU an SPI is routed to.
+    ///
+    /// On a GICv2 this is the first vCPU of its `GICD_ITARGETSR` byte. On a GICv3 this is the
+    /// vCPU whose affinity matches its `GICD_IROUTER`, or the first vCPU for 1-of-N routing.
+    pub fn spi_target(&self, irq: usize) -> Option<usize> {
+        match self.version {
+            GicVersion::V2 => {
+                let targets = *self.targets.get(irq)?;
+                (targets != 0)
+                    .then(|| targets.trailing_zeros() as usize)
+                    .filter(|&vcpu_id| vcpu_id < self.vcpu_num)
+            }
+            GicVersion::V3 => {
+                let route = *self.routes.get(irq)?;
+                if route & GICD_IROUTER_IRM != 0 {
+                    return Some(0);
+                }
+                self.affinities.iter().position(|&aff| aff == route)
+            }
+        }
+    }
+
+    /// Routes an SGI from `source` to `targets`.
+    pub fn send_sgi(
+        &mut self,
+        source: usize,
+        sgi: usize,
+        targets: impl IntoIterator<Item = usize>,
+    ) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        for vcpu_id in targets {
+            // Only a GICv2 records the source of SGIs, for at most 8 CPUs.
+            if source < 8 {
+                self.banked[vcpu_id].sgi_sources[sgi] |= 1 << source;
+            }
+            deliveries.push(Delivery { vcpu_id, irq: sgi });
+        }
+        deliveries
+    }
+
+    /// Collects the interrupts latched as pending which have become deliverable,
+    /// clearing their latched state.
+    fn collect_deliverable(&mut self) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        if !self.is_enabled() {
+            return deliveries;
+        }
+        for vcpu_id in 0..self.vcpu_num {
+            let bank = &mut self.banked[vcpu_id];
+            let mut ready = bank.pending & bank.enabled;
+            bank.pending &= !ready;
+            while ready != 0 {
+                let irq = ready.trailing_zeros() as usize;
+                ready &= !(1 << irq);
+                deliveries.push(Delivery { vcpu_id, irq });
+            }
+        }
+        for word in 1..NR_WORDS {
+            let mut ready = self.pending[word] & self.enabled[word];
+            while ready != 0 {
+                let bit = ready.trailing_zeros() as usize;
+                ready &= !(1 << bit);
+                let irq = word * 32 + bit;
+                // An SPI without any target stays pending.
+                if let Some(vcpu_id) = self.spi_target(irq) {
+                    self.pending[word] &= !(1 << bit);
+                    deliveries.push(Delivery { vcpu_id, irq });
+                }
+            }
+        }
+        deliveries
+    }
+
+    /// Reads a 32-bit word of a bitmap register, banked for the first word.
+    fn read_bitmap(&self, bitmap: Bitmap, vcpu_id: usize, word: usize) -> u32 {
+        if word == 0 {
+            let bank = &self.banked[vcpu_id];
+            return match bitmap {
+                Bitmap::Enabled => bank.enabled,
+                Bitmap::Pending => bank.pending,
+                Bitmap::Active => bank.active,
+            };
+        }
+        let words = match bitmap {
+            Bitmap::Enabled => &self.enabled,
+            Bitmap::Pending => &self.pending,
+            Bitmap::Active => &self.active,
+        };
+        words.get(word).copied().unwrap_or(0)
+    }
+
+    /// Updates a 32-bit word of a bitmap register, banked for the first word,
+    /// setting the bits of `value` if `set`, clearing them otherwise.
+    fn write_bitmap(&mut self, bitmap: Bitmap, vcpu_id: usize, word: usize, value: u32, set: bool) {
+        let reg = if word == 0 {
+            let bank = &mut self.banked[vcpu_id];
+            match bitmap {
+                Bitmap::Enabled => &mut bank.enabled,
+                Bitmap::Pending => &mut bank.pending,
+                Bitmap::Active => &mut bank.active,
+            }
+        } else {
+            let words = match bitmap {
+                Bitmap::Enabled => &mut self.enabled,
+                Bitmap::Pending => &mut self.pending,
+                Bitmap::Active => &mut self.active,
+            };
+            match words.get_mut(word) {
+                Some(reg) => reg,
+                None => return,
+            }
+        };
+        if set {
+            *reg |= value;
+        } else {
+            *reg &= !value;
+        }
+    }
+
+    /// Returns whether a register word holds the state of banked interrupts, which a GICv3
+    /// exposes in the `SGI_base` frame of the redistributors instead of the distributor.
+    fn is_banked_reg(reg: usize) -> bool {
+        matches!(
+            reg,
+            GICD_IGROUPR
+                | GICD_ISENABLER
+                | GICD_ICENABLER
+                | GICD_ISPENDR
+                | GICD_ICPENDR
+                | GICD_ISACTIVER
+                | GICD_ICACTIVER
+        ) || (GICD_IPRIORITYR..GICD_IPRIORITYR + NR_BANKED_IRQS).contains(&reg)
+            || reg == GICD_ICFGR
+            || reg == GICD_ICFGR + 4
+    }
+
+    /// Handles a guest read of the distributor registers.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - The ID of the accessing vCPU, selecting the banked registers.
+    /// * `offset` - The offset of the access from the distributor base.
+    /// * `width` - The width of the access in bytes.
+    pub fn handle_read(&self, vcpu_id: usize, offset: usize, width: usize) -> usize {
+        Self::read_access(offset, width, |reg| {
+            if self.version == GicVersion::V3 && Self::is_banked_reg(reg) {
+                0
+            } else {
+                self.read_reg(vcpu_id, reg)
+            }
+        })
+    }
+
+    /// Handles a guest read of the redistributor of a vCPU, on a GICv3.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - The ID of the vCPU owning the redistributor.
+    /// * `offset` - The offset of the access from the redistributor base of the vCPU.
+    /// * `width` - The width of the access in bytes.
+    pub fn handle_redist_read(&self, vcpu_id: usize, offset: usize, width: usize) -> usize {
+        Self::read_access(offset, width, |reg| match reg {
+            GICR_CTLR => 0,
+            GICR_IIDR => GICD_IIDR_VALUE,
+            GICR_TYPER => {
+                let last = if vcpu_id + 1 == self.vcpu_num {
+                    GICR_TYPER_LAST
+                } else {
+                    0
+                };
+                ((vcpu_id as u32) << 8) | last
+            }
+            // The affinity value, `Aff3.Aff2.Aff1.Aff0`.
+            o if o == GICR_TYPER + 4 => {
+                let aff = self.affinities[vcpu_id];
+                (((aff >> 32) as u32) << 24) | (aff as u32 & 0xff_ffff)
+            }
+            GICR_WAKER => {
+                if self.banked[vcpu_id].awake {
+                    0
+                } else {
+                    GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP
+                }
+            }
+            GICR_PIDR2 => GICD_PIDR2_VALUE_V3,
+            o if o >= GICR_SGI_BASE && Self::is_banked_reg(o - GICR_SGI_BASE) => {
+                self.read_reg(vcpu_id, o - GICR_SGI_BASE)
+            }
+            _ => 0,
+        })
+    }
+
+    /// Performs a read access of `width` bytes at `offset`, out of the 32-bit register words
+    /// returned by `read`. 64-bit accesses read two consecutive words.
+    fn read_access(offset: usize, width: usize, read: impl Fn(usize) -> u32) -> usize {
+        if width == 8 {
+            return read(offset) as usize | ((read(offset + 4) as usize) << 32);
+        }
+        let value = read(offset & !0x3);
+        // Byte and half-word accesses are only architected for byte-sized fields.
+        let shift = (offset & 0x3) * 8;
+        let mask = if width >= 4 {
+            u32::MAX
+        } else {
+            (1u32 << (width * 8)) - 1
+        };
+        ((value >> shift) & mask) as usize
+    }
+
+    /// Reads the 32-bit distributor register word at `reg`.
+    fn read_reg(&self, vcpu_id: usize, reg: usize) -> u32 {
+        let word = (reg & 0x7f) / 4;
+        let v2 = self.version == GicVersion::V2;
+        match reg {
+            GICD_CTLR if v2 => self.ctlr,
+            GICD_CTLR => self.ctlr | GICD_CTLR_ARE | GICD_CTLR_DS,
+            GICD_TYPER if v2 => ((self.vcpu_num as u32 - 1) << 5) | (NR_WORDS as u32 - 1),
+            GICD_TYPER => (GICD_TYPER_IDBITS << 19) | (NR_WORDS as u32 - 1),
+            GICD_IIDR => GICD_IIDR_VALUE,
+            // All the interrupts are Group 0 on a GICv2, and Group 1 on a GICv3.
+            o if (GICD_IGROUPR..GICD_ISENABLER).contains(&o) => {
+                if v2 {
+                    0
+                } else {
+                    u32::MAX
+                }
+            }
+            o if (GICD_ISENABLER..GICD_ISPENDR).contains(&o) => {
+                self.read_bitmap(Bitmap::Enabled, vcpu_id, word)
+            }
+            o if (GICD_ISPENDR..GICD_ISACTIVER).contains(&o) => {
+                self.read_bitmap(Bitmap::Pending, vcpu_id, word)
+            }
+            o if (GICD_ISACTIVER..GICD_IPRIORITYR).contains(&o) => {
+                self.read_bitmap(Bitmap::Active, vcpu_id, word)
+            }
+            o if (GICD_IPRIORITYR..GICD_IPRIORITYR + NR_IRQS).contains(&o) => {
+                let irq = o - GICD_IPRIORITYR;
+                self.read_bytes(irq, |d, i| d.priority(vcpu_id, i))
+            }
+            o if v2 && (GICD_ITARGETSR..GICD_ITARGETSR + NR_IRQS).contains(&o) => {
+                let irq = o - GICD_ITARGETSR;
+                // The banked interrupts always target the accessing vCPU.
+                self.read_bytes(irq, |d, i| {
+                    if i < NR_BANKED_IRQS {
+                        1 << vcpu_id
+                    } else {
+                        d.targets[i]
+                    }
+                })
+            }
+            o if (GICD_ICFGR..GICD_ICFGR + NR_IRQS / 4).contains(&o) => {
+                self.config[(o - GICD_ICFGR) / 4]
+            }
+            o if v2 && (GICD_CPENDSGIR..GICD_SPENDSGIR + 0x10).contains(&o) => {
+                let first = o & 0xf;
+                self.read_bytes(first, |d, i| d.banked[vcpu_id].sgi_sources[i])
+            }
+            o if !v2
+                && (GICD_IROUTER + 8 * NR_BANKED_IRQS..GICD_IROUTER + 8 * NR_IRQS).contains(&o) =>
+            {
+                let irq = (o - GICD_IROUTER) / 8;
+                (self.routes[irq] >> ((o & 0x4) * 8)) as u32
+            }
+            GICD_PIDR2 if v2 => GICD_PIDR2_VALUE,
+            GICD_PIDR2_V3 if !v2 => GICD_PIDR2_VALUE_V3,
+            _ => 0,
+        }
+    }
+
+    fn read_bytes(&self, first: usize, byte: impl Fn(&Self, usize) -> u8) -> u32 {
+        (0..4).fold(0, |value, i| {
+            let irq = first + i;
+            let b = if irq < NR_IRQS { byte(self, irq) } else { 0 };
+            value | ((b as u32) << (i * 8))
+        })
+    }
+
+    /// Handles a guest write of the distributor registers.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - The ID of the accessing vCPU, selecting the banked registers.
+    /// * `offset` - The offset of the access from the distributor base.
+    /// * `width` - The width of the access in bytes.
+    /// * `value` - The value written by the guest.
+    ///
+    /// # Returns
+    ///
+    /// The interrupts which have become deliverable and must be injected into their vCPUs.
+    pub fn handle_write(
+        &mut self,
+        vcpu_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        Self::write_access(offset, width, value, |offset, width, value| {
+            if self.version == GicVersion::V3 && Self::is_banked_reg(offset & !0x3) {
+                return;
+            }
+            deliveries.extend(self.write_reg(vcpu_id, offset, width, value));
+        });
+        deliveries.extend(self.collect_deliverable());
+        deliveries
+    }
+
+    /// Handles a guest write of the redistributor of a vCPU, on a GICv3.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - The ID of the vCPU owning the redistributor.
+    /// * `offset` - The offset of the access from the redistributor base of the vCPU.
+    /// * `width` - The width of the access in bytes.
+    /// * `value` - The value written by the guest.
+    ///
+    /// # Returns
+    ///
+    /// The interrupts which have become deliverable and must be injected into their vCPUs.
+    pub fn handle_redist_write(
+        &mut self,
+        vcpu_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> Vec<Delivery> {
+        Self::write_access(offset, width, value, |offset, width, value| {
+            match offset & !0x3 {
+                GICR_WAKER => {
+                    self.banked[vcpu_id].awake = value as u32 & GICR_WAKER_PROCESSOR_SLEEP == 0;
+                }
+                o if o >= GICR_SGI_BASE && Self::is_banked_reg(o - GICR_SGI_BASE) => {
+                    self.write_reg(vcpu_id, offset - GICR_SGI_BASE, width, value);
+                }
+                _ => {
+                    debug!(
+                        "VM[{}] vGICR[{}] ignored write {:#x} to offset {:#x}",
+                        self.vm_id, vcpu_id, value, offset
+                    );
+                }
+            }
+        });
+        self.collect_deliverable()
+    }
+
+    /// Performs a write access of `width` bytes at `offset` through `write`,
+    /// splitting 64-bit accesses into two consecutive 32-bit ones.
+    fn write_access(
+        offset: usize,
+        width: usize,
+        value: usize,
+        mut write: impl FnMut(usize, usize, usize),
+    ) {
+        if width == 8 {
+            write(offset, 4, value & 0xffff_ffff);
+            write(offset + 4, 4, value >> 32);
+        } else {
+            write(offset, width, value);
+        }
+    }
+
+    /// Writes the distributor register at `offset`.
+    ///
+    /// Returns the SGIs generated by the write, if any.
+    fn write_reg(
+        &mut self,
+        vcpu_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> Vec<Delivery> {
+        let reg = offset & !0x3;
+        let word = (offset & 0x7f) / 4;
+        let value32 = value as u32;
+        let v2 = self.version == GicVersion::V2;
+        match reg {
+            GICD_CTLR if v2 => self.ctlr = value32 & GICD_CTLR_ENABLE,
+            GICD_CTLR => self.ctlr = value32 & (GICD_CTLR_ENABLE | GICD_CTLR_ENABLE_GRP1),
+            o if (GICD_ISENABLER..GICD_ICENABLER).contains(&o) => {
+                self.write_bitmap(Bitmap::Enabled, vcpu_id, word, value32, true)
+            }
+            o if (GICD_ICENABLER..GICD_ISPENDR).contains(&o) => {
+                self.write_bitmap(Bitmap::Enabled, vcpu_id, word, value32, false)
+            }
+            o if (GICD_ISPENDR..GICD_ICPENDR).contains(&o) => {
+                self.write_bitmap(Bitmap::Pending, vcpu_id, word, value32, true)
+            }
+            o if (GICD_ICPENDR..GICD_ISACTIVER).contains(&o) => {
+                self.write_bitmap(Bitmap::Pending, vcpu_id, word, value32, false)
+            }
+            o if (GICD_ISACTIVER..GICD_ICACTIVER).contains(&o) => {
+                self.write_bitmap(Bitmap::Active, vcpu_id, word, value32, true)
+            }
+            o if (GICD_ICACTIVER..GICD_IPRIORITYR).contains(&o) => {
+                self.write_bitmap(Bitmap::Active, vcpu_id, word, value32, false)
+            }
+            o if (GICD_IPRIORITYR..GICD_IPRIORITYR + NR_IRQS).contains(&o) => {
+                let first = offset - GICD_IPRIORITYR;
+                for i in 0..width.min(4) {
+                    let irq = first + i;
+                    let prio = (value >> (i * 8)) as u8;
+                    if irq < NR_BANKED_IRQS {
+                        self.banked[vcpu_id].priority[irq] = prio;
+                    } else if irq < NR_IRQS {
+                        self.priority[irq] = prio;
+                    }
+                }
+            }
+            o if v2 && (GICD_ITARGETSR..GICD_ITARGETSR + NR_IRQS).contains(&o) => {
+                let first = offset - GICD_ITARGETSR;
+                let valid = ((1u32 << self.vcpu_num) - 1) as u8;
+                // The targets of the banked interrupts are read-only.
+                for i in 0..width.min(4) {
+                    let irq = first + i;
+                    if (NR_BANKED_IRQS..NR_IRQS).contains(&irq) {
+                        self.targets[irq] = (value >> (i * 8)) as u8 & valid;
+                    }
+                }
+            }
+            o if (GICD_ICFGR..GICD_ICFGR + NR_IRQS / 4).contains(&o) => {
+                let index = (o - GICD_ICFGR) / 4;
+                // The configuration of SGIs is read-only.
+                if index != 0 {
+                    self.config[index] = value32;
+                }
+            }
+            GICD_SGIR if v2 => return self.handle_sgir(vcpu_id, value32),
+            o if v2 && (GICD_CPENDSGIR..GICD_CPENDSGIR + 0x10).contains(&o) => {
+                let first = offset - GICD_CPENDSGIR;
+                for i in 0..width.min(4) {
+                    let sgi = first + i;
+                    if sgi < NR_SGIS {
+                        self.banked[vcpu_id].sgi_sources[sgi] &= !((value >> (i * 8)) as u8);
+                    }
+                }
+            }
+            o if v2 && (GICD_SPENDSGIR..GICD_SPENDSGIR + 0x10).contains(&o) => {
+                let first = offset - GICD_SPENDSGIR;
+                for i in 0..width.min(4) {
+                    let sgi = first + i;
+                    let sources = (value >> (i * 8)) as u8;
+                    if sgi < NR_SGIS && sources != 0 {
+                        self.banked[vcpu_id].sgi_sources[sgi] |= sources;
+                        self.banked[vcpu_id].pending |= 1 << sgi;
+                    }
+                }
+            }
+            o if !v2
+                && (GICD_IROUTER + 8 * NR_BANKED_IRQS..GICD_IROUTER + 8 * NR_IRQS).contains(&o) =>
+            {
+                let irq = (o - GICD_IROUTER) / 8;
+                let shift = (o & 0x4) * 8;
+                let route =
+                    (self.routes[irq] & !(0xffff_ffff << shift)) | ((value32 as u64) << shift);
+                self.routes[irq] = route & GICD_IROUTER_MASK;
+            }
+            _ => {
+                debug!(
+                    "VM[{}] vGICD ignored write {:#x} to offset {:#x}",
+                    self.vm_id, value, offset
+                );
+            }
+        }
+        Vec::new()
+    }
+
+    /// Handles a write to `GICD_SGIR`.
+    fn handle_sgir(&mut self, vcpu_id: usize, value: u32) -> Vec<Delivery> {
+        let sgi = (value & 0xf) as usize;
+        let targets = match (value >> 24) & 0b11 {
+            // Forward to the CPUs in the target list.
+            0b00 => (value >> 16) as u8,
+            // Forward to all the CPUs except the requesting one.
+            0b01 => (((1u32 << self.vcpu_num) - 1) & !(1 << vcpu_id)) as u8,
+            // Forward to the requesting CPU only.
+            0b10 => 1 << vcpu_id,
+            _ => return Vec::new(),
+        };
+        let targets = (0..self.vcpu_num).filter(|i| targets & (1 << i) != 0);
+        self.send_sgi(vcpu_id, sgi, targets)
+    }
+
+    /// Handles a guest write to `ICC_SGI1R_EL1`, on a GICv3.
+    ///
+    /// # Returns
+    ///
+    /// The SGIs to be injected into their target vCPUs.
+    pub fn handle_sgi1r(&mut self, vcpu_id: usize, value: u64) -> Vec<Delivery> {
+        let sgi = ((value >> 24) & 0xf) as usize;
+        let targets: Vec<usize> = if value & ICC_SGI1R_IRM != 0 {
+            (0..self.vcpu_num).filter(|&i| i != vcpu_id).collect()
+        } else {
+            // `Aff3.Aff2.Aff1` of the target cluster, in the layout of `GICD_IROUTER`.
+            let cluster = (((value >> 48) & 0xff) << 32)
+                | (((value >> 32) & 0xff) << 16)
+                | (((value >> 16) & 0xff) << 8);
+            // The range selector picks which 16 `Aff0` values the target list covers.
+            let first_aff0 = ((value >> 44) & 0xf) * 16;
+            let target_list = value & 0xffff;
+            (0..self.vcpu_num)
+                .filter(|&i| {
+                    let aff = self.affinities[i];
+                    let aff0 = aff & 0xff;
+                    aff & !0xff == cluster
+                        && (first_aff0..first_aff0 + 16).contains(&aff0)
+                        && target_list & (1 << (aff0 - first_aff0)) != 0
+                })
+                .collect()
+        };
+        self.send_sgi(vcpu_id, sgi, targets)
+    }
+}
+
+/// Converts an `MPIDR_EL1` value into its affinity in the layout of `GICD_IROUTER`.
+fn mpidr_to_route(mpidr: u64) -> u64 {
+    mpidr & (0xff_0000_0000 | 0xff_ffff)
+}
+
+/// Injects the interrupts produced by a distributor access into their vCPUs.
+pub fn deliver(vm_id: usize, deliveries: Vec<Delivery>) {
+    for Delivery { vcpu_id, irq } in deliveries {
+        vcpus::inject_interrupt(vm_id, vcpu_id, irq);
+    }
+}
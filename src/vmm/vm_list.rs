@@ -47,7 +47,6 @@ impl VMList {
     /// # Returns
     ///
     /// Returns `Some(VMRef)` if the VM was successfully removed, or `None` if the VM with the given ID did not exist.
-    #[allow(unused)]
     fn remove_vm(&mut self, vm_id: usize) -> Option<VMRef> {
         self.vm_list.remove(&vm_id)
     }
@@ -87,7 +86,6 @@ pub fn push_vm(vm: VMRef) {
 /// # Returns
 ///
 /// * `Option<VMRef>` - The removed VM reference if it exists, or `None` if not.
-#[allow(unused)]
 pub fn remove_vm(vm_id: usize) -> Option<VMRef> {
     GLOBAL_VM_LIST.lock().remove_vm(vm_id)
 }
@@ -0,0 +1,179 @@
+//! Virtual PLIC for riscv64 guests.
+//!
+//! A VM owning an emulated PLIC (an `emu_devices` entry of type
+//! [`EmuType::InterruptController`]) gets its own platform interrupt controller instead of
+//! sharing the host's, so that several riscv64 guests can run concurrently and receive the
+//! physical interrupts forwarded to them.
+//!
+//! [`EmuType::InterruptController`]: devices::EmuType::InterruptController
+//!
+//! The PLIC has two contexts per vCPU, as on the QEMU `virt` machine: context `2 * i` for the
+//! M-mode of hart `i`, which is never signalled, and context `2 * i + 1` for its S-mode. The
+//! external interrupt line of the S-mode context is reflected into the VS-level external
+//! interrupt pending bit of `hvip` by the vCPU's own task, right before it enters the guest.
+
+extern crate alloc;
+
+mod plic;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use kspin::SpinNoIrq;
+
+use crate::vmm::devices::{self, EmuDevice};
+use crate::vmm::{irq, vcpus};
+use plic::Plic;
+
+/// The number of PLIC contexts of a vCPU, for its M-mode and S-mode.
+const CONTEXTS_PER_VCPU: usize = 2;
+
+/// Access to the `hvip` CSR of the current CPU.
+mod hvip {
+    use core::arch::asm;
+
+    /// VS-level external interrupt pending.
+    const HVIP_VSEIP: usize = 1 << 10;
+
+    /// Asserts or deasserts the VS-level external interrupt of the current CPU.
+    pub fn set_vseip(asserted: bool) {
+        // `hvip` is CSR 0x645.
+        if asserted {
+            unsafe { asm!("csrs 0x645, {}", in(reg) HVIP_VSEIP) };
+        } else {
+            unsafe { asm!("csrc 0x645, {}", in(reg) HVIP_VSEIP) };
+        }
+    }
+}
+
+/// The virtual PLIC of a VM.
+pub struct VPlic {
+    vm_id: usize,
+    vcpu_num: usize,
+    base: GuestPhysAddr,
+    size: usize,
+    plic: SpinNoIrq<Plic>,
+}
+
+impl VPlic {
+    /// Returns the S-mode context of a vCPU.
+    fn s_context(vcpu_id: usize) -> usize {
+        vcpu_id * CONTEXTS_PER_VCPU + 1
+    }
+
+    /// Raises a source, such as one receiving a forwarded physical interrupt.
+    pub fn raise(&self, source: usize) {
+        if !self.plic.lock().set_pending(source) {
+            warn!("VM[{}] vPLIC has no source {}", self.vm_id, source);
+            return;
+        }
+        self.notify_claimable();
+    }
+
+    /// Wakes up the vCPUs whose external interrupt line is asserted.
+    fn notify_claimable(&self) {
+        for vcpu_id in 0..self.vcpu_num {
+            if self.has_pending(vcpu_id) {
+                vcpus::notify_vcpu(self.vm_id, vcpu_id);
+            }
+        }
+    }
+
+    /// Reflects the external interrupt line of a vCPU into `hvip`.
+    /// Must be called on the vCPU's own task, right before it enters the guest.
+    pub fn vcpu_enter(&self, vcpu_id: usize) {
+        hvip::set_vseip(self.has_pending(vcpu_id));
+    }
+
+    /// Returns whether the external interrupt line of a vCPU is asserted.
+    pub fn has_pending(&self, vcpu_id: usize) -> bool {
+        self.plic.lock().has_claimable(Self::s_context(vcpu_id))
+    }
+}
+
+impl EmuDevice for VPlic {
+    fn name(&self) -> &str {
+        "vPLIC"
+    }
+
+    fn mmio_regions(&self) -> Vec<Range<GuestPhysAddr>> {
+        vec![self.base..self.base + self.size]
+    }
+
+    /// Handles a guest read of the PLIC, which only supports 32-bit accesses.
+    fn handle_mmio_read(&self, _vcpu_id: usize, addr: GuestPhysAddr, width: usize) -> usize {
+        if width != 4 {
+            return 0;
+        }
+        self.plic.lock().read(addr - self.base) as usize
+    }
+
+    /// Handles a guest write of the PLIC, which only supports 32-bit accesses.
+    fn handle_mmio_write(&self, _vcpu_id: usize, addr: GuestPhysAddr, width: usize, value: usize) {
+        if width != 4 {
+            return;
+        }
+        let completed = self.plic.lock().write(addr - self.base, value as u32);
+        if let Some(source) = completed {
+            irq::complete_irq(self.vm_id, source);
+        }
+        // Enabling a source or lowering a threshold may assert the line of another vCPU.
+        self.notify_claimable();
+    }
+}
+
+/// The virtual PLICs of all VMs, stored in a BTreeMap where the key is the VM ID.
+///
+/// Also accessed from interrupt context, by the forwarding of routed interrupts, hence the
+/// IRQ-safe lock.
+static VM_VPLICS: SpinNoIrq<BTreeMap<usize, Arc<VPlic>>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Creates the virtual PLIC of a VM.
+///
+/// # Arguments
+///
+/// * `vm_id` - The ID of the VM.
+/// * `vcpu_num` - The number of vCPUs of the VM.
+/// * `region` - The guest physical address and size of the emulated PLIC, which must cover
+///   the contexts of all the vCPUs.
+pub fn create_vm_vplic(
+    vm_id: usize,
+    vcpu_num: usize,
+    (base, size): (GuestPhysAddr, usize),
+) -> AxResult {
+    let nr_contexts = vcpu_num * CONTEXTS_PER_VCPU;
+    if size < plic::CONTEXT_BASE + nr_contexts * plic::CONTEXT_STRIDE {
+        return ax_err!(InvalidInput, "vPLIC region too small for the vCPU contexts");
+    }
+    info!(
+        "VM[{}] vPLIC at {:?}, {} sources, {} contexts",
+        vm_id,
+        base,
+        plic::NR_SOURCES,
+        nr_contexts
+    );
+    let vplic = Arc::new(VPlic {
+        vm_id,
+        vcpu_num,
+        base,
+        size,
+        plic: SpinNoIrq::new(Plic::new(nr_contexts)),
+    });
+    devices::register_device(vm_id, vplic.clone())?;
+    VM_VPLICS.lock().insert(vm_id, vplic);
+    Ok(())
+}
+
+/// Removes the virtual PLIC of a VM, called when the VM is torn down.
+pub fn remove_vm_vplic(vm_id: usize) -> Option<Arc<VPlic>> {
+    VM_VPLICS.lock().remove(&vm_id)
+}
+
+/// Retrieves the virtual PLIC of a VM, if it has one.
+pub fn get_vm_vplic(vm_id: usize) -> Option<Arc<VPlic>> {
+    VM_VPLICS.lock().get(&vm_id).cloned()
+}
@@ -0,0 +1,201 @@
+//! Register model of an emulated PLIC.
+//!
+//! The layout is the one of the SiFive PLIC found on the QEMU `virt` machine: a priority
+//! register per source, a pending bitmap, and per hart context an enable bitmap, a priority
+//! threshold and a claim/complete register.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+/// The number of interrupt sources, source 0 being reserved.
+pub const NR_SOURCES: usize = 128;
+const NR_WORDS: usize = NR_SOURCES / 32;
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// The threshold register of context 0, followed by its claim/complete register.
+pub const CONTEXT_BASE: usize = 0x20_0000;
+pub const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The priority levels implemented, 0 meaning never interrupt.
+const PRIORITY_MASK: u32 = 0x7;
+
+type SourceBitmap = [u32; NR_WORDS];
+
+fn test_bit(bitmap: &SourceBitmap, source: usize) -> bool {
+    bitmap[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bitmap: &mut SourceBitmap, source: usize, value: bool) {
+    if value {
+        bitmap[source / 32] |= 1 << (source % 32);
+    } else {
+        bitmap[source / 32] &= !(1 << (source % 32));
+    }
+}
+
+/// The state of a hart context.
+#[derive(Default)]
+struct Context {
+    enable: SourceBitmap,
+    threshold: u32,
+}
+
+/// The state of an emulated PLIC.
+pub struct Plic {
+    priority: [u32; NR_SOURCES],
+    pending: SourceBitmap,
+    /// The sources claimed by a context and not completed yet, which the gateway holds back.
+    claimed: SourceBitmap,
+    contexts: Vec<Context>,
+}
+
+impl Plic {
+    /// Creates a PLIC with `nr_contexts` hart contexts, all its sources disabled.
+    pub fn new(nr_contexts: usize) -> Self {
+        Self {
+            priority: [0; NR_SOURCES],
+            pending: [0; NR_WORDS],
+            claimed: [0; NR_WORDS],
+            contexts: (0..nr_contexts).map(|_| Context::default()).collect(),
+        }
+    }
+
+    /// Marks a source pending. Returns `false` if there is no such source.
+    pub fn set_pending(&mut self, source: usize) -> bool {
+        if source == 0 || source >= NR_SOURCES {
+            return false;
+        }
+        set_bit(&mut self.pending, source, true);
+        true
+    }
+
+    /// Returns the highest priority source a context may claim, ties going to the lowest ID.
+    fn best(&self, context: usize) -> Option<usize> {
+        let ctx = self.contexts.get(context)?;
+        let mut best: Option<(usize, u32)> = None;
+        for source in 1..NR_SOURCES {
+            if !test_bit(&self.pending, source)
+                || !test_bit(&ctx.enable, source)
+                || test_bit(&self.claimed, source)
+            {
+                continue;
+            }
+            let priority = self.priority[source];
+            if priority > ctx.threshold && best.is_none_or(|(_, p)| priority > p) {
+                best = Some((source, priority));
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    /// Returns whether a context has an interrupt to claim, i.e. whether its external
+    /// interrupt line is asserted.
+    pub fn has_claimable(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                set_bit(&mut self.pending, source, false);
+                set_bit(&mut self.claimed, source, true);
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Handles a guest read of the PLIC registers. Reading a claim register claims the
+    /// interrupt it returns.
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => self
+                .priority
+                .get((offset - PRIORITY_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            PENDING_BASE..ENABLE_BASE => self
+                .pending
+                .get((offset - PENDING_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                self.contexts
+                    .get(context)
+                    .and_then(|ctx| ctx.enable.get(word).copied())
+                    .unwrap_or(0)
+            }
+            _ => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= self.contexts.len() {
+                    return 0;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.contexts[context].threshold,
+                    CONTEXT_CLAIM => self.claim(context),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    /// Handles a guest write of the PLIC registers.
+    ///
+    /// # Returns
+    ///
+    /// The source completed by the write, if it is a completion of a claimed source
+    /// enabled for the context.
+    pub fn write(&mut self, offset: usize, value: u32) -> Option<usize> {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let source = (offset - PRIORITY_BASE) / 4;
+                if source != 0 && source < NR_SOURCES {
+                    self.priority[source] = value & PRIORITY_MASK;
+                }
+            }
+            // The pending bits are read-only.
+            PENDING_BASE..ENABLE_BASE => {}
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if let Some(enable) = self
+                    .contexts
+                    .get_mut(context)
+                    .and_then(|ctx| ctx.enable.get_mut(word))
+                {
+                    // Source 0 does not exist.
+                    *enable = if word == 0 { value & !1 } else { value };
+                }
+            }
+            _ => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= self.contexts.len() {
+                    return None;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.contexts[context].threshold = value & PRIORITY_MASK,
+                    CONTEXT_CLAIM => {
+                        let source = value as usize;
+                        if source != 0
+                            && source < NR_SOURCES
+                            && test_bit(&self.claimed, source)
+                            && test_bit(&self.contexts[context].enable, source)
+                        {
+                            set_bit(&mut self.claimed, source, false);
+                            return Some(source);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,135 @@
+//! The hypervisor endpoint of the vsock connections with the guests.
+//!
+//! The hypervisor is the host of the guests' virtio-vsock devices, with the well-known CID 2.
+//! In-hypervisor services listen on a vsock port through [`listen`] to accept connections
+//! from the guest agents, or [`connect`] to a port listened on by a guest, and then exchange
+//! data through the [`VsockConn`] passed to their [`VsockHandler`]. The `vsock` commands of
+//! the [hypervisor shell](super::shell) are such a service.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use spin::Mutex;
+
+/// The CID of the hypervisor, the host of the guests.
+pub const HOST_CID: u64 = 2;
+
+/// The first port of the connections initiated by the hypervisor.
+const FIRST_EPHEMERAL_PORT: u32 = 0x4000_0000;
+
+/// A stream connection between the hypervisor and a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VsockConn {
+    /// The ID of the VM of the guest.
+    pub vm_id: usize,
+    /// The port on the hypervisor side.
+    pub host_port: u32,
+    /// The port on the guest side.
+    pub guest_port: u32,
+}
+
+impl VsockConn {
+    /// Sends data to the guest, buffered until the guest has room for it.
+    pub fn send(&self, data: &[u8]) -> AxResult {
+        endpoint(self.vm_id)?.send(self, data)
+    }
+
+    /// Closes the connection.
+    pub fn close(&self) {
+        if let Ok(endpoint) = endpoint(self.vm_id) {
+            endpoint.close(self);
+        }
+    }
+}
+
+/// A service on the hypervisor side of vsock connections.
+///
+/// The callbacks are invoked on the task of a vCPU of the guest, and may send data.
+pub trait VsockHandler: Send + Sync {
+    /// The connection has been established.
+    fn connected(&self, _conn: &VsockConn) {}
+
+    /// Data has been received from the guest.
+    fn received(&self, conn: &VsockConn, data: &[u8]);
+
+    /// The connection has been closed, or reset, by the guest or by a device reset.
+    fn closed(&self, _conn: &VsockConn) {}
+}
+
+/// The vsock device of a guest, which carries its connections.
+pub(crate) trait VsockEndpoint: Send + Sync {
+    /// Sends data on an established connection.
+    fn send(&self, conn: &VsockConn, data: &[u8]) -> AxResult;
+
+    /// Closes a connection.
+    fn close(&self, conn: &VsockConn);
+
+    /// Requests a connection to a port of the guest.
+    fn connect(&self, conn: &VsockConn, handler: Arc<dyn VsockHandler>) -> AxResult;
+}
+
+/// The services listening on the hypervisor, stored in a BTreeMap where the key is the port.
+static LISTENERS: Mutex<BTreeMap<u32, Arc<dyn VsockHandler>>> = Mutex::new(BTreeMap::new());
+
+/// The vsock devices of all VMs, stored in a BTreeMap where the key is the VM ID.
+static ENDPOINTS: Mutex<BTreeMap<usize, Weak<dyn VsockEndpoint>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_EPHEMERAL_PORT: AtomicU32 = AtomicU32::new(FIRST_EPHEMERAL_PORT);
+
+/// Listens on the hypervisor port `port`, accepting the connections from all the guests.
+pub fn listen(port: u32, handler: Arc<dyn VsockHandler>) -> AxResult {
+    let mut listeners = LISTENERS.lock();
+    if listeners.contains_key(&port) {
+        return ax_err!(AlreadyExists, "vsock port already listened on");
+    }
+    listeners.insert(port, handler);
+    Ok(())
+}
+
+/// Stops listening on the hypervisor port `port`, established connections are kept.
+pub fn unlisten(port: u32) {
+    LISTENERS.lock().remove(&port);
+}
+
+/// Connects to the port `guest_port` of the guest of `vm_id`.
+///
+/// The connection is usable once [`VsockHandler::connected`] has been called.
+pub fn connect(
+    vm_id: usize,
+    guest_port: u32,
+    handler: Arc<dyn VsockHandler>,
+) -> AxResult<VsockConn> {
+    let conn = VsockConn {
+        vm_id,
+        host_port: NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed),
+        guest_port,
+    };
+    endpoint(vm_id)?.connect(&conn, handler)?;
+    Ok(conn)
+}
+
+/// Returns the service listening on the hypervisor port `port`.
+pub(crate) fn listener(port: u32) -> Option<Arc<dyn VsockHandler>> {
+    LISTENERS.lock().get(&port).cloned()
+}
+
+/// Registers the vsock device of a VM.
+pub(crate) fn register_endpoint(vm_id: usize, endpoint: Weak<dyn VsockEndpoint>) -> AxResult {
+    let mut endpoints = ENDPOINTS.lock();
+    if endpoints.contains_key(&vm_id) {
+        return ax_err!(AlreadyExists, "VM already has a vsock device");
+    }
+    endpoints.insert(vm_id, endpoint);
+    Ok(())
+}
+
+fn endpoint(vm_id: usize) -> AxResult<Arc<dyn VsockEndpoint>> {
+    match ENDPOINTS.lock().get(&vm_id).and_then(Weak::upgrade) {
+        Some(endpoint) => Ok(endpoint),
+        None => ax_err!(NotFound, "VM has no vsock device"),
+    }
+}
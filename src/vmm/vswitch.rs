@@ -0,0 +1,117 @@
+//! The in-hypervisor L2 switches, connecting the virtual NICs of the VMs.
+//!
+//! Each switch forwards the Ethernet frames sent by one of its ports to the port that the
+//! destination MAC address has been learnt on, and floods the broadcast, multicast and unknown
+//! unicast frames to all the other ports. Switches are created on the first port attached to
+//! them, and identified by a number chosen in the VM configs.
+//!
+//! Bridging a switch to a NIC of the host would be another [`SwitchPort`], but is not supported
+//! yet: the ArceOS network stack does not give access to raw Ethernet frames.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+/// An Ethernet MAC address.
+pub type MacAddr = [u8; 6];
+
+/// The maximum number of MAC addresses learnt by a switch, the table is flushed beyond.
+const MAX_FDB_ENTRIES: usize = 1024;
+
+/// A port of a switch, such as a virtio-net device.
+pub trait SwitchPort: Send + Sync {
+    /// Receives a frame forwarded by the switch.
+    fn deliver(&self, frame: &[u8]);
+}
+
+/// An L2 switch.
+#[derive(Default)]
+struct Switch {
+    next_port: usize,
+    ports: BTreeMap<usize, Weak<dyn SwitchPort>>,
+    /// The forwarding database, mapping the learnt MAC addresses to their port.
+    fdb: BTreeMap<MacAddr, usize>,
+}
+
+/// All the switches, stored in a BTreeMap where the key is the switch ID.
+static SWITCHES: Mutex<BTreeMap<usize, Switch>> = Mutex::new(BTreeMap::new());
+
+fn is_multicast(mac: &MacAddr) -> bool {
+    mac[0] & 1 != 0
+}
+
+/// Attaches a port to the switch `switch_id`, creating the switch if needed.
+///
+/// Returns the ID of the port in the switch, used to transmit frames.
+pub fn attach(switch_id: usize, port: Weak<dyn SwitchPort>) -> usize {
+    let mut switches = SWITCHES.lock();
+    let switch = switches.entry(switch_id).or_default();
+    let port_id = switch.next_port;
+    switch.next_port += 1;
+    switch.ports.insert(port_id, port);
+    debug!("vswitch {}: port {} attached", switch_id, port_id);
+    port_id
+}
+
+/// Detaches a port from a switch, generally called when its VM is destroyed.
+#[allow(unused)]
+pub fn detach(switch_id: usize, port_id: usize) {
+    let mut switches = SWITCHES.lock();
+    if let Some(switch) = switches.get_mut(&switch_id) {
+        switch.ports.remove(&port_id);
+        switch.fdb.retain(|_, port| *port != port_id);
+        if switch.ports.is_empty() {
+            switches.remove(&switch_id);
+        }
+    }
+}
+
+/// Sends a frame from the port `port_id` of the switch `switch_id`.
+pub fn transmit(switch_id: usize, port_id: usize, frame: &[u8]) {
+    if frame.len() < 14 {
+        return;
+    }
+    let mut dst = MacAddr::default();
+    let mut src = MacAddr::default();
+    dst.copy_from_slice(&frame[0..6]);
+    src.copy_from_slice(&frame[6..12]);
+
+    let targets: Vec<Arc<dyn SwitchPort>> = {
+        let mut switches = SWITCHES.lock();
+        let Some(switch) = switches.get_mut(&switch_id) else {
+            return;
+        };
+        if !is_multicast(&src) {
+            if switch.fdb.len() >= MAX_FDB_ENTRIES && !switch.fdb.contains_key(&src) {
+                switch.fdb.clear();
+            }
+            switch.fdb.insert(src, port_id);
+        }
+        let known = (!is_multicast(&dst))
+            .then(|| switch.fdb.get(&dst).copied())
+            .flatten();
+        match known {
+            Some(dst_port) if dst_port == port_id => Vec::new(),
+            Some(dst_port) => switch
+                .ports
+                .get(&dst_port)
+                .and_then(Weak::upgrade)
+                .into_iter()
+                .collect(),
+            None => switch
+                .ports
+                .iter()
+                .filter(|(id, _)| **id != port_id)
+                .filter_map(|(_, port)| port.upgrade())
+                .collect(),
+        }
+    };
+    // Deliver without holding the switches, a port may transmit in turn.
+    for port in targets {
+        port.deliver(frame);
+    }
+}
@@ -0,0 +1,79 @@
+//! Emulation of the aarch64 EL1 physical timer.
+//!
+//! The guest programs the timer through the trapped `CNTP_*_EL0` system registers, whose
+//! compare values are expressed in the physical counter, shared with the host. The virtual
+//! offset `CNTVOFF_EL2` only applies to the virtual timer, whose `CNTV_*_EL0` registers are not
+//! trapped: the guest uses it directly.
+
+use std::os::arceos::modules::axhal;
+
+use super::VirtTimer;
+
+/// Non-secure EL1 physical timer PPI.
+const CNTPNS_IRQ: usize = 30;
+
+const CNTP_TVAL_EL0: usize = sysreg_enc(3, 3, 14, 2, 0);
+const CNTP_CTL_EL0: usize = sysreg_enc(3, 3, 14, 2, 1);
+const CNTP_CVAL_EL0: usize = sysreg_enc(3, 3, 14, 2, 2);
+
+const CNT_CTL_ENABLE: u64 = 1 << 0;
+const CNT_CTL_IMASK: u64 = 1 << 1;
+const CNT_CTL_ISTATUS: u64 = 1 << 2;
+
+/// Encodes a system register in the layout of the ISS field of an `MSR`/`MRS` trap.
+const fn sysreg_enc(op0: usize, op1: usize, crn: usize, crm: usize, op2: usize) -> usize {
+    (op0 << 20) | (op2 << 17) | (op1 << 14) | (crn << 10) | (crm << 1)
+}
+
+/// The guest-visible EL1 physical timer registers.
+#[derive(Default)]
+pub struct TimerRegs {
+    ctl: u64,
+    cval: u64,
+}
+
+impl VirtTimer {
+    /// Handles a trapped guest read of a timer system register.
+    ///
+    /// Returns `None` if `addr` is not a timer register.
+    pub fn handle_sysreg_read(&self, addr: usize) -> Option<u64> {
+        let inner = self.inner.lock();
+        let regs = &inner.regs;
+        let now = axhal::time::current_ticks();
+        match addr {
+            CNTP_CTL_EL0 => {
+                let fired = regs.ctl & CNT_CTL_ENABLE != 0 && now >= regs.cval;
+                Some(regs.ctl | if fired { CNT_CTL_ISTATUS } else { 0 })
+            }
+            CNTP_CVAL_EL0 => Some(regs.cval),
+            // TVAL is a signed 32-bit down-counter derived from CVAL.
+            CNTP_TVAL_EL0 => Some(regs.cval.wrapping_sub(now) as u32 as u64),
+            _ => None,
+        }
+    }
+
+    /// Handles a trapped guest write of a timer system register.
+    ///
+    /// Returns `false` if `addr` is not a timer register.
+    pub fn handle_sysreg_write(&self, addr: usize, value: u64) -> bool {
+        let mut inner = self.inner.lock();
+        match addr {
+            CNTP_CTL_EL0 => inner.regs.ctl = value & (CNT_CTL_ENABLE | CNT_CTL_IMASK),
+            CNTP_CVAL_EL0 => inner.regs.cval = value,
+            CNTP_TVAL_EL0 => {
+                inner.regs.cval =
+                    axhal::time::current_ticks().wrapping_add(value as i32 as i64 as u64)
+            }
+            _ => return false,
+        }
+
+        let ctl = inner.regs.ctl;
+        if ctl & CNT_CTL_ENABLE != 0 && ctl & CNT_CTL_IMASK == 0 {
+            let deadline = axhal::time::ticks_to_nanos(inner.regs.cval);
+            self.arm(&mut inner, deadline, None, CNTPNS_IRQ);
+        } else {
+            self.disarm(&mut inner);
+        }
+        true
+    }
+}
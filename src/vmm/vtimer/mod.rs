@@ -0,0 +1,146 @@
+//! Emulated per-vCPU guest timer.
+//!
+//! Each vCPU task owns a [`VirtTimer`] (see [`crate::task::TaskExt`]), which translates the
+//! guest's architectural timer programming into a [`VmmTimerEvent`](super::timer::VmmTimerEvent)
+//! registered through [`super::timer`]. When the event expires, the timer interrupt is
+//! injected into the owning vCPU through [`super::vcpus::inject_interrupt`].
+//!
+//! The architecture-specific front-ends decode the trapped guest accesses:
+//! * aarch64: EL1 physical timer system registers (`CNTP_CTL_EL0`, `CNTP_CVAL_EL0`, `CNTP_TVAL_EL0`).
+//! * riscv64: SBI `set_timer` calls and `stimecmp` writes, the interrupt being reflected into
+//!   `hvip` as the VS-level timer interrupt.
+//! * x86_64: the x2APIC LAPIC timer in one-shot, periodic and TSC-deadline modes.
+
+extern crate alloc;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+use self::aarch64 as arch;
+#[cfg(target_arch = "riscv64")]
+use self::riscv64 as arch;
+#[cfg(target_arch = "x86_64")]
+use self::x86_64 as arch;
+
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::{IRQ_S_TIMER, SBI_EXT_TIME};
+
+use alloc::sync::Arc;
+
+use kspin::SpinNoIrq;
+
+use crate::vmm::{timer, vcpus};
+
+/// The state of a [`VirtTimer`], shared with its pending timer event.
+struct VirtTimerInner {
+    /// The token of the pending timer event, if the timer is armed.
+    token: Option<usize>,
+    /// The absolute host deadline in nanoseconds of the pending timer event.
+    deadline: u64,
+    /// The reload period in nanoseconds, for periodic timers.
+    period: Option<u64>,
+    /// Incremented each time the timer is re-programmed, so that a stale event which has
+    /// already been picked by [`timer::check_events`] does not inject an interrupt.
+    generation: u64,
+    /// The guest-visible timer registers.
+    regs: arch::TimerRegs,
+}
+
+/// An emulated guest timer belonging to a single vCPU.
+pub struct VirtTimer {
+    vm_id: usize,
+    vcpu_id: usize,
+    inner: Arc<SpinNoIrq<VirtTimerInner>>,
+}
+
+impl VirtTimer {
+    /// Creates a new, disarmed timer for the given vCPU.
+    pub fn new(vm_id: usize, vcpu_id: usize) -> Self {
+        Self {
+            vm_id,
+            vcpu_id,
+            inner: Arc::new(SpinNoIrq::new(VirtTimerInner {
+                token: None,
+                deadline: 0,
+                period: None,
+                generation: 0,
+                regs: arch::TimerRegs::default(),
+            })),
+        }
+    }
+
+    /// Arms the timer to inject `irq` into the vCPU at `deadline` (host nanoseconds),
+    /// and then every `period` nanoseconds if given.
+    ///
+    /// Any previously armed deadline is canceled.
+    fn arm(&self, inner: &mut VirtTimerInner, deadline: u64, period: Option<u64>, irq: usize) {
+        Self::disarm_locked(inner);
+        inner.period = period;
+        Self::arm_locked(
+            self.inner.clone(),
+            inner,
+            self.vm_id,
+            self.vcpu_id,
+            deadline,
+            irq,
+        );
+    }
+
+    /// Cancels the pending deadline of the timer, if any.
+    fn disarm(&self, inner: &mut VirtTimerInner) {
+        Self::disarm_locked(inner);
+        inner.period = None;
+    }
+
+    fn disarm_locked(inner: &mut VirtTimerInner) {
+        inner.generation = inner.generation.wrapping_add(1);
+        if let Some(token) = inner.token.take() {
+            timer::cancel_timer(token);
+        }
+    }
+
+    fn arm_locked(
+        shared: Arc<SpinNoIrq<VirtTimerInner>>,
+        inner: &mut VirtTimerInner,
+        vm_id: usize,
+        vcpu_id: usize,
+        deadline: u64,
+        irq: usize,
+    ) {
+        let generation = inner.generation;
+        inner.deadline = deadline;
+        inner.token = Some(timer::register_vcpu_timer(
+            vm_id,
+            vcpu_id,
+            deadline,
+            move |_now| {
+                let mut inner = shared.lock();
+                if inner.generation != generation {
+                    return;
+                }
+                inner.token = None;
+                trace!(
+                    "VM[{}] VCpu[{}] vtimer fired, inject irq {}",
+                    vm_id, vcpu_id, irq
+                );
+                vcpus::inject_interrupt(vm_id, vcpu_id, irq);
+
+                if let Some(period) = inner.period {
+                    let next = inner.deadline + period;
+                    Self::arm_locked(shared.clone(), &mut inner, vm_id, vcpu_id, next, irq);
+                }
+            },
+        ));
+    }
+}
+
+impl Drop for VirtTimer {
+    fn drop(&mut self) {
+        Self::disarm_locked(&mut self.inner.lock());
+    }
+}
@@ -0,0 +1,95 @@
+//! Emulation of the RISC-V supervisor timer.
+//!
+//! The guest programs the timer either through the SBI `set_timer` call or, without the Sstc
+//! extension, by writing the trapped `stimecmp` CSR. Both take an absolute value of the `time`
+//! counter, which the guest shares with the host.
+//!
+//! The VS-level timer interrupt is a level: it stays pending while `time` is past `stimecmp`,
+//! until the guest programs a later deadline. The event armed for the deadline only wakes the
+//! vCPU up, the level itself is reflected into `hvip` by [`VirtTimer::vcpu_enter`] right before
+//! the vCPU enters the guest.
+
+use core::arch::asm;
+
+use std::os::arceos::modules::axhal;
+
+use super::VirtTimer;
+
+/// The SBI Timer extension ID ("TIME").
+pub const SBI_EXT_TIME: u64 = 0x5449_4D45;
+
+/// The `stimecmp` CSR number.
+const CSR_STIMECMP: usize = 0x14D;
+
+/// Supervisor timer interrupt.
+pub const IRQ_S_TIMER: usize = 5;
+
+/// VS-level timer interrupt pending.
+const HVIP_VSTIP: usize = 1 << 6;
+
+/// The guest-visible supervisor timer registers.
+pub struct TimerRegs {
+    stimecmp: u64,
+}
+
+impl Default for TimerRegs {
+    fn default() -> Self {
+        Self { stimecmp: u64::MAX }
+    }
+}
+
+impl VirtTimer {
+    /// Handles an SBI `set_timer` call with the absolute `stime_value`, which also clears the
+    /// pending timer interrupt if the new deadline is in the future.
+    pub fn handle_sbi_set_timer(&self, stime_value: u64) {
+        let mut inner = self.inner.lock();
+        inner.regs.stimecmp = stime_value;
+        if stime_value == u64::MAX {
+            self.disarm(&mut inner);
+        } else {
+            let deadline = axhal::time::ticks_to_nanos(stime_value);
+            self.arm(&mut inner, deadline, None, IRQ_S_TIMER);
+        }
+    }
+
+    /// Returns whether the deadline of the guest has passed, i.e. whether its timer interrupt
+    /// is pending.
+    pub fn is_pending(&self) -> bool {
+        axhal::time::current_ticks() >= self.inner.lock().regs.stimecmp
+    }
+
+    /// Asserts or deasserts the VS-level timer interrupt of the current CPU, depending on
+    /// whether the deadline of the guest has passed. Must be called on the vCPU's own task,
+    /// right before it enters the guest.
+    pub fn vcpu_enter(&self) {
+        // `hvip` is CSR 0x645.
+        if self.is_pending() {
+            unsafe { asm!("csrs 0x645, {}", in(reg) HVIP_VSTIP) };
+        } else {
+            unsafe { asm!("csrc 0x645, {}", in(reg) HVIP_VSTIP) };
+        }
+    }
+
+    /// Handles a trapped guest read of a timer CSR.
+    ///
+    /// Returns `None` if `addr` is not a timer CSR.
+    pub fn handle_sysreg_read(&self, addr: usize) -> Option<u64> {
+        match addr {
+            CSR_STIMECMP => Some(self.inner.lock().regs.stimecmp),
+            _ => None,
+        }
+    }
+
+    /// Handles a trapped guest write of a timer CSR.
+    ///
+    /// Returns `false` if `addr` is not a timer CSR.
+    pub fn handle_sysreg_write(&self, addr: usize, value: u64) -> bool {
+        match addr {
+            CSR_STIMECMP => {
+                self.handle_sbi_set_timer(value);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Emulation of the x2APIC LAPIC timer.
+//!
+//! The timer supports the one-shot, periodic and TSC-deadline modes selected by the LVT timer
+//! register. The initial count decrements at [`APIC_TIMER_FREQ_HZ`] divided by the divide
+//! configuration, and TSC deadlines are expressed in the host TSC, which the guest shares.
+//!
+//! The timer keeps counting while it is masked, it is only its interrupt which is not
+//! delivered, so the expiry of the current count is tracked apart from the timer event, which
+//! is armed or disarmed again after every write.
+
+use std::os::arceos::modules::axhal;
+
+use super::VirtTimer;
+
+/// The frequency at which the emulated LAPIC timer counts before division.
+const APIC_TIMER_FREQ_HZ: u64 = 1_000_000_000;
+
+const MSR_IA32_TSC_DEADLINE: usize = 0x6E0;
+const MSR_X2APIC_LVT_TIMER: usize = 0x832;
+const MSR_X2APIC_INIT_COUNT: usize = 0x838;
+const MSR_X2APIC_CUR_COUNT: usize = 0x839;
+const MSR_X2APIC_DIV_CONF: usize = 0x83E;
+
+const LVT_VECTOR_MASK: u32 = 0xff;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << LVT_TIMER_MODE_SHIFT;
+
+const TIMER_MODE_ONE_SHOT: u32 = 0b00;
+const TIMER_MODE_PERIODIC: u32 = 0b01;
+const TIMER_MODE_TSC_DEADLINE: u32 = 0b10;
+
+/// The guest-visible LAPIC timer registers.
+pub struct TimerRegs {
+    lvt: u32,
+    initial_count: u32,
+    divide_conf: u32,
+    tsc_deadline: u64,
+    /// The host time in nanoseconds at which the current count, or the first period of a
+    /// periodic count, expires, or the TSC deadline does.
+    expiry: Option<u64>,
+}
+
+impl Default for TimerRegs {
+    fn default() -> Self {
+        Self {
+            lvt: LVT_MASKED,
+            initial_count: 0,
+            divide_conf: 0,
+            tsc_deadline: 0,
+            expiry: None,
+        }
+    }
+}
+
+impl TimerRegs {
+    fn mode(&self) -> u32 {
+        (self.lvt & LVT_TIMER_MODE_MASK) >> LVT_TIMER_MODE_SHIFT
+    }
+
+    fn vector(&self) -> usize {
+        (self.lvt & LVT_VECTOR_MASK) as usize
+    }
+
+    /// Returns the divisor selected by the divide configuration register.
+    fn divisor(&self) -> u64 {
+        let value = (self.divide_conf & 0b11) | ((self.divide_conf >> 1) & 0b100);
+        if value == 0b111 { 1 } else { 2 << value }
+    }
+
+    /// Returns the duration in nanoseconds of `count` timer ticks.
+    ///
+    /// Computed on 128 bits, the product of a 32-bit count, a divisor of up to 128 and
+    /// `NANOS_PER_SEC` overflowing 64 bits.
+    fn count_to_nanos(&self, count: u64) -> u64 {
+        let nanos = count as u128 * self.divisor() as u128 * axhal::time::NANOS_PER_SEC as u128
+            / APIC_TIMER_FREQ_HZ as u128;
+        nanos.min(u64::MAX as u128) as u64
+    }
+
+    /// Returns the reload period in nanoseconds of a periodic count.
+    fn period(&self) -> Option<u64> {
+        (self.mode() == TIMER_MODE_PERIODIC && self.initial_count != 0)
+            .then(|| self.count_to_nanos(self.initial_count as u64).max(1))
+    }
+
+    /// Returns the next expiry after `now` of the current count or deadline, if any.
+    fn next_expiry(&self, now: u64) -> Option<u64> {
+        let expiry = self.expiry?;
+        match self.period() {
+            Some(period) if expiry < now => Some(expiry + ((now - expiry) / period + 1) * period),
+            // A TSC deadline written in the past expires right away.
+            _ => (expiry >= now).then_some(expiry),
+        }
+    }
+}
+
+/// Converts an absolute TSC value into a host time in nanoseconds.
+///
+/// `axhal` counts its ticks from boot, so the distance to the current TSC is converted instead
+/// of the raw value.
+fn tsc_to_nanos(tsc: u64) -> u64 {
+    let remaining = tsc.saturating_sub(unsafe { core::arch::x86_64::_rdtsc() });
+    axhal::time::monotonic_time_nanos().saturating_add(axhal::time::ticks_to_nanos(remaining))
+}
+
+impl VirtTimer {
+    /// Handles a trapped guest read of a LAPIC timer MSR.
+    ///
+    /// Returns `None` if `addr` is not a timer MSR.
+    pub fn handle_sysreg_read(&self, addr: usize) -> Option<u64> {
+        let inner = self.inner.lock();
+        let regs = &inner.regs;
+        match addr {
+            MSR_X2APIC_LVT_TIMER => Some(regs.lvt as u64),
+            MSR_X2APIC_INIT_COUNT => Some(regs.initial_count as u64),
+            MSR_X2APIC_DIV_CONF => Some(regs.divide_conf as u64),
+            MSR_IA32_TSC_DEADLINE => Some(regs.tsc_deadline),
+            MSR_X2APIC_CUR_COUNT => {
+                if regs.mode() == TIMER_MODE_TSC_DEADLINE {
+                    return Some(0);
+                }
+                let now = axhal::time::monotonic_time_nanos();
+                let remaining = regs.next_expiry(now).map_or(0, |expiry| expiry - now);
+                Some(remaining / regs.count_to_nanos(1).max(1))
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a trapped guest write of a LAPIC timer MSR.
+    ///
+    /// Returns `false` if `addr` is not a timer MSR.
+    pub fn handle_sysreg_write(&self, addr: usize, value: u64) -> bool {
+        let mut inner = self.inner.lock();
+        let regs = &mut inner.regs;
+        let now = axhal::time::monotonic_time_nanos();
+        match addr {
+            MSR_X2APIC_LVT_TIMER => {
+                let old_mode = regs.mode();
+                regs.lvt = value as u32;
+                // Switching the timer mode stops the timer.
+                if regs.mode() != old_mode {
+                    regs.initial_count = 0;
+                    regs.tsc_deadline = 0;
+                    regs.expiry = None;
+                }
+            }
+            // Takes effect on the next write of the initial count.
+            MSR_X2APIC_DIV_CONF => regs.divide_conf = value as u32,
+            MSR_X2APIC_INIT_COUNT => {
+                regs.initial_count = value as u32;
+                regs.expiry = (regs.mode() != TIMER_MODE_TSC_DEADLINE && regs.initial_count != 0)
+                    .then(|| now + regs.count_to_nanos(regs.initial_count as u64));
+            }
+            MSR_IA32_TSC_DEADLINE => {
+                regs.tsc_deadline = value;
+                regs.expiry = (regs.mode() == TIMER_MODE_TSC_DEADLINE && value != 0)
+                    .then(|| tsc_to_nanos(value));
+            }
+            _ => return false,
+        }
+
+        // Also re-arms an unmasked timer, or one whose vector changed.
+        let regs = &inner.regs;
+        match regs.next_expiry(now) {
+            // A masked timer never delivers its interrupt, so there is nothing to arm.
+            Some(deadline) if regs.lvt & LVT_MASKED == 0 => {
+                let (period, vector) = (regs.period(), regs.vector());
+                self.arm(&mut inner, deadline, period, vector)
+            }
+            Some(_) | None => self.disarm(&mut inner),
+        }
+        true
+    }
+}